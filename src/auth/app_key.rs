@@ -48,12 +48,20 @@ impl AuthProvider for AppKeyAuthProvider {
         let result = AuthResult {
             client_id: client.client_id.clone(), 
             sla: sla.clone(),
+            roles: client.roles.clone(),
+            scopes: Vec::new(),
         };
-        return Ok((head, result));
+        Ok((head, result))
     }
 }
 
 
+impl Default for AppKeyAuthProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl AppKeyAuthProvider {
 
     pub fn new() -> Self {
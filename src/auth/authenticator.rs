@@ -24,6 +24,9 @@ pub enum GatewayAuthError {
     #[error("Invalid jwt issuer")]
     InvalidIssuer,
 
+    #[error("Method not allowed")]
+    MethodNotAllowed(String), // methods allowed, for the Allow header
+
     #[error("Unknown auth error")]
     Unknown,
 }
@@ -35,20 +38,18 @@ pub struct AuthResponse {
     pub sla: String,
     pub service_filters: Vec<FilterSetting>,
     pub client_filters: Vec<FilterSetting>,
+    pub middlewares: Vec<String>, // chain of the service outermost first, default chain if empty
 }
 
+pub type AuthResultSender = oneshot::Sender<Result<(Parts, AuthResponse), GatewayAuthError>>;
+
 pub struct AuthRequest {
     pub head: Parts,
-    pub result: oneshot::Sender<Result<(Parts, AuthResponse), GatewayAuthError>>,
+    pub result: AuthResultSender,
 }
 
 impl AuthRequest {
-    pub fn into_parts(
-        self,
-    ) -> (
-        Parts,
-        oneshot::Sender<Result<(Parts, AuthResponse), GatewayAuthError>>,
-    ) {
+    pub fn into_parts(self) -> (Parts, AuthResultSender) {
         (self.head, self.result)
     }
 }
@@ -58,12 +59,16 @@ pub struct ServiceAuthInfo {
     pub auth: AuthSetting,
     pub filters: Vec<FilterSetting>,
     pub slas: HashMap<String, Vec<FilterSetting>>,
+    pub middlewares: Vec<String>,
+    pub allowed_methods: Vec<String>,  // all methods if empty
 }
 
 #[derive(Debug, Clone)]
 pub struct AuthResult {
     pub client_id: String,
     pub sla: String,
+    pub roles: Vec<String>,
+    pub scopes: Vec<String>, // from the scope claim of a JWT, empty for other auth
 }
 
 pub trait AuthProvider {
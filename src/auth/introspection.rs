@@ -0,0 +1,227 @@
+use super::{authenticator::GatewayAuthError, AuthProvider, AuthResult};
+use crate::config::{AuthSetting, ClientInfo, ConfigUpdate, IntrospectionAuth};
+use crate::middleware::upstream_tls_config;
+use hyper::client::HttpConnector;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::http::request::Parts;
+use hyper::{Body, Client, Request};
+use hyper_rustls::HttpsConnector;
+use lru::LruCache;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{event, Level};
+
+/// Seconds an introspection result is reused if the service sets no `cache_ttl`
+pub const DEFAULT_INTROSPECTION_TTL: u64 = 60;
+
+// longest wait for the introspection endpoint, auth of other requests waits meanwhile
+const INTROSPECTION_TIMEOUT: Duration = Duration::from_secs(3);
+
+// fields of an introspection response used by the gateway, RFC 7662 section 2.2
+#[derive(Debug, Clone, Deserialize)]
+struct Introspection {
+    active: bool,
+    #[serde(default)]
+    client_id: Option<String>,
+    #[serde(default)]
+    scope: Option<String>, // space separated
+    #[serde(default)]
+    exp: Option<u64>,
+}
+
+/// Identify clients by opaque bearer tokens, checked at the introspection endpoint of the
+/// service. The `client_id` of an active token is looked up in the configured clients.
+#[derive(Debug)]
+pub struct IntrospectionAuthProvider {
+    apps: HashMap<String, ClientInfo>,
+    settings: HashMap<String, IntrospectionAuth>, // settings[service_id]
+    cache: Mutex<LruCache<(String, String), (Introspection, Instant)>>, // (url, token) -> result and when it expires
+}
+
+impl AuthProvider for IntrospectionAuthProvider {
+    fn update_config(&mut self, update: ConfigUpdate) {
+        match update {
+            ConfigUpdate::ClientUpdate(client) => {
+                self.apps.insert(client.client_id.clone(), client);
+            }
+            ConfigUpdate::ClientRemove(cid) => {
+                self.apps.remove(&cid);
+            }
+            ConfigUpdate::ServiceUpdate(service) => match service.auth {
+                AuthSetting::Introspection(setting) => {
+                    self.settings.insert(service.service_id, setting);
+                }
+                _ => {
+                    self.settings.remove(&service.service_id);
+                }
+            },
+            ConfigUpdate::ServiceRemove(sid) => {
+                self.settings.remove(&sid);
+            }
+            _ => {}
+        }
+    }
+
+    fn identify_client(
+        &self,
+        head: Parts,
+        service_id: &str,
+    ) -> Result<(Parts, AuthResult), GatewayAuthError> {
+        let setting = self
+            .settings
+            .get(service_id)
+            .ok_or(GatewayAuthError::UnknownService)?;
+        let key = (setting.url.clone(), Self::extract_token(&head)?);
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(&key)
+            .filter(|(_, expires)| *expires > Instant::now())
+            .map(|(result, _)| result.clone());
+        let result = match cached {
+            Some(result) => result,
+            None => {
+                let result = Self::introspect(setting, &key.1)?;
+                let ttl = match setting.cache_ttl {
+                    0 => DEFAULT_INTROSPECTION_TTL,
+                    n => n,
+                };
+                let ttl = match result.exp {
+                    Some(exp) => ttl.min(exp.saturating_sub(Self::now())),
+                    None => ttl,
+                };
+                let expires = Instant::now() + Duration::from_secs(ttl);
+                self.cache
+                    .lock()
+                    .unwrap()
+                    .put(key, (result.clone(), expires));
+                result
+            }
+        };
+        if !result.active || result.exp.is_some_and(|exp| exp <= Self::now()) {
+            return Err(GatewayAuthError::InvalidToken);
+        }
+        let client = result
+            .client_id
+            .as_ref()
+            .and_then(|cid| self.apps.get(cid))
+            .ok_or(GatewayAuthError::UnknownClient)?;
+        let sla = client
+            .services
+            .get(service_id)
+            .ok_or(GatewayAuthError::InvalidSLA)?;
+        Ok((
+            head,
+            AuthResult {
+                client_id: client.client_id.clone(),
+                sla: sla.clone(),
+                roles: client.roles.clone(),
+                scopes: result
+                    .scope
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect(),
+            },
+        ))
+    }
+}
+
+impl Default for IntrospectionAuthProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IntrospectionAuthProvider {
+    pub fn new() -> Self {
+        IntrospectionAuthProvider {
+            apps: HashMap::new(),
+            settings: HashMap::new(),
+            cache: Mutex::new(LruCache::new(1024)),
+        }
+    }
+
+    fn extract_token(head: &Parts) -> Result<String, GatewayAuthError> {
+        let value = head
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .ok_or(GatewayAuthError::TokenNotFound)?;
+        match value.split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") && !token.is_empty() => {
+                Ok(token.trim().to_string())
+            }
+            _ => Err(GatewayAuthError::TokenNotFound),
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+
+    // auth providers are not async, the endpoint is called on a thread of its own
+    // while the auth service waits, at most INTROSPECTION_TIMEOUT
+    fn introspect(
+        setting: &IntrospectionAuth,
+        token: &str,
+    ) -> Result<Introspection, GatewayAuthError> {
+        let credentials = format!("{}:{}", setting.client_id, setting.client_secret);
+        let form =
+            serde_urlencoded::to_string([("token", token), ("token_type_hint", "access_token")])
+                .map_err(|_| GatewayAuthError::InvalidToken)?;
+        let request = Request::post(&setting.url)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(
+                AUTHORIZATION,
+                format!("Basic {}", base64::encode(credentials)),
+            )
+            .body(Body::from(form))
+            .map_err(|_| GatewayAuthError::Unknown)?;
+        let call = std::thread::spawn(move || -> Result<Introspection, String> {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(|e| e.to_string())?;
+            runtime.block_on(async {
+                let mut http = HttpConnector::new();
+                http.enforce_http(false);
+                let tls_config = upstream_tls_config(None);
+                let client: Client<_, Body> =
+                    Client::builder().build(HttpsConnector::from((http, tls_config)));
+                let call = async {
+                    let resp = client.request(request).await.map_err(|e| e.to_string())?;
+                    if !resp.status().is_success() {
+                        return Err(format!("status {}", resp.status()));
+                    }
+                    let body = hyper::body::to_bytes(resp.into_body())
+                        .await
+                        .map_err(|e| e.to_string())?;
+                    serde_json::from_slice(&body).map_err(|e| e.to_string())
+                };
+                tokio::time::timeout(INTROSPECTION_TIMEOUT, call)
+                    .await
+                    .map_err(|_| "timeout".to_string())?
+            })
+        });
+        match call.join() {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(e)) => {
+                event!(
+                    Level::WARN,
+                    "token introspection at {} failed: {}",
+                    setting.url,
+                    e
+                );
+                Err(GatewayAuthError::Unknown)
+            }
+            Err(_) => Err(GatewayAuthError::Unknown),
+        }
+    }
+}
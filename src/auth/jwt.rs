@@ -1,16 +1,23 @@
 use super::{authenticator::GatewayAuthError, AuthProvider, AuthResult};
-use crate::config::{ClientInfo, ConfigUpdate};
+use crate::config::{AuthSetting, ClientInfo, ConfigUpdate};
 use hyper::http::request::Parts;
 use jsonwebtoken::{decode, decode_header, errors, Algorithm, DecodingKey, Validation};
 use lru::LruCache;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::{collections::HashMap, sync::Mutex};
 use tracing::{event, Level};
 
+/// Claim with the client's scopes if the service sets no `scope_claim`
+pub const DEFAULT_SCOPE_CLAIM: &str = "scope";
+
+type Claims = HashMap<String, Value>;
+
 #[derive(Debug)]
 pub struct JWTAuthProvider {
     apps: HashMap<String, ClientInfo>,
-    token_cache: Mutex<LruCache<String, String>>,
+    scope_claims: HashMap<String, String>, // scope_claims[service_id], set by JWT services
+    token_cache: Mutex<LruCache<String, (String, Claims)>>, // token -> app_key and claims of verified tokens
 }
 
 impl AuthProvider for JWTAuthProvider {
@@ -23,6 +30,18 @@ impl AuthProvider for JWTAuthProvider {
             ConfigUpdate::ClientRemove(cid) => {
                 self.apps.remove(&cid);
             }
+            ConfigUpdate::ServiceUpdate(service) => match service.auth {
+                AuthSetting::JWT(jwt) if !jwt.scope_claim.is_empty() => {
+                    self.scope_claims
+                        .insert(service.service_id, jwt.scope_claim);
+                }
+                _ => {
+                    self.scope_claims.remove(&service.service_id);
+                }
+            },
+            ConfigUpdate::ServiceRemove(sid) => {
+                self.scope_claims.remove(&sid);
+            }
             _ => {}
         }
     }
@@ -45,35 +64,42 @@ impl AuthProvider for JWTAuthProvider {
 
         // check cache
         let mut cache = self.token_cache.lock().unwrap();
-        if let Some(cached_key) = cache.get(&token) {
+        let claims = if let Some((cached_key, claims)) = cache.get(&token) {
             event!(
                 Level::DEBUG,
                 "cached data {} {}",
                 cached_key,
                 client.app_key
             );
-            if cached_key.eq(&client.app_key) {
-                return Ok((
-                    head,
-                    AuthResult {
-                        client_id: client.client_id.clone(),
-                        sla: sla.clone(),
-                    },
-                ));
-            } else {
+            if !cached_key.eq(&client.app_key) {
                 return Err(GatewayAuthError::InvalidToken);
             }
+            claims.clone()
         } else {
-            Self::verify_token(token.clone(), &client.pub_key)?;
-            cache.put(token, client.app_key.clone());
-            return Ok((
-                head,
-                AuthResult {
-                    client_id: client.client_id.clone(),
-                    sla: sla.clone(),
-                },
-            ));
-        }
+            let claims = Self::verify_token(token.clone(), &client.pub_key)?.extra;
+            cache.put(token, (client.app_key.clone(), claims.clone()));
+            claims
+        };
+        let claim = self
+            .scope_claims
+            .get(service_id)
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_SCOPE_CLAIM);
+        Ok((
+            head,
+            AuthResult {
+                client_id: client.client_id.clone(),
+                sla: sla.clone(),
+                roles: client.roles.clone(),
+                scopes: Self::scopes_of(claims.get(claim)),
+            },
+        ))
+    }
+}
+
+impl Default for JWTAuthProvider {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -81,6 +107,7 @@ impl JWTAuthProvider {
     pub fn new() -> Self {
         JWTAuthProvider {
             apps: HashMap::new(),
+            scope_claims: HashMap::new(),
             token_cache: Mutex::new(LruCache::new(1024)),
         }
     }
@@ -106,11 +133,23 @@ impl JWTAuthProvider {
         }
     }
 
-    fn verify_token(token: String, pubkey: &str) -> Result<(), GatewayAuthError> {
+    // scopes of a claim, space separated like OAuth `scope` or a list like `roles`
+    fn scopes_of(claim: Option<&Value>) -> Vec<String> {
+        match claim {
+            Some(Value::String(scopes)) => scopes.split_whitespace().map(String::from).collect(),
+            Some(Value::Array(scopes)) => scopes
+                .iter()
+                .filter_map(|s| s.as_str().map(String::from))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn verify_token(token: String, pubkey: &str) -> Result<JwtClaims, GatewayAuthError> {
         let verify_key = DecodingKey::from_secret(pubkey.as_bytes());
         let validation = Validation::new(Algorithm::HS256);
         match decode::<JwtClaims>(&token, &verify_key, &validation) {
-            Ok(_) => Ok(()),
+            Ok(data) => Ok(data.claims),
             Err(err) => match *err.kind() {
                 errors::ErrorKind::InvalidToken => Err(GatewayAuthError::InvalidToken),
                 errors::ErrorKind::InvalidIssuer => Err(GatewayAuthError::InvalidIssuer),
@@ -126,4 +165,6 @@ pub struct JwtClaims {
     pub iat: Option<u64>, // Optional. Issued at (as UTC timestamp)
     pub iss: Option<String>, // Optional. Issuer
     pub sub: String, // Optional. Subject (whom token refers to)
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>, // other claims, like the scope claim
 }
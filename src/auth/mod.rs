@@ -1,12 +1,14 @@
 mod service;
 mod authenticator;
 mod jwt;
+mod introspection;
 mod app_key;
 mod no_auth;
 
 pub use authenticator::{AuthProvider, ServiceAuthInfo, AuthRequest, AuthResponse, AuthResult, GatewayAuthError};
-pub use service::AuthService;
+pub use service::{AuthService, ClientRoles, ClientScopes, PathMatching, Tenant, TenantMatching, AUTH, TENANT_HEADER, TENANT_PLACEHOLDER};
 pub use app_key::AppKeyAuthProvider;
 pub use jwt::JWTAuthProvider;
+pub use introspection::IntrospectionAuthProvider;
 pub use no_auth::NoAuthProvider;
 
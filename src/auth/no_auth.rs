@@ -12,12 +12,20 @@ impl AuthProvider for NoAuthProvider {
         let result = AuthResult {
             client_id: String::from(""),
             sla: String::from(""),
+            roles: Vec::new(),
+            scopes: Vec::new(),
         };
         Ok((head, result))
     }
 }
 
 
+impl Default for NoAuthProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl NoAuthProvider {
     pub fn new() -> Self {
         NoAuthProvider {}
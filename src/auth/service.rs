@@ -1,12 +1,120 @@
 use std::collections::HashMap;
-use crate::config::{ConfigUpdate, FilterSetting, AuthSetting};
+use crate::config::{ConfigUpdate, FilterSetting, AuthSetting, NoAuth};
+use crate::middleware::service_chain;
+use hyper::header::{HeaderName, HeaderValue};
 use hyper::http::request::Parts;
+use hyper::Uri;
 use tokio::sync::{mpsc, broadcast};
 use tracing::{event, Level};
-use crate::auth::{ServiceAuthInfo, AuthProvider, AuthRequest, AppKeyAuthProvider, JWTAuthProvider, NoAuthProvider};
+use crate::auth::{ServiceAuthInfo, AuthProvider, AuthRequest, AppKeyAuthProvider, JWTAuthProvider, IntrospectionAuthProvider, NoAuthProvider};
 use super::authenticator::{AuthResult, AuthResponse, GatewayAuthError};
 
 
+/// Name of auth in `disabled_middlewares`, it runs before the middleware chain
+pub const AUTH: &str = "Auth";
+
+/// Header carrying the tenant to upstreams if no tenant header is set
+pub const TENANT_HEADER: &str = "x-tenant-id";
+
+/// Placeholder of the tenant segment in a tenant path pattern
+pub const TENANT_PLACEHOLDER: &str = "{tenant}";
+
+/// Request extension with the tenant of a request, read into `RequestContext`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tenant(pub String);
+
+/// Request extension with the roles of the authenticated client, matched by ACL rules
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientRoles(pub Vec<String>);
+
+/// Request extension with the scopes granted to the client by its token
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientScopes(pub Vec<String>);
+
+/// Normalization of request paths before the service lookup.
+/// The request path itself is rewritten, so middlewares and upstream path rewrite see
+/// `/svc/x` for `//Svc/x` like the client had sent it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PathMatching {
+    pub collapse_slashes: bool,  // repeated leading slashes as one, `//svc/x` as `/svc/x`
+    pub ignore_case: bool,  // service path matched in any case, replaced by the configured one
+}
+
+/// Tenant from a path prefix, or from a header; the header is set to the tenant found,
+/// or removed, so upstreams only see tenants extracted by the gateway
+#[derive(Debug, Clone, Default)]
+pub struct TenantMatching {
+    prefix: Vec<String>,  // segments of the path pattern, empty if tenants are not in the path
+    header: Option<HeaderName>,  // read if the path has no tenant
+    forward: Option<HeaderName>,  // None if tenants are not extracted
+}
+
+impl TenantMatching {
+    /// Extract tenants from a path prefix like `/t/{tenant}`, stripped before the service
+    /// lookup, or from a header if the path has none. Upstreams get the tenant in the header,
+    /// `x-tenant-id` by default. Tenants are not extracted if both are None.
+    pub fn new(pattern: Option<&str>, header: Option<&str>) -> Result<Self, String> {
+        let prefix: Vec<String> = match pattern {
+            Some(p) => p.trim_matches('/').split('/').map(String::from).collect(),
+            None => Vec::new(),
+        };
+        let placeholders = prefix.iter().filter(|s| *s == TENANT_PLACEHOLDER).count();
+        if let Some(p) = pattern {
+            if placeholders != 1 || prefix.iter().any(|s| s.is_empty()) {
+                return Err(format!("tenant path pattern {:?} needs one {} segment", p, TENANT_PLACEHOLDER));
+            }
+        }
+        let header = match header {
+            Some(h) => Some(HeaderName::from_bytes(h.as_bytes()).map_err(|e| format!("tenant header {:?}: {}", h, e))?),
+            None => None,
+        };
+        let forward = (pattern.is_some() || header.is_some())
+            .then(|| header.clone().unwrap_or_else(|| HeaderName::from_static(TENANT_HEADER)));
+        Ok(TenantMatching { prefix, header, forward })
+    }
+
+    // tenant and the path without the prefix, `/t/acme/svc/x` gives `acme` and `/svc/x`
+    fn strip_prefix(&self, path: &str) -> Option<(String, String)> {
+        let mut segments = path.strip_prefix('/')?.splitn(self.prefix.len() + 1, '/');
+        let mut tenant = None;
+        for expect in self.prefix.iter() {
+            let segment = segments.next()?;
+            if expect == TENANT_PLACEHOLDER && !segment.is_empty() {
+                tenant = Some(segment.to_string());
+            } else if expect != segment {
+                return None;
+            }
+        }
+        Some((tenant?, format!("/{}", segments.next().unwrap_or(""))))
+    }
+
+    fn extract(&self, head: &mut Parts) -> Option<String> {
+        let forward = self.forward.as_ref()?;
+        let mut tenant = None;
+        if !self.prefix.is_empty() {
+            if let Some((found, rest)) = self.strip_prefix(head.uri.path()) {
+                head.uri = AuthService::replace_path(&head.uri, &rest);
+                tenant = Some(found);
+            }
+        }
+        if let (None, Some(header)) = (&tenant, &self.header) {
+            tenant = head.headers.get(header)
+                .and_then(|v| v.to_str().ok())
+                .filter(|v| !v.is_empty())
+                .map(String::from);
+        }
+        match tenant.as_deref().map(HeaderValue::from_str) {
+            Some(Ok(value)) => {
+                head.headers.insert(forward.clone(), value);
+            },
+            _ => {
+                head.headers.remove(forward);
+            },
+        }
+        tenant
+    }
+}
+
 pub struct AuthService {
     conf_receiver: broadcast::Receiver<ConfigUpdate>,
     auth_receiver: mpsc::Receiver<AuthRequest>,
@@ -14,6 +122,13 @@ pub struct AuthService {
     services: HashMap<String, ServiceAuthInfo>,
     service_path: HashMap<String, String>,
     authenticators: HashMap<String, Box<dyn AuthProvider + Send + 'static>>,
+
+    /// Service id handling requests matching no service path, with its own auth and upstreams.
+    /// Unmatched requests get 404 if None or the service is not configured.
+    pub default_service: Option<String>,
+    /// Path normalization of all services
+    pub path_matching: PathMatching,
+    pub tenant_matching: TenantMatching,
 }
 
 
@@ -26,12 +141,16 @@ impl AuthService {
             services: HashMap::new(),
             service_path: HashMap::new(),
             authenticators: HashMap::new(),
+            default_service: None,
+            path_matching: PathMatching::default(),
+            tenant_matching: TenantMatching::default(),
         }
     }
 
     pub async fn start(&mut self) {
         self.authenticators.insert(String::from("appkey"), Box::new(AppKeyAuthProvider::new()));
         self.authenticators.insert(String::from("jwt"), Box::new(JWTAuthProvider::new()));
+        self.authenticators.insert(String::from("introspection"), Box::new(IntrospectionAuthProvider::new()));
         self.authenticators.insert(String::from("noauth"), Box::new(NoAuthProvider::new()));
 
         event!(Level::INFO, "auth service started");
@@ -65,11 +184,18 @@ impl AuthService {
                 for sla in s.sla.iter() {
                     slas.insert(sla.name.clone(), sla.filters.clone());
                 }
+                // without auth every client is anonymous, like auth type None
+                let auth = match s.disabled_middlewares.iter().any(|n| n == AUTH) {
+                    true => AuthSetting::None(NoAuth {}),
+                    false => s.auth.clone(),
+                };
                 let service = ServiceAuthInfo {
                     service_id: s.service_id.clone(),
-                    auth: s.auth.clone(),
+                    auth,
                     filters: s.filters.clone(),
-                    slas: slas,
+                    slas,
+                    middlewares: service_chain(&s),
+                    allowed_methods: s.allowed_methods.clone(),
                 };
                 self.services.insert(s.service_id.clone(), service);
                 self.service_path.insert(s.path.clone(), s.service_id.clone());
@@ -81,31 +207,68 @@ impl AuthService {
         }
     }
 
-    pub fn auth_handler(&mut self, head: Parts) -> Result<(Parts, AuthResponse), GatewayAuthError> {
-        let service_path = Self::extract_service_path(&head.uri.path())?;
-        let service_id = self.service_path.get(&service_path).ok_or(GatewayAuthError::UnknownService)?;
-        let service = self.services.get(service_id).ok_or(GatewayAuthError::UnknownService)?;
+    pub fn auth_handler(&mut self, mut head: Parts) -> Result<(Parts, AuthResponse), GatewayAuthError> {
+        let matching = self.path_matching;
+        if matching.collapse_slashes && head.uri.path().starts_with("//") {
+            let path = format!("/{}", head.uri.path().trim_start_matches('/'));
+            head.uri = Self::replace_path(&head.uri, &path);
+        }
+        if let Some(tenant) = self.tenant_matching.extract(&mut head) {
+            head.extensions.insert(Tenant(tenant));
+        }
+        let service_path = Self::extract_service_path(head.uri.path())?;
+        let mut service_id = self.service_path.get(&service_path)
+            .filter(|sid| self.services.contains_key(*sid))
+            .cloned();
+        if service_id.is_none() && matching.ignore_case {
+            let found = self.service_path.iter()
+                .find(|(p, sid)| p.eq_ignore_ascii_case(&service_path) && self.services.contains_key(*sid));
+            if let Some((configured, sid)) = found {
+                // same length, only ascii case differs
+                let rest = head.uri.path().get(configured.len()..).unwrap_or("");
+                let path = format!("{}{}", configured, rest);
+                head.uri = Self::replace_path(&head.uri, &path);
+                service_id = Some(sid.clone());
+            }
+        }
+        let service_id = service_id
+            .or_else(|| self.default_service.clone())
+            .ok_or(GatewayAuthError::UnknownService)?;
+        let service = self.services.get(&service_id).ok_or(GatewayAuthError::UnknownService)?;
+        // answered before auth and the middleware chain
+        if !Self::method_allowed(service, head.method.as_str()) {
+            let allow = service.allowed_methods.join(", ").to_ascii_uppercase();
+            return Err(GatewayAuthError::MethodNotAllowed(allow));
+        }
         let provider = match service.auth {
             AuthSetting::AppKey(_) => self.authenticators.get("appkey").unwrap(),
             AuthSetting::JWT(_) => self.authenticators.get("jwt").unwrap(),
+            AuthSetting::Introspection(_) => self.authenticators.get("introspection").unwrap(),
             AuthSetting::None(_) => self.authenticators.get("noauth").unwrap(),
         };
 
-        let (head, auth_result) = provider.identify_client(head, service_id)?;
+        let (mut head, auth_result) = provider.identify_client(head, &service_id)?;
+        if !auth_result.roles.is_empty() {
+            head.extensions.insert(ClientRoles(auth_result.roles.clone()));
+        }
+        if !auth_result.scopes.is_empty() {
+            head.extensions.insert(ClientScopes(auth_result.scopes.clone()));
+        }
 
         let (sf, cf) = Self::get_filters(&auth_result, service)?;
         let resp = AuthResponse {
             client_id: auth_result.client_id.clone(),
-            service_id: service_id.clone(),
+            service_id,
             sla: auth_result.sla.clone(),
             service_filters: sf,
             client_filters: cf,
+            middlewares: service.middlewares.clone(),
         };
         Ok((head, resp))
     }
 
     fn get_filters(client: &AuthResult, service: &ServiceAuthInfo) -> Result<(Vec<FilterSetting>, Vec<FilterSetting>), GatewayAuthError> {
-        if client.client_id.eq("") {  // NoAuth
+        if client.client_id.is_empty() {  // NoAuth
             return Ok((service.filters.clone(), vec![]))
         }
 
@@ -116,6 +279,23 @@ impl AuthService {
         }
     }
 
+    // any method if the service doesn't list its allowed ones
+    fn method_allowed(service: &ServiceAuthInfo, method: &str) -> bool {
+        service.allowed_methods.is_empty()
+            || service.allowed_methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+    }
+
+    // uri with path replaced, query kept
+    fn replace_path(uri: &Uri, path: &str) -> Uri {
+        let path_and_query = match uri.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path.to_string(),
+        };
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = path_and_query.parse().ok();
+        Uri::from_parts(parts).unwrap_or_else(|_| uri.clone())
+    }
+
     fn extract_service_path(path: &str) -> Result<String, GatewayAuthError> {
         let path = path.strip_prefix("/").unwrap_or(path);
         let (service_path, _path) = match path.find("/") {
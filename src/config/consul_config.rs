@@ -0,0 +1,525 @@
+use crate::config::{ClientInfo, ConfigUpdate, ServiceInfo, SyncState, Upstream};
+use crate::middleware::upstream_tls_config;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, StatusCode};
+use hyper_rustls::HttpsConnector;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{event, Level};
+
+type ConsulError = Box<dyn std::error::Error + Send + Sync>;
+
+// blocking queries return after this long without changes
+const BLOCKING_WAIT: &str = "55s";
+const CATALOG_RETRY: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct KvEntry {
+    key: String,
+    value: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HealthEntry {
+    node: NodeEntry,
+    service: ServiceEntry,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct NodeEntry {
+    address: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ServiceEntry {
+    #[serde(rename = "ID")]
+    id: String,
+    address: String,
+    port: u16,
+}
+
+/// Healthy instance of a service registered in the consul catalog
+#[derive(Debug, Clone, PartialEq)]
+struct Instance {
+    id: String,
+    address: String,
+    port: u16,
+}
+
+enum ConsulEvent {
+    Kv(Vec<(String, String)>),
+    Instances(String, Vec<Instance>),
+}
+
+#[derive(Clone)]
+struct ConsulClient {
+    base: String,
+    token: Option<String>,
+    dc: Option<String>,
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+}
+
+impl ConsulClient {
+    // blocking query, returns None for missing keys and the index for the next query
+    async fn get<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+        index: u64,
+    ) -> Result<(Option<T>, u64), ConsulError> {
+        let mut url = url::Url::parse(&format!("{}{}", self.base, path))?;
+        {
+            let mut pairs = url.query_pairs_mut();
+            pairs.extend_pairs(query);
+            if let Some(dc) = &self.dc {
+                pairs.append_pair("dc", dc);
+            }
+            if index > 0 {
+                pairs.append_pair("index", &index.to_string());
+                pairs.append_pair("wait", BLOCKING_WAIT);
+            }
+        }
+        let mut req = Request::get(url.as_str());
+        if let Some(token) = &self.token {
+            req = req.header("X-Consul-Token", token);
+        }
+        let resp = self.client.request(req.body(Body::empty())?).await?;
+        let new_index = resp
+            .headers()
+            .get("X-Consul-Index")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.parse::<u64>().ok())
+            .unwrap_or(0);
+        // index going backwards means consul state was reset
+        let new_index = if new_index < index { 0 } else { new_index };
+        match resp.status() {
+            StatusCode::NOT_FOUND => Ok((None, new_index)),
+            s if s.is_success() => {
+                let body = hyper::body::to_bytes(resp.into_body()).await?;
+                Ok((Some(serde_json::from_slice(&body)?), new_index))
+            }
+            s => Err(format!("consul responded {} for {}", s, path).into()),
+        }
+    }
+
+    async fn kv(
+        &self,
+        prefix: &str,
+        index: u64,
+    ) -> Result<(Vec<(String, String)>, u64), ConsulError> {
+        let path = format!("/v1/kv/{}/", prefix);
+        let (entries, index) = self
+            .get::<Vec<KvEntry>>(&path, &[("recurse", "true")], index)
+            .await?;
+        let kvs = entries
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|e| {
+                let value = base64::decode(e.value?).ok()?;
+                Some((e.key, String::from_utf8(value).ok()?))
+            })
+            .collect();
+        Ok((kvs, index))
+    }
+
+    async fn instances(
+        &self,
+        target: &ConsulTarget,
+        index: u64,
+    ) -> Result<(Vec<Instance>, u64), ConsulError> {
+        let path = format!("/v1/health/service/{}", target.name);
+        let mut query = vec![("passing", "true")];
+        if let Some(tag) = &target.tag {
+            query.push(("tag", tag));
+        }
+        let (entries, index) = self.get::<Vec<HealthEntry>>(&path, &query, index).await?;
+        let mut instances: Vec<Instance> = entries
+            .unwrap_or_default()
+            .into_iter()
+            .map(|e| Instance {
+                id: e.service.id,
+                address: if e.service.address.is_empty() {
+                    e.node.address
+                } else {
+                    e.service.address
+                },
+                port: e.service.port,
+            })
+            .collect();
+        instances.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok((instances, index))
+    }
+}
+
+/// Upstream target like `consul://<service-name>/<path>?tag=<tag>&scheme=https`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ConsulTarget {
+    name: String,
+    tag: Option<String>,
+    scheme: String,
+    path: String,
+}
+
+impl ConsulTarget {
+    fn parse(target: &str) -> Option<Self> {
+        if !target.starts_with("consul://") {
+            return None;
+        }
+        let url = url::Url::parse(target).ok()?;
+        let query: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        Some(ConsulTarget {
+            name: url.host_str()?.to_string(),
+            tag: query.get("tag").cloned(),
+            scheme: query
+                .get("scheme")
+                .cloned()
+                .unwrap_or_else(|| "http".into()),
+            path: url.path().to_string(),
+        })
+    }
+
+    // watched catalog entry, same service name with different tags are watched separately
+    fn key(&self) -> String {
+        match &self.tag {
+            Some(tag) => format!("{}?tag={}", self.name, tag),
+            None => self.name.clone(),
+        }
+    }
+}
+
+// e.g. consul://:<acl-token>@<consul_host>:8500/juapi/<env-ns>.<env-name>?dc=dc1
+// keys are <path>/services/<service_id> and <path>/clients/<client_id> with json values.
+// Upstreams with `consul://<service-name>` target are replaced by healthy catalog instances.
+//
+// Returns error if connecting or initial load fails, Ok once a loaded watch is lost.
+// Every load is a full resync, entries deleted while disconnected are removed.
+pub async fn watch_config(
+    source: String,
+    sender: mpsc::Sender<ConfigUpdate>,
+    sync: &mut SyncState,
+) -> Result<(), ConsulError> {
+    let url = url::Url::parse(&source)?;
+    let query: HashMap<_, _> = url.query_pairs().into_owned().collect();
+    let scheme = query.get("scheme").map(|s| s.as_str()).unwrap_or("http");
+    let host = url.host_str().unwrap_or("127.0.0.1");
+    let port = url.port().unwrap_or(8500);
+    let token = url
+        .password()
+        .or_else(|| Some(url.username()).filter(|u| !u.is_empty()))
+        .map(String::from);
+    let tls_config = upstream_tls_config(None);
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    let connector = HttpsConnector::from((http, tls_config));
+    let consul = ConsulClient {
+        base: format!("{}://{}:{}", scheme, host, port),
+        token,
+        dc: query.get("dc").cloned(),
+        client: Client::builder().build(connector),
+    };
+    let prefix = url.path().trim_matches('/').to_string();
+
+    let (kvs, mut kv_index) = consul.kv(&prefix, 0).await?;
+    let (events_tx, mut events) = mpsc::channel(16);
+    let mut state = ConsulState {
+        prefix: prefix.clone(),
+        sender,
+        events_tx: events_tx.clone(),
+        services: HashMap::new(),
+        clients: HashMap::new(),
+        emitted: HashMap::new(),
+        instances: HashMap::new(),
+        watchers: HashMap::new(),
+        sync: std::mem::take(sync),
+    };
+    state.sync.start();
+    state.apply_kv(kvs, &consul).await;
+    for u in state.sync.finish() {
+        let _ = state.sender.send(u).await;
+    }
+    let _ = state.sender.send(ConfigUpdate::ConfigReady(true)).await;
+
+    let kv_consul = consul.clone();
+    let mut kv_watcher = tokio::spawn(async move {
+        loop {
+            match kv_consul.kv(&prefix, kv_index).await {
+                Ok((kvs, index)) => {
+                    if index != kv_index && events_tx.send(ConsulEvent::Kv(kvs)).await.is_err() {
+                        return;
+                    }
+                    kv_index = index;
+                    if index == 0 {
+                        tokio::time::sleep(CATALOG_RETRY).await;
+                    }
+                }
+                Err(e) => {
+                    event!(Level::ERROR, "Fail to watch consul kv: {}", e);
+                    return;
+                }
+            }
+        }
+    });
+
+    loop {
+        tokio::select! {
+            _ = &mut kv_watcher => break,
+            Some(event) = events.recv() => match event {
+                ConsulEvent::Kv(kvs) => state.apply_kv(kvs, &consul).await,
+                ConsulEvent::Instances(key, instances) => {
+                    state.apply_instances(key, instances).await
+                }
+            },
+        }
+    }
+    for (_, watcher) in state.watchers.drain() {
+        watcher.abort();
+    }
+    *sync = state.sync;
+    Ok(())
+}
+
+struct ConsulState {
+    prefix: String,
+    sender: mpsc::Sender<ConfigUpdate>,
+    events_tx: mpsc::Sender<ConsulEvent>,
+    // config from kv, before resolving consul targets
+    services: HashMap<String, ServiceInfo>,
+    clients: HashMap<String, ClientInfo>,
+    // resolved services sent to the gateway
+    emitted: HashMap<String, ServiceInfo>,
+    instances: HashMap<String, Vec<Instance>>,
+    watchers: HashMap<String, JoinHandle<()>>,
+    sync: SyncState, // entries sent, kept across reconnects
+}
+
+impl ConsulState {
+    async fn apply_kv(&mut self, kvs: Vec<(String, String)>, consul: &ConsulClient) {
+        let mut services = HashMap::new();
+        let mut clients = HashMap::new();
+        for (key, val) in kvs.iter() {
+            match extract_event(&self.prefix, key, val) {
+                Some(ConfigUpdate::ServiceUpdate(s)) => {
+                    services.insert(s.service_id.clone(), s);
+                }
+                Some(ConfigUpdate::ClientUpdate(c)) => {
+                    clients.insert(c.client_id.clone(), c);
+                }
+                _ => {}
+            }
+        }
+
+        let old_clients = std::mem::replace(&mut self.clients, clients.clone());
+        for cid in old_clients.keys().filter(|c| !clients.contains_key(*c)) {
+            self.send(ConfigUpdate::ClientRemove(cid.clone())).await;
+        }
+        for (cid, client) in clients {
+            if old_clients.get(&cid) != Some(&client) {
+                self.send(ConfigUpdate::ClientUpdate(client)).await;
+            }
+        }
+
+        let old_services = std::mem::replace(&mut self.services, services);
+        for sid in old_services.keys() {
+            if !self.services.contains_key(sid) {
+                self.emitted.remove(sid);
+                self.send(ConfigUpdate::ServiceRemove(sid.clone())).await;
+            }
+        }
+
+        // watch catalog for consul targets in use
+        let targets: HashMap<String, ConsulTarget> = self
+            .services
+            .values()
+            .flat_map(|s| s.upstreams.iter())
+            .filter_map(|u| ConsulTarget::parse(&u.target))
+            .map(|t| (t.key(), t))
+            .collect();
+        let unused: Vec<String> = self
+            .watchers
+            .keys()
+            .filter(|k| !targets.contains_key(*k))
+            .cloned()
+            .collect();
+        for key in unused {
+            if let Some(watcher) = self.watchers.remove(&key) {
+                watcher.abort();
+            }
+            self.instances.remove(&key);
+        }
+        for (key, target) in targets {
+            if self.watchers.contains_key(&key) {
+                continue;
+            }
+            let index = match consul.instances(&target, 0).await {
+                Ok((instances, index)) => {
+                    self.instances.insert(key.clone(), instances);
+                    index
+                }
+                Err(e) => {
+                    event!(Level::ERROR, "Fail to query consul service {}: {}", key, e);
+                    0
+                }
+            };
+            let watcher =
+                spawn_catalog_watcher(consul.clone(), target, index, self.events_tx.clone());
+            self.watchers.insert(key, watcher);
+        }
+
+        let sids: Vec<String> = self.services.keys().cloned().collect();
+        for sid in sids {
+            self.emit_service(&sid).await;
+        }
+    }
+
+    async fn apply_instances(&mut self, key: String, instances: Vec<Instance>) {
+        if !self.watchers.contains_key(&key) || self.instances.get(&key) == Some(&instances) {
+            return;
+        }
+        event!(
+            Level::INFO,
+            "consul service {} has {} healthy instances",
+            key,
+            instances.len()
+        );
+        self.instances.insert(key.clone(), instances);
+        let sids: Vec<String> = self
+            .services
+            .values()
+            .filter(|s| {
+                s.upstreams.iter().any(|u| {
+                    ConsulTarget::parse(&u.target)
+                        .map(|t| t.key() == key)
+                        .unwrap_or(false)
+                })
+            })
+            .map(|s| s.service_id.clone())
+            .collect();
+        for sid in sids {
+            self.emit_service(&sid).await;
+        }
+    }
+
+    // send service with resolved upstreams, if changed since last sent
+    async fn emit_service(&mut self, sid: &str) {
+        let service = match self.services.get(sid) {
+            Some(s) => s,
+            None => return,
+        };
+        let mut resolved = service.clone();
+        resolved.upstreams = service
+            .upstreams
+            .iter()
+            .flat_map(|u| self.resolve_upstream(u))
+            .collect();
+        if self.emitted.get(sid) == Some(&resolved) {
+            return;
+        }
+        if resolved.upstreams.is_empty() {
+            event!(Level::WARN, "service {} has no healthy upstream", sid);
+        }
+        self.emitted.insert(sid.into(), resolved.clone());
+        self.send(ConfigUpdate::ServiceUpdate(resolved)).await;
+    }
+
+    async fn send(&mut self, update: ConfigUpdate) {
+        self.sync.track(&update);
+        let _ = self.sender.send(update).await;
+    }
+
+    fn resolve_upstream(&self, upstream: &Upstream) -> Vec<Upstream> {
+        let target = match ConsulTarget::parse(&upstream.target) {
+            Some(t) => t,
+            None => return vec![upstream.clone()],
+        };
+        let instances = match self.instances.get(&target.key()) {
+            Some(instances) => instances,
+            None => return Vec::new(),
+        };
+        instances
+            .iter()
+            .map(|i| {
+                let host = if i.address.contains(':') {
+                    format!("[{}]", i.address)
+                } else {
+                    i.address.clone()
+                };
+                Upstream {
+                    id: format!("{}-{}", upstream.id, i.id),
+                    target: format!("{}://{}:{}{}", target.scheme, host, i.port, target.path),
+                    ..upstream.clone()
+                }
+            })
+            .collect()
+    }
+}
+
+fn spawn_catalog_watcher(
+    consul: ConsulClient,
+    target: ConsulTarget,
+    mut index: u64,
+    events: mpsc::Sender<ConsulEvent>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            match consul.instances(&target, index).await {
+                Ok((instances, new_index)) => {
+                    if new_index != index {
+                        let event = ConsulEvent::Instances(target.key(), instances);
+                        if events.send(event).await.is_err() {
+                            return;
+                        }
+                    }
+                    index = new_index;
+                    if index == 0 {
+                        tokio::time::sleep(CATALOG_RETRY).await;
+                    }
+                }
+                Err(e) => {
+                    event!(
+                        Level::WARN,
+                        "Fail to watch consul service {}: {}",
+                        target.key(),
+                        e
+                    );
+                    tokio::time::sleep(CATALOG_RETRY).await;
+                }
+            }
+        }
+    })
+}
+
+fn extract_event(prefix: &str, key: &str, val: &str) -> Option<ConfigUpdate> {
+    // key schema: <prefix>/<services|clients>/<entity-id>
+    let (entity_type, entity) = key
+        .strip_prefix(prefix)?
+        .strip_prefix('/')?
+        .split_once('/')?;
+    if entity.is_empty() {
+        return None;
+    }
+    match entity_type {
+        "services" => match serde_json::from_str::<ServiceInfo>(val) {
+            Ok(conf) => Some(ConfigUpdate::ServiceUpdate(conf)),
+            Err(e) => {
+                event!(Level::ERROR, "Invalid service config {}: {}", key, e);
+                None
+            }
+        },
+        "clients" => match serde_json::from_str::<ClientInfo>(val) {
+            Ok(conf) => Some(ConfigUpdate::ClientUpdate(conf)),
+            Err(e) => {
+                event!(Level::ERROR, "Invalid client config {}: {}", key, e);
+                None
+            }
+        },
+        _ => None,
+    }
+}
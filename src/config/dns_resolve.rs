@@ -0,0 +1,190 @@
+use crate::config::{ConfigUpdate, ServiceInfo, Upstream};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::{event, Level};
+
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// services with dns_refresh upstreams, before and after resolving
+#[derive(Default)]
+struct DnsState {
+    services: HashMap<String, ServiceInfo>,
+    emitted: HashMap<String, ServiceInfo>,
+    due: HashMap<String, Instant>,
+    addrs: HashMap<(String, String), Vec<SocketAddr>>, // addrs[(service_id, upstream_id)], last resolved
+}
+
+/// Pass config updates through, replacing upstreams with `dns_refresh` by one upstream per
+/// resolved address, like `<id>-<ip>:<port>` for `http://<host>:<port>/`.
+/// Hosts are resolved again every `dns_refresh` seconds, and the service is updated if the
+/// address set changed. The system resolver gives no record TTL, so the interval is configured.
+/// HTTPS targets are connected by address and verified against their host name, or
+/// `tls_server_name` if set.
+pub fn resolve_targets(mut input: mpsc::Receiver<ConfigUpdate>) -> mpsc::Receiver<ConfigUpdate> {
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let mut state = DnsState::default();
+        loop {
+            let next_due = state.due.values().min().cloned();
+            let update = tokio::select! {
+                update = input.recv() => match update {
+                    Some(update) => update,
+                    None => return,
+                },
+                _ = tokio::time::sleep_until(next_due.unwrap_or_else(Instant::now)), if next_due.is_some() => {
+                    let now = Instant::now();
+                    let due: Vec<String> = state
+                        .due
+                        .iter()
+                        .filter(|(_, at)| **at <= now)
+                        .map(|(sid, _)| sid.clone())
+                        .collect();
+                    for sid in due {
+                        if let Some(update) = state.refresh(&sid).await {
+                            if tx.send(update).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    continue;
+                }
+            };
+            let update = match update {
+                ConfigUpdate::ServiceUpdate(s) if has_dns_upstream(&s) => {
+                    let sid = s.service_id.clone();
+                    state.forget(&sid);
+                    state.services.insert(sid.clone(), s);
+                    state.refresh(&sid).await
+                }
+                ConfigUpdate::ServiceUpdate(s) => {
+                    state.forget(&s.service_id);
+                    Some(ConfigUpdate::ServiceUpdate(s))
+                }
+                ConfigUpdate::ServiceRemove(sid) => {
+                    state.forget(&sid);
+                    Some(ConfigUpdate::ServiceRemove(sid))
+                }
+                update => Some(update),
+            };
+            if let Some(update) = update {
+                if tx.send(update).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    rx
+}
+
+fn has_dns_upstream(service: &ServiceInfo) -> bool {
+    service.upstreams.iter().any(|u| u.dns_refresh.is_some())
+}
+
+impl DnsState {
+    fn forget(&mut self, sid: &str) {
+        self.services.remove(sid);
+        self.emitted.remove(sid);
+        self.due.remove(sid);
+        self.addrs.retain(|(s, _), _| s != sid);
+    }
+
+    // resolve upstreams of service, update if changed since last sent
+    async fn refresh(&mut self, sid: &str) -> Option<ConfigUpdate> {
+        let service = self.services.get(sid)?.clone();
+        let mut resolved = service.clone();
+        resolved.upstreams.clear();
+        let mut interval = u64::MAX;
+        for upstream in service.upstreams.iter() {
+            let refresh = match upstream.dns_refresh {
+                Some(refresh) => refresh,
+                None => {
+                    resolved.upstreams.push(upstream.clone());
+                    continue;
+                }
+            };
+            interval = interval.min(refresh.max(1));
+            let key = (sid.to_string(), upstream.id.clone());
+            // forced scheme decides the default port and TLS
+            let target = upstream.target_of(&service);
+            match lookup(&target).await {
+                Some(Ok(addrs)) if !addrs.is_empty() => {
+                    self.addrs.insert(key.clone(), addrs);
+                }
+                Some(Ok(_)) => {
+                    event!(Level::WARN, "{} resolved to no address", upstream.target);
+                }
+                Some(Err(e)) => {
+                    event!(Level::WARN, "Fail to resolve {}: {}", upstream.target, e);
+                }
+                None => {}
+            }
+            // last good addresses are kept on failure, host name used if never resolved
+            match self.addrs.get(&key) {
+                Some(addrs) => resolved.upstreams.extend(
+                    addrs
+                        .iter()
+                        .filter_map(|a| with_address(upstream, &target, a)),
+                ),
+                None => resolved.upstreams.push(upstream.clone()),
+            }
+        }
+        self.due
+            .insert(sid.into(), Instant::now() + Duration::from_secs(interval));
+        if self.emitted.get(sid) == Some(&resolved) {
+            return None;
+        }
+        if self.emitted.contains_key(sid) {
+            event!(Level::INFO, "Upstream addresses of service {} changed", sid);
+        }
+        self.emitted.insert(sid.into(), resolved.clone());
+        Some(ConfigUpdate::ServiceUpdate(resolved))
+    }
+}
+
+// sorted addresses of target host, None if the target is not resolved
+async fn lookup(target: &str) -> Option<std::io::Result<Vec<SocketAddr>>> {
+    let url = url::Url::parse(target).ok()?;
+    // https targets are verified against the host name, kept as tls_server_name
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return None;
+    }
+    let host = url.host_str()?.to_string();
+    let port = url.port_or_known_default()?;
+    let lookup = tokio::net::lookup_host((host.trim_matches(|c| c == '[' || c == ']'), port));
+    let result = match tokio::time::timeout(LOOKUP_TIMEOUT, lookup).await {
+        Ok(Ok(addrs)) => {
+            let mut addrs: Vec<SocketAddr> = addrs.collect();
+            addrs.sort();
+            addrs.dedup();
+            Ok(addrs)
+        }
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "dns lookup timeout",
+        )),
+    };
+    Some(result)
+}
+
+fn with_address(upstream: &Upstream, target: &str, addr: &SocketAddr) -> Option<Upstream> {
+    let mut url = url::Url::parse(target).ok()?;
+    let server_name = match url.scheme() {
+        "https" => upstream
+            .tls_server_name
+            .clone()
+            .or_else(|| url.domain().map(String::from)),
+        _ => upstream.tls_server_name.clone(),
+    };
+    url.set_ip_host(addr.ip()).ok()?;
+    url.set_port(Some(addr.port())).ok()?;
+    Some(Upstream {
+        id: format!("{}-{}", upstream.id, addr),
+        target: url.to_string(),
+        tls_server_name: server_name,
+        ..upstream.clone()
+    })
+}
@@ -1,10 +1,17 @@
-use crate::config::{ClientInfo, ConfigUpdate, ServiceInfo};
+use crate::config::{ClientInfo, ConfigUpdate, ServiceInfo, SyncState};
 use etcd_client::{Client, ConnectOptions, EventType, GetOptions, WatchOptions};
 use tokio::sync::mpsc;
 use tracing::{event, Level};
 
 // e.g. etcd://<env-ns>.<env-name>:<access-token>@<etcd_endpoint>/juapi/<env-ns>.<env-name>
-pub async fn watch_config(source: String, sender: mpsc::Sender<ConfigUpdate>) {
+//
+// Returns error if connecting or initial load fails, Ok once a loaded watch is lost.
+// Every load is a full resync, entries deleted while disconnected are removed.
+pub async fn watch_config(
+    source: String,
+    sender: mpsc::Sender<ConfigUpdate>,
+    state: &mut SyncState,
+) -> Result<(), etcd_client::Error> {
     let url = url::Url::parse(&source).unwrap();
     let host_str = url.host_str().unwrap_or("127.0.0.1");
     let port = url.port().unwrap_or(2379);
@@ -14,68 +21,73 @@ pub async fn watch_config(source: String, sender: mpsc::Sender<ConfigUpdate>) {
         let username = url.username();
         let password = url.password().unwrap_or_default();
         let options = Some(ConnectOptions::new().with_user(username, password));
-        Client::connect(endpoints, options).await.unwrap()
+        Client::connect(endpoints, options).await?
     } else {
-        Client::connect(endpoints, None).await.unwrap()
+        Client::connect(endpoints, None).await?
     };
 
     let get_option = GetOptions::new().with_prefix();
-    if let Ok(resp) = client.get(conf_path, Some(get_option)).await {
-        for kv in resp.kvs() {
-            let key = kv.key_str().unwrap();
-            let val = kv.value_str().unwrap();
-            let update = extract_event(key, val, false);
-            if let Some(u) = update {
-                let _ = sender.send(u).await;
-            }
+    let resp = client.get(conf_path, Some(get_option)).await?;
+    state.start();
+    for kv in resp.kvs() {
+        let update = match (kv.key_str(), kv.value_str()) {
+            (Ok(key), Ok(val)) => extract_event(key, val, false),
+            _ => None,
+        };
+        if let Some(u) = update {
+            state.track(&u);
+            let _ = sender.send(u).await;
         }
-        let _ = sender.send(ConfigUpdate::ConfigReady(true)).await;
+    }
+    for u in state.finish() {
+        let _ = sender.send(u).await;
+    }
+    let _ = sender.send(ConfigUpdate::ConfigReady(true)).await;
 
-        // watch further config changes
-        let revision = resp.header().unwrap().revision();
-        let watch_option = WatchOptions::new()
-            .with_prefix()
-            .with_start_revision(revision);
-        let (_watcher, mut stream) = client.watch(conf_path, Some(watch_option)).await.unwrap();
-        while let Some(resp) = stream.message().await.unwrap() {
-            if resp.canceled() {
-                event!(Level::INFO, "watch canceled!");
+    // watch further config changes, from the revision after loaded one
+    let revision = resp.header().map(|h| h.revision()).unwrap_or_default();
+    let watch_option = WatchOptions::new()
+        .with_prefix()
+        .with_start_revision(revision + 1);
+    let (_watcher, mut stream) = client.watch(conf_path, Some(watch_option)).await?;
+    loop {
+        let resp = match stream.message().await {
+            Ok(Some(resp)) => resp,
+            Ok(None) => break,
+            Err(e) => {
+                event!(Level::ERROR, "etcd watch error: {}", e);
                 break;
             }
-            for event in resp.events() {
-                let update = match event.event_type() {
-                    EventType::Put => {
-                        if let Some(kv) = event.kv() {
-                            extract_event(kv.key_str().unwrap(), kv.value_str().unwrap(), false)
-                        } else {
-                            None
-                        }
-                    }
-                    EventType::Delete => {
-                        if let Some(kv) = event.kv() {
-                            extract_event(kv.key_str().unwrap(), kv.value_str().unwrap(), true)
-                        } else {
-                            None
-                        }
-                    }
-                };
-                if let Some(u) = update {
-                    let _ = sender.send(u).await;
-                }
+        };
+        if resp.canceled() {
+            event!(Level::INFO, "watch canceled!");
+            break;
+        }
+        for event in resp.events() {
+            let is_delete = match event.event_type() {
+                EventType::Put => false,
+                EventType::Delete => true,
+            };
+            let update = match event.kv().map(|kv| (kv.key_str(), kv.value_str())) {
+                Some((Ok(key), Ok(val))) => extract_event(key, val, is_delete),
+                _ => None,
+            };
+            if let Some(u) = update {
+                state.track(&u);
+                let _ = sender.send(u).await;
             }
         }
-    } else {
-        event!(Level::ERROR, "Fail to connect etcd");
     }
+    Ok(())
 }
 
 fn extract_event(key: &str, val: &str, is_delete: bool) -> Option<ConfigUpdate> {
     // key schema:  /juapi/<env-ns>.<env-name>/<services|clients>/<entity-ns>.<entity-name>
     let key_segments: Vec<&str> = key.split('/').collect();
     if key_segments.len() == 5 {
-        let _env = key_segments.get(2).unwrap().clone();
-        let entity_type = key_segments.get(3).unwrap().clone();
-        let entity = key_segments.get(4).unwrap().clone();
+        let _env = *key_segments.get(2).unwrap();
+        let entity_type = *key_segments.get(3).unwrap();
+        let entity = *key_segments.get(4).unwrap();
         if is_delete {
             if entity_type.eq("services") {
                 return Some(ConfigUpdate::ServiceRemove(String::from(entity)));
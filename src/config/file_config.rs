@@ -1,7 +1,15 @@
-use crate::config::{ClientInfo, ConfigUpdate, ServiceInfo};
+use crate::config::{
+    validate_client, validate_service, ClientInfo, ConfigError, ConfigUpdate, ServiceInfo,
+};
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
 use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
 use std::collections::HashMap;
-use tokio::sync::mpsc;
+use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot};
 use tracing::{event, Level};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -10,12 +18,123 @@ struct ServiceConfig {
     pub services: Vec<ServiceInfo>,
 }
 
-pub async fn watch_config(config_file: String, sender: mpsc::Sender<ConfigUpdate>) {
+#[derive(Error, Debug)]
+pub enum FileConfigError {
+    #[error("environment variable {0} is not set and has no default")]
+    MissingEnv(String),
+
+    #[error("unclosed environment variable reference in {0:?}")]
+    UnclosedEnv(String),
+
+    #[error("{0}")]
+    Yaml(#[from] serde_yaml::Error),
+
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("{0}")]
+    Toml(#[from] toml::de::Error),
+
+    #[error("{0}")]
+    Read(#[from] std::io::Error),
+
+    #[error("{0}")]
+    Invalid(#[from] ConfigError),
+}
+
+/// Entries changed by a reload of the config file, or the errors rejecting the whole file
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReloadSummary {
+    pub services_added: Vec<String>,
+    pub services_updated: Vec<String>,
+    pub services_removed: Vec<String>,
+    pub clients_added: Vec<String>,
+    pub clients_updated: Vec<String>,
+    pub clients_removed: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+impl ReloadSummary {
+    fn of(old: &ServiceConfig, updates: &[ConfigUpdate]) -> Self {
+        let mut summary = ReloadSummary::default();
+        for update in updates {
+            match update {
+                ConfigUpdate::ServiceUpdate(s) => {
+                    match old.services.iter().any(|o| o.service_id == s.service_id) {
+                        true => summary.services_updated.push(s.service_id.clone()),
+                        false => summary.services_added.push(s.service_id.clone()),
+                    }
+                }
+                ConfigUpdate::ServiceRemove(sid) => summary.services_removed.push(sid.clone()),
+                ConfigUpdate::ClientUpdate(c) => {
+                    match old.clients.iter().any(|o| o.client_id == c.client_id) {
+                        true => summary.clients_updated.push(c.client_id.clone()),
+                        false => summary.clients_added.push(c.client_id.clone()),
+                    }
+                }
+                ConfigUpdate::ClientRemove(cid) => summary.clients_removed.push(cid.clone()),
+                ConfigUpdate::ConfigReady(_) => {}
+            }
+        }
+        summary
+    }
+}
+
+/// Forces a file source to re-read its file and apply the changes
+#[derive(Debug, Clone)]
+pub struct ConfigReloader(pub(crate) mpsc::Sender<oneshot::Sender<ReloadSummary>>);
+
+impl ConfigReloader {
+    /// Reload and wait until the changes are emitted, None if the source is closed
+    pub async fn reload(&self) -> Option<ReloadSummary> {
+        let (tx, rx) = oneshot::channel();
+        self.0.send(tx).await.ok()?;
+        rx.await.ok()
+    }
+
+    /// Reload without waiting, a pending reload reads the latest content anyway
+    pub fn trigger(&self) {
+        let (tx, _) = oneshot::channel();
+        let _ = self.0.try_send(tx);
+    }
+}
+
+/// Config file format, detected by file extension
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigFormat {
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl ConfigFormat {
+    /// `.json` and `.toml` files, yaml for any other extension
+    pub fn from_path(path: &str) -> Self {
+        let ext = std::path::Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match ext.as_deref() {
+            Some("json") => ConfigFormat::Json,
+            Some("toml") => ConfigFormat::Toml,
+            _ => ConfigFormat::Yaml,
+        }
+    }
+}
+
+/// Emit config of the file, then the changes whenever it is modified, on USR2 signal
+/// or a request on `reload`, answered with the changes
+pub async fn watch_config(
+    config_file: String,
+    sender: mpsc::Sender<ConfigUpdate>,
+    mut reload: mpsc::Receiver<oneshot::Sender<ReloadSummary>>,
+) {
+    let format = ConfigFormat::from_path(&config_file);
     let content = tokio::fs::read_to_string(&config_file)
         .await
         .expect("Failed to read config file");
-    let mut config =
-        serde_yaml::from_str::<ServiceConfig>(&content).expect("Failed to parse config file");
+    let mut config = parse_config(&content, format)
+        .unwrap_or_else(|e| panic!("Failed to parse config file: {}", e));
     for s in config.services.iter() {
         let _ = sender.send(ConfigUpdate::ServiceUpdate(s.clone())).await;
     }
@@ -24,56 +143,241 @@ pub async fn watch_config(config_file: String, sender: mpsc::Sender<ConfigUpdate
     }
     let _ = sender.send(ConfigUpdate::ConfigReady(true)).await;
 
-    // reload config file on USR2 signal
+    // reload config file on change or USR2 signal
     let mut usr2 = reload_signal::get_channel();
-    while let Some(_) = usr2.recv().await {
-        event!(Level::INFO, "Got reload signal");
-        if let Ok(new_content) = tokio::fs::read_to_string(&config_file).await {
-            if let Ok(new_config) = serde_yaml::from_str::<ServiceConfig>(&new_content) {
-                for cu in config_diff(&config, &new_config) {
+    let mut changes = watch_file(&config_file);
+    loop {
+        let reply = tokio::select! {
+            Some(_) = usr2.recv() => {
+                event!(Level::INFO, "Got reload signal");
+                None
+            }
+            Some(_) = changes.recv() => {
+                event!(Level::INFO, "Config file changed");
+                None
+            }
+            Some(reply) = reload.recv() => {
+                event!(Level::INFO, "Config reload requested");
+                Some(reply)
+            }
+            else => break,
+        };
+        let summary = match load_config(&config_file, format).await {
+            Ok(new_config) => {
+                let updates = config_diff(&config, &new_config);
+                if updates.is_empty() {
+                    event!(Level::INFO, "Config file reloaded, unchanged");
+                } else {
+                    event!(
+                        Level::INFO,
+                        "Config file reloaded, {} updates",
+                        updates.len()
+                    );
+                }
+                let summary = ReloadSummary::of(&config, &updates);
+                for cu in updates {
                     let _ = sender.send(cu).await;
                 }
                 config = new_config;
-            } else {
-                event!(Level::ERROR, "Failed to parse config file")
+                summary
             }
-        } else {
-            event!(Level::ERROR, "Failed to read config file")
+            Err(e) => {
+                event!(
+                    Level::ERROR,
+                    "Keep current config, failed to reload config file: {}",
+                    e
+                );
+                ReloadSummary {
+                    errors: vec![e.to_string()],
+                    ..Default::default()
+                }
+            }
+        };
+        if let Some(reply) = reply {
+            let _ = reply.send(summary);
         }
     }
     event!(Level::INFO, "Update channel closed");
 }
 
+// staging config is applied only if every entry is valid
+async fn load_config(
+    config_file: &str,
+    format: ConfigFormat,
+) -> Result<ServiceConfig, FileConfigError> {
+    let content = tokio::fs::read_to_string(config_file).await?;
+    let config = parse_config(&content, format)?;
+    for s in config.services.iter() {
+        validate_service(s)?;
+    }
+    for c in config.clients.iter() {
+        validate_client(c)?;
+    }
+    Ok(config)
+}
+
+// debounced changes of config file, its directory is watched so saving by rename is picked up
+fn watch_file(config_file: &str) -> mpsc::Receiver<()> {
+    let (tx, rx) = mpsc::channel(1);
+    let path = PathBuf::from(config_file);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let (event_tx, event_rx) = std::sync::mpsc::channel();
+    let watcher = notify::watcher(event_tx, Duration::from_secs(1)).and_then(|mut w| {
+        w.watch(&dir, RecursiveMode::NonRecursive)?;
+        Ok(w)
+    });
+    let watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            event!(
+                Level::WARN,
+                "Failed to watch config file, reload with USR2 signal only: {}",
+                e
+            );
+            return rx;
+        }
+    };
+    std::thread::spawn(move || {
+        let _watcher = watcher;
+        while let Ok(event) = event_rx.recv() {
+            let changed = match &event {
+                DebouncedEvent::Create(p)
+                | DebouncedEvent::Write(p)
+                | DebouncedEvent::Rename(_, p) => p.file_name() == path.file_name(),
+                DebouncedEvent::Error(e, _) => {
+                    event!(Level::WARN, "Config file watch error: {}", e);
+                    false
+                }
+                _ => false,
+            };
+            // a pending reload reads the latest content anyway
+            if changed {
+                if let Err(TrySendError::Closed(_)) = tx.try_send(()) {
+                    break;
+                }
+            }
+        }
+    });
+    rx
+}
+
+// expand environment variables in string values before parsing into config structs
+fn parse_config(content: &str, format: ConfigFormat) -> Result<ServiceConfig, FileConfigError> {
+    let mut value: Value = match format {
+        ConfigFormat::Yaml => serde_yaml::from_str(content)?,
+        ConfigFormat::Json => serde_json::from_str(content)?,
+        ConfigFormat::Toml => toml::from_str(content)?,
+    };
+    expand_env(&mut value)?;
+    // yaml text again, as untyped scalars like `id: 1` are accepted for string fields
+    let content = serde_yaml::to_string(&value)?;
+    Ok(serde_yaml::from_str(&content)?)
+}
+
+fn expand_env(value: &mut Value) -> Result<(), FileConfigError> {
+    match value {
+        Value::String(s) => *s = interpolate_env(s)?,
+        Value::Sequence(seq) => {
+            for v in seq.iter_mut() {
+                expand_env(v)?;
+            }
+        }
+        Value::Mapping(map) => {
+            for (_, v) in map.iter_mut() {
+                expand_env(v)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Replace `${VAR}` and `${VAR:-default}` with process environment, `$$` for a literal `$`.
+///
+/// Like shell, default is used when the variable is unset or empty.
+pub fn interpolate_env(input: &str) -> Result<String, FileConfigError> {
+    let mut result = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(pos) = rest.find('$') {
+        result.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if let Some(r) = rest.strip_prefix("$$") {
+            result.push('$');
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("${") {
+            let end = r
+                .find('}')
+                .ok_or_else(|| FileConfigError::UnclosedEnv(input.into()))?;
+            let (name, default) = match r[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&r[..end], None),
+            };
+            match (std::env::var(name), default) {
+                (Ok(v), Some(d)) if v.is_empty() => result.push_str(d),
+                (Ok(v), _) => result.push_str(&v),
+                (Err(_), Some(d)) => result.push_str(d),
+                (Err(_), None) => return Err(FileConfigError::MissingEnv(name.into())),
+            }
+            rest = &r[end + 1..];
+        } else {
+            result.push('$');
+            rest = &rest[1..];
+        }
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+// added or changed entries and removed ones, unchanged entries are left out
 fn config_diff(old: &ServiceConfig, new: &ServiceConfig) -> Vec<ConfigUpdate> {
     let mut result = Vec::new();
 
-    let mut exist_service: HashMap<String, bool> = HashMap::new();
+    let old_services: HashMap<&str, &ServiceInfo> = old
+        .services
+        .iter()
+        .map(|s| (s.service_id.as_str(), s))
+        .collect();
     for s in new.services.iter() {
-        exist_service.insert(s.service_id.clone(), true);
-        result.push(ConfigUpdate::ServiceUpdate(s.clone()));
+        if old_services.get(s.service_id.as_str()) != Some(&s) {
+            result.push(ConfigUpdate::ServiceUpdate(s.clone()));
+        }
     }
+    let new_services: HashMap<&str, &ServiceInfo> = new
+        .services
+        .iter()
+        .map(|s| (s.service_id.as_str(), s))
+        .collect();
     for os in old.services.iter() {
-        if let Some(_) = exist_service.get(&os.service_id) {
-            // pass
-        } else {
+        if !new_services.contains_key(os.service_id.as_str()) {
             result.push(ConfigUpdate::ServiceRemove(os.service_id.clone()))
         }
     }
 
-    let mut exist_client: HashMap<String, bool> = HashMap::new();
+    let old_clients: HashMap<&str, &ClientInfo> = old
+        .clients
+        .iter()
+        .map(|c| (c.client_id.as_str(), c))
+        .collect();
     for c in new.clients.iter() {
-        exist_client.insert(c.client_id.clone(), true);
-        result.push(ConfigUpdate::ClientUpdate(c.clone()));
+        if old_clients.get(c.client_id.as_str()) != Some(&c) {
+            result.push(ConfigUpdate::ClientUpdate(c.clone()));
+        }
     }
+    let new_clients: HashMap<&str, &ClientInfo> = new
+        .clients
+        .iter()
+        .map(|c| (c.client_id.as_str(), c))
+        .collect();
     for oc in old.clients.iter() {
-        if let Some(_) = exist_client.get(&oc.client_id) {
-            // pass
-        } else {
+        if !new_clients.contains_key(oc.client_id.as_str()) {
             result.push(ConfigUpdate::ClientRemove(oc.client_id.clone()))
         }
     }
 
-    return result;
+    result
 }
 
 #[cfg(unix)]
@@ -86,7 +390,7 @@ mod reload_signal {
         let mut usr2 = signal(SignalKind::user_defined2()).expect("Failed to bind on USR2 signal");
         let (tx, rx) = mpsc::channel(1);
         tokio::spawn(async move {
-            while let Some(_) = usr2.recv().await {
+            while usr2.recv().await.is_some() {
                 event!(Level::ERROR, "Got reload signal");
                 let _ = tx.send(()).await;
             }
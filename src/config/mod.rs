@@ -1,9 +1,15 @@
+mod dns_resolve;
 mod protocol;
+mod validate;
 mod watch;
 
+pub mod consul_config;
 pub mod etcd_config;
 pub mod file_config;
+pub mod redis_config;
 pub mod ws_config;
 
 pub use protocol::*;
-pub use watch::ConfigSource;
+pub use validate::{validate_client, validate_service, validate_update, ConfigError};
+pub use file_config::{ConfigReloader, ReloadSummary};
+pub use watch::{ConfigSource, SyncState};
@@ -1,8 +1,11 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
 
 use serde::{Serialize, Deserialize};
 
 
+// updates are passed by value through channels, boxing ServiceInfo gains nothing
+#[allow(clippy::large_enum_variant)]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag="type", content="data")]
 pub enum ConfigUpdate {
@@ -28,10 +31,12 @@ pub struct ClientInfo {
     pub pub_key: String,
     pub ip_whitelist: Vec<String>,
     pub services: HashMap<String, String>,
+    #[serde(default)]
+    pub roles: Vec<String>,  // roles matched by AclRules filters
 }
 
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ServiceInfo {
     pub service_id: String,
     pub path: String,
@@ -42,17 +47,226 @@ pub struct ServiceInfo {
     pub filters: Vec<FilterSetting>,
     pub sla: Vec<ServiceLevel>,
     pub upstreams: Vec<Upstream>,
+    #[serde(default)]
+    pub max_conn: u64,  // in-flight requests of the whole service, 0 for unlimited
+    #[serde(default)]
+    pub queue_depth: usize,  // requests waiting for the service worker, 10 if 0
+    #[serde(default)]
+    pub queue_fail_fast: bool,  // 503 when queue is full, instead of waiting
+    #[serde(default)]
+    pub max_proxy_tasks: usize,  // upstream calls spawned by the service worker at once, requests wait in the queue at the cap, unlimited if 0
+    #[serde(default)]
+    pub hash_load_bound: u32,  // percent of average load for consistent_hash, 0 to disable
+    #[serde(default)]
+    pub sticky: Option<StickySetting>,
+    #[serde(default)]
+    pub rewrite: PathRewrite,
+    #[serde(default)]
+    pub upstream_scheme: Option<UpstreamScheme>,  // scheme forced on requests to all upstreams, target scheme if not set
+    #[serde(default)]
+    pub access_log_sample: Option<u32>,  // per mille of 2xx responses in access log, global --access_log_sample if not set
+    #[serde(default)]
+    pub outlier: Option<OutlierSetting>,  // eject upstreams by error rate, off if not set
+    #[serde(default)]
+    pub routes: Vec<RouteRule>,  // first match picks upstreams, 404 if none matches, all upstreams if empty
+    #[serde(default)]
+    pub traffic_split: Option<TrafficSplit>,  // percent of requests to each upstream version, before load balance
+    #[serde(default)]
+    pub version_override: Option<VersionOverride>,  // header or cookie picking upstream version, bypassing traffic_split
+    #[serde(default)]
+    pub mirror: Option<MirrorSetting>,  // copy of sampled requests sent to a shadow upstream, responses discarded
+    #[serde(default)]
+    pub timing_headers: bool,  // X-Upstream-Time-Ms, X-Gateway-Time-Ms and Server-Timing of middlewares in responses, off to not leak timing
+    #[serde(default)]
+    pub retry: Option<RetrySetting>,  // retry idempotent requests without body, no retry if not set
+    #[serde(default)]
+    pub slow_start: u64,  // seconds to grow weight of added or recovered upstreams from 10% to full, 0 for off
+    #[serde(default)]
+    pub allowed_methods: Vec<String>,  // methods proxied like [GET, HEAD], others answered 405, all if empty
+    #[serde(default)]
+    pub body_limit: Option<BodyLimitSetting>,  // request body size by content type, answered 413 if over, unlimited if not set
+    #[serde(default)]
+    pub maintenance: bool,  // answer 503 without calling upstreams, switched without restarting the service
+    #[serde(default)]
+    pub maintenance_page: Option<MaintenancePage>,  // response in maintenance, json error if not set
+    #[serde(default)]
+    pub error_pages: Vec<ErrorPage>,  // bodies of error responses by status, before global --error_pages
+    #[serde(default)]
+    pub coalesce: Option<CoalesceSetting>,  // identical GETs in flight share one upstream call, off if not set
+    #[serde(default)]
+    pub idempotency: Option<IdempotencySetting>,  // responses replayed to retries with the same Idempotency-Key, off if not set
+    #[serde(default)]
+    pub failover_service_id: Option<String>,  // service taking requests while no upstream is ready, chains stop at a service already passed
+    #[serde(default)]
+    pub upgrade_idle_timeout: u64,  // seconds without data either way before a WebSocket or other upgraded connection is closed, 300 if 0
+    #[serde(default)]
+    pub middlewares: Vec<String>,  // middleware chain outermost first, ending with Upstream, default chain if empty
+    #[serde(default)]
+    pub disabled_middlewares: Vec<String>,  // middlewares skipped for this service, Auth to serve every request as anonymous client
 }
 
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+/// Request body size limits by `Content-Type`, a media type like `application/json` before
+/// a wildcard like `image/*`, `max_body_bytes` for the others. Chunked bodies are counted as
+/// they are sent upstream.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct BodyLimitSetting {
+    #[serde(default)]
+    pub max_body_bytes: u64,  // limit of content types not listed, unlimited if 0
+    #[serde(default)]
+    pub content_types: BTreeMap<String, u64>,  // limit by media type or wildcard, unlimited if 0
+}
+
+
+/// Static body replacing gateway errors and upstream error responses of a status,
+/// for clients whose Accept header lists its content type
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ErrorPage {
+    pub status: u16,  // 400 to 599
+    #[serde(default)]
+    pub content_type: String,  // text/html if empty, json pages also serve clients accepting */*
+    #[serde(default)]
+    pub body: String,
+    #[serde(default)]
+    pub file: String,  // body read from file when config is loaded, instead of body
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MaintenancePage {
+    #[serde(default)]
+    pub retry_after: u64,  // seconds in Retry-After header, not sent if 0
+    #[serde(default)]
+    pub content_type: String,  // text/html if empty
+    #[serde(default)]
+    pub body: String,
+}
+
+
+/// Retries on connection errors and 502, 503 or 504, capped by a budget of the whole service
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RetrySetting {
+    pub attempts: u32,  // retries after the first try
+    #[serde(default)]
+    pub budget_percent: Option<u32>,  // retries allowed as percent of requests in last 10 seconds, 20 if not set
+    #[serde(default)]
+    pub min_retries: Option<u32>,  // retries per second allowed beyond budget_percent, 10 if not set
+}
+
+
+/// Single-flight of identical GET and HEAD requests, keyed on method, URI, client and `vary` headers.
+/// Waiters get a copy of the leader response, or make their own call if it failed or can't be shared.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CoalesceSetting {
+    #[serde(default)]
+    pub vary: Vec<String>,  // request headers selecting different responses, like Accept-Encoding
+    #[serde(default)]
+    pub max_body: usize,  // bytes of response buffered for waiters, 1MB if 0, larger responses not shared
+}
+
+
+/// Responses of requests with an `Idempotency-Key` header, replayed to later requests of the client
+/// with the same key. Requests in flight with the key wait for the first one, across replicas
+/// with `--idempotency_redis`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IdempotencySetting {
+    pub ttl: u64,  // seconds a response is replayed
+    #[serde(default)]
+    pub max_body: usize,  // bytes of response stored, 1MB if 0, larger responses not stored
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MirrorSetting {
+    pub target: String,  // shadow upstream url, requests rewritten like other upstreams
+    pub percent: u32,  // of requests mirrored, 0 to 100
+    #[serde(default)]
+    pub max_body: usize,  // bytes of request body buffered for the copy, 1MB if 0, larger requests not mirrored
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct VersionOverride {
+    #[serde(default)]
+    pub header: String,  // like X-Canary, not checked if empty
+    #[serde(default)]
+    pub cookie: String,  // cookie name, not checked if empty
+    pub clients: Vec<String>,  // client ids allowed to override, "*" for any client
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TrafficSplit {
+    pub versions: Vec<VersionShare>,  // percents sum up to 100
+    #[serde(default)]
+    pub sticky_client: bool,  // same client always gets same version, random per request if false
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct VersionShare {
+    pub version: String,  // upstream version
+    pub percent: u32,
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RouteRule {
+    #[serde(default)]
+    pub methods: String,  // comma separated, any method if empty or "*"
+    pub path_pattern: String,  // glob on path after service path, like /read/*
+    pub upstreams: Vec<String>,  // ids of service upstreams, balanced by service load_balance
+    #[serde(default)]
+    pub timeout: Option<u64>,  // seconds, overrides upstream request_timeout and service timeout
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OutlierSetting {
+    pub error_percent: u32,  // eject upstream with more errors (5xx or failed) in window
+    pub window: u64,  // seconds of rolling statistics
+    #[serde(default)]
+    pub min_requests: u64,  // requests in window before errors are judged
+    pub cooldown: u64,  // seconds ejected before traffic is let back
+    #[serde(default)]
+    pub ramp_up: u64,  // seconds to grow traffic from 10% to full after cooldown, 0 for all at once
+    #[serde(default)]
+    pub max_ejection_percent: u32,  // of upstreams ejected at the same time, at least one, 0 for 50
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[serde(tag="mode", rename_all="snake_case")]
+pub enum PathRewrite {
+    #[default]
+    StripPrefix,  // remove service path, `/svc/a?q=1` to `<target>/a?q=1`
+    Keep,  // forward full path, `/svc/a?q=1` to `<target>/svc/a?q=1`
+    // replace first match in full request path, unmatched path is kept as is.
+    // original query is always kept,
+    // `?` in result starts extra query placed before the original one,
+    // `#` in result starts a fragment, which is never sent upstream
+    Regex { pattern: String, replacement: String },
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StickySetting {
+    pub cookie: String,
+    #[serde(default)]
+    pub secret: String,  // key to sign cookie, random key of current process if empty
+    #[serde(default)]
+    pub max_age: u64,  // seconds, 0 for session cookie
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ServiceLevel {
     pub name: String,
     pub filters: Vec<FilterSetting>,
 }
 
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Upstream {
     pub id: String,
     pub target: String,
@@ -62,10 +276,101 @@ pub struct Upstream {
     pub error_threshold: u64,
     pub error_reset: u64,
     pub retry_delay: u64,
+    #[serde(default)]
+    pub connect_timeout: Option<u64>,  // seconds, service timeout if not set
+    #[serde(default)]
+    pub request_timeout: Option<u64>,  // seconds, service timeout if not set
+    #[serde(default)]
+    pub pool_idle_timeout: Option<u64>,  // seconds, service timeout if not set
+    #[serde(default)]
+    pub pool_max_idle_per_host: Option<usize>,  // unlimited if not set, 0 to disable pooling
+    #[serde(default)]
+    pub tcp_keepalive: Option<u64>,  // seconds, 30 if not set, 0 to disable
+    #[serde(default)]
+    pub http2_only: bool,  // talk HTTP/2 to upstream, prior knowledge for http:// targets
+    #[serde(default)]
+    pub host_header: Option<String>,  // Host of proxied requests, client's Host if not set
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,  // static headers set on requests to this upstream
+    #[serde(default)]
+    pub dns_refresh: Option<u64>,  // seconds between resolving target host into one upstream per address, off if not set
+    #[serde(default)]
+    pub ca_file: Option<String>,  // PEM bundle trusted for https target, gateway --upstream_ca_file or native certs if not set
+    #[serde(default)]
+    pub insecure_skip_verify: bool,  // accept any certificate of https target, for development only
+    #[serde(default)]
+    pub client_cert: Option<String>,  // PEM certificate chain presented to https target for mutual TLS, with client_key
+    #[serde(default)]
+    pub client_key: Option<String>,  // PEM private key of client_cert
+    #[serde(default)]
+    pub tls_server_name: Option<String>,  // name sent in SNI and verified in the certificate of https target, target host if not set
+    #[serde(default)]
+    pub scheme: Option<UpstreamScheme>,  // scheme forced on requests to target, service upstream_scheme if not set
 }
 
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+impl Upstream {
+    /// Scheme forced on requests to this upstream, its own before the service's
+    pub fn forced_scheme(&self, service: &ServiceInfo) -> Option<UpstreamScheme> {
+        self.scheme.or(service.upstream_scheme)
+    }
+
+    /// Target as requests are sent, with the forced scheme of the service if any
+    pub fn target_of(&self, service: &ServiceInfo) -> String {
+        match self.forced_scheme(service) {
+            Some(scheme) => scheme.apply(&self.target),
+            None => self.target.clone(),
+        }
+    }
+}
+
+
+/// Scheme of upstream requests regardless of the one in `target`, e.g. https everywhere
+/// or plain http inside a trusted network
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamScheme {
+    Http,
+    Https,
+}
+
+impl UpstreamScheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UpstreamScheme::Http => "http",
+            UpstreamScheme::Https => "https",
+        }
+    }
+
+    /// Replace the scheme of a target, a port left out follows the new scheme,
+    /// `http://api/v1` forced to https is `https://api/v1` on port 443
+    pub fn apply(&self, target: &str) -> String {
+        let rest = target.split_once("://").map_or(target, |(_, rest)| rest);
+        format!("{}://{}", self.as_str(), rest)
+    }
+}
+
+
+/// Cap of requests per client in a billing period, counted across gateway replicas
+/// if a quota store is set with `--quota_redis`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct QuotaSetting {
+    pub limit: u64,  // requests in a period
+    #[serde(default)]
+    pub period: QuotaPeriod,
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaPeriod {
+    #[default]
+    Monthly,  // calendar month in UTC
+    Daily,  // UTC day
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct RateLimitSetting {
     pub interval: i32,  // seconds
     pub limit: i32,
@@ -73,7 +378,35 @@ pub struct RateLimitSetting {
 }
 
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+/// Rate limit by client address, for services without client identity like auth None.
+/// Addresses sharing a prefix share a bucket, so one client can't spread requests over a subnet.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IpRateLimitSetting {
+    pub interval: i32,  // seconds
+    pub limit: i32,
+    pub burst: i32,
+    #[serde(default)]
+    pub ipv4_prefix: u8,  // bits of IPv4 address grouped into a bucket, 32 if 0
+    #[serde(default)]
+    pub ipv6_prefix: u8,  // bits of IPv6 address grouped into a bucket, 64 if 0
+    #[serde(default)]
+    pub max_clients: usize,  // buckets kept, least recently seen dropped first, 100000 if 0
+}
+
+
+/// Rate limit by the tenant extracted from the request path or header, a bucket per tenant.
+/// Requests without a tenant pass.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TenantRateLimitSetting {
+    pub interval: i32,  // seconds
+    pub limit: i32,
+    pub burst: i32,
+    #[serde(default)]
+    pub max_tenants: usize,  // buckets kept, least recently seen dropped first, 100000 if 0
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct HeaderSetting {
     pub operate_on: String,
     pub injection: Vec<(String, String)>,
@@ -81,26 +414,133 @@ pub struct HeaderSetting {
 }
 
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ACLSetting {
     pub access_control: String,
     pub paths: Vec<PathMatcher>,
 }
 
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+/// Access rules evaluated in order, the first rule matching the client, method and path
+/// allows or denies the request, denied requests are answered with 403.
+/// Requests matching no rule get `default`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AclRulesSetting {
+    pub rules: Vec<AclRule>,
+    #[serde(default)]
+    pub default: AclAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AclRule {
+    pub id: String,  // logged when the rule denies a request
+    #[serde(default)]
+    pub clients: Vec<String>,  // client ids, with roles any client if both are empty
+    #[serde(default)]
+    pub roles: Vec<String>,  // roles of the client
+    #[serde(default)]
+    pub methods: String,  // comma separated like GET,HEAD, all if empty or *
+    pub path_regex: String,  // matched against the path after the service path, `^/admin/`
+    pub action: AclAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AclAction {
+    Allow,
+    #[default]
+    Deny,
+}
+
+
+/// Scopes a client must hold for the matching requests, taken from the JWT scope claim,
+/// requests lacking any of them are answered with 403
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RequireScopesSetting {
+    #[serde(default)]
+    pub methods: String,  // comma separated like POST,PUT, all if empty or *
+    #[serde(default)]
+    pub path_pattern: String,  // glob of the path after the service path like ACL, all if empty
+    pub scopes: Vec<String>,  // all required
+}
+
+
+/// JSON response body transformation, paths are dot separated field names like `data.user.email`,
+/// with `*` matching every array element or object value
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct JsonTransformSetting {
+    #[serde(default)]
+    pub removal: Vec<String>,  // paths of fields to drop
+    #[serde(default)]
+    pub rename: Vec<(String, String)>,  // (path, new field name)
+    #[serde(default)]
+    pub redaction: Vec<String>,  // paths of values replaced by "***"
+    #[serde(default)]
+    pub max_body: usize,  // bytes buffered for transformation, 1MB if 0, larger bodies pass through
+}
+
+
+/// JSON document embedded in config, like a JSON Schema, hashed by its serialization
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(transparent)]
+pub struct JsonDocument(pub serde_json::Value);
+
+impl Hash for JsonDocument {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.to_string().hash(state);
+    }
+}
+
+
+/// JSON Schema validation of `application/json` request bodies of the matching requests,
+/// invalid bodies are answered with 400 and the validation errors, other content types pass
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct JsonSchemaSetting {
+    #[serde(default)]
+    pub methods: String,  // comma separated like POST,PUT, all if empty or *
+    #[serde(default)]
+    pub path_pattern: String,  // glob of the path after the service path like ACL, all if empty
+    pub schema: JsonDocument,
+    #[serde(default)]
+    pub max_body: usize,  // bytes buffered for validation, 1MB if 0, larger bodies answered with 413
+}
+
+
+/// Faults injected before proxying, applied only if the gateway runs with --fault_injection
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FaultInjectionSetting {
+    #[serde(default)]
+    pub abort_percent: u32,  // of requests answered with abort_status, without calling upstream
+    #[serde(default)]
+    pub abort_status: u16,  // 503 if 0
+    #[serde(default)]
+    pub delay_percent: u32,  // of requests delayed before proxying
+    #[serde(default)]
+    pub delay_ms: u64,  // capped by service timeout, timed out requests get 504
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct PathMatcher {
     pub methods: String,
     pub path_pattern: String,
 }
 
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 #[serde(tag="type", content="setting")]
 pub enum FilterSetting {
     RateLimit(RateLimitSetting),
+    IpRateLimit(IpRateLimitSetting),
+    TenantRateLimit(TenantRateLimitSetting),
     Header(HeaderSetting),
     ACL(ACLSetting),
+    AclRules(AclRulesSetting),
+    RequireScopes(RequireScopesSetting),
+    JsonTransform(JsonTransformSetting),
+    JsonSchema(JsonSchemaSetting),
+    FaultInjection(FaultInjectionSetting),
+    Quota(QuotaSetting),
 }
 
 
@@ -108,29 +548,51 @@ impl FilterSetting {
     pub fn get_type(setting: &FilterSetting) -> String {
         match setting {
             FilterSetting::ACL(_) => "ACL".into(),
+            FilterSetting::AclRules(_) => "ACL".into(),
+            FilterSetting::RequireScopes(_) => "Scope".into(),
             FilterSetting::Header(_) => "Header".into(),
             FilterSetting::RateLimit(_) => "RateLimit".into(),
+            FilterSetting::IpRateLimit(_) => "RateLimit".into(),
+            FilterSetting::TenantRateLimit(_) => "RateLimit".into(),
+            FilterSetting::JsonTransform(_) => "JsonTransform".into(),
+            FilterSetting::JsonSchema(_) => "JsonSchema".into(),
+            FilterSetting::FaultInjection(_) => "FaultInjection".into(),
+            FilterSetting::Quota(_) => "Quota".into(),
         }
     }
 }
 
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct AppKeyAuth {}
 
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
-pub struct JwtAuth {}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct JwtAuth {
+    #[serde(default)]
+    pub scope_claim: String,  // claim with the client's scopes, space separated or a list, `scope` if empty
+}
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+/// OAuth2 token introspection (RFC 7662) of opaque bearer tokens
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IntrospectionAuth {
+    pub url: String,  // introspection endpoint, tokens are POSTed to it as a form
+    pub client_id: String,  // credentials of the gateway at the endpoint, sent as basic auth
+    pub client_secret: String,
+    #[serde(default)]
+    pub cache_ttl: u64,  // seconds an introspection result is reused, 60 if 0, never past the token's exp
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct NoAuth {}
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 #[serde(tag = "type")]
 pub enum AuthSetting {
     None(NoAuth),
     AppKey(AppKeyAuth),
     JWT(JwtAuth),
+    Introspection(IntrospectionAuth),
 }
 
 
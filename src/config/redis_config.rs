@@ -0,0 +1,175 @@
+use crate::config::{ClientInfo, ConfigUpdate, ServiceInfo, SyncState};
+use futures::StreamExt;
+use redis::aio::Connection;
+use redis::{
+    AsyncCommands, ConnectionAddr, ConnectionInfo, ErrorKind, RedisConnectionInfo, RedisResult,
+};
+use tokio::sync::mpsc;
+use tracing::{event, Level};
+
+// e.g. redis://:<password>@<redis_host>:6379/juapi/<env-ns>.<env-name>?db=0
+// keys are <path>/services/<service_id> and <path>/clients/<client_id> with json values,
+// changes are watched with keyspace notifications, which the operator enables on the server
+// with `notify-keyspace-events` including K$gx (or KA).
+//
+// Returns error if connecting or initial load fails, Ok once a loaded watch is lost.
+// Every load is a full resync, entries deleted while disconnected are removed.
+pub async fn watch_config(
+    source: String,
+    sender: mpsc::Sender<ConfigUpdate>,
+    state: &mut SyncState,
+) -> RedisResult<()> {
+    let url = url::Url::parse(&source).map_err(|e| {
+        (
+            ErrorKind::InvalidClientConfig,
+            "bad redis url",
+            e.to_string(),
+        )
+    })?;
+    let host = url.host_str().unwrap_or("127.0.0.1").to_string();
+    let port = url.port().unwrap_or(6379);
+    let db: i64 = url
+        .query_pairs()
+        .find(|(k, _)| k.eq("db"))
+        .and_then(|(_, v)| v.parse().ok())
+        .unwrap_or(0);
+    let prefix = url.path().trim_end_matches('/').to_string();
+    let info = ConnectionInfo {
+        addr: ConnectionAddr::Tcp(host, port),
+        redis: RedisConnectionInfo {
+            db,
+            username: Some(url.username().to_string()).filter(|u| !u.is_empty()),
+            password: url.password().map(String::from),
+        },
+    };
+    let client = redis::Client::open(info)?;
+    let mut conn = client.get_async_connection().await?;
+
+    // keyspace events for string commands and generic del/expire, CONFIG GET may be
+    // disabled on managed servers, then the setting is trusted
+    let flags: RedisResult<Vec<String>> = redis::cmd("CONFIG")
+        .arg("GET")
+        .arg("notify-keyspace-events")
+        .query_async(&mut conn)
+        .await;
+    match flags {
+        Ok(flags) => {
+            let missing = missing_keyspace_events(flags.get(1).map_or("", String::as_str));
+            if !missing.is_empty() {
+                return Err((
+                    ErrorKind::ClientError,
+                    "redis keyspace notifications disabled",
+                    format!("notify-keyspace-events lacks {}, needs K$gx", missing),
+                )
+                    .into());
+            }
+        }
+        Err(e) => event!(
+            Level::WARN,
+            "Fail to check redis keyspace notifications, make sure notify-keyspace-events includes K$gx: {}",
+            e
+        ),
+    }
+
+    // subscribe before loading, so changes during initial load are not lost
+    let mut pubsub = client.get_async_connection().await?.into_pubsub();
+    let channel_prefix = format!("__keyspace@{}__:", db);
+    pubsub
+        .psubscribe(format!("{}{}/*", channel_prefix, prefix))
+        .await?;
+
+    let pattern = format!("{}/*", prefix);
+    let keys: Vec<String> = {
+        let mut iter = conn.scan_match::<_, String>(&pattern).await?;
+        let mut keys = Vec::new();
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+        keys
+    };
+    state.start();
+    for key in keys.iter() {
+        if let Some(u) = load_key(&mut conn, &prefix, key).await? {
+            state.track(&u);
+            let _ = sender.send(u).await;
+        }
+    }
+    for u in state.finish() {
+        let _ = sender.send(u).await;
+    }
+    let _ = sender.send(ConfigUpdate::ConfigReady(true)).await;
+
+    // watch further config changes
+    let mut messages = pubsub.on_message();
+    while let Some(msg) = messages.next().await {
+        let key = match msg.get_channel_name().strip_prefix(&channel_prefix) {
+            Some(key) => key.to_string(),
+            None => continue,
+        };
+        let op: String = msg.get_payload().unwrap_or_default();
+        let update = match op.as_str() {
+            "set" | "rename_to" => match load_key(&mut conn, &prefix, &key).await {
+                Ok(update) => update,
+                Err(e) => {
+                    event!(Level::ERROR, "Fail to read redis key {}: {}", key, e);
+                    None
+                }
+            },
+            "del" | "expired" | "evicted" | "rename_from" => extract_event(&prefix, &key, None),
+            _ => None,
+        };
+        if let Some(u) = update {
+            state.track(&u);
+            let _ = sender.send(u).await;
+        }
+    }
+    event!(Level::INFO, "redis keyspace subscription closed");
+    Ok(())
+}
+
+// flags of K$gx not set in notify-keyspace-events, A covering $, g and x
+fn missing_keyspace_events(flags: &str) -> String {
+    "K$gx"
+        .chars()
+        .filter(|f| !(flags.contains(*f) || *f != 'K' && flags.contains('A')))
+        .collect()
+}
+
+async fn load_key(
+    conn: &mut Connection,
+    prefix: &str,
+    key: &str,
+) -> RedisResult<Option<ConfigUpdate>> {
+    let val: Option<String> = conn.get(key).await?;
+    Ok(val.and_then(|v| extract_event(prefix, key, Some(&v))))
+}
+
+fn extract_event(prefix: &str, key: &str, val: Option<&str>) -> Option<ConfigUpdate> {
+    // key schema: <prefix>/<services|clients>/<entity-id>
+    let (entity_type, entity) = key
+        .strip_prefix(prefix)?
+        .strip_prefix('/')?
+        .split_once('/')?;
+    if entity.is_empty() {
+        return None;
+    }
+    match (entity_type, val) {
+        ("services", None) => Some(ConfigUpdate::ServiceRemove(String::from(entity))),
+        ("clients", None) => Some(ConfigUpdate::ClientRemove(String::from(entity))),
+        ("services", Some(val)) => match serde_json::from_str::<ServiceInfo>(val) {
+            Ok(conf) => Some(ConfigUpdate::ServiceUpdate(conf)),
+            Err(e) => {
+                event!(Level::ERROR, "Invalid service config {}: {}", key, e);
+                None
+            }
+        },
+        ("clients", Some(val)) => match serde_json::from_str::<ClientInfo>(val) {
+            Ok(conf) => Some(ConfigUpdate::ClientUpdate(conf)),
+            Err(e) => {
+                event!(Level::ERROR, "Invalid client config {}: {}", key, e);
+                None
+            }
+        },
+        _ => None,
+    }
+}
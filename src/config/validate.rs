@@ -0,0 +1,589 @@
+use crate::config::{
+    ClientInfo, ConfigUpdate, FilterSetting, PathRewrite, ServiceInfo, UpstreamScheme,
+};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::Method;
+use std::collections::HashSet;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    #[error("service_id is empty")]
+    EmptyServiceId,
+
+    #[error("service {0}: invalid path {1:?}, expect a single segment like /name")]
+    InvalidPath(String, String),
+
+    #[error("service {0}: no upstream configured")]
+    NoUpstream(String),
+
+    #[error("service {0}: duplicated upstream id {1}")]
+    DuplicatedUpstream(String, String),
+
+    #[error("service {0}: upstream {1} has invalid target {2:?}")]
+    InvalidTarget(String, String, String),
+
+    #[error("service {0}: upstream {1} has invalid ca_file, {2}")]
+    InvalidCaFile(String, String, String),
+
+    #[error("service {0}: upstream {1} has invalid client certificate, {2}")]
+    InvalidClientCert(String, String, String),
+
+    #[error("service {0}: upstream {1} has invalid tls_server_name, {2}")]
+    InvalidServerName(String, String, String),
+
+    #[error("service {0}: upstream {1} cannot be forced to {2}, {3}")]
+    InvalidScheme(String, String, String, String),
+
+    #[error("service {0}: upstream {1} has zero max_conn")]
+    InvalidMaxConn(String, String),
+
+    #[error("service {0}: upstream {1} has invalid header {2:?}")]
+    InvalidHeader(String, String, String),
+
+    #[error("service {0}: total upstream weight is zero")]
+    ZeroWeight(String),
+
+    #[error("service {0}: invalid rewrite pattern {1:?}")]
+    InvalidRewrite(String, String),
+
+    #[error("service {0}: invalid outlier setting, {1}")]
+    InvalidOutlier(String, &'static str),
+
+    #[error("service {0}: invalid route {1}, {2}")]
+    InvalidRoute(String, usize, String),
+
+    #[error("service {0}: invalid traffic_split, {1}")]
+    InvalidTrafficSplit(String, String),
+
+    #[error("service {0}: invalid version_override, {1}")]
+    InvalidVersionOverride(String, String),
+
+    #[error("service {0}: invalid mirror, {1}")]
+    InvalidMirror(String, String),
+
+    #[error("service {0}: invalid coalesce, {1}")]
+    InvalidCoalesce(String, String),
+
+    #[error("service {0}: invalid idempotency, {1}")]
+    InvalidIdempotency(String, String),
+
+    #[error("service {0}: invalid introspection auth, {1}")]
+    InvalidIntrospection(String, String),
+
+    #[error("service {0}: invalid FaultInjection filter, {1}")]
+    InvalidFaultInjection(String, String),
+
+    #[error("service {0}: invalid IpRateLimit filter, {1}")]
+    InvalidIpRateLimit(String, String),
+
+    #[error("service {0}: invalid TenantRateLimit filter, {1}")]
+    InvalidTenantRateLimit(String, String),
+
+    #[error("service {0}: invalid JsonSchema filter, {1}")]
+    InvalidJsonSchema(String, String),
+
+    #[error("service {0}: invalid AclRules filter, {1}")]
+    InvalidAclRules(String, String),
+
+    #[error("service {0}: invalid RequireScopes filter, {1}")]
+    InvalidRequireScopes(String, String),
+
+    #[error("service {0}: invalid Quota filter, limit should be positive")]
+    InvalidQuota(String),
+
+    #[error("service {0}: invalid retry, {1}")]
+    InvalidRetry(String, String),
+
+    #[error("service {0}: invalid body_limit content type {1:?}")]
+    InvalidBodyLimit(String, String),
+
+    #[error("service {0}: invalid allowed method {1:?}")]
+    InvalidMethod(String, String),
+
+    #[error("service {0}: invalid failover_service_id, {1}")]
+    InvalidFailover(String, String),
+
+    #[error("service {0}: invalid maintenance_page, {1}")]
+    InvalidMaintenancePage(String, String),
+
+    #[error("service {0}: invalid error page of status {1}, {2}")]
+    InvalidErrorPage(String, u16, String),
+
+    #[error("service {0}: invalid middlewares, {1}")]
+    InvalidMiddlewares(String, String),
+
+    #[error("service {0}: middleware {1} needs a {1} filter in filters or sla to work")]
+    MissingMiddlewareSetting(String, String),
+
+    #[error("client_id is empty")]
+    EmptyClientId,
+}
+
+pub fn validate_service(service: &ServiceInfo) -> Result<(), ConfigError> {
+    let sid = &service.service_id;
+    if sid.is_empty() {
+        return Err(ConfigError::EmptyServiceId);
+    }
+    let segment = service.path.strip_prefix('/').unwrap_or("");
+    if segment.is_empty() || segment.contains('/') {
+        return Err(ConfigError::InvalidPath(sid.clone(), service.path.clone()));
+    }
+    if service.upstreams.is_empty() {
+        return Err(ConfigError::NoUpstream(sid.clone()));
+    }
+    if let crate::config::AuthSetting::Introspection(auth) = &service.auth {
+        let valid_url = match url::Url::parse(&auth.url) {
+            Ok(url) => (url.scheme() == "http" || url.scheme() == "https") && url.has_host(),
+            Err(_) => false,
+        };
+        let msg = if !valid_url {
+            format!("bad url {:?}", auth.url)
+        } else if auth.client_id.is_empty() {
+            "client_id is empty".into()
+        } else {
+            String::new()
+        };
+        if !msg.is_empty() {
+            return Err(ConfigError::InvalidIntrospection(sid.clone(), msg));
+        }
+    }
+
+    let mut upstream_ids = HashSet::new();
+    for u in service.upstreams.iter() {
+        if !upstream_ids.insert(u.id.as_str()) {
+            return Err(ConfigError::DuplicatedUpstream(sid.clone(), u.id.clone()));
+        }
+        let valid_target = match url::Url::parse(&u.target) {
+            Ok(url) => (url.scheme() == "http" || url.scheme() == "https") && url.has_host(),
+            Err(_) => false,
+        };
+        if !valid_target {
+            return Err(ConfigError::InvalidTarget(
+                sid.clone(),
+                u.id.clone(),
+                u.target.clone(),
+            ));
+        }
+        if u.max_conn == 0 {
+            return Err(ConfigError::InvalidMaxConn(sid.clone(), u.id.clone()));
+        }
+        if let Some(path) = &u.ca_file {
+            if let Err(e) = crate::middleware::load_ca_file(path) {
+                let msg = format!("{}: {}", path, e);
+                return Err(ConfigError::InvalidCaFile(sid.clone(), u.id.clone(), msg));
+            }
+        }
+        match (&u.client_cert, &u.client_key) {
+            (Some(cert), Some(key)) => {
+                if let Err(e) = crate::proxy::load_cert_key(cert, key) {
+                    let msg = format!("{}: {}", cert, e);
+                    return Err(ConfigError::InvalidClientCert(
+                        sid.clone(),
+                        u.id.clone(),
+                        msg,
+                    ));
+                }
+            }
+            (None, None) => {}
+            _ => {
+                let msg = "client_cert and client_key should be set together".into();
+                return Err(ConfigError::InvalidClientCert(
+                    sid.clone(),
+                    u.id.clone(),
+                    msg,
+                ));
+            }
+        }
+        // forced https to a port serving plain http fails later in the TLS handshake
+        if let Some(scheme) = u.forced_scheme(service) {
+            // url drops default ports, uri keeps the one written in target
+            let port = u.target.parse::<hyper::Uri>().ok().and_then(|uri| uri.port_u16());
+            let msg = match (scheme, port) {
+                (UpstreamScheme::Https, Some(80)) => Some("port 80 of target serves no TLS"),
+                (UpstreamScheme::Http, Some(443)) => Some("port 443 of target expects TLS"),
+                _ => None,
+            };
+            if let Some(msg) = msg {
+                return Err(ConfigError::InvalidScheme(
+                    sid.clone(),
+                    u.id.clone(),
+                    scheme.as_str().into(),
+                    format!("{} {:?}", msg, u.target),
+                ));
+            }
+        }
+        if let Some(name) = &u.tls_server_name {
+            let msg = match u.target_of(service).starts_with("https://") {
+                true => tokio_rustls::webpki::DNSNameRef::try_from_ascii_str(name)
+                    .err()
+                    .map(|_| format!("{:?} is not a DNS name", name)),
+                false => Some("only for https targets".into()),
+            };
+            if let Some(msg) = msg {
+                return Err(ConfigError::InvalidServerName(sid.clone(), u.id.clone(), msg));
+            }
+        }
+        let host = u.host_header.iter().map(|v| ("host", v.as_str()));
+        let headers = u.headers.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+        for (name, value) in host.chain(headers) {
+            let valid = HeaderName::from_bytes(name.as_bytes()).is_ok()
+                && HeaderValue::from_str(value).is_ok();
+            if !valid {
+                return Err(ConfigError::InvalidHeader(
+                    sid.clone(),
+                    u.id.clone(),
+                    name.into(),
+                ));
+            }
+        }
+    }
+    let total_weight: u64 = service.upstreams.iter().map(|u| u.weight as u64).sum();
+    if service.upstreams.len() > 1 && total_weight == 0 {
+        return Err(ConfigError::ZeroWeight(sid.clone()));
+    }
+    if let PathRewrite::Regex { pattern, .. } = &service.rewrite {
+        if regex::Regex::new(pattern).is_err() {
+            return Err(ConfigError::InvalidRewrite(sid.clone(), pattern.clone()));
+        }
+    }
+    if let Some(outlier) = &service.outlier {
+        if outlier.error_percent == 0 || outlier.error_percent > 100 {
+            let msg = "error_percent should be 1 to 100";
+            return Err(ConfigError::InvalidOutlier(sid.clone(), msg));
+        }
+        if outlier.window == 0 || outlier.cooldown == 0 {
+            let msg = "window and cooldown should not be zero";
+            return Err(ConfigError::InvalidOutlier(sid.clone(), msg));
+        }
+        if outlier.max_ejection_percent > 100 {
+            let msg = "max_ejection_percent should not exceed 100";
+            return Err(ConfigError::InvalidOutlier(sid.clone(), msg));
+        }
+    }
+    for (i, route) in service.routes.iter().enumerate() {
+        if glob::Pattern::new(&route.path_pattern).is_err() {
+            let msg = format!("bad path pattern {:?}", route.path_pattern);
+            return Err(ConfigError::InvalidRoute(sid.clone(), i, msg));
+        }
+        if route.upstreams.is_empty() {
+            let msg = "no upstream".into();
+            return Err(ConfigError::InvalidRoute(sid.clone(), i, msg));
+        }
+        if route.timeout == Some(0) {
+            let msg = "zero timeout".into();
+            return Err(ConfigError::InvalidRoute(sid.clone(), i, msg));
+        }
+        if let Some(id) = route
+            .upstreams
+            .iter()
+            .find(|id| !upstream_ids.contains(id.as_str()))
+        {
+            let msg = format!("unknown upstream {}", id);
+            return Err(ConfigError::InvalidRoute(sid.clone(), i, msg));
+        }
+    }
+
+    if let Some(split) = &service.traffic_split {
+        let total: u32 = split.versions.iter().map(|v| v.percent).sum();
+        if total != 100 {
+            let msg = format!("percents sum up to {}, expect 100", total);
+            return Err(ConfigError::InvalidTrafficSplit(sid.clone(), msg));
+        }
+        let versions: HashSet<&str> = service
+            .upstreams
+            .iter()
+            .map(|u| u.version.as_str())
+            .collect();
+        if let Some(v) = split
+            .versions
+            .iter()
+            .find(|v| !versions.contains(v.version.as_str()))
+        {
+            let msg = format!("no upstream of version {:?}", v.version);
+            return Err(ConfigError::InvalidTrafficSplit(sid.clone(), msg));
+        }
+    }
+
+    if let Some(o) = &service.version_override {
+        if o.header.is_empty() && o.cookie.is_empty() {
+            let msg = "neither header nor cookie set".into();
+            return Err(ConfigError::InvalidVersionOverride(sid.clone(), msg));
+        }
+        if !o.header.is_empty() && HeaderName::from_bytes(o.header.as_bytes()).is_err() {
+            let msg = format!("bad header name {:?}", o.header);
+            return Err(ConfigError::InvalidVersionOverride(sid.clone(), msg));
+        }
+    }
+
+    if let Some(m) = &service.mirror {
+        let valid_target = match url::Url::parse(&m.target) {
+            Ok(url) => (url.scheme() == "http" || url.scheme() == "https") && url.has_host(),
+            Err(_) => false,
+        };
+        if !valid_target {
+            let msg = format!("bad target {:?}", m.target);
+            return Err(ConfigError::InvalidMirror(sid.clone(), msg));
+        }
+        if m.percent > 100 {
+            let msg = format!("percent {} over 100", m.percent);
+            return Err(ConfigError::InvalidMirror(sid.clone(), msg));
+        }
+    }
+
+    if let Some(c) = &service.coalesce {
+        if let Some(h) = c
+            .vary
+            .iter()
+            .find(|h| HeaderName::from_bytes(h.as_bytes()).is_err())
+        {
+            let msg = format!("bad vary header {:?}", h);
+            return Err(ConfigError::InvalidCoalesce(sid.clone(), msg));
+        }
+    }
+
+    if service.idempotency.as_ref().is_some_and(|i| i.ttl == 0) {
+        let msg = "zero ttl".into();
+        return Err(ConfigError::InvalidIdempotency(sid.clone(), msg));
+    }
+
+    if let Some(r) = &service.retry {
+        // limits of the retry budget
+        if r.budget_percent.is_some_and(|p| p > 100_000) {
+            let msg = "budget_percent over 100000".into();
+            return Err(ConfigError::InvalidRetry(sid.clone(), msg));
+        }
+        if r.min_retries.is_some_and(|n| n >= i32::MAX as u32 / 60) {
+            let msg = "min_retries too large".into();
+            return Err(ConfigError::InvalidRetry(sid.clone(), msg));
+        }
+    }
+    if let Some(m) = service
+        .allowed_methods
+        .iter()
+        .find(|m| Method::from_bytes(m.as_bytes()).is_err())
+    {
+        return Err(ConfigError::InvalidMethod(sid.clone(), m.clone()));
+    }
+    // media type like application/json or a type wildcard like image/*
+    let bad_type = service.body_limit.iter().flat_map(|l| l.content_types.keys()).find(|t| {
+        let parts = t.split_once('/');
+        parts.is_none_or(|(t, sub)| t.is_empty() || t == "*" || sub.is_empty())
+    });
+    if let Some(t) = bad_type {
+        return Err(ConfigError::InvalidBodyLimit(sid.clone(), t.clone()));
+    }
+    if service.failover_service_id.as_ref() == Some(sid) {
+        let msg = "failover to itself".into();
+        return Err(ConfigError::InvalidFailover(sid.clone(), msg));
+    }
+
+    if let Some(page) = &service.maintenance_page {
+        if HeaderValue::from_str(&page.content_type).is_err() {
+            let msg = format!("bad content_type {:?}", page.content_type);
+            return Err(ConfigError::InvalidMaintenancePage(sid.clone(), msg));
+        }
+    }
+
+    for page in service.error_pages.iter() {
+        let msg = if !(400..=599).contains(&page.status) {
+            Some("status should be 400 to 599".into())
+        } else if HeaderValue::from_str(&page.content_type).is_err() {
+            Some(format!("bad content_type {:?}", page.content_type))
+        } else {
+            crate::middleware::load_error_page(page)
+                .err()
+                .map(|e| format!("{}: {}", page.file, e))
+        };
+        if let Some(msg) = msg {
+            return Err(ConfigError::InvalidErrorPage(sid.clone(), page.status, msg));
+        }
+    }
+
+    if !service.middlewares.is_empty() {
+        let mut names = HashSet::new();
+        let known = crate::middleware::DEFAULT_CHAIN;
+        let msg = if let Some(name) = service
+            .middlewares
+            .iter()
+            .find(|n| !known.contains(&n.as_str()))
+        {
+            Some(format!("unknown middleware {:?}", name))
+        } else if let Some(name) = service
+            .middlewares
+            .iter()
+            .find(|n| !names.insert(n.as_str()))
+        {
+            Some(format!("duplicated middleware {:?}", name))
+        } else if service.middlewares.last().map(String::as_str) != Some("Upstream") {
+            Some("Upstream should be the last one".into())
+        } else {
+            None
+        };
+        if let Some(msg) = msg {
+            return Err(ConfigError::InvalidMiddlewares(sid.clone(), msg));
+        }
+    }
+    // listed middlewares would be skipped without their filters
+    let filter_types: HashSet<String> = service
+        .filters
+        .iter()
+        .chain(service.sla.iter().flat_map(|sla| sla.filters.iter()))
+        .map(FilterSetting::get_type)
+        .collect();
+    if let Some(name) = service
+        .middlewares
+        .iter()
+        .find(|n| crate::middleware::require_setting(n) && !filter_types.contains(*n))
+    {
+        return Err(ConfigError::MissingMiddlewareSetting(
+            sid.clone(),
+            name.clone(),
+        ));
+    }
+    for name in service.disabled_middlewares.iter() {
+        let msg = if name == "Upstream" {
+            "Upstream can't be disabled".into()
+        } else if name != crate::auth::AUTH
+            && !crate::middleware::DEFAULT_CHAIN.contains(&name.as_str())
+        {
+            format!("unknown middleware {:?} disabled", name)
+        } else {
+            continue;
+        };
+        return Err(ConfigError::InvalidMiddlewares(sid.clone(), msg));
+    }
+
+    let filters = service
+        .filters
+        .iter()
+        .chain(service.sla.iter().flat_map(|sla| sla.filters.iter()));
+    for filter in filters {
+        if let FilterSetting::FaultInjection(f) = filter {
+            let msg = if f.abort_percent > 100 || f.delay_percent > 100 {
+                "percent over 100".into()
+            } else if f.abort_status != 0 && !(100..=599).contains(&f.abort_status) {
+                format!("bad abort_status {}", f.abort_status)
+            } else {
+                continue;
+            };
+            return Err(ConfigError::InvalidFaultInjection(sid.clone(), msg));
+        }
+    }
+    let filters = service
+        .filters
+        .iter()
+        .chain(service.sla.iter().flat_map(|sla| sla.filters.iter()));
+    for filter in filters {
+        if let FilterSetting::JsonSchema(f) = filter {
+            let msg = if let Err(e) = glob::Pattern::new(&f.path_pattern) {
+                format!("bad path_pattern {:?}, {}", f.path_pattern, e)
+            } else if let Err(e) = jsonschema::JSONSchema::compile(&f.schema.0) {
+                format!("bad schema, {}", e)
+            } else {
+                continue;
+            };
+            return Err(ConfigError::InvalidJsonSchema(sid.clone(), msg));
+        }
+    }
+    let filters = service
+        .filters
+        .iter()
+        .chain(service.sla.iter().flat_map(|sla| sla.filters.iter()));
+    for filter in filters {
+        if let FilterSetting::AclRules(f) = filter {
+            let mut ids = HashSet::new();
+            for rule in f.rules.iter() {
+                let msg = if rule.id.is_empty() {
+                    "rule without id".into()
+                } else if !ids.insert(rule.id.as_str()) {
+                    format!("duplicated rule id {:?}", rule.id)
+                } else if let Err(e) = regex::Regex::new(&rule.path_regex) {
+                    format!("rule {}: bad path_regex {:?}, {}", rule.id, rule.path_regex, e)
+                } else {
+                    continue;
+                };
+                return Err(ConfigError::InvalidAclRules(sid.clone(), msg));
+            }
+        }
+    }
+    let filters = service
+        .filters
+        .iter()
+        .chain(service.sla.iter().flat_map(|sla| sla.filters.iter()));
+    for filter in filters {
+        if let FilterSetting::RequireScopes(f) = filter {
+            let msg = if f.scopes.iter().all(|s| s.trim().is_empty()) {
+                "no scopes".into()
+            } else if let Err(e) = glob::Pattern::new(&f.path_pattern) {
+                format!("bad path_pattern {:?}, {}", f.path_pattern, e)
+            } else {
+                continue;
+            };
+            return Err(ConfigError::InvalidRequireScopes(sid.clone(), msg));
+        }
+    }
+    let has_empty_quota = service
+        .filters
+        .iter()
+        .chain(service.sla.iter().flat_map(|sla| sla.filters.iter()))
+        .any(|f| matches!(f, FilterSetting::Quota(q) if q.limit == 0));
+    if has_empty_quota {
+        return Err(ConfigError::InvalidQuota(sid.clone()));
+    }
+    for filter in service.filters.iter() {
+        if let FilterSetting::IpRateLimit(f) = filter {
+            let msg = if f.interval <= 0 || f.limit <= 0 || f.burst <= 0 {
+                "interval, limit and burst must be positive".into()
+            } else if f.ipv4_prefix > 32 || f.ipv6_prefix > 128 {
+                format!("bad prefix /{} or /{}", f.ipv4_prefix, f.ipv6_prefix)
+            } else {
+                continue;
+            };
+            return Err(ConfigError::InvalidIpRateLimit(sid.clone(), msg));
+        }
+    }
+    let in_sla = service
+        .sla
+        .iter()
+        .flat_map(|sla| sla.filters.iter())
+        .any(|f| matches!(f, FilterSetting::IpRateLimit(_)));
+    if in_sla {
+        let msg = "only allowed in service filters".into();
+        return Err(ConfigError::InvalidIpRateLimit(sid.clone(), msg));
+    }
+    for filter in service.filters.iter() {
+        if let FilterSetting::TenantRateLimit(f) = filter {
+            if f.interval <= 0 || f.limit <= 0 || f.burst <= 0 {
+                let msg = "interval, limit and burst must be positive".into();
+                return Err(ConfigError::InvalidTenantRateLimit(sid.clone(), msg));
+            }
+        }
+    }
+    let in_sla = service
+        .sla
+        .iter()
+        .flat_map(|sla| sla.filters.iter())
+        .any(|f| matches!(f, FilterSetting::TenantRateLimit(_)));
+    if in_sla {
+        let msg = "only allowed in service filters".into();
+        return Err(ConfigError::InvalidTenantRateLimit(sid.clone(), msg));
+    }
+    Ok(())
+}
+
+pub fn validate_client(client: &ClientInfo) -> Result<(), ConfigError> {
+    if client.client_id.is_empty() {
+        return Err(ConfigError::EmptyClientId);
+    }
+    Ok(())
+}
+
+/// Check service and client updates before they are applied
+pub fn validate_update(update: &ConfigUpdate) -> Result<(), ConfigError> {
+    match update {
+        ConfigUpdate::ServiceUpdate(s) => validate_service(s),
+        ConfigUpdate::ClientUpdate(c) => validate_client(c),
+        _ => Ok(()),
+    }
+}
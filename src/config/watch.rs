@@ -1,7 +1,15 @@
-use crate::config::{etcd_config, file_config, ws_config, ConfigUpdate};
+use crate::config::file_config::ConfigReloader;
+use crate::config::{
+    consul_config, dns_resolve, etcd_config, file_config, redis_config, validate_update, ws_config,
+    ConfigUpdate, ServiceInfo,
+};
+use futures::ready;
 use futures::Stream;
 use pin_project::pin_project;
 use rand::Rng;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::task::{Context, Poll};
 use std::{pin::Pin, time::Duration};
 use tokio::sync::mpsc;
@@ -11,56 +19,255 @@ use tracing::{event, Level};
 pub struct ConfigSource {
     #[pin]
     reciever: mpsc::Receiver<ConfigUpdate>,
+    validate: bool,
+    services: HashMap<String, u64>, // hash of emitted services
+    reload: Option<ConfigReloader>,
 }
 
 impl ConfigSource {
+    /// Watch config source, invalid service or client updates are logged and dropped,
+    /// so the previous good config stays in use. Upstreams with `dns_refresh` are resolved.
     pub fn new(source: String) -> Self {
+        let Self {
+            reciever,
+            services,
+            reload,
+            ..
+        } = Self::unvalidated(source);
+        ConfigSource {
+            reciever: dns_resolve::resolve_targets(reciever),
+            validate: true,
+            services,
+            reload,
+        }
+    }
+
+    /// Config pushed by the application via the returned sender, validated like other sources
+    pub fn channel() -> (mpsc::Sender<ConfigUpdate>, Self) {
         let (tx, rx) = mpsc::channel(16);
+        let config = ConfigSource {
+            reciever: dns_resolve::resolve_targets(rx),
+            validate: true,
+            services: HashMap::new(),
+            reload: None,
+        };
+        (tx, config)
+    }
+
+    /// Reloader forcing a file source to re-read its file and emit the changes, like on SIGHUP.
+    /// None for other sources, which push their changes.
+    pub fn reloader(&self) -> Option<ConfigReloader> {
+        self.reload.clone()
+    }
+
+    /// Watch config source and pass through every update, for inspecting broken config
+    pub fn unvalidated(source: String) -> Self {
+        let (tx, rx) = mpsc::channel(16);
+        let mut reload = None;
         if source.starts_with("file:///") {
+            let (reload_tx, reload_rx) = mpsc::channel(1);
+            reload = Some(ConfigReloader(reload_tx));
             tokio::spawn(async move {
-                file_config::watch_config(source.replace("file:///", ""), tx).await;
+                file_config::watch_config(source.replace("file:///", ""), tx, reload_rx).await;
             });
         } else if source.starts_with("ws://") || source.starts_with("wss://") {
             tokio::spawn(async move {
+                let mut state = SyncState::default();
+                let mut attempt = 0;
                 loop {
-                    ws_config::watch_config(source.clone(), tx.clone()).await;
-                    let wait_time = rand::thread_rng().gen_range(10..100);
+                    match ws_config::watch_config(source.clone(), tx.clone(), &mut state).await {
+                        // connection was established before lost, start over
+                        Ok(()) => attempt = 0,
+                        Err(e) => event!(Level::ERROR, "Fail to watch ws config: {}", e),
+                    }
+                    attempt += 1;
+                    let wait_time = reconnect_backoff(attempt);
                     event!(
                         Level::WARN,
-                        "ws connection lost, sleep {}s to reconnect",
-                        &wait_time
+                        "ws connection lost, sleep {:?} to reconnect, attempt {}",
+                        wait_time,
+                        attempt
                     );
-                    tokio::time::sleep(Duration::from_secs(wait_time)).await;
+                    tokio::time::sleep(wait_time).await;
                 }
             });
         } else if source.starts_with("etcd://") {
             tokio::spawn(async move {
+                let mut state = SyncState::default();
+                let mut attempt = 0;
+                loop {
+                    match etcd_config::watch_config(source.clone(), tx.clone(), &mut state).await {
+                        // config was loaded before connection lost, start over
+                        Ok(()) => attempt = 0,
+                        Err(e) => event!(Level::ERROR, "Fail to watch etcd config: {}", e),
+                    }
+                    attempt += 1;
+                    let wait_time = reconnect_backoff(attempt);
+                    event!(
+                        Level::WARN,
+                        "etcd connection lost, sleep {:?} to reconnect, attempt {}",
+                        wait_time,
+                        attempt
+                    );
+                    tokio::time::sleep(wait_time).await;
+                }
+            });
+        } else if source.starts_with("redis://") {
+            tokio::spawn(async move {
+                let mut state = SyncState::default();
+                let mut attempt = 0;
+                loop {
+                    match redis_config::watch_config(source.clone(), tx.clone(), &mut state).await {
+                        // config was loaded before connection lost, start over
+                        Ok(()) => attempt = 0,
+                        Err(e) => event!(Level::ERROR, "Fail to watch redis config: {}", e),
+                    }
+                    attempt += 1;
+                    let wait_time = reconnect_backoff(attempt);
+                    event!(
+                        Level::WARN,
+                        "redis connection lost, sleep {:?} to reconnect, attempt {}",
+                        wait_time,
+                        attempt
+                    );
+                    tokio::time::sleep(wait_time).await;
+                }
+            });
+        } else if source.starts_with("consul://") {
+            tokio::spawn(async move {
+                let mut state = SyncState::default();
+                let mut attempt = 0;
                 loop {
-                    etcd_config::watch_config(source.clone(), tx.clone()).await;
-                    let wait_time = rand::thread_rng().gen_range(10..100);
+                    match consul_config::watch_config(source.clone(), tx.clone(), &mut state).await
+                    {
+                        // config was loaded before connection lost, start over
+                        Ok(()) => attempt = 0,
+                        Err(e) => event!(Level::ERROR, "Fail to watch consul config: {}", e),
+                    }
+                    attempt += 1;
+                    let wait_time = reconnect_backoff(attempt);
                     event!(
                         Level::WARN,
-                        "etcd connection lost, sleep {}s to reconnect",
-                        &wait_time
+                        "consul connection lost, sleep {:?} to reconnect, attempt {}",
+                        wait_time,
+                        attempt
                     );
-                    tokio::time::sleep(Duration::from_secs(wait_time)).await;
+                    tokio::time::sleep(wait_time).await;
                 }
             });
         } else {
             // try read as config file
+            let (reload_tx, reload_rx) = mpsc::channel(1);
+            reload = Some(ConfigReloader(reload_tx));
             tokio::spawn(async move {
-                file_config::watch_config(source, tx).await;
+                file_config::watch_config(source, tx, reload_rx).await;
             });
         }
-        ConfigSource { reciever: rx }
+        ConfigSource {
+            reciever: rx,
+            validate: false,
+            services: HashMap::new(),
+            reload,
+        }
+    }
+}
+
+/// Services and clients received from a source across reconnects, so a full resync
+/// after reconnecting also removes the entries deleted while disconnected
+#[derive(Debug, Default)]
+pub struct SyncState {
+    known: HashSet<(bool, String)>, // (is_service, id)
+    seen: Option<HashSet<(bool, String)>>,
+}
+
+impl SyncState {
+    /// A full sync of current entries begins
+    pub fn start(&mut self) {
+        self.seen = Some(HashSet::new());
+    }
+
+    pub fn track(&mut self, update: &ConfigUpdate) {
+        let (key, removed) = match update {
+            ConfigUpdate::ServiceUpdate(s) => ((true, s.service_id.clone()), false),
+            ConfigUpdate::ServiceRemove(sid) => ((true, sid.clone()), true),
+            ConfigUpdate::ClientUpdate(c) => ((false, c.client_id.clone()), false),
+            ConfigUpdate::ClientRemove(cid) => ((false, cid.clone()), true),
+            ConfigUpdate::ConfigReady(_) => return,
+        };
+        if let Some(seen) = self.seen.as_mut() {
+            seen.insert(key.clone());
+        }
+        if removed {
+            self.known.remove(&key);
+        } else {
+            self.known.insert(key);
+        }
+    }
+
+    /// Full sync is done, removals for known entries missing from it
+    pub fn finish(&mut self) -> Vec<ConfigUpdate> {
+        let seen = match self.seen.take() {
+            Some(seen) => seen,
+            None => return Vec::new(),
+        };
+        let stale: Vec<_> = self.known.difference(&seen).cloned().collect();
+        stale
+            .into_iter()
+            .map(|key| {
+                self.known.remove(&key);
+                match key {
+                    (true, sid) => ConfigUpdate::ServiceRemove(sid),
+                    (false, cid) => ConfigUpdate::ClientRemove(cid),
+                }
+            })
+            .collect()
     }
 }
 
+// capped exponential backoff with jitter, 1s for the first attempt up to 60s
+fn reconnect_backoff(attempt: u32) -> Duration {
+    let base = (1u64 << attempt.saturating_sub(1).min(6)).min(60);
+    let jitter = rand::thread_rng().gen_range(0..=base * 500);
+    Duration::from_millis(base * 1000 / 2 + jitter)
+}
+
 impl Stream for ConfigSource {
     type Item = ConfigUpdate;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let mut this = self.project();
-        this.reciever.poll_recv(cx)
+        loop {
+            let update = match ready!(this.reciever.as_mut().poll_recv(cx)) {
+                Some(update) => update,
+                None => return Poll::Ready(None),
+            };
+            if *this.validate {
+                if let Err(e) = validate_update(&update) {
+                    event!(Level::ERROR, "Reject invalid config update: {}", e);
+                    continue;
+                }
+            }
+            // only changed services are emitted, so others keep their workers and pools
+            match &update {
+                ConfigUpdate::ServiceUpdate(s) => {
+                    let hash = service_hash(s);
+                    if this.services.insert(s.service_id.clone(), hash) == Some(hash) {
+                        event!(Level::DEBUG, "Skip unchanged service {}", s.service_id);
+                        continue;
+                    }
+                }
+                ConfigUpdate::ServiceRemove(sid) if this.services.remove(sid).is_none() => {
+                    continue;
+                }
+                _ => {}
+            }
+            return Poll::Ready(Some(update));
+        }
     }
 }
+
+fn service_hash(service: &ServiceInfo) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    service.hash(&mut hasher);
+    hasher.finish()
+}
@@ -1,35 +1,119 @@
 use tokio::sync::mpsc;
-use crate::config::ConfigUpdate;
+use crate::config::{ConfigUpdate, SyncState};
 use async_tungstenite::tokio::connect_async;
-use async_tungstenite::tungstenite::Message;
+use async_tungstenite::tungstenite::client::IntoClientRequest;
+use async_tungstenite::tungstenite::http::HeaderValue;
+use async_tungstenite::tungstenite::{Error, Message};
 use futures_util::{StreamExt, SinkExt};
+use ring::hmac;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tracing::{event, Level};
 
-pub async fn watch_config(ws_url: String, sender: mpsc::Sender<ConfigUpdate>) {
+/// Token used if there is none in the source url
+pub const TOKEN_ENV: &str = "HYPERAPI_WS_TOKEN";
+
+// e.g. wss://:<token>@<control-plane>/config, token is sent as `Authorization: Bearer <token>`.
+// With `?auth=hmac` the token is a shared secret to sign the handshake instead,
+// `wss://<key-id>:<secret>@<control-plane>/config?auth=hmac` sends
+//   X-Hyperapi-Key: <key-id>
+//   X-Hyperapi-Timestamp: <unix seconds>
+//   X-Hyperapi-Signature: base64(HMAC-SHA256(secret, "<timestamp>\n<path>?<query>"))
+// wss server certificate is verified against system roots, SSL_CERT_FILE to use a private CA.
+//
+// Returns error if connecting fails, Ok once an established connection is lost.
+// Updates before the first ConfigReady of a connection are a full resync,
+// entries deleted while disconnected are removed then.
+pub async fn watch_config(ws_url: String, sender: mpsc::Sender<ConfigUpdate>, state: &mut SyncState) -> Result<(), Error> {
     event!(Level::INFO, "connecting to websocket");
-    let result = connect_async(ws_url.clone()).await;
-    if let Ok((mut ws, _)) = result {
-        while let Some(res) = ws.next().await {
-            if let Ok(msg) = res {
-                match msg {
-                    Message::Text(txt) => {
-                        let update = serde_json::from_str::<ConfigUpdate>(&txt);
-                        if let Ok(up) = update {
-                            let _ = sender.send(up).await;
-                        } else {
-                            event!(Level::ERROR, "bad config update message: {:?}", update);
+    let mut url = url::Url::parse(&ws_url).unwrap();
+    let headers = auth_headers(&mut url);
+    let mut request = url.as_str().into_client_request()?;
+    for (name, value) in headers {
+        let value = HeaderValue::from_str(&value).map_err(|e| Error::HttpFormat(e.into()))?;
+        request.headers_mut().insert(name, value);
+    }
+    let (mut ws, _) = match connect_async(request).await {
+        Ok(conn) => conn,
+        Err(Error::Http(resp)) if resp.status() == 401 || resp.status() == 403 => {
+            event!(Level::ERROR, "websocket config source rejected credentials: {}", resp.status());
+            return Err(Error::Http(resp));
+        },
+        Err(e) => return Err(e),
+    };
+    state.start();
+    while let Some(res) = ws.next().await {
+        match res {
+            Ok(Message::Text(txt)) => {
+                let update = serde_json::from_str::<ConfigUpdate>(&txt);
+                match update {
+                    Ok(ConfigUpdate::ConfigReady(ready)) => {
+                        for u in state.finish() {
+                            let _ = sender.send(u).await;
                         }
+                        let _ = sender.send(ConfigUpdate::ConfigReady(ready)).await;
                     },
-                    Message::Ping(sn) => {
-                        let _ = ws.send(Message::Pong(sn)).await;
+                    Ok(up) => {
+                        state.track(&up);
+                        let _ = sender.send(up).await;
                     },
-                    Message::Close(_) => {
-                        break;
+                    Err(e) => {
+                        event!(Level::ERROR, "bad config update message: {:?}", e);
                     },
-                    _ => {},
                 }
-            }
+            },
+            Ok(Message::Ping(sn)) => {
+                let _ = ws.send(Message::Pong(sn)).await;
+            },
+            Ok(Message::Close(_)) => {
+                break;
+            },
+            Ok(_) => {},
+            Err(e) => {
+                event!(Level::ERROR, "websocket error: {}", e);
+                break;
+            },
         }
     }
+    Ok(())
+}
+
+// credentials are moved from url to handshake headers
+fn auth_headers(url: &mut url::Url) -> Vec<(&'static str, String)> {
+    let key_id = url.username().to_string();
+    let token = url
+        .password()
+        .map(String::from)
+        .or_else(|| std::env::var(TOKEN_ENV).ok())
+        .filter(|t| !t.is_empty());
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    let token = match token {
+        Some(token) => token,
+        None => return Vec::new(),
+    };
+    if url.scheme() == "ws" {
+        event!(Level::WARN, "websocket config credentials are sent without TLS, use wss://");
+    }
+    if !url.query_pairs().any(|(k, v)| k.eq("auth") && v.eq("hmac")) {
+        return vec![("Authorization", format!("Bearer {}", token))];
+    }
 
-}
\ No newline at end of file
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        .to_string();
+    let message = match url.query() {
+        Some(query) => format!("{}\n{}?{}", timestamp, url.path(), query),
+        None => format!("{}\n{}", timestamp, url.path()),
+    };
+    let key = hmac::Key::new(hmac::HMAC_SHA256, token.as_bytes());
+    let signature = base64::encode(hmac::sign(&key, message.as_bytes()).as_ref());
+    let mut headers = Vec::new();
+    if !key_id.is_empty() {
+        headers.push(("X-Hyperapi-Key", key_id));
+    }
+    headers.push(("X-Hyperapi-Timestamp", timestamp));
+    headers.push(("X-Hyperapi-Signature", signature));
+    headers
+}
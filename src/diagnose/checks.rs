@@ -0,0 +1,265 @@
+use super::{CheckStatus, DiagnoseReport};
+use crate::config::{
+    validate_client, validate_service, ClientInfo, ConfigSource, ConfigUpdate, ServiceInfo,
+    Upstream,
+};
+use crate::middleware::tls_config_of;
+use futures::StreamExt;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time::timeout;
+use tokio_rustls::rustls::{Certificate, RootCertStore};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
+
+const LOAD_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+const CERT_EXPIRY_WARNING: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// Load config from source and check it without starting the gateway,
+/// https upstreams without their own ca_file trusting `roots`, native certs if None
+pub async fn diagnose(source: String, roots: Option<&RootCertStore>) -> DiagnoseReport {
+    let mut report = DiagnoseReport::default();
+    let (services, clients) = load_config(source, &mut report).await;
+    check_consistency(&services, &clients, &mut report);
+
+    for service in services.iter() {
+        for upstream in service.upstreams.iter() {
+            check_upstream(service, upstream, roots, &mut report).await;
+        }
+    }
+    report
+}
+
+async fn load_config(
+    source: String,
+    report: &mut DiagnoseReport,
+) -> (Vec<ServiceInfo>, Vec<ClientInfo>) {
+    // invalid entries are reported by consistency checks
+    let mut config = ConfigSource::unvalidated(source.clone());
+    let mut services = BTreeMap::new();
+    let mut clients = BTreeMap::new();
+    let loading = async {
+        while let Some(update) = config.next().await {
+            match update {
+                ConfigUpdate::ServiceUpdate(s) => {
+                    services.insert(s.service_id.clone(), s);
+                }
+                ConfigUpdate::ServiceRemove(sid) => {
+                    services.remove(&sid);
+                }
+                ConfigUpdate::ClientUpdate(c) => {
+                    clients.insert(c.client_id.clone(), c);
+                }
+                ConfigUpdate::ClientRemove(cid) => {
+                    clients.remove(&cid);
+                }
+                ConfigUpdate::ConfigReady(_) => return true,
+            }
+        }
+        false
+    };
+    match timeout(LOAD_TIMEOUT, loading).await {
+        Ok(true) => {
+            let detail = format!(
+                "{} services and {} clients from {}",
+                services.len(),
+                clients.len(),
+                source
+            );
+            report.ok("config", "load", detail);
+        }
+        Ok(false) => {
+            report.fail(
+                "config",
+                "load",
+                format!("{} closed before config ready", source),
+            );
+        }
+        Err(_) => {
+            report.fail(
+                "config",
+                "load",
+                format!("timeout loading config from {}", source),
+            );
+        }
+    }
+    (
+        services.into_values().collect(),
+        clients.into_values().collect(),
+    )
+}
+
+fn check_consistency(
+    services: &[ServiceInfo],
+    clients: &[ClientInfo],
+    report: &mut DiagnoseReport,
+) {
+    let mut service_path: HashMap<&str, &str> = HashMap::new();
+    for s in services.iter() {
+        let subject = format!("service {}", s.service_id);
+        match validate_service(s) {
+            Ok(()) => report.ok(&subject, "config", "valid".into()),
+            Err(e) => report.fail(&subject, "config", e.to_string()),
+        }
+        if let Some(other) = service_path.insert(&s.path, &s.service_id) {
+            report.fail(
+                &subject,
+                "path",
+                format!("{} is also used by service {}", s.path, other),
+            );
+        }
+    }
+
+    for c in clients.iter() {
+        let subject = format!("client {}", c.client_id);
+        if let Err(e) = validate_client(c) {
+            report.fail(&subject, "config", e.to_string());
+            continue;
+        }
+        let before = report.results.len();
+        for (sid, sla) in c.services.iter() {
+            match services.iter().find(|s| s.service_id.eq(sid)) {
+                Some(s) => {
+                    if !s.sla.iter().any(|l| l.name.eq(sla)) {
+                        report.fail(
+                            &subject,
+                            "sla",
+                            format!("{} is not defined by service {}", sla, sid),
+                        );
+                    }
+                }
+                None => report.warn(&subject, "service", format!("unknown service {}", sid)),
+            }
+        }
+        if report.results.len() == before {
+            report.ok(&subject, "config", "valid".into());
+        }
+    }
+}
+
+async fn check_upstream(
+    service: &ServiceInfo,
+    upstream: &Upstream,
+    roots: Option<&RootCertStore>,
+    report: &mut DiagnoseReport,
+) {
+    let subject = format!("service {} upstream {}", service.service_id, upstream.id);
+    let check_timeout = match upstream.connect_timeout.unwrap_or(service.timeout as u64) {
+        0 => DEFAULT_CHECK_TIMEOUT,
+        t => Duration::from_secs(t),
+    };
+    let target = upstream.target_of(service);
+    let url = match url::Url::parse(&target) {
+        Ok(url) => url,
+        Err(e) => {
+            report.fail(&subject, "target", format!("{}: {}", target, e));
+            return;
+        }
+    };
+    let host = url.host_str().unwrap_or("").to_string();
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    // dns resolution
+    let addrs: Vec<SocketAddr> =
+        match timeout(check_timeout, lookup_host((host.as_str(), port))).await {
+            Ok(Ok(addrs)) => addrs.collect(),
+            Ok(Err(e)) => {
+                report.fail(&subject, "dns", format!("{}: {}", host, e));
+                return;
+            }
+            Err(_) => {
+                report.fail(&subject, "dns", format!("{}: resolve timeout", host));
+                return;
+            }
+        };
+    if addrs.is_empty() {
+        report.fail(&subject, "dns", format!("{}: no address", host));
+        return;
+    }
+    let resolved: Vec<String> = addrs.iter().map(|a| a.to_string()).collect();
+    report.ok(
+        &subject,
+        "dns",
+        format!("{} resolved to {}", host, resolved.join(", ")),
+    );
+
+    // tcp connectivity
+    let stream = match timeout(check_timeout, TcpStream::connect(&addrs[..])).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            report.fail(&subject, "tcp", format!("{}:{}: {}", host, port, e));
+            return;
+        }
+        Err(_) => {
+            report.fail(
+                &subject,
+                "tcp",
+                format!("{}:{}: connect timeout", host, port),
+            );
+            return;
+        }
+    };
+    let peer = stream
+        .peer_addr()
+        .map(|a| a.to_string())
+        .unwrap_or_else(|_| format!("{}:{}", host, port));
+    report.ok(&subject, "tcp", format!("connected to {}", peer));
+
+    // tls handshake and certificate, verified as the gateway does for this upstream
+    if url.scheme() == "https" {
+        let config = match tls_config_of(upstream, roots) {
+            Ok(config) => config,
+            Err(e) => {
+                report.fail(&subject, "tls", format!("cannot load TLS config: {}", e));
+                return;
+            }
+        };
+        let server_name = upstream.tls_server_name.as_deref().unwrap_or(&host);
+        let dns_name = match DNSNameRef::try_from_ascii_str(server_name) {
+            Ok(name) => name,
+            Err(_) => {
+                report.fail(
+                    &subject,
+                    "tls",
+                    format!("{} is not a valid server name", server_name),
+                );
+                return;
+            }
+        };
+        let connector = TlsConnector::from(Arc::new(config));
+        match timeout(check_timeout, connector.connect(dns_name, stream)).await {
+            Ok(Ok(tls)) => {
+                report.ok(&subject, "tls", "handshake succeeded".into());
+                let (_, session) = tls.get_ref();
+                let certs = tokio_rustls::rustls::Session::get_peer_certificates(session);
+                match certs.as_ref().and_then(|c| c.first()) {
+                    Some(cert) => check_certificate(&subject, cert, report),
+                    None => report.fail(&subject, "cert", "no peer certificate".into()),
+                }
+            }
+            Ok(Err(e)) => report.fail(&subject, "tls", format!("handshake failed: {}", e)),
+            Err(_) => report.fail(&subject, "tls", "handshake timeout".into()),
+        }
+    }
+}
+
+fn check_certificate(subject: &str, cert: &Certificate, report: &mut DiagnoseReport) {
+    match x509_parser::parse_x509_certificate(&cert.0) {
+        Ok((_, x509)) => {
+            let not_after = x509.validity().not_after.to_rfc2822();
+            let (status, detail) = match x509.validity().time_to_expiration() {
+                None => (CheckStatus::Fail, format!("expired at {}", not_after)),
+                Some(left) if left < CERT_EXPIRY_WARNING => {
+                    (CheckStatus::Warn, format!("expires soon at {}", not_after))
+                }
+                Some(_) => (CheckStatus::Ok, format!("valid until {}", not_after)),
+            };
+            report.add(subject, "cert", status, detail);
+        }
+        Err(e) => report.fail(subject, "cert", format!("cannot parse certificate: {}", e)),
+    }
+}
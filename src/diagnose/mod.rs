@@ -0,0 +1,5 @@
+mod checks;
+mod report;
+
+pub use checks::diagnose;
+pub use report::{CheckResult, CheckStatus, DiagnoseReport};
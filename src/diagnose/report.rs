@@ -0,0 +1,72 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckStatus {
+    Ok,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    pub subject: String,
+    pub check: String,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DiagnoseReport {
+    pub results: Vec<CheckResult>,
+}
+
+impl DiagnoseReport {
+    pub fn ok(&mut self, subject: &str, check: &str, detail: String) {
+        self.add(subject, check, CheckStatus::Ok, detail);
+    }
+
+    pub fn warn(&mut self, subject: &str, check: &str, detail: String) {
+        self.add(subject, check, CheckStatus::Warn, detail);
+    }
+
+    pub fn fail(&mut self, subject: &str, check: &str, detail: String) {
+        self.add(subject, check, CheckStatus::Fail, detail);
+    }
+
+    pub fn add(&mut self, subject: &str, check: &str, status: CheckStatus, detail: String) {
+        self.results.push(CheckResult {
+            subject: subject.into(),
+            check: check.into(),
+            status,
+            detail,
+        });
+    }
+
+    pub fn count(&self, status: CheckStatus) -> usize {
+        self.results.iter().filter(|r| r.status == status).count()
+    }
+
+    pub fn has_failure(&self) -> bool {
+        self.count(CheckStatus::Fail) > 0
+    }
+}
+
+impl fmt::Display for DiagnoseReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for r in self.results.iter() {
+            let tag = match r.status {
+                CheckStatus::Ok => "[ OK ]",
+                CheckStatus::Warn => "[WARN]",
+                CheckStatus::Fail => "[FAIL]",
+            };
+            writeln!(f, "{} {} {}: {}", tag, r.subject, r.check, r.detail)?;
+        }
+        write!(
+            f,
+            "{} checks, {} warnings, {} failures",
+            self.results.len(),
+            self.count(CheckStatus::Warn),
+            self.count(CheckStatus::Fail)
+        )
+    }
+}
@@ -2,18 +2,23 @@ pub mod proxy;
 pub mod config;
 pub mod middleware;
 pub mod auth;
+pub mod diagnose;
 
 
 #[macro_export]
 macro_rules! start_middleware_macro {
     ($t:ty, $s:expr, $c:expr) => {
+        $crate::start_middleware_macro!($t, <$t>::default(), $s, $c);
+    };
+    ($t:ty, $mw:expr, $s:expr, $c:expr) => {
         let (tx, rx) = mpsc::channel(16);
         let conf_update = $c.subscribe();
+        let mw: $t = $mw;
         tokio::spawn(async move {
             event!(Level::INFO, "Starting UpstreamMiddleware");
-            crate::middleware::start_middleware::<$t>(rx, conf_update).await
+            $crate::middleware::start_middleware(mw, rx, conf_update).await
         });
-        $s.push(crate::middleware::MiddlewareHandle {
+        $s.push($crate::middleware::MiddlewareHandle {
             name: <$t>::name(),
             pre: <$t>::pre(),
             post: <$t>::post(),
@@ -1,34 +1,46 @@
-use clap::{App, Arg};
-use hyper::server::conn::AddrIncoming;
+use clap::{App, AppSettings, Arg, ArgMatches};
+use futures::TryFutureExt;
+use hyper::server::accept::Accept;
 use hyper::service::make_service_fn;
 use hyper::Server;
-use hyperapi::config::ConfigSource;
-use hyperapi::proxy::{GatewayServer, TlsAcceptor, TlsConfigBuilder};
+use hyperapi::auth::{PathMatching, TenantMatching};
+use hyperapi::config::{ConfigReloader, ConfigSource, ErrorPage};
+use hyperapi::diagnose::diagnose;
+use hyperapi::middleware::{
+    load_ca_file, set_latency_buckets, AccessLog, AccessLogFormat, Cidr, ErrorPages,
+    UpstreamMiddleware,
+};
+use hyperapi::proxy::https::Transport;
+use hyperapi::proxy::{
+    bind_tcp, AdminHandler, ConnectionSettings, GatewayServer, GatewaySettings, HardenedHeaders,
+    HeaderLimits, HealthCheck, ListenAddr, Listener, ProxyProtocolAcceptor, ResponseHeaders,
+    SniCert, TlsOptions, TlsReloader, UnixIncoming,
+};
 use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
 use tracing::{event, Level};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_log::LogTracer;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::{filter::EnvFilter, Registry};
 
-#[tokio::main]
-async fn main() {
-    // setup logging
-    LogTracer::init().expect("Unable to setup log tracer!");
-    let app_name = concat!(env!("CARGO_PKG_NAME"), "-", env!("CARGO_PKG_VERSION")).to_string();
-    let (non_blocking_writer, _guard) = tracing_appender::non_blocking(std::io::stdout());
-    let bunyan_formatting_layer = BunyanFormattingLayer::new(app_name, non_blocking_writer);
-    let subscriber = Registry::default()
-        .with(EnvFilter::new("INFO"))
-        .with(JsonStorageLayer)
-        .with(bunyan_formatting_layer);
-    tracing::subscriber::set_global_default(subscriber).unwrap();
+fn main() {
+    let matches = cli().get_matches();
+    let runtime = runtime(&matches);
+    runtime.block_on(run(matches));
+}
 
-    let matches = App::new("hyperapi")
+fn cli() -> App<'static> {
+    App::new("hyperapi")
         .version("0.2.4")
         .author("Leric Zhang <leric.zhang@gmail.com>")
         .about("The gateway to API")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::new("config")
                 .required(true)
@@ -42,64 +54,791 @@ async fn main() {
             Arg::new("listen")
                 .required(true)
                 .takes_value(true)
+                .multiple_occurrences(true)
                 .short('L')
                 .long("listen")
-                .help("Listening port"),
+                .value_name("ADDR[,cert=FILE,key=FILE][,proxy_protocol]")
+                .help("Listening address, host:port or unix:/path/to.sock, HTTPS with own cert and key, PROXY protocol header expected with proxy_protocol"),
         )
         .arg(
             Arg::new("cert_file")
                 .takes_value(true)
                 .long("cert_file")
                 .default_value("")
-                .help("HTTPS cert file"),
+                .help("HTTPS cert file of listeners without own cert"),
         )
         .arg(
             Arg::new("key_file")
                 .takes_value(true)
                 .long("key_file")
                 .default_value("")
-                .help("HTTPS private key file"),
+                .help("HTTPS private key file of listeners without own cert"),
+        )
+        .arg(
+            Arg::new("sni_cert")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .long("sni_cert")
+                .value_name("HOST:CERT:KEY")
+                .help("HTTPS cert and key files for a SNI hostname, like *.example.com"),
+        )
+        .arg(
+            Arg::new("tls_min_version")
+                .takes_value(true)
+                .long("tls_min_version")
+                .possible_values(["1.2", "1.3"])
+                .help("Minimum TLS version accepted by HTTPS server"),
+        )
+        .arg(
+            Arg::new("tls_ciphers")
+                .takes_value(true)
+                .long("tls_ciphers")
+                .value_name("SUITE,...")
+                .help("Comma separated cipher suites, like TLS13_AES_256_GCM_SHA384"),
+        )
+        .arg(
+            Arg::new("tls_alpn")
+                .takes_value(true)
+                .long("tls_alpn")
+                .value_name("PROTOCOL,...")
+                .help("Comma separated ALPN protocols, default h2,http/1.1"),
+        )
+        .arg(
+            Arg::new("healthz_path")
+                .takes_value(true)
+                .long("healthz_path")
+                .default_value("/healthz")
+                .help("Liveness probe path, answered without proxying"),
+        )
+        .arg(
+            Arg::new("readyz_path")
+                .takes_value(true)
+                .long("readyz_path")
+                .default_value("/readyz")
+                .help("Readiness probe path, ready once config is loaded"),
+        )
+        .arg(
+            Arg::new("metrics_path")
+                .takes_value(true)
+                .long("metrics_path")
+                .default_value("/metrics")
+                .help("Prometheus metrics path"),
+        )
+        .arg(
+            Arg::new("admin_listen")
+                .takes_value(true)
+                .long("admin_listen")
+                .help("Admin listening address, serves metrics instead of the public port"),
+        )
+        .arg(
+            Arg::new("admin_token")
+                .takes_value(true)
+                .long("admin_token")
+                .help("Bearer token of admin api, or env HYPERAPI_ADMIN_TOKEN"),
+        )
+        .arg(
+            Arg::new("drain_timeout")
+                .takes_value(true)
+                .long("drain_timeout")
+                .default_value("30")
+                .help("Seconds to wait for in-flight requests on shutdown"),
+        )
+        .arg(
+            Arg::new("default_service")
+                .takes_value(true)
+                .long("default_service")
+                .value_name("ID")
+                .help("Service handling requests of unknown service paths, 404 if not set"),
+        )
+        .arg(
+            Arg::new("collapse_slashes")
+                .long("collapse_slashes")
+                .help("Match //svc/x as /svc/x, the request path is rewritten"),
+        )
+        .arg(
+            Arg::new("ignore_path_case")
+                .long("ignore_path_case")
+                .help("Match service paths in any case, /Svc/x is rewritten to the configured /svc/x"),
+        )
+        .arg(
+            Arg::new("tenant_path")
+                .takes_value(true)
+                .long("tenant_path")
+                .value_name("PATTERN")
+                .help("Path prefix with the tenant, like /t/{tenant}, stripped before the service lookup"),
+        )
+        .arg(
+            Arg::new("tenant_header")
+                .takes_value(true)
+                .long("tenant_header")
+                .value_name("NAME")
+                .help("Header with the tenant if the path has none, forwarded to upstreams, default x-tenant-id"),
+        )
+        .arg(
+            Arg::new("allow_empty_config")
+                .long("allow_empty_config")
+                .help("Serve without waiting for a valid initial config, for config pushed after start"),
+        )
+        .arg(
+            Arg::new("startup_timeout")
+                .takes_value(true)
+                .long("startup_timeout")
+                .value_name("SECS")
+                .default_value("30")
+                .help("Seconds to wait for the initial config before exiting"),
+        )
+        .arg(
+            Arg::new("request_timeout")
+                .takes_value(true)
+                .long("request_timeout")
+                .value_name("SECS")
+                .help("Total deadline of a request including auth and queueing, answered with 504"),
+        )
+        .arg(
+            Arg::new("max_header_bytes")
+                .takes_value(true)
+                .long("max_header_bytes")
+                .value_name("BYTES")
+                .help("Total size of request header names and values, larger requests are answered with 431"),
+        )
+        .arg(
+            Arg::new("max_headers")
+                .takes_value(true)
+                .long("max_headers")
+                .value_name("N")
+                .help("Number of request headers up to 100, requests with more are answered with 431"),
+        )
+        .arg(
+            Arg::new("http1_keepalive")
+                .takes_value(true)
+                .long("http1_keepalive")
+                .value_name("BOOL")
+                .default_value("true")
+                .help("Keep HTTP/1 client connections open between requests, false closes them after each response"),
+        )
+        .arg(
+            Arg::new("http1_half_close")
+                .long("http1_half_close")
+                .help("Keep responding on HTTP/1 connections whose client shut down its write side"),
+        )
+        .arg(
+            Arg::new("http2_max_concurrent_streams")
+                .takes_value(true)
+                .long("http2_max_concurrent_streams")
+                .value_name("N")
+                .help("Concurrent streams of an HTTP/2 client connection, unlimited by default"),
+        )
+        .arg(
+            Arg::new("reuse_port")
+                .long("reuse_port")
+                .help("Bind TCP listeners with SO_REUSEPORT, for gateway processes sharing a port on Linux"),
+        )
+        .arg(
+            Arg::new("worker_threads")
+                .takes_value(true)
+                .long("worker_threads")
+                .value_name("N")
+                .help("Runtime worker threads, or env HYPERAPI_WORKER_THREADS, number of CPU cores by default"),
+        )
+        .arg(
+            Arg::new("max_blocking_threads")
+                .takes_value(true)
+                .long("max_blocking_threads")
+                .value_name("N")
+                .help("Threads for blocking work like file reads, or env HYPERAPI_MAX_BLOCKING_THREADS, 512 by default"),
+        )
+        .arg(
+            Arg::new("thread_stack_size")
+                .takes_value(true)
+                .long("thread_stack_size")
+                .value_name("BYTES")
+                .help("Stack size of runtime threads, or env HYPERAPI_THREAD_STACK_SIZE, 2 MiB by default"),
+        )
+        .arg(
+            Arg::new("access_log_format")
+                .takes_value(true)
+                .long("access_log_format")
+                .possible_values(["json", "combined"])
+                .default_value("json")
+                .help("Access log format, json event or Apache/Nginx combined line on stdout"),
+        )
+        .arg(
+            Arg::new("verbose_errors")
+                .long("verbose_errors")
+                .help("Include internal error detail in responses, do not use in production"),
+        )
+        .arg(
+            Arg::new("fault_injection")
+                .long("fault_injection")
+                .help("Apply FaultInjection filters, for resilience testing only"),
+        )
+        .arg(
+            Arg::new("upstream_ca_file")
+                .takes_value(true)
+                .long("upstream_ca_file")
+                .value_name("FILE")
+                .help("PEM bundle trusted for https upstreams instead of native certificates"),
+        )
+        .arg(
+            Arg::new("trusted_proxies")
+                .takes_value(true)
+                .long("trusted_proxies")
+                .value_name("CIDR,...")
+                .help("Comma separated proxies whose X-Forwarded-For names the client, like 10.0.0.0/8"),
+        )
+        .arg(
+            Arg::new("quota_redis")
+                .takes_value(true)
+                .long("quota_redis")
+                .value_name("URL")
+                .help("Redis keeping quota usage across restarts and replicas, like redis://host:6379/0"),
         )
-        .get_matches();
+        .arg(
+            Arg::new("idempotency_redis")
+                .takes_value(true)
+                .long("idempotency_redis")
+                .value_name("URL")
+                .help("Redis sharing Idempotency-Key responses across replicas, like redis://host:6379/0"),
+        )
+        .arg(
+            Arg::new("error_pages")
+                .takes_value(true)
+                .long("error_pages")
+                .value_name("FILE")
+                .help("YAML or JSON list of error pages for services without their own"),
+        )
+        .arg(
+            Arg::new("access_log_sample")
+                .takes_value(true)
+                .long("access_log_sample")
+                .default_value("1000")
+                .help("Per mille of successful requests in access log, errors are always logged"),
+        )
+        .arg(
+            Arg::new("metrics_clients")
+                .takes_value(true)
+                .long("metrics_clients")
+                .value_name("ID,...")
+                .help("Client ids labeled in metrics, others are counted as other"),
+        )
+        .arg(
+            Arg::new("latency_buckets")
+                .takes_value(true)
+                .long("latency_buckets")
+                .value_name("SECS,...")
+                .help("Bucket bounds of request and upstream latency histograms, or env HYPERAPI_LATENCY_BUCKETS"),
+        )
+        .arg(
+            Arg::new("server_header")
+                .takes_value(true)
+                .long("server_header")
+                .value_name("VALUE")
+                .help("Server header of all responses, replacing the upstream one, empty to remove it"),
+        )
+        .arg(
+            Arg::new("strip_response_headers")
+                .takes_value(true)
+                .long("strip_response_headers")
+                .value_name("NAME,...")
+                .help("Comma separated headers removed from all responses, like X-Powered-By"),
+        )
+        .subcommand(
+            App::new("diagnose")
+                .about("Check config and upstream connectivity, then exit")
+                .arg(
+                    Arg::new("config")
+                        .required(true)
+                        .takes_value(true)
+                        .short('c')
+                        .long("config")
+                        .value_name("FILE")
+                        .help("Set config file path"),
+                ),
+        )
+}
+
+// multi-thread runtime with tokio defaults, unless tuned by flags or env
+fn runtime(matches: &ArgMatches) -> tokio::runtime::Runtime {
+    let setting = |name: &str, env: &str| {
+        matches
+            .value_of(name)
+            .map(String::from)
+            .or_else(|| std::env::var(env).ok())
+            .filter(|v| !v.is_empty())
+            .map(|v| {
+                let n: usize = v.parse().unwrap_or_else(|_| panic!("Invalid {}", name));
+                assert!(n > 0, "Invalid {}", name);
+                n
+            })
+    };
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(n) = setting("worker_threads", "HYPERAPI_WORKER_THREADS") {
+        builder.worker_threads(n);
+    }
+    if let Some(n) = setting("max_blocking_threads", "HYPERAPI_MAX_BLOCKING_THREADS") {
+        builder.max_blocking_threads(n);
+    }
+    if let Some(bytes) = setting("thread_stack_size", "HYPERAPI_THREAD_STACK_SIZE") {
+        builder.thread_stack_size(bytes);
+    }
+    builder.build().expect("Cannot start runtime")
+}
+
+async fn run(matches: ArgMatches) {
+    // setup logging
+    LogTracer::init().expect("Unable to setup log tracer!");
+    let app_name = concat!(env!("CARGO_PKG_NAME"), "-", env!("CARGO_PKG_VERSION")).to_string();
+    let (non_blocking_writer, _guard) = tracing_appender::non_blocking(std::io::stdout());
+    let bunyan_formatting_layer = BunyanFormattingLayer::new(app_name, non_blocking_writer);
+    let subscriber = Registry::default()
+        .with(EnvFilter::new("INFO"))
+        .with(JsonStorageLayer)
+        .with(bunyan_formatting_layer);
+    tracing::subscriber::set_global_default(subscriber).unwrap();
+
+    let upstream_roots = matches.value_of("upstream_ca_file").map(|path| {
+        load_ca_file(path).unwrap_or_else(|e| panic!("Invalid upstream CA file {}: {}", path, e))
+    });
+    if let Some(diag) = matches.subcommand_matches("diagnose") {
+        let config = diag.value_of("config").unwrap();
+        let report = diagnose(config.into(), upstream_roots.as_ref()).await;
+        println!("{}", report);
+        std::process::exit(if report.has_failure() { 1 } else { 0 });
+    }
+
     let config = matches.value_of("config").unwrap();
-    let listen = matches.value_of("listen").unwrap();
+    let listeners: Vec<Listener> = matches
+        .values_of("listen")
+        .unwrap()
+        .map(|v| {
+            v.parse()
+                .unwrap_or_else(|e| panic!("Invalid listen address {}", e))
+        })
+        .collect();
     let cert_file = matches.value_of("cert_file").unwrap();
     let key_file = matches.value_of("key_file").unwrap();
+    let sni_certs: Vec<SniCert> = matches
+        .values_of("sni_cert")
+        .map(|values| values.map(parse_sni_cert).collect())
+        .unwrap_or_default();
+    let tls_options = TlsOptions {
+        min_version: matches.value_of("tls_min_version").map(String::from),
+        cipher_suites: matches.value_of("tls_ciphers").map(split_list),
+        alpn_protocols: matches.value_of("tls_alpn").map(split_list),
+    };
+    let drain_timeout: u64 = matches
+        .value_of("drain_timeout")
+        .unwrap()
+        .parse()
+        .expect("Invalid drain timeout");
+    let drain_timeout = Duration::from_secs(drain_timeout);
+    let startup_timeout: u64 = matches
+        .value_of("startup_timeout")
+        .unwrap()
+        .parse()
+        .expect("Invalid startup timeout");
+    let request_timeout = matches.value_of("request_timeout").map(|v| {
+        let secs: u64 = v.parse().expect("Invalid request timeout");
+        assert!(secs > 0, "Invalid request timeout");
+        Duration::from_secs(secs)
+    });
+    let header_limits = HeaderLimits {
+        max_bytes: matches.value_of("max_header_bytes").map(|v| {
+            let bytes: usize = v.parse().expect("Invalid max header bytes");
+            assert!(bytes > 0, "Invalid max header bytes");
+            bytes
+        }),
+        max_count: matches.value_of("max_headers").map(|v| {
+            let count: usize = v.parse().expect("Invalid max headers");
+            // hyper parses 100 headers at most
+            assert!(count > 0 && count <= 100, "Invalid max headers");
+            count
+        }),
+    };
+    let connection = ConnectionSettings {
+        http1_keepalive: matches
+            .value_of("http1_keepalive")
+            .unwrap()
+            .parse()
+            .expect("Invalid http1 keepalive"),
+        http1_half_close: matches.is_present("http1_half_close"),
+        http2_max_concurrent_streams: matches.value_of("http2_max_concurrent_streams").map(|v| {
+            let streams: u32 = v.parse().expect("Invalid http2 max concurrent streams");
+            assert!(streams > 0, "Invalid http2 max concurrent streams");
+            streams
+        }),
+    };
 
-    let config_source = ConfigSource::new(config.into());
-    let addr = listen.parse().expect("Invalid listen address");
+    let access_log_format: AccessLogFormat = matches
+        .value_of("access_log_format")
+        .unwrap()
+        .parse()
+        .unwrap();
+    let (access_writer, _access_guard) = tracing_appender::non_blocking(std::io::stdout());
+    let access_log_sample: u32 = matches
+        .value_of("access_log_sample")
+        .unwrap()
+        .parse()
+        .expect("Invalid access log sample rate");
+    let mut access_log = AccessLog::new(
+        access_log_format,
+        Box::new(access_writer),
+        access_log_sample,
+    );
+    if let Some(clients) = matches.value_of("metrics_clients") {
+        access_log = access_log.with_metrics_clients(split_list(clients));
+    }
+    let mut settings = GatewaySettings {
+        access_log,
+        ..Default::default()
+    };
+    let latency_buckets = matches
+        .value_of("latency_buckets")
+        .map(String::from)
+        .or_else(|| std::env::var("HYPERAPI_LATENCY_BUCKETS").ok())
+        .filter(|v| !v.is_empty());
+    if let Some(buckets) = latency_buckets {
+        let buckets: Vec<f64> = split_list(&buckets)
+            .iter()
+            .map(|v| {
+                v.parse()
+                    .unwrap_or_else(|e| panic!("Invalid latency bucket {}: {}", v, e))
+            })
+            .collect();
+        if let Err(e) = set_latency_buckets(buckets) {
+            panic!("Invalid latency buckets: {}", e);
+        }
+    }
+    settings.verbose_errors = matches.is_present("verbose_errors");
+    settings.default_service = matches.value_of("default_service").map(String::from);
+    settings.path_matching = PathMatching {
+        collapse_slashes: matches.is_present("collapse_slashes"),
+        ignore_case: matches.is_present("ignore_path_case"),
+    };
+    settings.tenant_matching = TenantMatching::new(
+        matches.value_of("tenant_path"),
+        matches.value_of("tenant_header"),
+    )
+    .unwrap_or_else(|e| panic!("Invalid tenant matching: {}", e));
+    settings.enable_fault_injection = matches.is_present("fault_injection");
+    settings.upstream_roots = upstream_roots;
+    if let Some(proxies) = matches.value_of("trusted_proxies") {
+        let proxies: Vec<Cidr> = split_list(proxies)
+            .iter()
+            .map(|v| {
+                v.parse()
+                    .unwrap_or_else(|e| panic!("Invalid trusted proxy: {}", e))
+            })
+            .collect();
+        settings.trusted_proxies = proxies;
+    }
+    if let Some(url) = matches.value_of("quota_redis") {
+        match redis::Client::open(url) {
+            Ok(client) => settings.quota_store = Some(client),
+            Err(e) => {
+                event!(Level::ERROR, "Invalid quota redis {}: {}", url, e);
+                drop(_access_guard);
+                drop(_guard);
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(url) = matches.value_of("idempotency_redis") {
+        match redis::Client::open(url) {
+            Ok(client) => settings.idempotency_store = Some(client),
+            Err(e) => {
+                event!(Level::ERROR, "Invalid idempotency redis {}: {}", url, e);
+                drop(_access_guard);
+                drop(_guard);
+                std::process::exit(1);
+            }
+        }
+    }
+    let response_headers = ResponseHeaders {
+        server: matches.value_of("server_header").map(String::from),
+        removal: matches
+            .value_of("strip_response_headers")
+            .map(split_list)
+            .unwrap_or_default(),
+    };
+    let response_headers = HardenedHeaders::new(response_headers)
+        .unwrap_or_else(|e| panic!("Invalid response headers: {}", e));
+    if let Some(path) = matches.value_of("error_pages") {
+        let pages: Vec<ErrorPage> = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|s| serde_yaml::from_str(&s).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| panic!("Invalid error pages {}: {}", path, e));
+        settings.error_pages = ErrorPages::load(&pages)
+            .unwrap_or_else(|e| panic!("Cannot load error pages {}: {}", path, e));
+    }
 
-    let server = GatewayServer::new(config_source);
-    let server = Arc::new(Mutex::new(server));
+    let config_source = ConfigSource::new(config.into());
+    let reload = config_source.reloader();
+    if let Some(reload) = reload.clone() {
+        tokio::spawn(reload_on_hangup(reload));
+    }
 
-    let incoming = AddrIncoming::bind(&addr).unwrap();
-    if cert_file != "" && key_file != "" {
-        event!(Level::INFO, "Starting https gateway edge server");
-        let make_svc = make_service_fn(|_| {
-            let handler = {
-                let lock = server.lock().expect("GatewayServer status error");
-                lock.make_service()
-            };
+    let mut server = GatewayServer::with_settings(config_source, settings);
+    server.health = HealthCheck {
+        liveness_path: matches.value_of("healthz_path").unwrap().into(),
+        readiness_path: matches.value_of("readyz_path").unwrap().into(),
+        drain: server.health.drain.clone(),
+    };
+    server.request_timeout = request_timeout;
+    server.header_limits = header_limits;
+    server.response_headers = response_headers;
+    server.connection = connection;
+    // a gateway without services would answer 404 to everything
+    if !matches.is_present("allow_empty_config") {
+        match server
+            .wait_initial_config(Duration::from_secs(startup_timeout))
+            .await
+        {
+            Ok((services, clients)) => event!(
+                Level::INFO,
+                "Initial config loaded, {} services and {} clients",
+                services,
+                clients
+            ),
+            Err(e) => {
+                event!(Level::ERROR, "Startup check failed, {}", e);
+                drop(_access_guard);
+                drop(_guard);
+                std::process::exit(1);
+            }
+        }
+    }
+    let metrics_path = matches.value_of("metrics_path").unwrap().to_string();
+    if let Some(admin_listen) = matches.value_of("admin_listen") {
+        let admin_addr = admin_listen.parse().expect("Invalid admin listen address");
+        let token = matches
+            .value_of("admin_token")
+            .map(String::from)
+            .or_else(|| std::env::var("HYPERAPI_ADMIN_TOKEN").ok())
+            .filter(|t| !t.is_empty());
+        let admin = AdminHandler {
+            metrics_path,
+            token,
+            config: server.config.clone(),
+            reload,
+            drain: server.health.drain.clone(),
+            quota: server.quota.clone(),
+        };
+        let make_svc = make_service_fn(move |_| {
+            let handler = admin.clone();
             async move { Ok::<_, Infallible>(handler) }
         });
-        let config = TlsConfigBuilder::new()
-            .key_path(key_file)
-            .cert_path(cert_file)
-            .build()
-            .expect("Fail to load TLS certificates");
-        let acceptor = TlsAcceptor::new(config, incoming);
-        let server = Server::builder(acceptor).serve(make_svc);
-        server.await.expect("Server failed to start");
+        event!(Level::INFO, "Starting admin server");
+        tokio::spawn(Server::bind(&admin_addr).serve(make_svc));
+        server.metrics_path = None;
     } else {
-        event!(Level::INFO, "Starting http gateway edge server");
-        let make_svc = make_service_fn(|_| {
-            let handler = {
-                let lock = server.lock().expect("GatewayServer status error");
-                lock.make_service()
-            };
-            async move { Ok::<_, Infallible>(handler) }
-        });
-        let server = Server::builder(incoming).serve(make_svc);
-        server.await.expect("Server failed to start");
+        server.metrics_path = Some(metrics_path);
+    }
+    let server = Arc::new(Mutex::new(server));
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        event!(
+            Level::INFO,
+            "Shutdown signal received, draining connections"
+        );
+        let _ = shutdown_tx.send(true);
+    });
+
+    let default_cert = (!cert_file.is_empty() && !key_file.is_empty())
+        .then(|| (cert_file.into(), key_file.into()));
+    let reuse_port = matches.is_present("reuse_port");
+    // bind all listeners before serving, any failure stops the process
+    let edges: Vec<Edge> = listeners
+        .into_iter()
+        .map(|listener| {
+            let tls = listener
+                .cert
+                .or_else(|| default_cert.clone())
+                .map(|(cert, key)| {
+                    let tls = TlsReloader::new(&cert, &key, sni_certs.clone(), tls_options.clone())
+                        .unwrap_or_else(|e| panic!("Fail to load TLS certificates: {}", e));
+                    tls.watch().expect("Fail to watch TLS certificates");
+                    tls
+                });
+            let scheme = if tls.is_some() { "https" } else { "http" };
+            event!(
+                Level::INFO,
+                "Starting {} gateway edge server on {}",
+                scheme,
+                listener.addr
+            );
+            let shutdown = shutdown_rx.clone();
+            let proxy_protocol = listener.proxy_protocol;
+            match listener.addr {
+                ListenAddr::Tcp(addr) => {
+                    let incoming = bind_tcp(&addr, reuse_port)
+                        .unwrap_or_else(|e| panic!("Fail to bind {}: {}", addr, e));
+                    proxied_edge(incoming, proxy_protocol, tls, server.clone(), shutdown)
+                }
+                ListenAddr::Unix(path) => {
+                    let incoming = UnixIncoming::bind(&path)
+                        .unwrap_or_else(|e| panic!("Fail to bind unix socket: {}", e));
+                    proxied_edge(incoming, proxy_protocol, tls, server.clone(), shutdown)
+                }
+            }
+        })
+        .collect();
+    let edges = futures::future::try_join_all(edges).map_ok(|_| ());
+    let code = serve(edges, &server, shutdown_rx, drain_timeout).await;
+    // flush logs, exit skips destructors
+    drop(_access_guard);
+    drop(_guard);
+    std::process::exit(code);
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value.split(',').map(|v| v.trim().to_string()).collect()
+}
+
+fn parse_sni_cert(value: &str) -> SniCert {
+    let mut parts = value.splitn(3, ':');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(host), Some(cert), Some(key)) if !host.is_empty() => SniCert {
+            host: host.into(),
+            cert_path: cert.into(),
+            key_path: key.into(),
+        },
+        _ => panic!("Invalid sni cert {:?}, expect HOST:CERT:KEY", value),
+    }
+}
+
+async fn shutdown_signal() {
+    let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("Fail to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = term.recv() => {},
+    }
+}
+
+// re-read config file on SIGHUP, for filesystems where changes are not noticed
+async fn reload_on_hangup(reload: ConfigReloader) {
+    let mut hup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("Fail to install SIGHUP handler");
+    while hup.recv().await.is_some() {
+        event!(Level::INFO, "SIGHUP received, reloading config");
+        reload.trigger();
+    }
+}
+
+async fn wait_shutdown(mut rx: watch::Receiver<bool>) {
+    while !*rx.borrow() {
+        if rx.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+type Edge = Pin<Box<dyn Future<Output = hyper::Result<()>> + Send>>;
+
+// PROXY protocol header comes first, before TLS handshake
+fn proxied_edge<I>(
+    incoming: I,
+    proxy_protocol: bool,
+    tls: Option<TlsReloader>,
+    server: Arc<Mutex<GatewayServer>>,
+    shutdown: watch::Receiver<bool>,
+) -> Edge
+where
+    I: Accept<Error = std::io::Error> + Send + Unpin + 'static,
+    I::Conn: Transport + Send + Unpin + 'static,
+{
+    if proxy_protocol {
+        tls_edge(ProxyProtocolAcceptor::new(incoming), tls, server, shutdown)
+    } else {
+        tls_edge(incoming, tls, server, shutdown)
+    }
+}
+
+fn tls_edge<I>(
+    incoming: I,
+    tls: Option<TlsReloader>,
+    server: Arc<Mutex<GatewayServer>>,
+    shutdown: watch::Receiver<bool>,
+) -> Edge
+where
+    I: Accept<Error = std::io::Error> + Send + Unpin + 'static,
+    I::Conn: Transport + Send + Unpin + 'static,
+{
+    match tls {
+        Some(tls) => edge(tls.acceptor(incoming), server, shutdown),
+        None => edge(incoming, server, shutdown),
+    }
+}
+
+// server of a listener, stops accepting on shutdown
+fn edge<I>(incoming: I, server: Arc<Mutex<GatewayServer>>, shutdown: watch::Receiver<bool>) -> Edge
+where
+    I: Accept + Send + 'static,
+    I::Conn: Transport + Send + Unpin + 'static,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let (buf_size, connection) = {
+        let server = server.lock().unwrap();
+        (server.header_limits.buf_size(), server.connection)
+    };
+    let make_svc = make_service_fn(move |conn: &I::Conn| {
+        let mut handler = {
+            let lock = server.lock().expect("GatewayServer status error");
+            lock.make_service()
+        };
+        handler.remote_addr = conn.remote_addr();
+        async move { Ok::<_, Infallible>(handler) }
+    });
+    let mut builder = connection.apply(Server::builder(incoming));
+    if let Some(size) = buf_size {
+        builder = builder.http1_max_buf_size(size);
+    }
+    let edge = builder
+        .serve(make_svc)
+        .with_graceful_shutdown(wait_shutdown(shutdown));
+    Box::pin(edge)
+}
+
+// serve until shutdown, then drain connections and upstream requests before deadline,
+// listeners are dropped before returning so unix socket files are removed
+async fn serve<F>(
+    edge: F,
+    server: &Mutex<GatewayServer>,
+    shutdown: watch::Receiver<bool>,
+    drain_timeout: Duration,
+) -> i32
+where
+    F: Future<Output = hyper::Result<()>>,
+{
+    tokio::pin!(edge);
+    tokio::select! {
+        result = &mut edge => {
+            result.expect("Server failed to start");
+            return 0;
+        },
+        _ = wait_shutdown(shutdown) => {},
+    }
+
+    let deadline = Instant::now() + drain_timeout;
+    match tokio::time::timeout_at(deadline, edge).await {
+        Ok(result) => result.expect("Server failed to shutdown"),
+        Err(_) => {
+            event!(Level::ERROR, "Drain deadline exceeded, closing connections");
+            return 1;
+        }
+    }
+    server.lock().unwrap().shutdown();
+    if !UpstreamMiddleware::wait_idle(deadline).await {
+        event!(
+            Level::ERROR,
+            "Drain deadline exceeded, upstream requests still running"
+        );
+        return 1;
     }
+    event!(Level::INFO, "Gateway server stopped");
+    0
 }
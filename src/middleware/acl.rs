@@ -1,26 +1,22 @@
-use crate::config::{ACLSetting, ConfigUpdate, FilterSetting};
+use crate::auth::ClientRoles;
+use crate::config::{ACLSetting, AclAction, AclRulesSetting, ConfigUpdate, FilterSetting};
 use crate::middleware::{Middleware, MwNextAction, MwPostRequest, MwPreRequest, MwPreResponse};
 use glob::Pattern;
 use hyper::{Body, Request};
+use regex::Regex;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
 use tracing::{event, Level};
 
 use super::middleware::GatewayError;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct ACLMiddleware {
     service_acl: HashMap<String, HashMap<String, Vec<ACLMatcher>>>, // service_acl[service_id][sla] = Vec<PathMatcher>
-}
-
-impl Default for ACLMiddleware {
-    fn default() -> Self {
-        ACLMiddleware {
-            service_acl: HashMap::new(),
-        }
-    }
+    service_rules: HashMap<String, HashMap<AclRulesSetting, Arc<AclRuleSet>>>, // service_rules[service_id][setting], compiled at config update
 }
 
 impl Middleware for ACLMiddleware {
@@ -36,8 +32,8 @@ impl Middleware for ACLMiddleware {
         let MwPreRequest {
             context,
             request,
-            service_filters: _,
-            client_filters: _,
+            service_filters,
+            client_filters,
             result,
         } = task;
         let mut pass = true;
@@ -51,14 +47,41 @@ impl Middleware for ACLMiddleware {
                 }
             }
         }
-        if pass {
+        if !pass {
+            let _ = result.send(Err(GatewayError::AccessBlocked("Not Found".into())));
+            return Box::pin(async {});
+        }
+        let compiled = self.service_rules.get(&context.service_id);
+        let roles = request
+            .extensions()
+            .get::<ClientRoles>()
+            .map(|r| r.0.as_slice())
+            .unwrap_or_default();
+        let denied = service_filters
+            .iter()
+            .chain(client_filters.iter())
+            .filter_map(|f| match f {
+                FilterSetting::AclRules(s) => compiled.and_then(|c| c.get(s)),
+                _ => None,
+            })
+            .find_map(|rules| rules.denied_by(&context.client_id, roles, &request));
+        if let Some(rule) = denied {
+            event!(
+                Level::INFO,
+                "{} {} of client {:?} denied by acl rule {}",
+                request.method(),
+                request.uri().path(),
+                context.client_id,
+                rule
+            );
+            let detail = format!("denied by acl rule {}", rule);
+            let _ = result.send(Err(GatewayError::Forbidden(detail)));
+        } else {
             let pre_resp = MwPreResponse {
-                context: context,
+                context,
                 next: MwNextAction::Next(request),
             };
             let _ = result.send(Ok(pre_resp));
-        } else {
-            let _ = result.send(Err(GatewayError::AccessBlocked("Not Found".into())));
         }
         Box::pin(async {})
     }
@@ -70,6 +93,29 @@ impl Middleware for ACLMiddleware {
     fn config_update(&mut self, update: ConfigUpdate) {
         match update {
             ConfigUpdate::ServiceUpdate(service) => {
+                let mut rules = HashMap::new();
+                let filters = service
+                    .filters
+                    .iter()
+                    .chain(service.sla.iter().flat_map(|sla| sla.filters.iter()));
+                for filter in filters {
+                    if let FilterSetting::AclRules(setting) = filter {
+                        match AclRuleSet::new(setting) {
+                            Ok(set) => {
+                                rules.insert(setting.clone(), Arc::new(set));
+                            }
+                            Err(e) => {
+                                event!(
+                                    Level::ERROR,
+                                    "bad acl rules of {}: {}",
+                                    service.service_id,
+                                    e
+                                );
+                            }
+                        }
+                    }
+                }
+                self.service_rules.insert(service.service_id.clone(), rules);
                 let mut matchers = Vec::new();
                 for filter in service.filters {
                     if let FilterSetting::ACL(acl) = filter {
@@ -91,6 +137,7 @@ impl Middleware for ACLMiddleware {
             }
             ConfigUpdate::ServiceRemove(service_id) => {
                 self.service_acl.remove(&service_id);
+                self.service_rules.remove(&service_id);
             }
             _ => {}
         }
@@ -144,3 +191,73 @@ impl ACLMatcher {
         !self.on_match
     }
 }
+
+// compiled AclRulesSetting
+#[derive(Debug)]
+struct AclRuleSet {
+    rules: Vec<AclRuleMatcher>,
+    default: AclAction,
+}
+
+#[derive(Debug)]
+struct AclRuleMatcher {
+    id: String,
+    clients: HashSet<String>,
+    roles: HashSet<String>,
+    methods: HashSet<String>, // empty for all
+    path: Regex,
+    action: AclAction,
+}
+
+impl AclRuleSet {
+    fn new(setting: &AclRulesSetting) -> Result<Self, String> {
+        let mut rules = Vec::new();
+        for rule in &setting.rules {
+            let path = Regex::new(&rule.path_regex)
+                .map_err(|e| format!("rule {}: bad path_regex, {}", rule.id, e))?;
+            let methods = rule
+                .methods
+                .split(',')
+                .map(|m| m.trim().to_ascii_uppercase())
+                .filter(|m| !m.is_empty() && m != "*")
+                .collect();
+            rules.push(AclRuleMatcher {
+                id: rule.id.clone(),
+                clients: rule.clients.iter().cloned().collect(),
+                roles: rule.roles.iter().cloned().collect(),
+                methods,
+                path,
+                action: rule.action,
+            });
+        }
+        Ok(AclRuleSet {
+            rules,
+            default: setting.default,
+        })
+    }
+
+    /// Id of the rule denying a request of the client, `default` if no rule matched,
+    /// None if the request is allowed
+    fn denied_by(&self, client_id: &str, roles: &[String], req: &Request<Body>) -> Option<String> {
+        // path after the service path, `/svc/admin/1` is matched as `/admin/1`
+        let path = req.uri().path();
+        let path = path.strip_prefix('/').unwrap_or(path);
+        let left = path.find('/').map(|offset| &path[offset..]).unwrap_or("/");
+        let method = req.method().as_str();
+        let matched = self.rules.iter().find(|rule| {
+            let anyone = rule.clients.is_empty() && rule.roles.is_empty();
+            let subject = anyone
+                || rule.clients.contains(client_id)
+                || roles.iter().any(|r| rule.roles.contains(r));
+            subject
+                && (rule.methods.is_empty() || rule.methods.contains(method))
+                && rule.path.is_match(left)
+        });
+        match matched {
+            Some(rule) if rule.action == AclAction::Deny => Some(rule.id.clone()),
+            Some(_) => None,
+            None if self.default == AclAction::Deny => Some("default".into()),
+            None => None,
+        }
+    }
+}
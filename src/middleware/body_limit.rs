@@ -0,0 +1,87 @@
+use crate::config::BodyLimitSetting;
+use crate::middleware::GatewayError;
+use futures::StreamExt;
+use hyper::body::HttpBody;
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Request};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// limit of a content type, media type before `type/*`, None if unlimited
+fn limit_of(setting: &BodyLimitSetting, content_type: Option<&str>) -> Option<u64> {
+    let mime = content_type
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    let wildcard = mime.split_once('/').map(|(t, _)| format!("{}/*", t));
+    let limit = setting
+        .content_types
+        .iter()
+        .find(|(pattern, _)| pattern.eq_ignore_ascii_case(&mime))
+        .or_else(|| {
+            let wildcard = wildcard.as_deref()?;
+            setting
+                .content_types
+                .iter()
+                .find(|(pattern, _)| pattern.eq_ignore_ascii_case(wildcard))
+        })
+        .map(|(_, limit)| *limit)
+        .unwrap_or(setting.max_body_bytes);
+    Some(limit).filter(|limit| *limit > 0)
+}
+
+/// Check the request body against the limit of its content type. A declared `Content-Length`
+/// over the limit fails right away, a chunked body fails as it is sent upstream once it
+/// grows over the limit, with `PayloadTooLarge` as the body error.
+pub(crate) fn limit_body(
+    setting: &BodyLimitSetting,
+    request: &mut Request<Body>,
+) -> Result<(), GatewayError> {
+    let headers = request.headers();
+    let limit = match limit_of(
+        setting,
+        headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()),
+    ) {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+    let too_large = move || GatewayError::PayloadTooLarge(format!("over {} bytes", limit));
+    let declared = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    match declared {
+        Some(len) if len > limit => return Err(too_large()),
+        Some(_) => return Ok(()),
+        None if request.body().is_end_stream() => return Ok(()),
+        None => {}
+    }
+    let mut sent = 0;
+    let body = std::mem::take(request.body_mut()).map(move |chunk| {
+        let chunk = chunk.map_err(BoxError::from)?;
+        sent += chunk.len() as u64;
+        match sent > limit {
+            true => Err(BoxError::from(too_large())),
+            false => Ok(chunk),
+        }
+    });
+    *request.body_mut() = Body::wrap_stream(body);
+    Ok(())
+}
+
+/// Body of an upstream request cut by `limit_body`, the client's fault and not the upstream's
+pub(crate) fn body_too_large(e: &(dyn std::error::Error + 'static)) -> Option<GatewayError> {
+    let mut source = Some(e);
+    while let Some(cause) = source {
+        if let Some(GatewayError::PayloadTooLarge(detail)) = cause.downcast_ref() {
+            return Some(GatewayError::PayloadTooLarge(detail.clone()));
+        }
+        source = cause.source();
+    }
+    None
+}
+
+/// Upstream call failed by the request body, not counted against the upstream
+pub(crate) fn is_client_error(e: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(e.downcast_ref(), Some(GatewayError::PayloadTooLarge(_)))
+}
@@ -1,6 +1,6 @@
 use futures::ready;
 use hyper::{Body, Request, Response, header::HeaderName, http::HeaderValue};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use tower::Service;
 use std::task::{Context, Poll};
 use std::future::Future;
@@ -8,8 +8,10 @@ use std::pin::Pin;
 use std::sync::{Arc, Mutex};
 use pin_project::pin_project;
 use super::state::*;
+use crate::middleware::body_limit::is_client_error;
 
 
+#[derive(Clone)]
 pub struct CircuitBreakerService<S> {
     inner: S,
     state: Arc<Mutex<CircuitBreakerState>>,
@@ -25,6 +27,19 @@ impl<S> CircuitBreakerService<S> {
         });
         CircuitBreakerService { inner, config, state: Arc::new(Mutex::new(state)) }
     }
+
+    /// Whether the circuit is open, requests wait for the retry delay
+    pub fn is_open(&self) -> bool {
+        self.config.error_threshold > 0 && self.state.lock().unwrap().is_open(&self.config)
+    }
+
+    /// Time until an open circuit lets a request through, None if closed
+    pub fn retry_in(&self) -> Option<Duration> {
+        if self.config.error_threshold == 0 {
+            return None
+        }
+        self.state.lock().unwrap().retry_in(&self.config)
+    }
 }
 
 
@@ -52,7 +67,7 @@ impl<S> Service<Request<Body>> for CircuitBreakerService<S>
     fn call(&mut self, req: Request<Body>) -> Self::Future {
         let fut = self.inner.call(req);
         let state = self.state.clone();
-        CBFuture { fut, state, config: self.config.clone() }
+        CBFuture { fut, state, config: self.config }
     }
 }
 
@@ -86,7 +101,7 @@ impl<Fut> Future for CBFuture<Fut>
         if let Ok(mut r) = result {
             if r.status().as_u16() >= 500 {
                 let mut state = this.state.lock().unwrap();
-                state.error(&this.config);
+                state.error(this.config);
                 let header = r.headers_mut();
                 let state_value = format!("{:?}", state);
                 let state_value = HeaderValue::from_str(&state_value).unwrap();
@@ -94,16 +109,18 @@ impl<Fut> Future for CBFuture<Fut>
                 Poll::Ready(Ok(r))
             } else {
                 let mut state = this.state.lock().unwrap();
-                state.success(&this.config);
+                state.success(this.config);
                 let header = r.headers_mut();
                 let state_value = format!("{:?}", state);
                 let state_value = HeaderValue::from_str(&state_value).unwrap();
                 header.insert(HeaderName::from_static("circuit-breaker"), state_value);
                 Poll::Ready(Ok(r))
             }
+        } else if result.as_ref().is_err_and(|e| is_client_error(e.as_ref())) {
+            Poll::Ready(result)
         } else {
             let mut state = this.state.lock().unwrap();
-            state.error(&this.config);
+            state.error(this.config);
             Poll::Ready(result)
         }
     }
@@ -1,4 +1,5 @@
 mod state;
+#[allow(clippy::module_inception)]
 mod circuit_breaker;
 
 
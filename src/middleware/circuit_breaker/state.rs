@@ -40,20 +40,45 @@ impl CircuitBreakerState {
             CircuitBreakerState::Open(state) => {
                 if now.duration_since(state.last_attempt).unwrap() >= config.retry_delay {
                     *self = CircuitBreakerState::HalfOpen(HalfOpenState {last_attempt: now});
-                    return true;
+                    true
                 } else {
-                    return false;
+                    false
                 }
             },
             CircuitBreakerState::Close(_state) => {
-                return true;
+                true
             },
             CircuitBreakerState::HalfOpen(_state) => {
-                return false;
+                false
             },
         }
     }
 
+    // not letting requests through, a half open circuit waits for its probe
+    pub fn is_open(&self, config: &CircuitBreakerConfig) -> bool {
+        match self {
+            CircuitBreakerState::Open(state) => {
+                state.last_attempt.elapsed().unwrap_or_default() < config.retry_delay
+            },
+            CircuitBreakerState::Close(_state) => false,
+            CircuitBreakerState::HalfOpen(_state) => true,
+        }
+    }
+
+    // time left of the retry delay while not letting requests through,
+    // a half open circuit waits for its probe, at most the retry delay
+    pub fn retry_in(&self, config: &CircuitBreakerConfig) -> Option<Duration> {
+        if !self.is_open(config) {
+            return None;
+        }
+        let last_attempt = match self {
+            CircuitBreakerState::Open(state) => state.last_attempt,
+            CircuitBreakerState::HalfOpen(state) => state.last_attempt,
+            CircuitBreakerState::Close(_state) => return None,
+        };
+        Some(config.retry_delay.saturating_sub(last_attempt.elapsed().unwrap_or_default()))
+    }
+
     pub fn success(&mut self, _config: &CircuitBreakerConfig) {
         let now = SystemTime::now();
         match self {
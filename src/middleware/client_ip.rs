@@ -0,0 +1,102 @@
+use hyper::{Body, Request};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+/// Address block like `10.0.0.0/8` or `2001:db8::/32`, a single address without prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = canonical(ip);
+        if ip.is_ipv4() != self.network.is_ipv4() {
+            return false;
+        }
+        ip_prefix(ip, self.prefix, self.prefix) == self.network
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let ip: IpAddr = addr.parse().map_err(|_| format!("bad address {:?}", s))?;
+        let ip = canonical(ip);
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("bad prefix {:?}", s))?,
+            None => max,
+        };
+        Ok(Cidr {
+            network: ip_prefix(ip, prefix, prefix),
+            prefix,
+        })
+    }
+}
+
+/// Client address of a request, from the connection or its PROXY protocol header.
+/// If the peer is one of the `trusted` proxies, like load balancers in front of the gateway,
+/// `X-Forwarded-For` is walked from right to left, the first untrusted hop is the client.
+pub fn client_ip(req: &Request<Body>, trusted: &[Cidr]) -> Option<IpAddr> {
+    let peer = canonical(req.extensions().get::<SocketAddr>()?.ip());
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|c| c.contains(ip));
+    if !is_trusted(peer) {
+        return Some(peer);
+    }
+    let mut hops: Vec<&str> = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .collect();
+    let mut client = peer;
+    while let Some(hop) = hops.pop() {
+        // garbage can't be trusted to name a client, the last valid hop is used
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) => client = canonical(ip),
+            Err(_) => break,
+        }
+        if !is_trusted(client) {
+            break;
+        }
+    }
+    Some(client)
+}
+
+// IPv4 mapped IPv6 addresses count as IPv4
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        v4 => v4,
+    }
+}
+
+/// Network of `ip` by prefix length
+pub(crate) fn ip_prefix(ip: IpAddr, ipv4_prefix: u8, ipv6_prefix: u8) -> IpAddr {
+    match canonical(ip) {
+        IpAddr::V4(v4) => {
+            let mask = u32::MAX
+                .checked_shl(32 - ipv4_prefix.min(32) as u32)
+                .unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+        }
+        IpAddr::V6(v6) => {
+            let mask = u128::MAX
+                .checked_shl(128 - ipv6_prefix.min(128) as u32)
+                .unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+        }
+    }
+}
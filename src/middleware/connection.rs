@@ -0,0 +1,146 @@
+use futures::future::BoxFuture;
+use futures::TryFutureExt;
+use hyper::client::connect::{Connected, Connection};
+use hyper::{Body, Response, Uri};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tower::Service;
+
+lazy_static::lazy_static! {
+
+    static ref UPSTREAM_CONNECTIONS: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_upstream_connection_uses_total",
+        "Upstream requests on a new or a reused pooled connection",
+        &["service", "upstream", "connection"]
+    ).unwrap();
+
+}
+
+// response extension shared by all responses of a connection, set once it served a request
+#[derive(Debug, Clone)]
+struct ConnectionUse(Arc<AtomicBool>);
+
+/// Count whether a response came over a new or a reused upstream connection
+pub(crate) fn record_connection_use(resp: &mut Response<Body>, service: &str, upstream: &str) {
+    if let Some(ConnectionUse(used)) = resp.extensions_mut().remove::<ConnectionUse>() {
+        let connection = match used.swap(true, Ordering::Relaxed) {
+            true => "reused",
+            false => "new",
+        };
+        UPSTREAM_CONNECTIONS
+            .with_label_values(&[service, upstream, connection])
+            .inc();
+    }
+}
+
+/// Connector tagging connections, so responses tell whether their connection was pooled
+#[derive(Debug, Clone)]
+pub(crate) struct TrackedConnector<C>(pub C);
+
+impl<C> Service<Uri> for TrackedConnector<C>
+where
+    C: Service<Uri>,
+    C::Future: Send + 'static,
+{
+    type Response = TrackedConnection<C::Response>;
+    type Error = C::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        Box::pin(self.0.call(dst).map_ok(|inner| TrackedConnection {
+            inner,
+            used: ConnectionUse(Arc::new(AtomicBool::new(false))),
+        }))
+    }
+}
+
+/// Connector replacing the host of the connected uri, port kept. Around the TLS connector it
+/// sets the name the certificate is verified against, inside it the address connected to.
+#[derive(Debug, Clone)]
+pub(crate) struct HostOverride<C> {
+    pub inner: C,
+    pub host: Option<String>,
+}
+
+impl<C> Service<Uri> for HostOverride<C>
+where
+    C: Service<Uri>,
+    C::Error: Into<Box<dyn std::error::Error + Send + Sync>> + 'static,
+    C::Future: Send + 'static,
+{
+    type Response = C::Response;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let dst = match &self.host {
+            Some(host) => match with_host(&dst, host) {
+                Ok(dst) => dst,
+                Err(e) => return Box::pin(async move { Err(e.into()) }),
+            },
+            None => dst,
+        };
+        Box::pin(self.inner.call(dst).map_err(Into::into))
+    }
+}
+
+fn with_host(dst: &Uri, host: &str) -> Result<Uri, hyper::http::Error> {
+    let authority = match dst.port_u16() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    let mut parts = dst.clone().into_parts();
+    parts.authority = Some(authority.parse()?);
+    Ok(Uri::from_parts(parts)?)
+}
+
+pub(crate) struct TrackedConnection<IO> {
+    inner: IO,
+    used: ConnectionUse,
+}
+
+impl<IO: Connection> Connection for TrackedConnection<IO> {
+    fn connected(&self) -> Connected {
+        self.inner.connected().extra(self.used.clone())
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for TrackedConnection<IO> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for TrackedConnection<IO> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
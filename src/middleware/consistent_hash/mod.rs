@@ -0,0 +1,5 @@
+mod in_flight;
+mod ring;
+
+pub use in_flight::InFlight;
+pub use ring::HashRing;
@@ -0,0 +1,182 @@
+use super::InFlight;
+use fnv::FnvHasher;
+use hyper::Request;
+use std::hash::Hasher;
+use tower::steer::Picker;
+
+// virtual nodes per upstream on the ring
+const REPLICAS: usize = 160;
+
+/// Consistent hash ring with virtual nodes, optionally bounding the load of each node.
+///
+/// With a load bound of `p` percent, a node is skipped when its in flight requests
+/// reach `p%` of the average, and the key walks on to the next node on the ring.
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    ring: Vec<(u64, usize)>, // (point, node index), sorted by point
+    load_bound: u32,
+}
+
+impl HashRing {
+    pub fn new(nodes: &[String], load_bound: u32) -> Self {
+        let mut ring = Vec::with_capacity(nodes.len() * REPLICAS);
+        for (index, node) in nodes.iter().enumerate() {
+            for replica in 0..REPLICAS {
+                ring.push((hash_key(format!("{}#{}", node, replica).as_bytes()), index));
+            }
+        }
+        ring.sort_unstable();
+        let load_bound = if load_bound > 0 {
+            load_bound.max(100)
+        } else {
+            0
+        };
+        HashRing { ring, load_bound }
+    }
+
+    pub fn pick(&self, key: &[u8], loads: &[usize]) -> usize {
+        if self.ring.is_empty() {
+            return 0;
+        }
+        let point = hash_key(key);
+        let start = self.ring.partition_point(|(p, _)| *p < point);
+        let first = self.ring[start % self.ring.len()].1;
+        if self.load_bound == 0 || loads.is_empty() {
+            return first;
+        }
+
+        let total: usize = loads.iter().sum();
+        let capacity = ((total + 1) as u64 * self.load_bound as u64)
+            .div_ceil(loads.len() as u64 * 100) as usize;
+        for offset in 0..self.ring.len() {
+            let node = self.ring[(start + offset) % self.ring.len()].1;
+            if loads.get(node).copied().unwrap_or(0) < capacity {
+                return node;
+            }
+        }
+        first
+    }
+}
+
+// fixed hash, so keys map to the same nodes across restarts, builds and gateway instances.
+// FNV mixed by the murmur3 finalizer, similar keys like `node#1` and `node#2` land far apart.
+fn hash_key(key: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(key);
+    let mut h = hasher.finish();
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+impl<S, B> Picker<InFlight<S>, Request<B>> for HashRing {
+    fn pick(&mut self, req: &Request<B>, services: &[InFlight<S>]) -> usize {
+        let key = req
+            .headers()
+            .get("x-lb-hash")
+            .map(|v| v.as_bytes())
+            .unwrap_or(b"empty");
+        let loads: Vec<usize> = services.iter().map(|s| s.in_flight()).collect();
+        HashRing::pick(self, key, &loads)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(count: usize) -> Vec<String> {
+        (0..count)
+            .map(|i| format!("http://10.0.0.{}:8080/", i))
+            .collect()
+    }
+
+    fn keys() -> impl Iterator<Item = String> {
+        (0..10_000).map(|i| format!("user-{}", i))
+    }
+
+    #[test]
+    fn test_stable_mapping() {
+        let ring = HashRing::new(&nodes(5), 0);
+        let again = HashRing::new(&nodes(5), 0);
+        for key in keys() {
+            let node = ring.pick(key.as_bytes(), &[]);
+            assert_eq!(ring.pick(key.as_bytes(), &[]), node);
+            assert_eq!(again.pick(key.as_bytes(), &[]), node);
+        }
+        // same hash in every process
+        assert_eq!(hash_key(b"user-1"), 0x41a2_fca5_c684_01c5);
+    }
+
+    #[test]
+    fn test_spread() {
+        let ring = HashRing::new(&nodes(5), 0);
+        let mut counts = [0; 5];
+        for key in keys() {
+            counts[ring.pick(key.as_bytes(), &[])] += 1;
+        }
+        // 2000 each if even
+        assert!(
+            counts.iter().all(|c| (1400..2600).contains(c)),
+            "{:?}",
+            counts
+        );
+    }
+
+    #[test]
+    fn test_minimal_remapping() {
+        let five = HashRing::new(&nodes(5), 0);
+        let six = HashRing::new(&nodes(6), 0);
+        let mut moved = 0;
+        for key in keys() {
+            let before = five.pick(key.as_bytes(), &[]);
+            let after = six.pick(key.as_bytes(), &[]);
+            if before != after {
+                // keys only move to the added node
+                assert_eq!(after, 5);
+                moved += 1;
+            }
+        }
+        // about 1/6 of the keys
+        assert!((1200..2200).contains(&moved), "{}", moved);
+
+        // removing a node moves only its keys
+        let mut remaining = nodes(5);
+        remaining.remove(2);
+        let four = HashRing::new(&remaining, 0);
+        let mut moved = 0;
+        for key in keys() {
+            let before = five.pick(key.as_bytes(), &[]);
+            let after = four.pick(key.as_bytes(), &[]);
+            let after = if after >= 2 { after + 1 } else { after };
+            if before != after {
+                assert_eq!(before, 2);
+                moved += 1;
+            }
+        }
+        assert!((1400..2600).contains(&moved), "{}", moved);
+    }
+
+    #[test]
+    fn test_load_bound() {
+        let ring = HashRing::new(&nodes(4), 125);
+        let key = b"user-1";
+        let home = ring.pick(key, &[0; 4]);
+
+        // within the bound the key stays
+        let mut loads = [2; 4];
+        assert_eq!(ring.pick(key, &loads), home);
+
+        // home node at the bound, the key walks to a node under it
+        loads[home] = 6;
+        let other = ring.pick(key, &loads);
+        assert_ne!(other, home);
+        assert_eq!(ring.pick(key, &loads), other);
+
+        // without a bound the load is ignored
+        let unbounded = HashRing::new(&nodes(4), 0);
+        assert_eq!(unbounded.pick(key, &loads), unbounded.pick(key, &[0; 4]));
+    }
+}
@@ -0,0 +1,187 @@
+use crate::config::{ConfigUpdate, ErrorPage};
+use crate::middleware::{Middleware, MwPostRequest, MwPostResponse, MwPreRequest};
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Response};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use tracing::{event, Level};
+
+const DEFAULT_CONTENT_TYPE: &str = "text/html";
+
+#[derive(Debug, Clone)]
+struct LoadedPage {
+    mime: String, // lowercase media type without parameters
+    content_type: HeaderValue,
+    body: Bytes,
+}
+
+// pages[status], in config order
+type PageSet = HashMap<u16, Vec<LoadedPage>>;
+
+/// Loaded `--error_pages`, used when the service has no page for a status
+#[derive(Debug, Clone, Default)]
+pub struct ErrorPages(PageSet);
+
+impl ErrorPages {
+    pub fn load(pages: &[ErrorPage]) -> io::Result<Self> {
+        load_pages(pages).map(ErrorPages)
+    }
+}
+
+/// Read the body of an error page, from its file if set
+pub fn load_error_page(page: &ErrorPage) -> io::Result<Bytes> {
+    match page.file.as_str() {
+        "" => Ok(Bytes::from(page.body.clone())),
+        path => std::fs::read(path).map(Bytes::from),
+    }
+}
+
+fn load_pages(pages: &[ErrorPage]) -> io::Result<PageSet> {
+    let mut set = PageSet::new();
+    for page in pages {
+        let content_type = match page.content_type.as_str() {
+            "" => DEFAULT_CONTENT_TYPE,
+            t => t,
+        };
+        let loaded = LoadedPage {
+            mime: media_type(content_type),
+            content_type: HeaderValue::from_str(content_type)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            body: load_error_page(page)?,
+        };
+        set.entry(page.status).or_default().push(loaded);
+    }
+    Ok(set)
+}
+
+fn media_type(content_type: &str) -> String {
+    let mime = content_type.split(';').next().unwrap_or("");
+    mime.trim().to_ascii_lowercase()
+}
+
+fn is_json(mime: &str) -> bool {
+    mime == "application/json" || mime.starts_with("application/") && mime.ends_with("+json")
+}
+
+// client lists the media type or its type/*, json pages also serve */* and a missing Accept
+fn accepts(accept: &str, mime: &str) -> bool {
+    if accept.trim().is_empty() {
+        return is_json(mime);
+    }
+    let wildcard = format!("{}/*", mime.split('/').next().unwrap_or(""));
+    accept.split(',').any(|item| {
+        let mut params = item.split(';');
+        let range = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let rejected = params.any(|p| {
+            let p = p.trim();
+            p.starts_with("q=") && p[2..].parse::<f32>().is_ok_and(|q| q == 0.0)
+        });
+        !rejected && (range == mime || range == wildcard || range == "*/*" && is_json(mime))
+    })
+}
+
+/// Replace bodies of error responses with static pages negotiated by the client's Accept header,
+/// from service `error_pages` or the global `--error_pages`
+#[derive(Debug, Default)]
+pub struct ErrorPageMiddleware {
+    pages: HashMap<String, PageSet>, // pages[service_id]
+    default_pages: ErrorPages,
+}
+
+impl ErrorPageMiddleware {
+    /// Middleware serving `default_pages` for services without their own page of a status
+    pub fn new(default_pages: ErrorPages) -> Self {
+        ErrorPageMiddleware {
+            pages: HashMap::new(),
+            default_pages,
+        }
+    }
+
+    fn page_of(&self, service_id: &str, status: u16, accept: &str) -> Option<LoadedPage> {
+        let pick = |set: &PageSet| {
+            set.get(&status)?
+                .iter()
+                .find(|p| accepts(accept, &p.mime))
+                .cloned()
+        };
+        self.pages
+            .get(service_id)
+            .and_then(pick)
+            .or_else(|| pick(&self.default_pages.0))
+    }
+}
+
+impl Middleware for ErrorPageMiddleware {
+    fn name() -> String {
+        "ErrorPage".into()
+    }
+
+    fn pre() -> bool {
+        false
+    }
+
+    fn require_setting() -> bool {
+        false
+    }
+
+    fn request(&mut self, _task: MwPreRequest) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        panic!("never got here")
+    }
+
+    fn response(&mut self, task: MwPostRequest) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let MwPostRequest {
+            context,
+            mut response,
+            result,
+            ..
+        } = task;
+        let status = response.status().as_u16();
+        let grpc = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .is_some_and(|v| v.as_bytes().starts_with(b"application/grpc"));
+        if status >= 400 && !grpc {
+            if let Some(page) = self.page_of(&context.service_id, status, &context.accept) {
+                let (mut parts, _) = response.into_parts();
+                parts.headers.remove(CONTENT_ENCODING);
+                parts.headers.insert(CONTENT_TYPE, page.content_type);
+                parts.headers.insert(CONTENT_LENGTH, page.body.len().into());
+                response = Response::from_parts(parts, Body::from(page.body));
+            }
+        }
+        let _ = result.send(Ok(MwPostResponse { context, response }));
+        Box::pin(async {})
+    }
+
+    fn config_update(&mut self, update: ConfigUpdate) {
+        match update {
+            ConfigUpdate::ServiceUpdate(service) => {
+                match load_pages(&service.error_pages) {
+                    Ok(pages) if pages.is_empty() => {
+                        self.pages.remove(&service.service_id);
+                    }
+                    Ok(pages) => {
+                        self.pages.insert(service.service_id, pages);
+                    }
+                    // validated before update, file may be gone since
+                    Err(e) => {
+                        event!(
+                            Level::ERROR,
+                            "Cannot load error pages of service {}: {}",
+                            service.service_id,
+                            e
+                        );
+                        self.pages.remove(&service.service_id);
+                    }
+                }
+            }
+            ConfigUpdate::ServiceRemove(service_id) => {
+                self.pages.remove(&service_id);
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use crate::config::{ConfigUpdate, FilterSetting};
+use crate::middleware::{
+    GatewayError, Middleware, MwNextAction, MwPostRequest, MwPreRequest, MwPreResponse,
+};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Response, StatusCode};
+use rand::Rng;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use tracing::{event, Level};
+
+const DEFAULT_ABORT_STATUS: u16 = 503;
+
+lazy_static::lazy_static! {
+    static ref INJECTED_FAULTS: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_injected_faults_total",
+        "Faults injected by FaultInjection filters",
+        &["service", "fault"]
+    ).unwrap();
+}
+
+/// Delay or abort requests by FaultInjection filters, for resilience testing
+#[derive(Debug, Default)]
+pub struct FaultInjectionMiddleware {
+    enabled: bool, // filters are ignored unless enabled by --fault_injection
+    timeouts: HashMap<String, Duration>, // timeouts[service_id], caps injected delay
+}
+
+impl FaultInjectionMiddleware {
+    /// Apply FaultInjection filters if `enabled`, off by default so a stray filter never
+    /// hurts production
+    pub fn new(enabled: bool) -> Self {
+        FaultInjectionMiddleware {
+            enabled,
+            ..Default::default()
+        }
+    }
+}
+
+impl Middleware for FaultInjectionMiddleware {
+    fn name() -> String {
+        "FaultInjection".into()
+    }
+
+    fn post() -> bool {
+        false
+    }
+
+    fn request(&mut self, task: MwPreRequest) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let MwPreRequest {
+            context,
+            request,
+            service_filters,
+            client_filters,
+            result,
+        } = task;
+        let mut delay = Duration::ZERO;
+        let mut abort = None;
+        if self.enabled {
+            let mut rng = rand::thread_rng();
+            for setting in service_filters
+                .iter()
+                .chain(client_filters.iter())
+                .filter_map(|f| match f {
+                    FilterSetting::FaultInjection(s) => Some(s),
+                    _ => None,
+                })
+            {
+                if rng.gen_range(0..100) < setting.delay_percent {
+                    delay = delay.max(Duration::from_millis(setting.delay_ms));
+                }
+                if abort.is_none() && rng.gen_range(0..100) < setting.abort_percent {
+                    abort = Some(setting.abort_status);
+                }
+            }
+        }
+        if delay.is_zero() && abort.is_none() {
+            let response = MwPreResponse {
+                context,
+                next: MwNextAction::Next(request),
+            };
+            let _ = result.send(Ok(response));
+            return Box::pin(async {});
+        }
+
+        let timeout = self.timeouts.get(&context.service_id).cloned();
+        // delay out of the middleware loop
+        tokio::spawn(async move {
+            let service_id = context.service_id.clone();
+            if !delay.is_zero() {
+                INJECTED_FAULTS
+                    .with_label_values(&[&service_id, "delay"])
+                    .inc();
+                if let Some(timeout) = timeout.filter(|t| delay >= *t) {
+                    tokio::time::sleep(timeout).await;
+                    let _ = result.send(Err(GatewayError::TimeoutError));
+                    return;
+                }
+                tokio::time::sleep(delay).await;
+            }
+            let next = match abort {
+                Some(status) => {
+                    INJECTED_FAULTS
+                        .with_label_values(&[&service_id, "abort"])
+                        .inc();
+                    MwNextAction::Return(abort_response(status))
+                }
+                None => MwNextAction::Next(request),
+            };
+            let _ = result.send(Ok(MwPreResponse { context, next }));
+        });
+        Box::pin(async {})
+    }
+
+    fn response(&mut self, _task: MwPostRequest) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        panic!("never got here")
+    }
+
+    fn config_update(&mut self, update: ConfigUpdate) {
+        match update {
+            ConfigUpdate::ServiceUpdate(service) => {
+                let has_filter = service
+                    .filters
+                    .iter()
+                    .chain(service.sla.iter().flat_map(|sla| sla.filters.iter()))
+                    .any(|f| matches!(f, FilterSetting::FaultInjection(_)));
+                if has_filter && !self.enabled {
+                    event!(
+                        Level::WARN,
+                        "FaultInjection filters of service {} ignored, not enabled by --fault_injection",
+                        service.service_id
+                    );
+                }
+                let timeout = Duration::from_secs(service.timeout as u64);
+                self.timeouts.insert(service.service_id, timeout);
+            }
+            ConfigUpdate::ServiceRemove(service_id) => {
+                self.timeouts.remove(&service_id);
+            }
+            _ => {}
+        }
+    }
+}
+
+// json body like gateway errors, marked as injected
+fn abort_response(status: u16) -> Response<Body> {
+    let status = match status {
+        0 => DEFAULT_ABORT_STATUS,
+        s => s,
+    };
+    let status = StatusCode::from_u16(status).unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+    let body = serde_json::json!({"error": "Fault injected", "code": "fault_injected"});
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .header("X-Fault-Injected", "abort")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
@@ -9,15 +9,9 @@ use hyper::{
 use std::future::Future;
 use std::pin::Pin;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct HeaderMiddleware {}
 
-impl Default for HeaderMiddleware {
-    fn default() -> Self {
-        HeaderMiddleware {}
-    }
-}
-
 impl Middleware for HeaderMiddleware {
     fn name() -> String {
         "Header".into()
@@ -43,7 +37,7 @@ impl Middleware for HeaderMiddleware {
             }
         }
         let resp = MwPreResponse {
-            context: context,
+            context,
             next: MwNextAction::Next(request),
         };
         let _ = result.send(Ok(resp));
@@ -70,8 +64,8 @@ impl Middleware for HeaderMiddleware {
             }
         }
         let resp = MwPostResponse {
-            context: context,
-            response: response,
+            context,
+            response,
         };
         let _ = result.send(Ok(resp));
         Box::pin(async {})
@@ -0,0 +1,390 @@
+use crate::config::IdempotencySetting;
+use crate::middleware::json_transform::read_capped;
+use crate::middleware::{GatewayError, MwNextAction, MwPreRequest, MwPreResponse, RequestContext};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::http::response::Parts;
+use hyper::{Body, Request, Response};
+use redis::aio::MultiplexedConnection;
+use redis::RedisResult;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::{event, Level};
+
+/// Request header naming a request, retries with the same key get the first response
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Response header set on responses replayed for a retry
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+
+const DEFAULT_MAX_BODY: usize = 1024 * 1024;
+const MAX_KEY_LEN: usize = 255;
+const KEY_PREFIX: &str = "hyperapi:idempotency";
+// stored value of a key whose first request is in flight on some replica
+const PENDING: &str = "pending";
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+lazy_static::lazy_static! {
+    static ref REPLAYED_REQUESTS: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_idempotent_replays_total",
+        "Requests answered with the stored response of an earlier request with the same Idempotency-Key",
+        &["service"]
+    ).unwrap();
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String, // base64
+}
+
+impl StoredResponse {
+    fn of(parts: &Parts, body: &[u8]) -> Self {
+        let headers = parts
+            .headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        StoredResponse {
+            status: parts.status.as_u16(),
+            headers,
+            body: base64::encode(body),
+        }
+    }
+
+    fn response(&self) -> Response<Body> {
+        let mut builder = Response::builder().status(self.status);
+        for (name, value) in self.headers.iter() {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        let body = base64::decode(&self.body).unwrap_or_default();
+        let mut resp = builder
+            .body(Body::from(body))
+            .unwrap_or_else(|_| Response::new(Body::empty()));
+        resp.headers_mut().insert(
+            HeaderName::from_static(IDEMPOTENT_REPLAYED_HEADER),
+            HeaderValue::from_static("true"),
+        );
+        resp
+    }
+}
+
+// result of claiming a key before sending its first request
+enum Claim {
+    Claimed,
+    Stored(StoredResponse),
+    Pending,
+}
+
+type Waiter = oneshot::Sender<Option<StoredResponse>>;
+
+/// Requests with an `Idempotency-Key` are sent once per client and key, later ones get the
+/// stored response. Responses of 5xx, failed or too large requests are not stored.
+#[derive(Debug)]
+pub struct Idempotency {
+    service_id: String,
+    ttl: Duration,
+    lock_ttl: Duration, // of the pending marker, in case its replica never answers
+    max_body: usize,
+    flights: Mutex<HashMap<String, Vec<Waiter>>>, // flights[key] = waiters of the first request
+    store: IdempotencyStore,
+}
+
+impl Idempotency {
+    pub fn new(
+        service_id: &str,
+        setting: &IdempotencySetting,
+        timeout: u32,
+        store: IdempotencyStore,
+    ) -> Self {
+        Idempotency {
+            service_id: service_id.into(),
+            ttl: Duration::from_secs(setting.ttl),
+            lock_ttl: Duration::from_secs(timeout as u64 + 1),
+            max_body: match setting.max_body {
+                0 => DEFAULT_MAX_BODY,
+                n => n,
+            },
+            flights: Mutex::new(HashMap::new()),
+            store,
+        }
+    }
+
+    /// Key of a request with an `Idempotency-Key` header of an identified client.
+    /// Anonymous clients can't be told apart, their keys are ignored.
+    pub fn key(&self, context: &RequestContext, req: &Request<Body>) -> Option<String> {
+        let key = req.headers().get(IDEMPOTENCY_KEY_HEADER)?.to_str().ok()?;
+        if key.is_empty() || key.len() > MAX_KEY_LEN || context.client_id.is_empty() {
+            return None;
+        }
+        Some(format!("{}:{}:{}", self.service_id, context.client_id, key))
+    }
+
+    /// Send `task` through `dispatch` unless a response is stored for its key, requests with a
+    /// key in flight wait for it. The next waiter is sent if the first request is not stored.
+    pub async fn run<F, Fut>(&self, key: String, task: MwPreRequest, dispatch: F)
+    where
+        F: Fn(MwPreRequest) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        loop {
+            let waiting = {
+                let mut flights = self.flights.lock().unwrap();
+                match flights.get_mut(&key) {
+                    Some(waiters) => {
+                        let (tx, rx) = oneshot::channel();
+                        waiters.push(tx);
+                        Some(rx)
+                    }
+                    None => {
+                        flights.insert(key.clone(), Vec::new());
+                        None
+                    }
+                }
+            };
+            let rx = match waiting {
+                Some(rx) => rx,
+                None => return self.lead(key, task, dispatch).await,
+            };
+            if let Some(stored) = rx.await.ok().flatten() {
+                return self.replay(task, &stored);
+            }
+        }
+    }
+
+    fn replay(&self, task: MwPreRequest, stored: &StoredResponse) {
+        REPLAYED_REQUESTS
+            .with_label_values(&[&self.service_id])
+            .inc();
+        let _ = task.result.send(Ok(MwPreResponse {
+            context: task.context,
+            next: MwNextAction::Return(stored.response()),
+        }));
+    }
+
+    async fn lead<F, Fut>(&self, key: String, task: MwPreRequest, dispatch: F)
+    where
+        F: Fn(MwPreRequest) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        // waiters are released even if the first request is dropped midway
+        let mut flight = Flight {
+            owner: self,
+            key,
+            stored: None,
+        };
+        loop {
+            match self.store.claim(&flight.key, self.lock_ttl).await {
+                Claim::Claimed => break,
+                Claim::Stored(stored) => {
+                    self.replay(task, &stored);
+                    flight.stored = Some(stored);
+                    return;
+                }
+                // first request of another replica
+                Claim::Pending => tokio::time::sleep(POLL_INTERVAL).await,
+            }
+        }
+
+        let MwPreRequest {
+            context,
+            request,
+            service_filters,
+            client_filters,
+            result,
+        } = task;
+        let (tx, rx) = oneshot::channel();
+        dispatch(MwPreRequest {
+            context,
+            request,
+            service_filters,
+            client_filters,
+            result: tx,
+        })
+        .await;
+        let outcome = rx
+            .await
+            .unwrap_or_else(|e| Err(GatewayError::ChannelRecvError(e.to_string())));
+
+        let outcome = match outcome {
+            Ok(MwPreResponse {
+                context,
+                next: MwNextAction::Return(resp),
+            }) if !resp.status().is_server_error() => {
+                let (parts, body) = resp.into_parts();
+                let body = match read_capped(body, self.max_body).await {
+                    Ok(body) => {
+                        let stored = StoredResponse::of(&parts, &body);
+                        self.store.save(&flight.key, &stored, self.ttl).await;
+                        flight.stored = Some(stored);
+                        Body::from(body)
+                    }
+                    Err(body) => body,
+                };
+                Ok(MwPreResponse {
+                    context,
+                    next: MwNextAction::Return(Response::from_parts(parts, body)),
+                })
+            }
+            other => other,
+        };
+        if flight.stored.is_none() {
+            self.store.release(&flight.key).await;
+        }
+        drop(flight);
+        let _ = result.send(outcome);
+    }
+}
+
+// first request of a key in this gateway, its waiters get the stored response or None once dropped
+struct Flight<'a> {
+    owner: &'a Idempotency,
+    key: String,
+    stored: Option<StoredResponse>,
+}
+
+impl Drop for Flight<'_> {
+    fn drop(&mut self) {
+        let waiters = self.owner.flights.lock().unwrap().remove(&self.key);
+        for waiter in waiters.unwrap_or_default() {
+            let _ = waiter.send(self.stored.clone());
+        }
+    }
+}
+
+/// Responses stored by the services of a gateway, shared across replicas through a redis
+/// `client` and kept in memory of the gateway if None.
+#[derive(Clone, Default)]
+pub struct IdempotencyStore {
+    client: Option<redis::Client>,
+    connection: Arc<tokio::sync::Mutex<Option<MultiplexedConnection>>>,
+    stored: Arc<Mutex<HashMap<String, (Instant, StoredResponse)>>>, // with their expiry
+}
+
+impl std::fmt::Debug for IdempotencyStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IdempotencyStore")
+            .field("client", &self.client)
+            .finish()
+    }
+}
+
+impl IdempotencyStore {
+    pub fn new(client: Option<redis::Client>) -> Self {
+        IdempotencyStore {
+            client,
+            ..Default::default()
+        }
+    }
+
+    async fn connection(&self) -> Option<MultiplexedConnection> {
+        let client = self.client.as_ref()?;
+        let mut conn = self.connection.lock().await;
+        if conn.is_none() {
+            match client.get_multiplexed_tokio_connection().await {
+                Ok(c) => *conn = Some(c),
+                Err(e) => event!(Level::WARN, "Fail to connect idempotency store: {}", e),
+            }
+        }
+        conn.clone()
+    }
+
+    // requests are sent without deduplication while the store is failing
+    async fn store_failed(&self, e: redis::RedisError) {
+        event!(Level::WARN, "Fail to use idempotency store: {}", e);
+        *self.connection.lock().await = None;
+    }
+
+    async fn claim(&self, key: &str, lock_ttl: Duration) -> Claim {
+        if self.client.is_none() {
+            let stored = self.stored.lock().unwrap();
+            return match stored.get(key) {
+                Some((expiry, resp)) if *expiry > Instant::now() => Claim::Stored(resp.clone()),
+                _ => Claim::Claimed,
+            };
+        }
+        let mut conn = match self.connection().await {
+            Some(conn) => conn,
+            None => return Claim::Claimed,
+        };
+        let store_key = format!("{}:{}", KEY_PREFIX, key);
+        let claimed: RedisResult<Option<String>> = redis::cmd("SET")
+            .arg(&store_key)
+            .arg(PENDING)
+            .arg("NX")
+            .arg("PX")
+            .arg(lock_ttl.as_millis() as u64)
+            .query_async(&mut conn)
+            .await;
+        match claimed {
+            Ok(Some(_)) => return Claim::Claimed,
+            Ok(None) => {}
+            Err(e) => {
+                self.store_failed(e).await;
+                return Claim::Claimed;
+            }
+        }
+        let value: RedisResult<Option<String>> = redis::cmd("GET")
+            .arg(&store_key)
+            .query_async(&mut conn)
+            .await;
+        match value {
+            Ok(Some(v)) if v != PENDING => match serde_json::from_str(&v) {
+                Ok(stored) => Claim::Stored(stored),
+                Err(e) => {
+                    event!(Level::WARN, "Invalid stored response of {}: {}", key, e);
+                    Claim::Claimed
+                }
+            },
+            // pending, or released since SET
+            Ok(_) => Claim::Pending,
+            Err(e) => {
+                self.store_failed(e).await;
+                Claim::Claimed
+            }
+        }
+    }
+
+    async fn save(&self, key: &str, stored: &StoredResponse, ttl: Duration) {
+        if self.client.is_none() {
+            let now = Instant::now();
+            let mut responses = self.stored.lock().unwrap();
+            responses.retain(|_, (expiry, _)| *expiry > now);
+            responses.insert(key.into(), (now + ttl, stored.clone()));
+            return;
+        }
+        if let Some(mut conn) = self.connection().await {
+            let value = serde_json::to_string(stored).unwrap_or_default();
+            let saved: RedisResult<()> = redis::cmd("SET")
+                .arg(format!("{}:{}", KEY_PREFIX, key))
+                .arg(value)
+                .arg("PX")
+                .arg(ttl.as_millis() as u64)
+                .query_async(&mut conn)
+                .await;
+            if let Err(e) = saved {
+                self.store_failed(e).await;
+            }
+        }
+    }
+
+    // drop the pending marker of a request not stored, for the next one to be sent
+    async fn release(&self, key: &str) {
+        if self.client.is_none() {
+            return;
+        }
+        if let Some(mut conn) = self.connection().await {
+            let deleted: RedisResult<()> = redis::cmd("DEL")
+                .arg(format!("{}:{}", KEY_PREFIX, key))
+                .query_async(&mut conn)
+                .await;
+            if let Err(e) = deleted {
+                self.store_failed(e).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use crate::config::{ConfigUpdate, FilterSetting, JsonSchemaSetting};
+use crate::middleware::json_transform::is_plain_json;
+use crate::middleware::{
+    GatewayError, Middleware, MwNextAction, MwPostRequest, MwPreRequest, MwPreResponse,
+};
+use glob::Pattern;
+use hyper::body::HttpBody;
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode};
+use jsonschema::JSONSchema;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tracing::{event, Level};
+
+const DEFAULT_MAX_BODY: usize = 1024 * 1024;
+// validation errors listed in a 400 response
+const MAX_DETAILS: usize = 10;
+
+/// Validate JSON request bodies against the schemas of JsonSchema filters,
+/// invalid bodies are answered with 400 and never reach upstreams
+#[derive(Default)]
+pub struct JsonSchemaMiddleware {
+    schemas: HashMap<String, HashMap<JsonSchemaSetting, Arc<SchemaRule>>>, // schemas[service_id][setting], compiled at config update
+}
+
+impl Middleware for JsonSchemaMiddleware {
+    fn name() -> String {
+        "JsonSchema".into()
+    }
+
+    fn post() -> bool {
+        false
+    }
+
+    fn request(&mut self, task: MwPreRequest) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let MwPreRequest {
+            context,
+            request,
+            service_filters,
+            client_filters,
+            result,
+        } = task;
+        let compiled = self.schemas.get(&context.service_id);
+        let rules: Vec<Arc<SchemaRule>> = service_filters
+            .iter()
+            .chain(client_filters.iter())
+            .filter_map(|f| match f {
+                FilterSetting::JsonSchema(s) => compiled.and_then(|c| c.get(s)).cloned(),
+                _ => None,
+            })
+            .filter(|rule| rule.matches(&request))
+            .collect();
+        if rules.is_empty() || !is_plain_json(request.headers()) {
+            let _ = result.send(Ok(MwPreResponse {
+                context,
+                next: MwNextAction::Next(request),
+            }));
+            return Box::pin(async {});
+        }
+        // buffer body out of the middleware loop
+        tokio::spawn(async move {
+            let checked = validate_request(request, &rules).await;
+            let _ = result.send(checked.map(|next| MwPreResponse { context, next }));
+        });
+        Box::pin(async {})
+    }
+
+    fn response(&mut self, _task: MwPostRequest) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        panic!("never got here")
+    }
+
+    fn config_update(&mut self, update: ConfigUpdate) {
+        match update {
+            ConfigUpdate::ServiceUpdate(service) => {
+                let mut rules = HashMap::new();
+                let filters = service
+                    .filters
+                    .iter()
+                    .chain(service.sla.iter().flat_map(|sla| sla.filters.iter()));
+                for filter in filters {
+                    if let FilterSetting::JsonSchema(setting) = filter {
+                        match SchemaRule::new(setting) {
+                            Ok(rule) => {
+                                rules.insert(setting.clone(), Arc::new(rule));
+                            }
+                            Err(e) => {
+                                event!(
+                                    Level::ERROR,
+                                    "bad json schema of {}: {}",
+                                    service.service_id,
+                                    e
+                                );
+                            }
+                        }
+                    }
+                }
+                self.schemas.insert(service.service_id.clone(), rules);
+            }
+            ConfigUpdate::ServiceRemove(service_id) => {
+                self.schemas.remove(&service_id);
+            }
+            _ => {}
+        }
+    }
+}
+
+// compiled JsonSchemaSetting
+struct SchemaRule {
+    methods: HashSet<String>, // empty for all
+    pattern: Option<Pattern>,
+    schema: JSONSchema,
+    max_body: usize,
+}
+
+impl SchemaRule {
+    fn new(setting: &JsonSchemaSetting) -> Result<Self, String> {
+        let methods = setting
+            .methods
+            .split(',')
+            .map(|m| m.trim().to_ascii_uppercase())
+            .filter(|m| !m.is_empty() && m != "*")
+            .collect();
+        let pattern = match setting.path_pattern.as_str() {
+            "" => None,
+            p => Some(Pattern::new(p).map_err(|e| e.to_string())?),
+        };
+        let schema = JSONSchema::compile(&setting.schema.0).map_err(|e| e.to_string())?;
+        Ok(SchemaRule {
+            methods,
+            pattern,
+            schema,
+            max_body: match setting.max_body {
+                0 => DEFAULT_MAX_BODY,
+                n => n,
+            },
+        })
+    }
+
+    fn matches(&self, request: &Request<Body>) -> bool {
+        if !self.methods.is_empty() && !self.methods.contains(request.method().as_str()) {
+            return false;
+        }
+        // path after the service path, `/svc/users/1` is matched as `/users/1`
+        let path = request.uri().path();
+        let path = path.strip_prefix('/').unwrap_or(path);
+        let left = path.find('/').map(|offset| &path[offset..]).unwrap_or("/");
+        self.pattern.as_ref().is_none_or(|p| p.matches(left))
+    }
+}
+
+async fn validate_request(
+    request: Request<Body>,
+    rules: &[Arc<SchemaRule>],
+) -> Result<MwNextAction, GatewayError> {
+    let max_body = rules
+        .iter()
+        .map(|r| r.max_body)
+        .min()
+        .unwrap_or(DEFAULT_MAX_BODY);
+    let declared = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if declared.is_some_and(|len| len > max_body) {
+        return Err(GatewayError::PayloadTooLarge(format!(
+            "over {} bytes",
+            max_body
+        )));
+    }
+    let (parts, mut body) = request.into_parts();
+    // chunked bodies are counted as they arrive
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => return Ok(MwNextAction::Return(invalid(vec![detail("", e)]))),
+        };
+        if bytes.len() + chunk.len() > max_body {
+            return Err(GatewayError::PayloadTooLarge(format!(
+                "over {} bytes",
+                max_body
+            )));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    let value: Value = match serde_json::from_slice(&bytes) {
+        Ok(value) => value,
+        Err(e) => return Ok(MwNextAction::Return(invalid(vec![detail("", e)]))),
+    };
+    let details: Vec<Value> = rules
+        .iter()
+        .filter_map(|rule| rule.schema.validate(&value).err())
+        .flatten()
+        .take(MAX_DETAILS)
+        .map(|e| detail(&e.instance_path.to_string(), &e))
+        .collect();
+    if !details.is_empty() {
+        return Ok(MwNextAction::Return(invalid(details)));
+    }
+    Ok(MwNextAction::Next(Request::from_parts(
+        parts,
+        Body::from(bytes),
+    )))
+}
+
+// validation error at a JSON pointer into the body, empty for the whole body
+fn detail(path: &str, message: impl Display) -> Value {
+    json!({ "path": path, "message": message.to_string() })
+}
+
+// 400 with the validation errors, always sent unlike error details
+fn invalid(details: Vec<Value>) -> Response<Body> {
+    let body = json!({
+        "error": "Bad Request",
+        "code": "invalid_body",
+        "details": details,
+    });
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.to_string().into())
+        .unwrap()
+}
@@ -0,0 +1,224 @@
+use crate::config::{ConfigUpdate, FilterSetting, JsonTransformSetting};
+use crate::middleware::{
+    Middleware, MwNextAction, MwPostRequest, MwPostResponse, MwPreRequest, MwPreResponse,
+};
+use futures::stream::{self, StreamExt};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderMap, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Response};
+use serde_json::{Map, Value};
+use std::future::Future;
+use std::pin::Pin;
+use tracing::{event, Level};
+
+const DEFAULT_MAX_BODY: usize = 1024 * 1024;
+const REDACTED: &str = "***";
+
+/// Transform JSON response bodies by service and client filters, other responses pass through
+#[derive(Debug, Default)]
+pub struct JsonTransformMiddleware {}
+
+impl Middleware for JsonTransformMiddleware {
+    fn name() -> String {
+        "JsonTransform".into()
+    }
+
+    fn pre() -> bool {
+        false
+    }
+
+    fn request(&mut self, task: MwPreRequest) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let resp = MwPreResponse {
+            context: task.context,
+            next: MwNextAction::Next(task.request),
+        };
+        let _ = task.result.send(Ok(resp));
+        Box::pin(async {})
+    }
+
+    fn response(&mut self, task: MwPostRequest) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let MwPostRequest {
+            context,
+            response,
+            service_filters,
+            client_filters,
+            result,
+        } = task;
+        let settings: Vec<JsonTransformSetting> = service_filters
+            .into_iter()
+            .chain(client_filters)
+            .filter_map(|f| match f {
+                FilterSetting::JsonTransform(s) => Some(s),
+                _ => None,
+            })
+            .collect();
+        // buffer body out of the middleware loop
+        tokio::spawn(async move {
+            let response = transform_response(response, &settings).await;
+            let _ = result.send(Ok(MwPostResponse { context, response }));
+        });
+        Box::pin(async {})
+    }
+
+    fn config_update(&mut self, _update: ConfigUpdate) {}
+}
+
+async fn transform_response(
+    response: Response<Body>,
+    settings: &[JsonTransformSetting],
+) -> Response<Body> {
+    if settings.is_empty() || !is_plain_json(response.headers()) {
+        return response;
+    }
+    let max_body = settings
+        .iter()
+        .map(|s| match s.max_body {
+            0 => DEFAULT_MAX_BODY,
+            n => n,
+        })
+        .max()
+        .unwrap_or(DEFAULT_MAX_BODY);
+    let declared = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if declared.is_some_and(|len| len > max_body) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match read_capped(body, max_body).await {
+        Ok(bytes) => bytes,
+        Err(body) => return Response::from_parts(parts, body),
+    };
+    let mut value: Value = match serde_json::from_slice(&bytes) {
+        Ok(v) => v,
+        Err(e) => {
+            event!(Level::DEBUG, "response is not valid json, {}", e);
+            return Response::from_parts(parts, Body::from(bytes));
+        }
+    };
+    for setting in settings {
+        apply_setting(&mut value, setting);
+    }
+    let body = serde_json::to_vec(&value).unwrap_or_else(|_| bytes.to_vec());
+    parts.headers.insert(CONTENT_LENGTH, body.len().into());
+    Response::from_parts(parts, Body::from(body))
+}
+
+// application/json or application/*+json, not compressed
+pub(crate) fn is_plain_json(headers: &HeaderMap) -> bool {
+    let encoded = headers
+        .get(CONTENT_ENCODING)
+        .is_some_and(|v| !v.as_bytes().eq_ignore_ascii_case(b"identity"));
+    if encoded {
+        return false;
+    }
+    let mime = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_ascii_lowercase());
+    match mime {
+        Some(mime) => {
+            mime == "application/json"
+                || mime.starts_with("application/") && mime.ends_with("+json")
+        }
+        None => false,
+    }
+}
+
+// whole body if within limit, otherwise a body replaying what was read followed by the rest
+pub(crate) async fn read_capped(mut body: Body, limit: usize) -> Result<Bytes, Body> {
+    let mut chunks: Vec<Bytes> = Vec::new();
+    let mut size = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                let read = stream::iter(chunks.into_iter().map(Ok));
+                return Err(Body::wrap_stream(
+                    read.chain(stream::once(async { Err(e) })),
+                ));
+            }
+        };
+        size += chunk.len();
+        chunks.push(chunk);
+        if size > limit {
+            let read = stream::iter(chunks.into_iter().map(Ok::<_, hyper::Error>));
+            return Err(Body::wrap_stream(read.chain(body)));
+        }
+    }
+    if chunks.len() == 1 {
+        return Ok(chunks.pop().unwrap());
+    }
+    Ok(chunks.concat().into())
+}
+
+fn apply_setting(value: &mut Value, setting: &JsonTransformSetting) {
+    for path in setting.removal.iter() {
+        for_each_field(value, &split_path(path), &mut |map, key| {
+            map.remove(key);
+        });
+    }
+    for path in setting.redaction.iter() {
+        for_each_field(value, &split_path(path), &mut |map, key| {
+            if let Some(v) = map.get_mut(key) {
+                *v = Value::String(REDACTED.into());
+            }
+        });
+    }
+    // renamed last, so other paths refer to upstream field names
+    for (path, name) in setting.rename.iter() {
+        for_each_field(value, &split_path(path), &mut |map, key| {
+            if let Some(v) = map.remove(key) {
+                map.insert(name.clone(), v);
+            }
+        });
+    }
+}
+
+fn split_path(path: &str) -> Vec<&str> {
+    path.split('.').filter(|s| !s.is_empty()).collect()
+}
+
+// call `op` with the parent object and name of each field matching path
+fn for_each_field(
+    value: &mut Value,
+    path: &[&str],
+    op: &mut dyn FnMut(&mut Map<String, Value>, &str),
+) {
+    let (first, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    match value {
+        Value::Object(map) if rest.is_empty() => {
+            if *first == "*" {
+                let keys: Vec<String> = map.keys().cloned().collect();
+                for key in keys {
+                    op(map, &key);
+                }
+            } else {
+                op(map, first);
+            }
+        }
+        Value::Object(map) if *first == "*" => {
+            for v in map.values_mut() {
+                for_each_field(v, rest, op);
+            }
+        }
+        Value::Object(map) => {
+            if let Some(v) = map.get_mut(*first) {
+                for_each_field(v, rest, op);
+            }
+        }
+        Value::Array(items) if *first == "*" => {
+            for v in items.iter_mut() {
+                for_each_field(v, rest, op);
+            }
+        }
+        _ => {}
+    }
+}
@@ -1,8 +1,20 @@
+use crate::auth::Tenant;
 use crate::config::ConfigUpdate;
-use crate::middleware::{Middleware, MwPostRequest, MwPostResponse, MwPreRequest};
+use crate::middleware::{
+    latency_buckets, observe_with_exemplar, Middleware, MwNextAction, MwPostRequest,
+    MwPostResponse, MwPreRequest, MwPreResponse,
+};
+use hyper::body::HttpBody;
 use hyper::http::HeaderValue;
+use hyper::{Body, Request};
+use std::collections::{HashMap, HashSet};
 use std::future::Future;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::{pin::Pin, time::SystemTime};
+use tracing::{event, Level};
 
 lazy_static::lazy_static! {
     static ref HTTP_COUNTER: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
@@ -15,16 +27,303 @@ lazy_static::lazy_static! {
         "gateway_request_duration_seconds",
         "Request latency histgram",
         &["service", "app", "upstream", "version"],
-        vec![0.01, 0.02, 0.05, 0.1, 0.25, 0.5, 1.0, 3.0]
+        latency_buckets()
+    ).unwrap();
+
+    static ref CLIENT_REQUESTS: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_client_requests_total",
+        "Requests by service, client and status class, for billing and capacity planning",
+        &["service", "client", "status"]
+    ).unwrap();
+
+    static ref CLIENT_RESPONSE_BYTES: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_client_response_bytes_total",
+        "Response body bytes sent by service and client",
+        &["service", "client"]
+    ).unwrap();
+
+    static ref TENANT_REQUESTS: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_tenant_requests_total",
+        "Requests with a tenant by service, tenant and status class",
+        &["service", "tenant", "status"]
     ).unwrap();
 }
 
-#[derive(Debug)]
-pub struct LoggerMiddleware {}
+/// Access log line format
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessLogFormat {
+    /// structured event through tracing, formatted like other logs
+    Json,
+    /// Apache/Nginx combined line, followed by service, upstream, latency in ms and request id
+    Combined,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(AccessLogFormat::Json),
+            "combined" => Ok(AccessLogFormat::Combined),
+            _ => Err(format!(
+                "Unknown access log format {:?}, expect json or combined",
+                s
+            )),
+        }
+    }
+}
+
+/// Sample rate to log every successful request, in per mille
+pub const SAMPLE_ALL: u32 = 1000;
+
+/// Client label of metrics for clients out of the `--metrics_clients` allowlist
+pub const OTHER_CLIENTS: &str = "other";
+
+/// Access log of a gateway, shared by the logger middleware and the request handler
+/// logging requests rejected before the middleware chain
+#[derive(Clone)]
+pub struct AccessLog {
+    writer: Arc<Mutex<AccessLogWriter>>,
+    // client ids with their own metric labels, None for all
+    metrics_clients: Option<Arc<HashSet<String>>>,
+}
+
+struct AccessLogWriter {
+    format: AccessLogFormat,
+    writer: Box<dyn Write + Send>,
+    sample: u32, // per mille of 2xx responses to log, unless the service sets its own
+}
 
-impl Default for LoggerMiddleware {
+impl AccessLog {
+    /// Access log in `format` logging `sample` per mille of successful requests,
+    /// combined lines are written to `writer`
+    pub fn new(format: AccessLogFormat, writer: Box<dyn Write + Send>, sample: u32) -> Self {
+        AccessLog {
+            writer: Arc::new(Mutex::new(AccessLogWriter {
+                format,
+                writer,
+                sample: sample.min(SAMPLE_ALL),
+            })),
+            metrics_clients: None,
+        }
+    }
+
+    /// Limit client labels of metrics to these client ids, others share the `other` label.
+    /// Every client has its own label by default.
+    pub fn with_metrics_clients(mut self, clients: Vec<String>) -> Self {
+        self.metrics_clients = Some(Arc::new(clients.into_iter().collect()));
+        self
+    }
+
+    // anonymous requests keep their empty client id
+    fn client_label<'a>(&self, client_id: &'a str) -> &'a str {
+        match &self.metrics_clients {
+            Some(clients) if !client_id.is_empty() && !clients.contains(client_id) => OTHER_CLIENTS,
+            _ => client_id,
+        }
+    }
+}
+
+impl Default for AccessLog {
     fn default() -> Self {
-        LoggerMiddleware {}
+        AccessLog::new(
+            AccessLogFormat::Json,
+            Box::new(std::io::stdout()),
+            SAMPLE_ALL,
+        )
+    }
+}
+
+impl std::fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let format = self.writer.lock().unwrap().format;
+        f.debug_tuple("AccessLog").field(&format).finish()
+    }
+}
+
+/// Request line captured in pre-filter, before inner middlewares change the request
+#[derive(Debug, Clone, Default)]
+pub struct AccessInfo {
+    pub method: String,
+    pub uri: String, // path and query
+    pub version: String,
+    pub remote_addr: Option<SocketAddr>,
+    pub user_agent: String,
+    pub referer: String,
+    pub tenant: String,
+}
+
+impl AccessInfo {
+    pub fn from_request(req: &Request<Body>) -> Self {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|v: &HeaderValue| v.to_str().ok())
+                .unwrap_or("")
+                .to_string()
+        };
+        AccessInfo {
+            method: req.method().to_string(),
+            uri: req
+                .uri()
+                .path_and_query()
+                .map(|p| p.to_string())
+                .unwrap_or_else(|| req.uri().path().to_string()),
+            version: format!("{:?}", req.version()),
+            remote_addr: req.extensions().get::<SocketAddr>().cloned(),
+            user_agent: header(hyper::header::USER_AGENT),
+            referer: header(hyper::header::REFERER),
+            tenant: req
+                .extensions()
+                .get::<Tenant>()
+                .map(|t| t.0.clone())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// One access log entry, written in the configured format
+#[derive(Debug)]
+pub struct AccessRecord<'a> {
+    pub info: &'a AccessInfo,
+    pub request_id: String,
+    pub service_id: &'a str,
+    pub client_id: &'a str,
+    pub upstream_id: &'a str,
+    pub status: u16,
+    pub latency_ms: u128,
+    pub bytes_sent: Option<u64>,
+    pub sample: Option<u32>, // per mille of 2xx responses to log, rate of the log if not set
+}
+
+impl<'a> AccessRecord<'a> {
+    pub fn write(&self, log: &AccessLog) {
+        // non-2xx responses are always logged,
+        // successful ones are sampled by request id, so all lines of a request agree
+        let mut log = log.writer.lock().unwrap();
+        let sample = self.sample.unwrap_or(log.sample).min(SAMPLE_ALL);
+        let success = (200..300).contains(&self.status);
+        if success && sample_bucket(&self.request_id) >= sample {
+            return;
+        }
+        let sampled = success && sample < SAMPLE_ALL;
+        let bytes = self
+            .bytes_sent
+            .map(|b| b.to_string())
+            .unwrap_or_else(|| "-".into());
+        let remote = self
+            .info
+            .remote_addr
+            .map(|a| a.ip().to_string())
+            .unwrap_or_else(|| "-".into());
+        match log.format {
+            AccessLogFormat::Json => event!(
+                target: "access_log",
+                Level::INFO,
+                request_id = self.request_id.as_str(),
+                remote_addr = remote.as_str(),
+                method = self.info.method.as_str(),
+                path = self.info.uri.as_str(),
+                status = self.status,
+                service_id = self.service_id,
+                client_id = self.client_id,
+                tenant = self.info.tenant.as_str(),
+                upstream_id = self.upstream_id,
+                latency_ms = self.latency_ms as u64,
+                bytes_sent = bytes.as_str(),
+                user_agent = self.info.user_agent.as_str(),
+                sampled = sampled,
+                "{} {} {}",
+                self.info.method,
+                self.info.uri,
+                self.status
+            ),
+            AccessLogFormat::Combined => {
+                let line = format!(
+                    "{} - {} [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" \"{}\" \"{}\" {} {} {}\n",
+                    remote,
+                    or_dash(self.client_id),
+                    log_time(),
+                    self.info.method,
+                    self.info.uri,
+                    self.info.version,
+                    self.status,
+                    bytes,
+                    or_dash(&self.info.referer),
+                    or_dash(&self.info.user_agent),
+                    or_dash(self.service_id),
+                    or_dash(self.upstream_id),
+                    self.latency_ms,
+                    self.request_id,
+                    if sampled { "sampled" } else { "-" },
+                );
+                let _ = log.writer.write_all(line.as_bytes());
+            }
+        }
+    }
+}
+
+// body of unknown length relayed through a channel counting its bytes, trailers kept for grpc
+fn counted(mut body: Body, bytes: prometheus::IntCounter) -> Body {
+    let (mut sender, counted) = Body::channel();
+    tokio::spawn(async move {
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(_) => return sender.abort(),
+            };
+            bytes.inc_by(chunk.len() as u64);
+            if sender.send_data(chunk).await.is_err() {
+                return;
+            }
+        }
+        if let Ok(Some(trailers)) = body.trailers().await {
+            let _ = sender.send_trailers(trailers).await;
+        }
+    });
+    counted
+}
+
+// stable bucket in 0..1000 of request id, FNV-1a
+fn sample_bucket(request_id: &str) -> u32 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in request_id.bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    (hash % SAMPLE_ALL as u64) as u32
+}
+
+fn or_dash(value: &str) -> &str {
+    if value.is_empty() {
+        "-"
+    } else {
+        value
+    }
+}
+
+fn log_time() -> String {
+    let format = time::format_description::parse(
+        "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] +0000",
+    )
+    .unwrap();
+    time::OffsetDateTime::now_utc()
+        .format(&format)
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Default)]
+pub struct LoggerMiddleware {
+    service_sample: HashMap<String, u32>,
+    access_log: AccessLog,
+}
+
+impl LoggerMiddleware {
+    pub fn new(access_log: AccessLog) -> Self {
+        LoggerMiddleware {
+            service_sample: HashMap::new(),
+            access_log,
+        }
     }
 }
 
@@ -33,22 +332,31 @@ impl Middleware for LoggerMiddleware {
         "Logger".into()
     }
 
-    fn pre() -> bool {
-        false
-    }
-
     fn require_setting() -> bool {
         false
     }
 
-    fn request(&mut self, _task: MwPreRequest) -> Pin<Box<dyn Future<Output = ()> + Send>> {
-        panic!("never got here");
+    fn request(&mut self, task: MwPreRequest) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let MwPreRequest {
+            mut context,
+            request,
+            service_filters: _,
+            client_filters: _,
+            result,
+        } = task;
+        context.access = AccessInfo::from_request(&request);
+        let resp = MwPreResponse {
+            context,
+            next: MwNextAction::Next(request),
+        };
+        let _ = result.send(Ok(resp));
+        Box::pin(async {})
     }
 
     fn response(&mut self, task: MwPostRequest) -> Pin<Box<dyn Future<Output = ()> + Send>> {
         let MwPostRequest {
             context,
-            response,
+            mut response,
             service_filters: _,
             client_filters: _,
             result,
@@ -70,29 +378,82 @@ impl Middleware for LoggerMiddleware {
 
         let elapsed = SystemTime::now()
             .duration_since(context.start_time)
-            .unwrap();
-        HTTP_REQ_DURATION_HIST
-            .with_label_values(&[&context.service_id, &context.client_id, upstream, version])
-            .observe(elapsed.as_secs_f64());
+            .unwrap_or_default();
+        let client = self.access_log.client_label(&context.client_id);
+        observe_with_exemplar(
+            &HTTP_REQ_DURATION_HIST,
+            "gateway_request_duration_seconds",
+            &[
+                ("service", &context.service_id),
+                ("app", client),
+                ("upstream", upstream),
+                ("version", version),
+            ],
+            elapsed.as_secs_f64(),
+            &context.request_id,
+        );
         let path = context.api_path.clone();
         HTTP_COUNTER
             .with_label_values(&[
                 &context.service_id,
-                &context.client_id,
+                client,
                 upstream,
                 version,
                 &status,
                 &path,
             ])
             .inc_by(1);
+        let class = format!("{}xx", response.status().as_u16() / 100);
+        CLIENT_REQUESTS
+            .with_label_values(&[&context.service_id, client, &class])
+            .inc();
+        if !context.tenant.is_empty() {
+            TENANT_REQUESTS
+                .with_label_values(&[&context.service_id, &context.tenant, &class])
+                .inc();
+        }
+        let bytes = CLIENT_RESPONSE_BYTES.with_label_values(&[&context.service_id, client]);
+        let bytes_sent = response.body().size_hint().exact();
 
-        let response = MwPostResponse {
-            context: context,
-            response: response,
-        };
+        AccessRecord {
+            info: &context.access,
+            request_id: context.request_id.clone(),
+            service_id: &context.service_id,
+            client_id: &context.client_id,
+            upstream_id: upstream,
+            status: response.status().as_u16(),
+            latency_ms: elapsed.as_millis(),
+            bytes_sent,
+            sample: self.service_sample.get(&context.service_id).cloned(),
+        }
+        .write(&self.access_log);
+        match bytes_sent {
+            Some(n) => bytes.inc_by(n),
+            None => {
+                let body = std::mem::take(response.body_mut());
+                *response.body_mut() = counted(body, bytes);
+            }
+        }
+
+        let response = MwPostResponse { context, response };
         let _ = result.send(Ok(response));
         Box::pin(async {})
     }
 
-    fn config_update(&mut self, _update: ConfigUpdate) {}
+    fn config_update(&mut self, update: ConfigUpdate) {
+        match update {
+            ConfigUpdate::ServiceUpdate(service) => match service.access_log_sample {
+                Some(sample) => {
+                    self.service_sample.insert(service.service_id, sample);
+                }
+                None => {
+                    self.service_sample.remove(&service.service_id);
+                }
+            },
+            ConfigUpdate::ServiceRemove(service_id) => {
+                self.service_sample.remove(&service_id);
+            }
+            _ => {}
+        }
+    }
 }
@@ -1,34 +1,97 @@
-use crate::{auth::AuthResponse, config::ConfigUpdate, config::FilterSetting};
+use super::{
+    ACLMiddleware, AccessInfo, ErrorPageMiddleware, FaultInjectionMiddleware, HeaderMiddleware,
+    JsonSchemaMiddleware, JsonTransformMiddleware, LoggerMiddleware, QuotaMiddleware,
+    RateLimitMiddleware, ScopeMiddleware, UpstreamMiddleware,
+};
+use crate::proxy::RequestHandler;
+use crate::{
+    auth::AuthResponse, auth::GatewayAuthError, auth::Tenant, config::ConfigUpdate,
+    config::FilterSetting, config::ServiceInfo,
+};
+use hyper::http::HeaderValue;
 use hyper::{Body, Request, Response};
 use std::future::Future;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 use std::{collections::HashMap, pin::Pin, time::SystemTime};
 use thiserror::Error;
 use tokio::sync::oneshot;
 use tokio::sync::oneshot::error::RecvError;
 use tokio::sync::{broadcast, mpsc};
-use tracing::{span, Instrument, Level};
+use tracing::{event, span, Instrument, Level};
 use uuid::Uuid;
 
+/// Header carrying request id, read from client and sent to upstream and back
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Header with the class of an upstream connection failure, sent if verbose errors are enabled
+pub const GATEWAY_ERROR_HEADER: &str = "x-gateway-error";
+
+/// Middlewares of the gateway in default order, outermost first
+pub const DEFAULT_CHAIN: [&str; 11] = [
+    "Logger",
+    "ErrorPage",
+    "ACL",
+    "Scope",
+    "RateLimit",
+    "JsonSchema",
+    "Quota",
+    "Header",
+    "JsonTransform",
+    "FaultInjection",
+    "Upstream",
+];
+
 #[derive(Error, Debug, Clone)]
 pub enum GatewayError {
     #[error("Upstream request timeout")]
     TimeoutError,
 
+    #[error("Request deadline exceeded")]
+    DeadlineExceeded,
+
     #[error("Service not found")]
     ServiceNotFound(String),
 
     #[error("Service not ready")]
     ServiceNotReady(String),
 
+    #[error("Method not allowed")]
+    MethodNotAllowed(String), // methods allowed, for the Allow header
+
+    #[error("Request header too large")]
+    HeaderTooLarge(String),
+
+    #[error("Request body too large")]
+    PayloadTooLarge(String),
+
+    #[error("Service overloaded")]
+    ServiceOverloaded(String),
+
+    #[error("Circuit breaker open")]
+    CircuitOpen(u64), // seconds until the circuit lets a request through, for Retry-After
+
     #[error("Upstream error")]
     UpstreamError(String),
 
+    #[error("Upstream connect error")]
+    UpstreamConnectError(&'static str, String),
+
     #[error("Rate Limit")]
     RateLimited(String),
 
+    #[error("Quota exceeded")]
+    QuotaExceeded(String),
+
     #[error("URL Access Deny")]
     AccessBlocked(String),
 
+    #[error("Access forbidden")]
+    Forbidden(String), // rule denying the request
+
+    #[error("Unauthorized")]
+    Unauthorized(String), // missing or invalid credentials
+
     #[error("Interal server error")]
     GatewayInteralError(String),
 
@@ -39,17 +102,245 @@ pub enum GatewayError {
     Unknown,
 }
 
+impl GatewayError {
+    /// HTTP status, error code and client message of this error
+    pub fn status(&self) -> (u16, &'static str, &'static str) {
+        match self {
+            GatewayError::AccessBlocked(_) => (404, "not_found", "Not Found"),
+            GatewayError::Forbidden(_) => (403, "forbidden", "Forbidden"),
+            GatewayError::Unauthorized(_) => (401, "unauthorized", "Unauthorized"),
+            GatewayError::RateLimited(_) => (429, "rate_limited", "Rate Limited"),
+            GatewayError::QuotaExceeded(_) => (429, "quota_exceeded", "Quota exceeded"),
+            GatewayError::GatewayInteralError(_) => {
+                (500, "internal_error", "Gateway Internal Error")
+            }
+            GatewayError::ServiceNotReady(_) => (503, "service_not_ready", "Service not ready"),
+            GatewayError::ServiceOverloaded(_) => (503, "service_overloaded", "Service overloaded"),
+            GatewayError::CircuitOpen(_) => (503, "circuit_open", "Service unavailable"),
+            GatewayError::ServiceNotFound(_) => (404, "service_not_found", "Service not found"),
+            GatewayError::MethodNotAllowed(_) => (405, "method_not_allowed", "Method Not Allowed"),
+            GatewayError::HeaderTooLarge(_) => {
+                (431, "header_too_large", "Request Header Fields Too Large")
+            }
+            GatewayError::PayloadTooLarge(_) => (413, "payload_too_large", "Payload Too Large"),
+            GatewayError::TimeoutError => (504, "upstream_timeout", "Request Timeout"),
+            GatewayError::DeadlineExceeded => (504, "deadline_exceeded", "Request Timeout"),
+            GatewayError::UpstreamError(_) | GatewayError::UpstreamConnectError(..) => {
+                (502, "upstream_error", "Upstream Error")
+            }
+            GatewayError::ChannelRecvError(_) => (500, "internal_error", "Gateway Error"),
+            GatewayError::Unknown => (500, "unknown", "Gateway Error"),
+        }
+    }
+
+    /// Internal detail of this error, never sent to clients unless verbose errors are enabled
+    pub fn detail(&self) -> Option<&str> {
+        match self {
+            GatewayError::TimeoutError
+            | GatewayError::DeadlineExceeded
+            | GatewayError::MethodNotAllowed(_)
+            | GatewayError::CircuitOpen(_)
+            | GatewayError::Unknown => None,
+            GatewayError::ServiceNotFound(detail)
+            | GatewayError::ServiceNotReady(detail)
+            | GatewayError::ServiceOverloaded(detail)
+            | GatewayError::HeaderTooLarge(detail)
+            | GatewayError::PayloadTooLarge(detail)
+            | GatewayError::UpstreamError(detail)
+            | GatewayError::UpstreamConnectError(_, detail)
+            | GatewayError::RateLimited(detail)
+            | GatewayError::QuotaExceeded(detail)
+            | GatewayError::AccessBlocked(detail)
+            | GatewayError::Forbidden(detail)
+            | GatewayError::Unauthorized(detail)
+            | GatewayError::GatewayInteralError(detail)
+            | GatewayError::ChannelRecvError(detail) => Some(detail),
+        }
+    }
+
+    /// Response sent to client for this error, a json body `{"error": <message>, "code": <code>}`,
+    /// and `"detail"` if `verbose`, with the `x-gateway-error` class of connection failures.
+    /// Verbose errors are for development only.
+    /// Upstream errors are logged where they occur, other internal errors are logged here.
+    pub fn response(&self, grpc: bool, verbose: bool) -> Response<Body> {
+        if let GatewayError::GatewayInteralError(detail) | GatewayError::ChannelRecvError(detail) =
+            self
+        {
+            event!(Level::ERROR, "{}: {}", self, detail);
+        }
+        let mut resp = if grpc {
+            RequestHandler::grpc_gateway_error(self)
+        } else {
+            let (status, code, msg) = self.status();
+            let mut body = serde_json::json!({ "error": msg, "code": code });
+            if let Some(detail) = self.detail().filter(|_| verbose) {
+                body["detail"] = detail.into();
+            }
+            Response::builder()
+                .status(status)
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .body(body.to_string().into())
+                .unwrap()
+        };
+        if let (GatewayError::UpstreamConnectError(class, _), true) = (self, verbose) {
+            resp.headers_mut()
+                .insert(GATEWAY_ERROR_HEADER, HeaderValue::from_static(class));
+        }
+        if let GatewayError::CircuitOpen(secs) = self {
+            resp.headers_mut()
+                .insert(hyper::header::RETRY_AFTER, HeaderValue::from(*secs));
+        }
+        if let GatewayError::MethodNotAllowed(allow) = self {
+            if let Ok(allow) = HeaderValue::from_str(allow) {
+                resp.headers_mut().insert(hyper::header::ALLOW, allow);
+            }
+        }
+        resp
+    }
+}
+
+impl From<GatewayAuthError> for GatewayError {
+    fn from(err: GatewayAuthError) -> Self {
+        let detail = err.to_string();
+        match err {
+            GatewayAuthError::TokenNotFound
+            | GatewayAuthError::InvalidToken
+            | GatewayAuthError::InvalidIssuer => GatewayError::Unauthorized(detail),
+            GatewayAuthError::UnknownService => GatewayError::ServiceNotFound(detail),
+            GatewayAuthError::UnknownClient | GatewayAuthError::InvalidSLA => {
+                GatewayError::Forbidden(detail)
+            }
+            GatewayAuthError::MethodNotAllowed(allow) => GatewayError::MethodNotAllowed(allow),
+            GatewayAuthError::Unknown => GatewayError::GatewayInteralError(detail),
+        }
+    }
+}
+
+/// Default bucket bounds in seconds of request and upstream latency histograms
+pub const DEFAULT_LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// fixed once the first latency histogram is registered
+static LATENCY_BUCKETS: OnceLock<Vec<f64>> = OnceLock::new();
+
+/// Set bucket bounds in seconds of request and upstream latency histograms, before any
+/// request is served. Fails on bounds not positive and increasing, or once histograms are
+/// registered, they keep their buckets for the life of the process.
+pub fn set_latency_buckets(buckets: Vec<f64>) -> Result<(), String> {
+    let increasing = buckets.windows(2).all(|w| w[0] < w[1]);
+    if buckets.is_empty() || !increasing || buckets.iter().any(|b| !b.is_finite() || *b <= 0.0) {
+        return Err(format!(
+            "buckets {:?} should be positive and increasing",
+            buckets
+        ));
+    }
+    LATENCY_BUCKETS
+        .set(buckets)
+        .map_err(|_| "latency histograms are already registered".into())
+}
+
+// buckets of latency histograms, the default ones unless set before
+pub(crate) fn latency_buckets() -> Vec<f64> {
+    LATENCY_BUCKETS
+        .get_or_init(|| DEFAULT_LATENCY_BUCKETS.to_vec())
+        .clone()
+}
+
+lazy_static::lazy_static! {
+    static ref STAGE_DURATION: prometheus::HistogramVec = prometheus::register_histogram_vec!(
+        "gateway_middleware_duration_seconds",
+        "Time spent in pre and post filters of each middleware, and in auth",
+        &["stage"],
+        vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 3.0]
+    ).unwrap();
+}
+
+/// Response extension with time spent in each middleware of the request, outermost first.
+/// Inner middlewares are not included in the time of a middleware.
+#[derive(Debug, Clone, Default)]
+pub struct StageTimings(pub Vec<(String, Duration)>);
+
+impl StageTimings {
+    /// Add a stage in front of the timings of a response
+    pub fn record(resp: &mut Response<Body>, stage: &str, spent: Duration) {
+        let timings = resp.extensions_mut().get_mut::<StageTimings>();
+        match timings {
+            Some(timings) => timings.0.insert(0, (stage.to_string(), spent)),
+            None => {
+                let timings = StageTimings(vec![(stage.to_string(), spent)]);
+                resp.extensions_mut().insert(timings);
+            }
+        }
+    }
+
+    /// `Server-Timing` header value like `Auth;dur=0.42, Upstream;dur=12.5`, in milliseconds
+    pub fn header_value(&self) -> String {
+        self.0
+            .iter()
+            .map(|(stage, spent)| format!("{};dur={:.2}", stage, spent.as_secs_f64() * 1000.0))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+pub(crate) fn observe_stage(stage: &str, spent: Duration) {
+    STAGE_DURATION
+        .with_label_values(&[stage])
+        .observe(spent.as_secs_f64());
+}
+
+// time of a middleware while it runs its filters, observed once the middleware is done
+struct StageTimer {
+    stage: String,
+    spent: Duration,
+    running: Option<Instant>,
+}
+
+impl StageTimer {
+    fn start(stage: &str) -> Self {
+        StageTimer {
+            stage: stage.to_string(),
+            spent: Duration::ZERO,
+            running: Some(Instant::now()),
+        }
+    }
+
+    fn resume(&mut self) {
+        self.running = Some(Instant::now());
+    }
+
+    fn pause(&mut self) -> Duration {
+        if let Some(started) = self.running.take() {
+            self.spent += started.elapsed();
+        }
+        self.spent
+    }
+
+    fn record(&mut self, resp: &mut Response<Body>) {
+        let spent = self.pause();
+        StageTimings::record(resp, &self.stage, spent);
+    }
+}
+
+impl Drop for StageTimer {
+    fn drop(&mut self) {
+        let spent = self.pause();
+        observe_stage(&self.stage, spent);
+    }
+}
+
 impl From<hyper::Error> for GatewayError {
     fn from(e: hyper::Error) -> Self {
         let msg = format!("Upstream service error: {:?}", e);
-        GatewayError::UpstreamError(msg.into())
+        GatewayError::UpstreamError(msg)
     }
 }
 
 impl From<RecvError> for GatewayError {
     fn from(e: RecvError) -> Self {
         let msg = format!("Internal comm error: {:?}", e);
-        GatewayError::ChannelRecvError(msg.into())
+        GatewayError::ChannelRecvError(msg)
     }
 }
 
@@ -142,12 +433,16 @@ pub struct RequestContext {
     pub start_time: SystemTime,
     pub service_filters: HashMap<String, Vec<FilterSetting>>,
     pub client_filters: HashMap<String, Vec<FilterSetting>>,
-    pub request_id: Uuid,
+    pub request_id: String,
+    pub access: AccessInfo,
+    pub accept: String,       // Accept header of client, for error pages
+    pub tenant: String,       // empty if tenants are not extracted or the request has none
+    pub verbose_errors: bool, // error detail in responses, set by the request handler
 }
 
 impl RequestContext {
     pub fn new(req: &Request<Body>, auth: &AuthResponse) -> Self {
-        let req_id = Self::extract_request_id(req);
+        let req_id = Self::read_request_id(req).unwrap_or_else(|| Uuid::new_v4().to_string());
         let (service_path, api_path) = Self::split_path(req.uri().path());
         let mut context = RequestContext {
             service_id: auth.service_id.clone(),
@@ -159,10 +454,23 @@ impl RequestContext {
             service_filters: HashMap::new(),
             client_filters: HashMap::new(),
             request_id: req_id,
+            access: AccessInfo::default(),
+            accept: req
+                .headers()
+                .get(hyper::header::ACCEPT)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("")
+                .to_string(),
+            tenant: req
+                .extensions()
+                .get::<Tenant>()
+                .map(|t| t.0.clone())
+                .unwrap_or_default(),
+            verbose_errors: false,
         };
         // group FilterSettings by Middlewares
         for sf in &auth.service_filters {
-            let filter_type = FilterSetting::get_type(&sf);
+            let filter_type = FilterSetting::get_type(sf);
             if let Some(filters) = context.service_filters.get_mut(&filter_type) {
                 filters.push(sf.clone());
             } else {
@@ -172,7 +480,7 @@ impl RequestContext {
             }
         }
         for cf in &auth.client_filters {
-            let filter_type = FilterSetting::get_type(&cf);
+            let filter_type = FilterSetting::get_type(cf);
             if let Some(filters) = context.client_filters.get_mut(&filter_type) {
                 filters.push(cf.clone());
             } else {
@@ -191,24 +499,34 @@ impl RequestContext {
         (format!("/{}", service_path), String::from(api_path))
     }
 
-    fn extract_request_id(_req: &Request<Body>) -> Uuid {
-        // if let Some(value) = req.headers().get("request_id".into()) {
-        //     if let Ok(id) = Uuid::parse_str(value.to_str().unwrap_or("")) {
-        //         return id
-        //     }
-        // }
-        Uuid::new_v4()
+    /// Request id from `X-Request-Id`, a new UUID v4 is generated and set if absent or invalid
+    pub fn extract_request_id(req: &mut Request<Body>) -> String {
+        if let Some(id) = Self::read_request_id(req) {
+            return id;
+        }
+        let id = Uuid::new_v4().to_string();
+        req.headers_mut()
+            .insert(REQUEST_ID_HEADER, HeaderValue::from_str(&id).unwrap());
+        id
+    }
+
+    // visible ascii only, so ids can't break log lines
+    fn read_request_id(req: &Request<Body>) -> Option<String> {
+        let id = req.headers().get(REQUEST_ID_HEADER)?.to_str().ok()?;
+        if id.is_empty() || id.len() > 128 || !id.bytes().all(|b| b.is_ascii_graphic()) {
+            return None;
+        }
+        Some(id.to_string())
     }
 }
 
 pub async fn start_middleware<MW>(
+    mut mw: MW,
     mut tasks: mpsc::Receiver<MiddlewareRequest>,
     mut updates: broadcast::Receiver<ConfigUpdate>,
 ) where
-    MW: Middleware + Default,
+    MW: Middleware,
 {
-    let mut mw = MW::default();
-
     loop {
         tokio::select! {
             task = tasks.recv() => {
@@ -217,7 +535,7 @@ pub async fn start_middleware<MW>(
                         let ctx = x.context.clone();
                         let span = span!(Level::DEBUG, "pre_filter",
                                         service=ctx.service_id.as_str(),
-                                        trace_id=ctx.request_id.to_string().as_str(),
+                                        trace_id=ctx.request_id.as_str(),
                                         app_id=ctx.client_id.as_str(),
                                         middleware=MW::name().as_str());
                         mw.request(x).instrument(span).await;
@@ -226,12 +544,12 @@ pub async fn start_middleware<MW>(
                         let ctx = x.context.clone();
                         let span = span!(Level::DEBUG, "post_filter",
                                         service=ctx.service_id.as_str(),
-                                        trace_id=ctx.request_id.to_string().as_str(),
+                                        trace_id=ctx.request_id.as_str(),
                                         app_id=ctx.client_id.as_str(),
                                         middleware=MW::name().as_str());
                         mw.response(x).instrument(span).await;
                     },
-                    None => {},
+                    None => break,  // all handles dropped, server is shutting down
                 }
             },
             update = updates.recv() => {
@@ -246,6 +564,58 @@ pub async fn start_middleware<MW>(
     }
 }
 
+/// Whether the named middleware is skipped without filters of its type,
+/// from `Middleware::require_setting` of the gateway middlewares
+pub fn require_setting(name: &str) -> bool {
+    fn of<M: Middleware>() -> (String, bool) {
+        (M::name(), M::require_setting())
+    }
+    [
+        of::<LoggerMiddleware>(),
+        of::<ErrorPageMiddleware>(),
+        of::<ACLMiddleware>(),
+        of::<ScopeMiddleware>(),
+        of::<RateLimitMiddleware>(),
+        of::<JsonSchemaMiddleware>(),
+        of::<QuotaMiddleware>(),
+        of::<HeaderMiddleware>(),
+        of::<JsonTransformMiddleware>(),
+        of::<FaultInjectionMiddleware>(),
+        of::<UpstreamMiddleware>(),
+    ]
+    .iter()
+    .any(|(n, required)| n == name && *required)
+}
+
+/// Middleware order of a service without its disabled middlewares, empty for the default chain
+pub fn service_chain(service: &ServiceInfo) -> Vec<String> {
+    if service.disabled_middlewares.is_empty() {
+        return service.middlewares.clone();
+    }
+    let order = match service.middlewares.is_empty() {
+        true => DEFAULT_CHAIN.iter().map(|n| n.to_string()).collect(),
+        false => service.middlewares.clone(),
+    };
+    order
+        .into_iter()
+        .filter(|n| !service.disabled_middlewares.contains(n))
+        .collect()
+}
+
+/// Stack of a service with its own middleware order, outermost first like in `ServiceInfo`.
+/// The gateway stack is used as is if the order is empty.
+pub fn service_stack(stack: Vec<MiddlewareHandle>, order: &[String]) -> Vec<MiddlewareHandle> {
+    if order.is_empty() {
+        return stack;
+    }
+    // last in stack run first
+    order
+        .iter()
+        .rev()
+        .filter_map(|name| stack.iter().find(|mw| &mw.name == name).cloned())
+        .collect()
+}
+
 // recursively apply middlewares
 pub fn middleware_chain(
     req: Request<Body>,
@@ -289,11 +659,13 @@ pub fn middleware_chain(
     let resp_client_filters = client_filters.clone();
 
     // if middleware requires setting to work, and settings are empty, skip this middleware
-    if require_setting && service_filters.len() == 0 && client_filters.len() == 0 {
+    if require_setting && service_filters.is_empty() && client_filters.is_empty() {
         return middleware_chain(req, context, mw_stack);
     }
 
     let fut = async move {
+        let grpc = RequestHandler::is_grpc(&req);
+        let mut timer = StageTimer::start(&name);
         // request middleware pre-filter
         let pre_resp: Result<MwPreResponse, GatewayError> = {
             if pre {
@@ -301,8 +673,8 @@ pub fn middleware_chain(
                 let pre_req = MwPreRequest {
                     context,
                     request: req,
-                    service_filters: service_filters,
-                    client_filters: client_filters,
+                    service_filters,
+                    client_filters,
                     result: tx,
                 };
                 let _ = chan.send(MiddlewareRequest::Request(pre_req)).await;
@@ -318,15 +690,22 @@ pub fn middleware_chain(
         };
 
         let MwPreResponse { context, next } = pre_resp?;
+        timer.pause();
 
         match next {
             // call inner middleware
             MwNextAction::Next(request) => {
                 let context_copy = context.clone();
-                let inner_resp = middleware_chain(request, context, mw_stack).await?;
+                let inner_resp = match middleware_chain(request, context, mw_stack).await {
+                    Ok(resp) => resp,
+                    // errors of inner middlewares go through post-filter as responses, e.g. access log
+                    Err(err) if post => err.response(grpc, context_copy.verbose_errors),
+                    Err(err) => return Err(err),
+                };
 
                 // call middleware post-filter
                 if post {
+                    timer.resume();
                     let (tx, rx) = oneshot::channel();
                     let post_req = MwPostRequest {
                         context: context_copy,
@@ -336,14 +715,20 @@ pub fn middleware_chain(
                         result: tx,
                     };
                     let _ = chan.send(MiddlewareRequest::Response(post_req)).await;
-                    let resp = rx.await??;
-                    Ok(resp.response)
+                    let mut resp = rx.await??.response;
+                    timer.record(&mut resp);
+                    Ok(resp)
                 } else {
-                    Ok(inner_resp)
+                    let mut resp = inner_resp;
+                    timer.record(&mut resp);
+                    Ok(resp)
                 }
             }
             // if pre-filter returns response, terminate chain and return
-            MwNextAction::Return(response) => Ok(response),
+            MwNextAction::Return(mut response) => {
+                timer.record(&mut response);
+                Ok(response)
+            }
         }
     };
 
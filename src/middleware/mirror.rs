@@ -0,0 +1,107 @@
+use crate::config::{MirrorSetting, ServiceInfo, Upstream};
+use crate::middleware::json_transform::read_capped;
+use crate::middleware::proxy::{outcome, ProxyHandler};
+use crate::middleware::MwPreRequest;
+use hyper::body::HttpBody;
+use hyper::{Body, Request};
+use rand::Rng;
+use rustls::RootCertStore;
+
+const DEFAULT_MAX_BODY: usize = 1024 * 1024;
+
+lazy_static::lazy_static! {
+    static ref MIRRORED_REQUESTS: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_mirrored_requests_total",
+        "Requests copied to shadow upstream by outcome, skipped if body is over max_body",
+        &["service", "status"]
+    ).unwrap();
+}
+
+/// Copy sampled requests to a shadow upstream, its responses and errors are discarded
+#[derive(Debug)]
+pub struct Mirror {
+    service_id: String,
+    handler: ProxyHandler,
+    percent: u32,
+    max_body: usize,
+}
+
+impl Mirror {
+    pub fn new(
+        service: &ServiceInfo,
+        setting: &MirrorSetting,
+        roots: Option<&RootCertStore>,
+    ) -> Option<Self> {
+        // connection settings of the first upstream, without its headers
+        let upstream = Upstream {
+            id: "mirror".into(),
+            target: setting.target.clone(),
+            version: "mirror".into(),
+            http2_only: false,
+            host_header: None,
+            headers: Vec::new(),
+            ..service.upstreams.first()?.clone()
+        };
+        Some(Mirror {
+            service_id: service.service_id.clone(),
+            handler: ProxyHandler::new(service, &upstream, roots),
+            percent: setting.percent,
+            max_body: match setting.max_body {
+                0 => DEFAULT_MAX_BODY,
+                n => n,
+            },
+        })
+    }
+
+    pub fn sampled(&self) -> bool {
+        self.percent > 0 && rand::thread_rng().gen_range(0..100) < self.percent
+    }
+
+    /// Buffer request body and send a copy to the shadow upstream, returning the task to proxy
+    pub async fn tee(&self, task: MwPreRequest) -> MwPreRequest {
+        let MwPreRequest {
+            context,
+            request,
+            service_filters,
+            client_filters,
+            result,
+        } = task;
+        let (parts, body) = request.into_parts();
+        let body = match read_capped(body, self.max_body).await {
+            Ok(bytes) => {
+                let mut copy = Request::new(Body::from(bytes.clone()));
+                *copy.method_mut() = parts.method.clone();
+                *copy.uri_mut() = parts.uri.clone();
+                *copy.version_mut() = parts.version;
+                *copy.headers_mut() = parts.headers.clone();
+                let fut = self.handler.forward(copy);
+                let service_id = self.service_id.clone();
+                tokio::spawn(async move {
+                    let result = fut.await;
+                    MIRRORED_REQUESTS
+                        .with_label_values(&[&service_id, outcome(&result)])
+                        .inc();
+                    // read to the end so the connection goes back to pool
+                    if let Ok(resp) = result {
+                        let mut body = resp.into_body();
+                        while let Some(Ok(_)) = body.data().await {}
+                    }
+                });
+                Body::from(bytes)
+            }
+            Err(body) => {
+                MIRRORED_REQUESTS
+                    .with_label_values(&[&self.service_id, "skipped"])
+                    .inc();
+                body
+            }
+        };
+        MwPreRequest {
+            context,
+            request: Request::from_parts(parts, body),
+            service_filters,
+            client_filters,
+            result,
+        }
+    }
+}
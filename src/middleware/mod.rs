@@ -1,23 +1,66 @@
 mod acl;
+mod body_limit;
 mod circuit_breaker;
+mod client_ip;
+mod connection;
+mod consistent_hash;
+mod error_page;
+mod fault_injection;
 mod header;
+mod idempotency;
+mod json_schema;
+mod json_transform;
 mod logger;
+#[allow(clippy::module_inception)]
 mod middleware;
+mod mirror;
+mod openmetrics;
+mod outlier;
 mod proxy;
+mod quota;
 mod rate_limit;
+mod retry;
+mod round_robin;
+mod route;
+mod scope;
+mod single_flight;
+mod sticky;
+mod traffic_split;
+mod upgrade;
 mod upstream;
 mod weighted;
 
 pub use middleware::{
-    middleware_chain, start_middleware, GatewayError, Middleware, MiddlewareHandle,
-    MiddlewareRequest, MwNextAction, MwPostRequest, MwPostResponse, MwPreRequest, MwPreResponse,
-    RequestContext,
+    middleware_chain, require_setting, service_chain, service_stack, set_latency_buckets,
+    start_middleware, GatewayError, Middleware, MiddlewareHandle, MiddlewareRequest, MwNextAction,
+    MwPostRequest, MwPostResponse, MwPreRequest, MwPreResponse, RequestContext, StageTimings,
+    DEFAULT_CHAIN, DEFAULT_LATENCY_BUCKETS, GATEWAY_ERROR_HEADER, REQUEST_ID_HEADER,
 };
 
+pub(crate) use middleware::{latency_buckets, observe_stage};
+pub(crate) use openmetrics::observe_with_exemplar;
+pub(crate) use proxy::tls_config_of;
+
 pub use acl::ACLMiddleware;
+pub use client_ip::{client_ip, Cidr};
+pub use error_page::{load_error_page, ErrorPageMiddleware, ErrorPages};
+pub use fault_injection::FaultInjectionMiddleware;
 pub use header::HeaderMiddleware;
-pub use logger::LoggerMiddleware;
+pub use idempotency::{IDEMPOTENCY_KEY_HEADER, IDEMPOTENT_REPLAYED_HEADER};
+pub use json_schema::JsonSchemaMiddleware;
+pub use json_transform::JsonTransformMiddleware;
+pub use logger::{AccessInfo, AccessLog, AccessLogFormat, AccessRecord, LoggerMiddleware};
+pub use openmetrics::{accepts_openmetrics, encode_openmetrics, OPENMETRICS_CONTENT_TYPE};
+pub use quota::{period_of, QuotaCounters, QuotaMiddleware, QuotaUsage};
 pub use rate_limit::RateLimitMiddleware;
+pub use scope::ScopeMiddleware;
 pub use upstream::UpstreamMiddleware;
 
 pub use circuit_breaker::{CircuitBreakerConfig, CircuitBreakerService};
+pub use outlier::{EjectionGroup, OutlierDetection};
+pub use proxy::{
+    connect_error_class, load_ca_file, upstream_tls_config, Deadline,
+    UpstreamTime,
+};
+pub use upgrade::{is_upgrade, relay};
+pub use weighted::{RuntimeWeight, SlowStart, WeightedBalance};
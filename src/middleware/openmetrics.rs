@@ -0,0 +1,214 @@
+use crate::middleware::latency_buckets;
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+use prometheus::HistogramVec;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Content type of the OpenMetrics text exposition
+pub const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+// longest label set of an exemplar allowed by OpenMetrics, in characters
+const MAX_EXEMPLAR_LABELS: usize = 128;
+
+#[derive(Clone)]
+struct Exemplar {
+    trace_id: String,
+    value: f64,
+    timestamp: f64,
+}
+
+// label names and values of a histogram series, sorted by name as gathered
+type SeriesKey = Vec<(String, String)>;
+// latest exemplar of each bucket of a series, +Inf last
+type SeriesExemplars = HashMap<SeriesKey, Vec<Option<Exemplar>>>;
+
+lazy_static::lazy_static! {
+    // exemplars[metric][series][bucket]
+    static ref EXEMPLARS: Mutex<HashMap<String, SeriesExemplars>> = Mutex::new(HashMap::new());
+}
+
+/// Observe a latency histogram and keep the trace id as exemplar of the bucket it falls in
+pub(crate) fn observe_with_exemplar(
+    histogram: &HistogramVec,
+    name: &str,
+    labels: &[(&str, &str)],
+    value: f64,
+    trace_id: &str,
+) {
+    let values: Vec<&str> = labels.iter().map(|(_, v)| *v).collect();
+    histogram.with_label_values(&values).observe(value);
+    if trace_id.is_empty() || "trace_id".len() + trace_id.chars().count() > MAX_EXEMPLAR_LABELS {
+        return;
+    }
+    let mut series: SeriesKey = labels
+        .iter()
+        .map(|(n, v)| (n.to_string(), v.to_string()))
+        .collect();
+    series.sort();
+    let buckets = latency_buckets();
+    let bucket = buckets
+        .iter()
+        .position(|upper| value <= *upper)
+        .unwrap_or(buckets.len());
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let mut exemplars = EXEMPLARS.lock().unwrap();
+    let slots = exemplars
+        .entry(name.to_string())
+        .or_default()
+        .entry(series)
+        .or_insert_with(|| vec![None; buckets.len() + 1]);
+    slots[bucket] = Some(Exemplar {
+        trace_id: trace_id.to_string(),
+        value,
+        timestamp,
+    });
+}
+
+/// Client asks for OpenMetrics in `Accept`, plain Prometheus text otherwise
+pub fn accepts_openmetrics(accept: &str) -> bool {
+    accept.split(',').any(|item| {
+        let mut params = item.split(';');
+        let range = params.next().unwrap_or("").trim();
+        let rejected = params.any(|p| {
+            let p = p.trim();
+            p.starts_with("q=") && p[2..].parse::<f32>().is_ok_and(|q| q == 0.0)
+        });
+        !rejected && range.eq_ignore_ascii_case("application/openmetrics-text")
+    })
+}
+
+/// Encode metric families in the OpenMetrics text format, with trace id exemplars on buckets
+/// of latency histograms
+pub fn encode_openmetrics(families: &[MetricFamily]) -> String {
+    let exemplars = EXEMPLARS.lock().unwrap().clone();
+    let mut out = String::new();
+    for family in families {
+        let name = family.get_name();
+        let kind = family.get_field_type();
+        let family_name = match kind {
+            MetricType::COUNTER => name.strip_suffix("_total").unwrap_or(name),
+            _ => name,
+        };
+        let type_name = match kind {
+            MetricType::COUNTER => "counter",
+            MetricType::GAUGE => "gauge",
+            MetricType::HISTOGRAM => "histogram",
+            MetricType::SUMMARY => "summary",
+            MetricType::UNTYPED => "unknown",
+        };
+        let _ = writeln!(out, "# TYPE {} {}", family_name, type_name);
+        if !family.get_help().is_empty() {
+            let _ = writeln!(out, "# HELP {} {}", family_name, escape(family.get_help()));
+        }
+        let series_exemplars = exemplars.get(name);
+        for metric in family.get_metric() {
+            let labels = metric.get_label();
+            match kind {
+                MetricType::COUNTER => {
+                    let value = metric.get_counter().get_value();
+                    sample(&mut out, family_name, "_total", labels, value);
+                }
+                MetricType::GAUGE => {
+                    sample(&mut out, name, "", labels, metric.get_gauge().get_value());
+                }
+                MetricType::UNTYPED => {
+                    sample(&mut out, name, "", labels, metric.get_untyped().get_value());
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for q in summary.get_quantile() {
+                        let quantile = ("quantile", float(q.get_quantile()));
+                        let _ = write!(out, "{}", name);
+                        write_labels(&mut out, labels, Some(quantile));
+                        let _ = writeln!(out, " {}", float(q.get_value()));
+                    }
+                    sample(&mut out, name, "_sum", labels, summary.get_sample_sum());
+                    let count = summary.get_sample_count() as f64;
+                    sample(&mut out, name, "_count", labels, count);
+                }
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let mut series: SeriesKey = labels
+                        .iter()
+                        .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+                        .collect();
+                    series.sort();
+                    let slots = series_exemplars.and_then(|e| e.get(&series));
+                    let exemplar = |i: usize| slots.and_then(|s| s.get(i)).cloned().flatten();
+                    let buckets = histogram.get_bucket();
+                    for (i, bucket) in buckets.iter().enumerate() {
+                        let le = ("le", format!("{:?}", bucket.get_upper_bound()));
+                        let _ = write!(out, "{}_bucket", name);
+                        write_labels(&mut out, labels, Some(le));
+                        let _ = write!(out, " {}", bucket.get_cumulative_count());
+                        write_exemplar(&mut out, exemplar(i));
+                        out.push('\n');
+                    }
+                    let _ = write!(out, "{}_bucket", name);
+                    write_labels(&mut out, labels, Some(("le", "+Inf".into())));
+                    let _ = write!(out, " {}", histogram.get_sample_count());
+                    write_exemplar(&mut out, exemplar(buckets.len()));
+                    out.push('\n');
+                    let count = histogram.get_sample_count() as f64;
+                    sample(&mut out, name, "_count", labels, count);
+                    sample(&mut out, name, "_sum", labels, histogram.get_sample_sum());
+                }
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+fn sample(out: &mut String, name: &str, suffix: &str, labels: &[LabelPair], value: f64) {
+    let _ = write!(out, "{}{}", name, suffix);
+    write_labels(out, labels, None);
+    let _ = writeln!(out, " {}", float(value));
+}
+
+fn write_labels(out: &mut String, labels: &[LabelPair], extra: Option<(&str, String)>) {
+    if labels.is_empty() && extra.is_none() {
+        return;
+    }
+    let pairs = labels
+        .iter()
+        .map(|l| (l.get_name(), escape(l.get_value())))
+        .chain(extra);
+    let pairs: Vec<String> = pairs.map(|(n, v)| format!("{}=\"{}\"", n, v)).collect();
+    let _ = write!(out, "{{{}}}", pairs.join(","));
+}
+
+fn write_exemplar(out: &mut String, exemplar: Option<Exemplar>) {
+    if let Some(e) = exemplar {
+        let _ = write!(
+            out,
+            " # {{trace_id=\"{}\"}} {} {}",
+            escape(&e.trace_id),
+            float(e.value),
+            e.timestamp
+        );
+    }
+}
+
+fn float(value: f64) -> String {
+    match value {
+        v if v.is_nan() => "NaN".into(),
+        v if v == f64::INFINITY => "+Inf".into(),
+        v if v == f64::NEG_INFINITY => "-Inf".into(),
+        v => v.to_string(),
+    }
+}
+
+// label values and help text, backslash, double quote and newline escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,4 @@
+mod service;
+mod stats;
+
+pub use service::{EjectionGroup, OutlierDetection};
@@ -0,0 +1,286 @@
+use super::stats::{OutlierState, RollingWindow};
+use crate::middleware::body_limit::is_client_error;
+use crate::config::OutlierSetting;
+use futures::ready;
+use hyper::{Body, Request, Response};
+use pin_project::pin_project;
+use rand::Rng;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{Instant, Sleep};
+use tower::load_shed::error::Overloaded;
+use tower::Service;
+use tracing::{event, Level};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// recovering upstream skipped by a request is checked again after this
+const RAMP_UP_RETRY: Duration = Duration::from_millis(10);
+
+lazy_static::lazy_static! {
+    static ref UPSTREAM_EJECTED: prometheus::IntGaugeVec = prometheus::register_int_gauge_vec!(
+        "gateway_upstream_ejected",
+        "Upstreams ejected by outlier detection.",
+        &["service", "upstream"]
+    ).unwrap();
+}
+
+/// Upstreams of a service, bounding how many are ejected at the same time
+#[derive(Debug)]
+pub struct EjectionGroup {
+    ejected: AtomicUsize,
+    max_ejected: usize,
+}
+
+impl EjectionGroup {
+    pub fn new(upstreams: usize, max_ejection_percent: u32) -> Self {
+        let percent = match max_ejection_percent {
+            0 => 50,
+            p => p as usize,
+        };
+        EjectionGroup {
+            ejected: AtomicUsize::new(0),
+            max_ejected: (upstreams * percent / 100).max(1),
+        }
+    }
+
+    fn try_eject(&self) -> bool {
+        self.ejected
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < self.max_ejected).then(|| n + 1)
+            })
+            .is_ok()
+    }
+
+    fn release(&self) {
+        self.ejected.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct Detector {
+    service_id: String,
+    upstream_id: String,
+    setting: OutlierSetting,
+    window: RollingWindow,
+    state: OutlierState,
+    group: Arc<EjectionGroup>,
+}
+
+impl Detector {
+    // Ok if a request may be sent now, otherwise when to check again
+    fn admit(&mut self) -> Result<(), Instant> {
+        let now = Instant::now();
+        match self.state {
+            OutlierState::Healthy => Ok(()),
+            OutlierState::Ejected { until } if now < until => Err(until),
+            OutlierState::Ejected { until } => {
+                self.group.release();
+                UPSTREAM_EJECTED
+                    .with_label_values(&[&self.service_id, &self.upstream_id])
+                    .set(0);
+                event!(
+                    Level::INFO,
+                    "Upstream {} of service {} is back from ejection",
+                    self.upstream_id,
+                    self.service_id
+                );
+                self.state = OutlierState::Recovering { since: until };
+                self.admit()
+            }
+            OutlierState::Recovering { since } => {
+                let ramp_up = Duration::from_secs(self.setting.ramp_up);
+                let elapsed = now.saturating_duration_since(since);
+                if elapsed >= ramp_up {
+                    self.state = OutlierState::Healthy;
+                    return Ok(());
+                }
+                // 10% of traffic at first, growing linearly to all
+                let share = 0.1 + 0.9 * elapsed.as_secs_f64() / ramp_up.as_secs_f64();
+                if rand::thread_rng().gen_bool(share) {
+                    Ok(())
+                } else {
+                    Err(now + RAMP_UP_RETRY)
+                }
+            }
+        }
+    }
+
+    fn record(&mut self, error: bool) {
+        if let OutlierState::Ejected { .. } = self.state {
+            return; // requests sent before ejection
+        }
+        self.window.record(error);
+        if !error {
+            return;
+        }
+        let (requests, errors) = self.window.totals();
+        if requests < self.setting.min_requests.max(1)
+            || errors * 100 <= requests * self.setting.error_percent as u64
+        {
+            return;
+        }
+        if !self.group.try_eject() {
+            event!(
+                Level::DEBUG,
+                "Upstream {} of service {} not ejected, too many upstreams ejected",
+                self.upstream_id,
+                self.service_id
+            );
+            return;
+        }
+        let cooldown = Duration::from_secs(self.setting.cooldown);
+        event!(
+            Level::WARN,
+            "Eject upstream {} of service {} for {:?}, {} errors in {} requests",
+            self.upstream_id,
+            self.service_id,
+            cooldown,
+            errors,
+            requests
+        );
+        UPSTREAM_EJECTED
+            .with_label_values(&[&self.service_id, &self.upstream_id])
+            .set(1);
+        self.window.reset();
+        self.state = OutlierState::Ejected {
+            until: Instant::now() + cooldown,
+        };
+    }
+}
+
+impl Drop for Detector {
+    fn drop(&mut self) {
+        if let OutlierState::Ejected { .. } = self.state {
+            let labels = [self.service_id.as_str(), self.upstream_id.as_str()];
+            let _ = UPSTREAM_EJECTED.remove_label_values(&labels);
+        }
+    }
+}
+
+/// Passive outlier detection, upstream is not ready while ejected for its error rate.
+/// Unlike circuit breaker, decision is made on statistics of a rolling window.
+pub struct OutlierDetection<S> {
+    inner: S,
+    detector: Option<Arc<Mutex<Detector>>>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> OutlierDetection<S> {
+    pub fn new(
+        inner: S,
+        service_id: &str,
+        upstream_id: &str,
+        setting: &OutlierSetting,
+        group: Arc<EjectionGroup>,
+    ) -> Self {
+        let detector = Detector {
+            service_id: service_id.into(),
+            upstream_id: upstream_id.into(),
+            setting: setting.clone(),
+            window: RollingWindow::new(Duration::from_secs(setting.window)),
+            state: OutlierState::Healthy,
+            group,
+        };
+        OutlierDetection {
+            inner,
+            detector: Some(Arc::new(Mutex::new(detector))),
+            sleep: None,
+        }
+    }
+
+    /// Pass through without outlier detection
+    pub fn disabled(inner: S) -> Self {
+        OutlierDetection {
+            inner,
+            detector: None,
+            sleep: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Whether the upstream is ejected for its error rate until its cooldown is over
+    pub fn is_ejected(&self) -> bool {
+        self.detector.as_ref().is_some_and(|d| {
+            matches!(d.lock().unwrap().state, OutlierState::Ejected { until } if Instant::now() < until)
+        })
+    }
+}
+
+impl<S: Clone> Clone for OutlierDetection<S> {
+    fn clone(&self) -> Self {
+        OutlierDetection {
+            inner: self.inner.clone(),
+            detector: self.detector.clone(),
+            sleep: None,
+        }
+    }
+}
+
+impl<S> Service<Request<Body>> for OutlierDetection<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = BoxError>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = OutlierFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if let Some(detector) = &self.detector {
+            let admit = detector.lock().unwrap().admit();
+            if let Err(until) = admit {
+                // wake balancer to check again, instead of waiting for next request
+                let sleep = self
+                    .sleep
+                    .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(until)));
+                sleep.as_mut().reset(until);
+                if sleep.as_mut().poll(cx).is_ready() {
+                    cx.waker().wake_by_ref();
+                }
+                return Poll::Pending;
+            }
+        }
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        OutlierFuture {
+            fut: self.inner.call(req),
+            detector: self.detector.clone(),
+        }
+    }
+}
+
+#[pin_project]
+pub struct OutlierFuture<Fut> {
+    #[pin]
+    fut: Fut,
+    detector: Option<Arc<Mutex<Detector>>>,
+}
+
+impl<Fut> Future for OutlierFuture<Fut>
+where
+    Fut: Future<Output = Result<Response<Body>, BoxError>>,
+{
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let result = ready!(this.fut.poll(cx));
+        if let Some(detector) = this.detector {
+            // shed requests never reached upstream
+            let error = match &result {
+                Ok(resp) => resp.status().is_server_error(),
+                Err(e) => !e.is::<Overloaded>() && !is_client_error(e.as_ref()),
+            };
+            detector.lock().unwrap().record(error);
+        }
+        Poll::Ready(result)
+    }
+}
@@ -0,0 +1,70 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+const BUCKETS: usize = 10;
+
+/// Request and error counters of a rolling window, in buckets of a tenth of the window
+#[derive(Debug)]
+pub struct RollingWindow {
+    buckets: [(u64, u64); BUCKETS], // (requests, errors)
+    bucket_span: Duration,
+    current: usize,
+    current_start: Instant,
+}
+
+impl RollingWindow {
+    pub fn new(window: Duration) -> Self {
+        RollingWindow {
+            buckets: [(0, 0); BUCKETS],
+            bucket_span: (window / BUCKETS as u32).max(Duration::from_millis(1)),
+            current: 0,
+            current_start: Instant::now(),
+        }
+    }
+
+    pub fn record(&mut self, error: bool) {
+        self.advance();
+        let bucket = &mut self.buckets[self.current];
+        bucket.0 += 1;
+        if error {
+            bucket.1 += 1;
+        }
+    }
+
+    /// Requests and errors in window
+    pub fn totals(&mut self) -> (u64, u64) {
+        self.advance();
+        self.buckets
+            .iter()
+            .fold((0, 0), |(r, e), (br, be)| (r + br, e + be))
+    }
+
+    pub fn reset(&mut self) {
+        self.buckets = [(0, 0); BUCKETS];
+        self.current_start = Instant::now();
+    }
+
+    // clear buckets passed since last record
+    fn advance(&mut self) {
+        let passed = self.current_start.elapsed().as_nanos() / self.bucket_span.as_nanos();
+        if passed == 0 {
+            return;
+        }
+        if passed >= BUCKETS as u128 {
+            self.reset();
+            return;
+        }
+        for _ in 0..passed {
+            self.current = (self.current + 1) % BUCKETS;
+            self.buckets[self.current] = (0, 0);
+        }
+        self.current_start += self.bucket_span * passed as u32;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutlierState {
+    Healthy,
+    Ejected { until: Instant },
+    Recovering { since: Instant },
+}
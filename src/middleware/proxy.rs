@@ -1,16 +1,45 @@
-use crate::{config::Upstream, middleware::GatewayError};
+use crate::config::{PathRewrite, ServiceInfo, Upstream, UpstreamScheme};
+use crate::middleware::body_limit::body_too_large;
+use crate::middleware::connection::{record_connection_use, HostOverride, TrackedConnector};
+use crate::middleware::upgrade::{take_upgrade, tunnel, DEFAULT_IDLE_TIMEOUT};
+use crate::middleware::{latency_buckets, observe_with_exemplar, GatewayError, REQUEST_ID_HEADER};
+use crate::proxy::load_cert_key;
 use hyper::client::Client;
 use hyper::client::HttpConnector;
-use hyper::{header::HeaderValue, Body, Request, Response, Uri};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, Request, Response, StatusCode, Uri};
 use hyper_rustls::HttpsConnector;
-use rustls::ClientConfig;
+use regex::Regex;
+use rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+};
 use std::future::Future;
+use std::io;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio_rustls::webpki::DNSNameRef;
 use tower::Service;
 use tracing::{event, Level};
 
+const DEFAULT_TCP_KEEPALIVE: u64 = 30;
+
+pub const UPSTREAM_TIME_HEADER: &str = "x-upstream-time-ms";
+
+/// Response extension with time spent in upstream request, set if service has timing_headers
+#[derive(Debug, Clone, Copy)]
+pub struct UpstreamTime(pub Duration);
+
+/// Request extension with the end of the total request budget, upstream calls get what is left
+/// if it is less than their own timeout
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline(pub Instant);
+
+/// Request extension with the timeout of the matched route, instead of the upstream one
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RouteTimeout(pub Duration);
+
 lazy_static::lazy_static! {
 
     static ref HTTP_REQ_INPROGRESS: prometheus::IntGaugeVec = prometheus::register_int_gauge_vec!(
@@ -19,6 +48,172 @@ lazy_static::lazy_static! {
         &["service", "upstream", "version"]
     ).unwrap();
 
+    static ref UPSTREAM_DURATION_HIST: prometheus::HistogramVec = prometheus::register_histogram_vec!(
+        "gateway_upstream_duration_seconds",
+        "Upstream response time histgram",
+        &["service", "upstream", "version"],
+        latency_buckets()
+    ).unwrap();
+
+    static ref UPSTREAM_RESPONSES: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_upstream_responses_total",
+        "Upstream responses by status class, timeout or error",
+        &["service", "upstream", "version", "status"]
+    ).unwrap();
+
+    static ref UPSTREAM_CONNECT_ERRORS: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_upstream_connect_errors_total",
+        "Failed upstream connections by class, like upstream_connection_refused",
+        &["service", "upstream", "class"]
+    ).unwrap();
+
+    static ref NATIVE_ROOTS: RootCertStore = native_roots();
+}
+
+type ProxyFuture = Pin<
+    Box<
+        dyn Future<Output = Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>>>
+            + Send
+            + 'static,
+    >,
+>;
+
+// status class label, timeouts and connection errors have their own outcome
+pub(crate) fn outcome(
+    result: &Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>>,
+) -> &'static str {
+    match result {
+        Ok(resp) => match resp.status().as_u16() {
+            100..=199 => "1xx",
+            200..=299 => "2xx",
+            300..=399 => "3xx",
+            400..=499 => "4xx",
+            _ => "5xx",
+        },
+        Err(e) if matches!(e.downcast_ref(), Some(GatewayError::TimeoutError)) => "timeout",
+        Err(_) => "error",
+    }
+}
+
+/// Class of a failure to connect to an upstream: `upstream_connection_refused`,
+/// `upstream_dns_failure`, `upstream_tls_handshake_failure` or `upstream_connect_error`.
+/// None for errors on an established connection.
+pub fn connect_error_class(e: &(dyn std::error::Error + 'static)) -> Option<&'static str> {
+    match e.downcast_ref::<hyper::Error>() {
+        Some(e) if e.is_connect() => {}
+        _ => return None,
+    }
+    let mut source = e.source();
+    while let Some(cause) = source {
+        if cause.is::<TLSError>() {
+            return Some("upstream_tls_handshake_failure");
+        }
+        let io_err = cause.downcast_ref::<io::Error>();
+        if io_err.is_some_and(|e| e.kind() == io::ErrorKind::ConnectionRefused) {
+            return Some("upstream_connection_refused");
+        }
+        if cause.to_string().starts_with("dns error") {
+            return Some("upstream_dns_failure");
+        }
+        // rustls errors are wrapped as io error payloads, which io errors skip as source
+        source = match io_err.and_then(|e| e.get_ref()) {
+            Some(inner) => Some(inner),
+            None => cause.source(),
+        };
+    }
+    Some("upstream_connect_error")
+}
+
+/// Load certificates of a PEM bundle, failing if it has none
+pub fn load_ca_file(path: &str) -> io::Result<RootCertStore> {
+    let file = std::fs::File::open(path)?;
+    let mut store = RootCertStore::empty();
+    let (valid, _) = store
+        .add_pem_file(&mut io::BufReader::new(file))
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "malformed PEM file"))?;
+    if valid == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no CA certificates found",
+        ));
+    }
+    Ok(store)
+}
+
+// native cert store, or the bundled webpki roots in minimal containers without one
+fn native_roots() -> RootCertStore {
+    let store = match rustls_native_certs::load_native_certs() {
+        Ok(store) => store,
+        Err((Some(store), err)) => {
+            log::warn!("Could not load all certificates: {:?}", err);
+            store
+        }
+        Err((None, err)) => {
+            log::warn!("Could not load native certificates: {:?}", err);
+            RootCertStore::empty()
+        }
+    };
+    if !store.is_empty() {
+        return store;
+    }
+    event!(
+        Level::WARN,
+        "No native CA certificates found, trusting bundled webpki roots"
+    );
+    let mut store = RootCertStore::empty();
+    store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    store
+}
+
+/// TLS client config trusting `roots`, the native cert store or bundled webpki roots if None
+pub fn upstream_tls_config(roots: Option<&RootCertStore>) -> ClientConfig {
+    let mut tls_config = ClientConfig::new();
+    tls_config.root_store = roots.unwrap_or(&NATIVE_ROOTS).clone();
+    tls_config
+}
+
+// TLS client config for an upstream with its own ca_file, client identity or insecure_skip_verify,
+// trusting the gateway `roots` without a ca_file
+pub(crate) fn tls_config_of(
+    upstream: &Upstream,
+    roots: Option<&RootCertStore>,
+) -> io::Result<ClientConfig> {
+    let mut tls_config = match &upstream.ca_file {
+        Some(path) => {
+            let mut tls_config = ClientConfig::new();
+            tls_config.root_store = load_ca_file(path)?;
+            tls_config
+        }
+        None => upstream_tls_config(roots),
+    };
+    if let (Some(cert), Some(key)) = (&upstream.client_cert, &upstream.client_key) {
+        let (cert, key) = load_cert_key(cert, key)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        tls_config
+            .set_single_client_cert(cert, key)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    }
+    if upstream.insecure_skip_verify {
+        tls_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(SkipVerification));
+    }
+    Ok(tls_config)
+}
+
+// accept any server certificate, the connection is encrypted but not authenticated
+struct SkipVerification;
+
+impl ServerCertVerifier for SkipVerification {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[Certificate],
+        _dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -26,111 +221,312 @@ pub struct ProxyHandler {
     service_id: String,
     upstream_id: String,
     upstream: String,
+    scheme: Option<UpstreamScheme>, // forced over the scheme of upstream target
     version: String,
     timeout: Duration,
-    client: Client<HttpsConnector<HttpConnector>, Body>,
+    http2_only: bool,
+    rewrite: PathRewrite,
+    rewrite_regex: Option<Regex>,
+    headers: Vec<(HeaderName, HeaderValue)>, // host override and static headers
+    timing_headers: bool,
+    upgrade_idle_timeout: Duration, // of upgraded connections, instead of timeout
+    client:
+        Client<TrackedConnector<HostOverride<HttpsConnector<HostOverride<HttpConnector>>>>, Body>,
 }
 
 impl ProxyHandler {
-    pub fn new(service_id: &str, upstream: &Upstream, timeout: u32) -> Self {
+    /// Handler of requests to `upstream`, trusting `roots` for https targets without a ca_file
+    pub fn new(service: &ServiceInfo, upstream: &Upstream, roots: Option<&RootCertStore>) -> Self {
         let mut connector = HttpConnector::new();
-        let timeout = Duration::from_secs(timeout as u64);
-        connector.set_connect_timeout(Some(timeout));
-        connector.set_keepalive(Some(Duration::from_secs(30)));
-
-        let mut tls_config = ClientConfig::new();
-        tls_config.root_store = match rustls_native_certs::load_native_certs() {
-            Ok(store) => store,
-            Err((Some(store), err)) => {
-                log::warn!("Could not load all certificates: {:?}", err);
-                store
-            }
-            Err((None, err)) => Err(err).expect("cannot access native cert store"),
+        let timeout = Duration::from_secs(service.timeout as u64);
+        let connect_timeout = upstream
+            .connect_timeout
+            .map(Duration::from_secs)
+            .unwrap_or(timeout);
+        let request_timeout = upstream
+            .request_timeout
+            .map(Duration::from_secs)
+            .unwrap_or(timeout);
+        connector.set_connect_timeout(Some(connect_timeout));
+        let keepalive = upstream.tcp_keepalive.unwrap_or(DEFAULT_TCP_KEEPALIVE);
+        connector.set_keepalive(match keepalive {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        });
+
+        let https = upstream.target_of(service).starts_with("https://");
+        if upstream.insecure_skip_verify && https {
+            event!(
+                Level::WARN,
+                "DANGEROUS: certificate of upstream {} in service {} is not verified",
+                upstream.id,
+                service.service_id
+            );
+        }
+        let mut tls_config = tls_config_of(upstream, roots).unwrap_or_else(|e| {
+            // https requests fail on an empty root store, http ones still work
+            event!(
+                Level::ERROR,
+                "Cannot load TLS config of upstream {}: {}",
+                upstream.id,
+                e
+            );
+            ClientConfig::new()
+        });
+        if upstream.http2_only {
+            tls_config.alpn_protocols = vec![b"h2".to_vec()];
+        }
+        // let https targets through to the TLS layer
+        connector.enforce_http(false);
+        // with a server name, the TLS layer sees it as host and the TCP layer the target host
+        let target_host = upstream
+            .tls_server_name
+            .as_ref()
+            .and_then(|_| upstream.target.parse::<Uri>().ok())
+            .and_then(|uri| uri.host().map(String::from));
+        let connector = HostOverride {
+            inner: connector,
+            host: target_host,
+        };
+        let tls = HostOverride {
+            inner: HttpsConnector::from((connector, tls_config)),
+            host: upstream.tls_server_name.clone(),
         };
-        if tls_config.root_store.is_empty() {
-            panic!("no CA certificates found");
+        let idle_timeout = upstream
+            .pool_idle_timeout
+            .map(Duration::from_secs)
+            .unwrap_or(timeout);
+        let mut builder = Client::builder();
+        builder.pool_idle_timeout(idle_timeout);
+        if let Some(max_idle) = upstream.pool_max_idle_per_host {
+            builder.pool_max_idle_per_host(max_idle);
         }
+        builder.http2_only(upstream.http2_only);
+        let client = builder.build::<_, Body>(TrackedConnector(tls));
 
-        let tls = HttpsConnector::from((connector, tls_config));
-        let client = Client::builder()
-            .pool_idle_timeout(timeout)
-            .build::<_, Body>(tls);
+        let rewrite_regex = match &service.rewrite {
+            PathRewrite::Regex { pattern, .. } => match Regex::new(pattern) {
+                Ok(regex) => Some(regex),
+                Err(e) => {
+                    event!(Level::ERROR, "Invalid rewrite pattern {:?}: {}", pattern, e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        let host = upstream.host_header.iter().map(|v| ("host", v));
+        let headers = upstream.headers.iter().map(|(k, v)| (k.as_str(), v));
+        let headers = host
+            .chain(headers)
+            .filter_map(|(k, v)| {
+                let header = HeaderName::from_bytes(k.as_bytes()).ok()?;
+                let value = HeaderValue::from_str(v).ok()?;
+                Some((header, value))
+            })
+            .collect();
 
         ProxyHandler {
-            service_id: String::from(service_id),
+            service_id: service.service_id.clone(),
             client,
-            timeout,
+            timeout: request_timeout,
             upstream: upstream.target.clone(),
+            scheme: upstream.forced_scheme(service),
             upstream_id: upstream.id.clone(),
             version: upstream.version.clone(),
+            http2_only: upstream.http2_only,
+            rewrite: service.rewrite.clone(),
+            rewrite_regex,
+            headers,
+            timing_headers: service.timing_headers,
+            upgrade_idle_timeout: Duration::from_secs(match service.upgrade_idle_timeout {
+                0 => DEFAULT_IDLE_TIMEOUT,
+                secs => secs,
+            }),
         }
     }
 
-    fn alter_request(req: Request<Body>, endpoint: &str) -> Request<Body> {
+    // only the head is rewritten, body is streamed to upstream as it arrives
+    fn alter_request(&self, req: Request<Body>) -> Result<Request<Body>, GatewayError> {
         let (mut parts, body) = req.into_parts();
-        parts.version = hyper::http::Version::HTTP_11;
-        let path_and_query = parts
-            .uri
-            .path_and_query()
-            .map(|pq| pq.as_str())
-            .unwrap_or("/");
-        let path = path_and_query.strip_prefix("/").unwrap_or("/");
-        let path_left = if let Some(offset) = path.find("/") {
-            let (_service_id, path_left) = path.split_at(offset);
-            path_left
-        } else {
-            ""
+        if !self.http2_only {
+            parts.version = hyper::http::Version::HTTP_11;
+        }
+        let path = parts.uri.path();
+        let rewritten;
+        let (path_left, extra_query) = match &self.rewrite {
+            // `/svc` and `/svc/` both map to the target root
+            PathRewrite::StripPrefix => {
+                let left = path
+                    .strip_prefix('/')
+                    .and_then(|p| p.find('/').map(|offset| &p[offset..]))
+                    .unwrap_or("/");
+                (left, None)
+            }
+            PathRewrite::Keep => (path, None),
+            PathRewrite::Regex {
+                pattern,
+                replacement,
+            } => {
+                let regex = self.rewrite_regex.as_ref().ok_or_else(|| {
+                    GatewayError::GatewayInteralError(format!("Invalid rewrite {:?}", pattern))
+                })?;
+                rewritten = regex.replace(path, replacement.as_str());
+                let left = rewritten.split('#').next().unwrap_or("");
+                match left.split_once('?') {
+                    Some((left, query)) => (left, Some(query)),
+                    None => (left, None),
+                }
+            }
+        };
+        let target = match self.scheme {
+            Some(scheme) => scheme.apply(&self.upstream),
+            None => self.upstream.clone(),
         };
-        let mut new_uri = String::from(endpoint.trim_end_matches('/'));
+        let mut new_uri = String::from(target.trim_end_matches('/'));
+        if !path_left.starts_with('/') {
+            new_uri.push('/');
+        }
         new_uri.push_str(path_left);
+        let query: Vec<&str> = extra_query
+            .into_iter()
+            .chain(parts.uri.query())
+            .filter(|q| !q.is_empty())
+            .collect();
+        if !query.is_empty() {
+            new_uri.push('?');
+            new_uri.push_str(&query.join("&"));
+        }
 
-        parts.uri = new_uri.parse::<Uri>().unwrap();
-        Request::from_parts(parts, body)
+        parts.uri = new_uri.parse::<Uri>().map_err(|e| {
+            GatewayError::GatewayInteralError(format!("Invalid rewritten uri {:?}: {}", new_uri, e))
+        })?;
+        for (name, value) in self.headers.iter() {
+            parts.headers.insert(name, value.clone());
+        }
+        Ok(Request::from_parts(parts, body))
+    }
+
+    // send rewritten request within route or upstream timeout, capped by the deadline
+    fn send(&self, req: Request<Body>) -> ProxyFuture {
+        let timeout = match req.extensions().get::<RouteTimeout>() {
+            Some(RouteTimeout(timeout)) => *timeout,
+            None => self.timeout,
+        };
+        let timeout = match req.extensions().get::<Deadline>() {
+            Some(Deadline(at)) => timeout.min(at.saturating_duration_since(Instant::now())),
+            None => timeout,
+        };
+        let sleep = tokio::time::sleep(timeout);
+        let fut = self.client.request(req);
+        Box::pin(async move {
+            tokio::select! {
+                resp = fut => {
+                    resp.map_err(|e| e.into())
+                },
+                _ = sleep => {
+                    Err(GatewayError::TimeoutError.into())
+                },
+            }
+        })
+    }
+
+    /// Send request to the upstream without metrics and response headers, for mirrored traffic
+    pub fn forward(&self, req: Request<Body>) -> ProxyFuture {
+        match self.alter_request(req) {
+            Ok(req) => self.send(req),
+            Err(e) => Box::pin(async move { Err(e.into()) }),
+        }
     }
 }
 
 impl Service<Request<Body>> for ProxyHandler {
     type Response = Response<Body>;
     type Error = Box<dyn std::error::Error + Send + Sync>;
-    type Future =
-        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+    type Future = ProxyFuture;
 
     fn poll_ready(&mut self, _c: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, req: Request<Body>) -> Self::Future {
-        let req = ProxyHandler::alter_request(req, &self.upstream);
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        let upgrade = take_upgrade(&mut req);
+        let req = match self.alter_request(req) {
+            Ok(req) => req,
+            Err(e) => return Box::pin(async move { Err(e.into()) }),
+        };
         event!(Level::DEBUG, "{:?}", req.uri());
         let upstream_id = self.upstream_id.to_string();
         let version = self.version.to_string();
         let service_id = self.service_id.clone();
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
         HTTP_REQ_INPROGRESS
             .with_label_values(&[&service_id, &upstream_id, &version])
             .inc();
 
-        let sleep = tokio::time::sleep(self.timeout.clone());
-        let fut = self.client.request(req);
+        let timing_headers = self.timing_headers;
+        let idle_timeout = self.upgrade_idle_timeout;
+        let start = Instant::now();
+        let fut = self.send(req);
         Box::pin(async move {
-            let result: Result<Response<Body>, GatewayError> = tokio::select! {
-                resp = fut => {
-                    Ok(resp?)
-                },
-                _ = sleep => {
-                    Err(GatewayError::TimeoutError)
-                },
-            };
+            let result = fut.await;
+            let elapsed = start.elapsed();
 
             HTTP_REQ_INPROGRESS
                 .with_label_values(&[&service_id, &upstream_id, &version])
                 .dec();
+            observe_with_exemplar(
+                &UPSTREAM_DURATION_HIST,
+                "gateway_upstream_duration_seconds",
+                &[
+                    ("service", &service_id),
+                    ("upstream", &upstream_id),
+                    ("version", &version),
+                ],
+                elapsed.as_secs_f64(),
+                &request_id,
+            );
+            UPSTREAM_RESPONSES
+                .with_label_values(&[&service_id, &upstream_id, &version, outcome(&result)])
+                .inc();
 
-            let mut resp = result?;
+            let mut resp = result.map_err(|e| match connect_error_class(e.as_ref()) {
+                Some(class) => {
+                    UPSTREAM_CONNECT_ERRORS
+                        .with_label_values(&[&service_id, &upstream_id, class])
+                        .inc();
+                    let detail = format!("Upstream {} unreachable: {}", upstream_id, e);
+                    GatewayError::UpstreamConnectError(class, detail).into()
+                }
+                None => match body_too_large(e.as_ref()) {
+                    Some(err) => err.into(),
+                    None => e,
+                },
+            })?;
+            record_connection_use(&mut resp, &service_id, &upstream_id);
+            // request timeout ends with the handshake, the tunnel lives until idle
+            if let Some(client) = upgrade {
+                if resp.status() == StatusCode::SWITCHING_PROTOCOLS {
+                    let upstream = hyper::upgrade::on(&mut resp);
+                    tokio::spawn(tunnel(client, upstream, idle_timeout, service_id.clone()));
+                }
+            }
             let header = resp.headers_mut();
             let us_id = HeaderValue::from_str(&upstream_id).unwrap();
             let us_version = HeaderValue::from_str(&version).unwrap();
             header.append("X-UPSTREAM-ID", us_id);
             header.append("X-UPSTREAM-VERSION", us_version);
+            // time to response headers, body streaming not included
+            if timing_headers {
+                header.insert(UPSTREAM_TIME_HEADER, (elapsed.as_millis() as u64).into());
+                resp.extensions_mut().insert(UpstreamTime(elapsed));
+            }
+            // body is relayed as the client reads it, a slow client stalls the upstream
+            // instead of growing buffers in the gateway
             Ok(resp)
         })
     }
@@ -0,0 +1,279 @@
+use crate::config::{ConfigUpdate, FilterSetting, QuotaPeriod};
+use crate::middleware::{
+    GatewayError, Middleware, MwNextAction, MwPostRequest, MwPreRequest, MwPreResponse,
+};
+use crate::proxy::RequestHandler;
+use hyper::header::{HeaderValue, RETRY_AFTER};
+use redis::{AsyncCommands, RedisResult};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time::{Date, Month, OffsetDateTime};
+use tracing::{event, Level};
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const KEY_PREFIX: &str = "hyperapi:quota";
+// stored counts are kept a day after their period, for late replicas and inspection
+const KEY_GRACE: u64 = 86400;
+
+type CounterKey = (String, String, QuotaPeriod); // (service_id, client_id, period)
+
+// requests of a client in a period, `stored` is the total of all replicas at the last flush
+#[derive(Debug, Default)]
+struct Counter {
+    period: String,
+    limit: u64,
+    stored: u64,
+    flushing: u64,
+    pending: u64,
+}
+
+impl Counter {
+    fn used(&self) -> u64 {
+        self.stored + self.flushing + self.pending
+    }
+}
+
+/// Requests of a client in the current quota period, for the admin api
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub service_id: String,
+    pub client_id: String,
+    pub period: String,
+    pub used: u64,
+    pub limit: u64,
+}
+
+/// Request counters of a gateway, shared by its quota middleware and the admin api
+#[derive(Debug, Clone, Default)]
+pub struct QuotaCounters(Arc<Mutex<HashMap<CounterKey, Counter>>>);
+
+impl QuotaCounters {
+    /// Usage of clients seen in their current period, sorted by service and client
+    pub fn usage(&self) -> Vec<QuotaUsage> {
+        let mut usage: Vec<QuotaUsage> = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((service_id, client_id, _), c)| QuotaUsage {
+                service_id: service_id.clone(),
+                client_id: client_id.clone(),
+                period: c.period.clone(),
+                used: c.used(),
+                limit: c.limit,
+            })
+            .collect();
+        usage.sort_by(|a, b| {
+            (&a.service_id, &a.client_id, &a.period).cmp(&(&b.service_id, &b.client_id, &b.period))
+        });
+        usage
+    }
+}
+
+/// Count requests of each client against the `Quota` filters of its SLA, answering 429
+/// once the cap of the period is used up
+#[derive(Debug, Default)]
+pub struct QuotaMiddleware {
+    counters: QuotaCounters,
+    store: Option<redis::Client>,
+    flusher_started: bool,
+}
+
+impl QuotaMiddleware {
+    /// Middleware counting into `counters`. Usage is shared across replicas and restarts
+    /// through the redis `store`, and counted in memory of each gateway if None.
+    pub fn new(counters: QuotaCounters, store: Option<redis::Client>) -> Self {
+        QuotaMiddleware {
+            counters,
+            store,
+            flusher_started: false,
+        }
+    }
+
+    fn start_flusher(&mut self) {
+        let client = match &self.store {
+            Some(client) if !self.flusher_started => client.clone(),
+            _ => return,
+        };
+        self.flusher_started = true;
+        let counters = self.counters.clone();
+        tokio::spawn(async move {
+            let mut conn = None;
+            let mut ticker = tokio::time::interval(FLUSH_INTERVAL);
+            loop {
+                ticker.tick().await;
+                if conn.is_none() {
+                    match client.get_async_connection().await {
+                        Ok(c) => conn = Some(c),
+                        Err(e) => {
+                            event!(Level::WARN, "Fail to connect quota store: {}", e);
+                            continue;
+                        }
+                    }
+                }
+                if let Some(c) = conn.as_mut() {
+                    if let Err(e) = flush(&counters, c).await {
+                        event!(Level::WARN, "Fail to flush quota usage: {}", e);
+                        conn = None;
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl Middleware for QuotaMiddleware {
+    fn name() -> String {
+        "Quota".into()
+    }
+
+    fn post() -> bool {
+        false
+    }
+
+    fn request(&mut self, task: MwPreRequest) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let MwPreRequest {
+            context,
+            request,
+            service_filters,
+            client_filters,
+            result,
+        } = task;
+        let now = OffsetDateTime::now_utc();
+        let mut exceeded: Option<u64> = None;
+        {
+            let mut counters = self.counters.0.lock().unwrap();
+            let mut charged = Vec::new();
+            for filter in service_filters.iter().chain(client_filters.iter()) {
+                let quota = match filter {
+                    FilterSetting::Quota(quota) => quota,
+                    _ => continue,
+                };
+                let (period, left) = period_of(quota.period, now);
+                let key = (
+                    context.service_id.clone(),
+                    context.client_id.clone(),
+                    quota.period,
+                );
+                let counter = counters.entry(key.clone()).or_default();
+                if counter.period != period {
+                    *counter = Counter {
+                        period,
+                        ..Default::default()
+                    };
+                }
+                counter.limit = quota.limit;
+                if counter.used() >= quota.limit {
+                    exceeded = Some(exceeded.unwrap_or(0).max(left));
+                } else {
+                    charged.push(key);
+                }
+            }
+            // rejected requests don't use up other quotas
+            if exceeded.is_none() {
+                for key in charged {
+                    if let Some(counter) = counters.get_mut(&key) {
+                        counter.pending += 1;
+                    }
+                }
+            }
+        }
+        self.start_flusher();
+
+        let next = match exceeded {
+            Some(retry_after) => {
+                let err = GatewayError::QuotaExceeded(format!("Quota of {}", context.client_id));
+                let grpc = RequestHandler::is_grpc(&request);
+                let mut resp = err.response(grpc, context.verbose_errors);
+                resp.headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+                MwNextAction::Return(resp)
+            }
+            None => MwNextAction::Next(request),
+        };
+        let _ = result.send(Ok(MwPreResponse { context, next }));
+        Box::pin(async {})
+    }
+
+    fn response(&mut self, _task: MwPostRequest) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        panic!("never got here")
+    }
+
+    fn config_update(&mut self, update: ConfigUpdate) {
+        if let ConfigUpdate::ServiceRemove(sid) = update {
+            self.counters
+                .0
+                .lock()
+                .unwrap()
+                .retain(|(s, _, _), _| *s != sid);
+        }
+    }
+}
+
+/// Label of the period containing `now`, like `2021-06` or `2021-06-30`, and seconds until it ends
+pub fn period_of(period: QuotaPeriod, now: OffsetDateTime) -> (String, u64) {
+    let date = now.date();
+    let (label, end) = match period {
+        QuotaPeriod::Monthly => {
+            let (year, month) = match date.month() {
+                Month::December => (date.year() + 1, Month::January),
+                month => (date.year(), month.next()),
+            };
+            let label = format!("{:04}-{:02}", date.year(), date.month() as u8);
+            (label, Date::from_calendar_date(year, month, 1).ok())
+        }
+        QuotaPeriod::Daily => {
+            let label = format!(
+                "{:04}-{:02}-{:02}",
+                date.year(),
+                date.month() as u8,
+                date.day()
+            );
+            (label, date.next_day())
+        }
+    };
+    let left = end
+        .map(|end| (end.midnight().assume_utc() - now).whole_seconds().max(1) as u64)
+        .unwrap_or(1);
+    (label, left)
+}
+
+// add counts of this gateway to the store and read back totals of all replicas
+async fn flush(counters: &QuotaCounters, conn: &mut redis::aio::Connection) -> RedisResult<()> {
+    let batch: Vec<(CounterKey, String, u64)> = {
+        let mut counters = counters.0.lock().unwrap();
+        let now = OffsetDateTime::now_utc();
+        // counters of past periods are dropped once flushed
+        counters.retain(|(_, _, period), c| {
+            c.pending + c.flushing > 0 || period_of(*period, now).0 == c.period
+        });
+        counters
+            .iter_mut()
+            .map(|(key, c)| {
+                c.flushing += c.pending;
+                c.pending = 0;
+                (key.clone(), c.period.clone(), c.flushing)
+            })
+            .collect()
+    };
+    for (key, period, count) in batch {
+        let (service_id, client_id, kind) = &key;
+        let store_key = format!("{}:{}:{}:{}", KEY_PREFIX, service_id, client_id, period);
+        // on error counts are flushed again with the next batch
+        let total: u64 = conn.incr(&store_key, count).await?;
+        if count > 0 {
+            let ttl = period_of(*kind, OffsetDateTime::now_utc()).1 + KEY_GRACE;
+            let _: () = conn.expire(&store_key, ttl as usize).await?;
+        }
+        let mut counters = counters.0.lock().unwrap();
+        if let Some(c) = counters.get_mut(&key).filter(|c| c.period == period) {
+            c.stored = total;
+            c.flushing -= count.min(c.flushing);
+        }
+    }
+    Ok(())
+}
@@ -1,27 +1,41 @@
-use crate::config::{ConfigUpdate, FilterSetting, RateLimitSetting};
+use crate::config::{
+    ConfigUpdate, FilterSetting, IpRateLimitSetting, RateLimitSetting, TenantRateLimitSetting,
+};
+use crate::middleware::client_ip::{client_ip, ip_prefix, Cidr};
 use crate::middleware::{
     GatewayError, Middleware, MwNextAction, MwPostRequest, MwPreRequest, MwPreResponse,
+    RequestContext,
 };
+use crate::proxy::RequestHandler;
+use hyper::header::{HeaderValue, RETRY_AFTER};
+use hyper::{Body, Request, Response};
+use lru::LruCache;
 use std::collections::HashMap;
 use std::future::Future;
+use std::net::IpAddr;
 use std::pin::Pin;
 use std::time::{Duration, Instant};
 
-#[derive(Debug)]
+const DEFAULT_MAX_CLIENTS: usize = 100_000;
+
+#[derive(Debug, Default)]
 pub struct RateLimitMiddleware {
+    ip_limit: HashMap<String, Vec<IpLimiter>>, // ip_limit[service_id] = Vec<IpLimiter>
+    tenant_limit: HashMap<String, Vec<TenantLimiter>>, // tenant_limit[service_id] = Vec<TenantLimiter>
     service_limit: HashMap<String, Vec<TokenBucket>>, // service_limit[service_id] = Vec<TokenBucket>
     client_limit: HashMap<String, HashMap<String, Vec<TokenBucket>>>, // client_limit[service_id][client_id] = Vec<TokenBucket>
     sla: HashMap<String, HashMap<String, Vec<TokenBucket>>>, // sla[service_id][sla_id] = Vec<RateLimit>
     client_sla: HashMap<String, HashMap<String, String>>, // client_sla[client_id][service_id] = sla:String
+    trusted_proxies: Vec<Cidr>, // peers whose X-Forwarded-For names the client
 }
 
-impl Default for RateLimitMiddleware {
-    fn default() -> Self {
+impl RateLimitMiddleware {
+    /// Middleware taking the client address of IP rate limits from `X-Forwarded-For`
+    /// of `trusted_proxies`
+    pub fn new(trusted_proxies: Vec<Cidr>) -> Self {
         RateLimitMiddleware {
-            service_limit: HashMap::new(),
-            client_limit: HashMap::new(),
-            sla: HashMap::new(),
-            client_sla: HashMap::new(),
+            trusted_proxies,
+            ..Default::default()
         }
     }
 }
@@ -45,6 +59,39 @@ impl Middleware for RateLimitMiddleware {
             result,
         } = task;
         let mut pass = true;
+        let mut retry_after = Duration::ZERO;
+        // requests without client address pass
+        let ip = client_ip(&request, &self.trusted_proxies);
+        if let (Some(ip_limits), Some(ip)) = (self.ip_limit.get_mut(&context.service_id), ip) {
+            for limit in ip_limits {
+                if let Some(wait) = limit.check(ip, now) {
+                    retry_after = retry_after.max(wait);
+                }
+            }
+        }
+        if !retry_after.is_zero() {
+            let resp = limited(&request, &context, "IP Rate Limit", retry_after);
+            let _ = result.send(Ok(MwPreResponse {
+                context,
+                next: MwNextAction::Return(resp),
+            }));
+            return Box::pin(async {});
+        }
+        if let Some(tenant_limits) = self.tenant_limit.get_mut(&context.service_id) {
+            for limit in tenant_limits {
+                if let Some(wait) = limit.check(&context.tenant, now) {
+                    retry_after = retry_after.max(wait);
+                }
+            }
+        }
+        if !retry_after.is_zero() {
+            let resp = limited(&request, &context, "Tenant Rate Limit", retry_after);
+            let _ = result.send(Ok(MwPreResponse {
+                context,
+                next: MwNextAction::Return(resp),
+            }));
+            return Box::pin(async {});
+        }
         if let Some(service_limits) = self.service_limit.get_mut(&context.service_id) {
             for limit in service_limits {
                 if !limit.check(now) {
@@ -116,6 +163,25 @@ impl Middleware for RateLimitMiddleware {
                 }
                 self.service_limit
                     .insert(service.service_id.clone(), service_limits);
+                let ip_limits = service
+                    .filters
+                    .iter()
+                    .filter_map(|f| match f {
+                        FilterSetting::IpRateLimit(s) => Some(IpLimiter::new(s)),
+                        _ => None,
+                    })
+                    .collect();
+                self.ip_limit.insert(service.service_id.clone(), ip_limits);
+                let tenant_limits = service
+                    .filters
+                    .iter()
+                    .filter_map(|f| match f {
+                        FilterSetting::TenantRateLimit(s) => Some(TenantLimiter::new(s)),
+                        _ => None,
+                    })
+                    .collect();
+                self.tenant_limit
+                    .insert(service.service_id.clone(), tenant_limits);
 
                 // setup sla limit for client update lookup
                 let mut service_sla: HashMap<String, Vec<TokenBucket>> = HashMap::new();
@@ -148,6 +214,8 @@ impl Middleware for RateLimitMiddleware {
             }
             ConfigUpdate::ServiceRemove(service_id) => {
                 self.service_limit.remove(&service_id);
+                self.ip_limit.remove(&service_id);
+                self.tenant_limit.remove(&service_id);
                 self.client_limit.remove(&service_id);
             }
             _ => {}
@@ -189,4 +257,111 @@ impl TokenBucket {
             false
         }
     }
+
+    /// Time until the next refill
+    pub fn retry_after(&self, now: Instant) -> Duration {
+        self.interval
+            .saturating_sub(now.duration_since(self.refresh_at))
+            .max(Duration::from_secs(1))
+    }
+}
+
+// 429 with seconds to wait rounded up
+fn limited(
+    request: &Request<Body>,
+    context: &RequestContext,
+    reason: &str,
+    retry_after: Duration,
+) -> Response<Body> {
+    let err = GatewayError::RateLimited(reason.into());
+    let mut resp = err.response(RequestHandler::is_grpc(request), context.verbose_errors);
+    let secs = retry_after.as_secs() + (retry_after.subsec_nanos() > 0) as u64;
+    resp.headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(secs));
+    resp
+}
+
+// token buckets of client address prefixes, least recently seen evicted
+#[derive(Debug)]
+struct IpLimiter {
+    setting: RateLimitSetting,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    buckets: LruCache<IpAddr, TokenBucket>,
+}
+
+impl IpLimiter {
+    fn new(setting: &IpRateLimitSetting) -> Self {
+        IpLimiter {
+            setting: RateLimitSetting {
+                interval: setting.interval,
+                limit: setting.limit,
+                burst: setting.burst,
+            },
+            ipv4_prefix: match setting.ipv4_prefix {
+                0 => 32,
+                n => n,
+            },
+            ipv6_prefix: match setting.ipv6_prefix {
+                0 => 64,
+                n => n,
+            },
+            buckets: LruCache::new(match setting.max_clients {
+                0 => DEFAULT_MAX_CLIENTS,
+                n => n,
+            }),
+        }
+    }
+
+    // time to wait if limited
+    fn check(&mut self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let key = ip_prefix(ip, self.ipv4_prefix, self.ipv6_prefix);
+        if !self.buckets.contains(&key) {
+            self.buckets.put(key, TokenBucket::new(&self.setting));
+        }
+        let bucket = self.buckets.get_mut(&key)?;
+        match bucket.check(now) {
+            true => None,
+            false => Some(bucket.retry_after(now)),
+        }
+    }
+}
+
+// token buckets of tenants, least recently seen evicted
+#[derive(Debug)]
+struct TenantLimiter {
+    setting: RateLimitSetting,
+    buckets: LruCache<String, TokenBucket>,
+}
+
+impl TenantLimiter {
+    fn new(setting: &TenantRateLimitSetting) -> Self {
+        TenantLimiter {
+            setting: RateLimitSetting {
+                interval: setting.interval,
+                limit: setting.limit,
+                burst: setting.burst,
+            },
+            buckets: LruCache::new(match setting.max_tenants {
+                0 => DEFAULT_MAX_CLIENTS,
+                n => n,
+            }),
+        }
+    }
+
+    // time to wait if limited, requests without tenant pass
+    fn check(&mut self, tenant: &str, now: Instant) -> Option<Duration> {
+        if tenant.is_empty() {
+            return None;
+        }
+        if !self.buckets.contains(tenant) {
+            self.buckets
+                .put(tenant.to_string(), TokenBucket::new(&self.setting));
+        }
+        let bucket = self.buckets.get_mut(tenant)?;
+        match bucket.check(now) {
+            true => None,
+            false => Some(bucket.retry_after(now)),
+        }
+    }
 }
@@ -0,0 +1,125 @@
+use crate::config::RetrySetting;
+use crate::middleware::upgrade::is_upgrade;
+use crate::middleware::{Deadline, GatewayError};
+use hyper::body::HttpBody;
+use hyper::header::HeaderMap;
+use hyper::{Body, Method, Request, Response, Uri, Version};
+use std::time::Duration;
+use tower::retry::budget::Budget;
+
+const BUDGET_TTL: Duration = Duration::from_secs(10);
+const DEFAULT_BUDGET_PERCENT: u32 = 20;
+const DEFAULT_MIN_RETRIES: u32 = 10;
+
+lazy_static::lazy_static! {
+    static ref UPSTREAM_RETRIES: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_upstream_retries_total",
+        "Requests retried on another upstream pick",
+        &["service"]
+    ).unwrap();
+
+    static ref RETRY_BUDGET_EXHAUSTED: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_retry_budget_exhausted_total",
+        "Retries not made because the service retry budget ran out",
+        &["service"]
+    ).unwrap();
+}
+
+/// Retries of a service, capped by a budget shared by all its requests
+#[derive(Debug)]
+pub struct RetryPolicy {
+    service_id: String,
+    attempts: u32,
+    budget: Budget,
+}
+
+impl RetryPolicy {
+    pub fn new(service_id: &str, setting: &RetrySetting) -> Self {
+        let percent = setting.budget_percent.unwrap_or(DEFAULT_BUDGET_PERCENT);
+        let min_retries = setting.min_retries.unwrap_or(DEFAULT_MIN_RETRIES);
+        RetryPolicy {
+            service_id: service_id.into(),
+            attempts: setting.attempts,
+            budget: Budget::new(BUDGET_TTL, min_retries, percent as f32 / 100.0),
+        }
+    }
+
+    /// Count a new request, earning budget for retries
+    pub fn deposit(&self) {
+        self.budget.deposit();
+    }
+
+    /// Whether a failed attempt is retried, taking one retry from budget
+    pub fn allow(
+        &self,
+        attempt: u32,
+        result: &Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>>,
+    ) -> bool {
+        if attempt >= self.attempts || !retryable(result) {
+            return false;
+        }
+        if self.budget.withdraw().is_err() {
+            RETRY_BUDGET_EXHAUSTED
+                .with_label_values(&[&self.service_id])
+                .inc();
+            return false;
+        }
+        UPSTREAM_RETRIES
+            .with_label_values(&[&self.service_id])
+            .inc();
+        true
+    }
+}
+
+// connection errors and unavailable upstreams, timed out requests may have been processed
+fn retryable(result: &Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>>) -> bool {
+    match result {
+        Ok(resp) => matches!(resp.status().as_u16(), 502..=504),
+        Err(e) => !matches!(e.downcast_ref(), Some(GatewayError::TimeoutError)),
+    }
+}
+
+/// Head of a request that can be sent again, idempotent and without body
+#[derive(Debug)]
+pub struct ReplayRequest {
+    method: Method,
+    uri: Uri,
+    version: Version,
+    headers: HeaderMap,
+    deadline: Option<Deadline>,
+}
+
+impl ReplayRequest {
+    pub fn of(req: &Request<Body>) -> Option<Self> {
+        let idempotent = matches!(
+            *req.method(),
+            Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE
+        );
+        if !idempotent || req.body().size_hint().exact() != Some(0) {
+            return None;
+        }
+        // connection of a replayed handshake could not be switched
+        if is_upgrade(req) {
+            return None;
+        }
+        Some(ReplayRequest {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            version: req.version(),
+            headers: req.headers().clone(),
+            deadline: req.extensions().get::<Deadline>().copied(),
+        })
+    }
+
+    pub fn build(&self) -> Request<Body> {
+        let mut req = Request::new(Body::empty());
+        *req.method_mut() = self.method.clone();
+        *req.uri_mut() = self.uri.clone();
+        *req.version_mut() = self.version;
+        *req.headers_mut() = self.headers.clone();
+        if let Some(deadline) = self.deadline {
+            req.extensions_mut().insert(deadline);
+        }
+        req
+    }
+}
@@ -0,0 +1,207 @@
+use tower::discover::{Change, Discover};
+use tower::load::Load;
+use tower::ready_cache::{error::Failed, ReadyCache};
+use futures_util::ready;
+use futures_util::future::{self, TryFutureExt};
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::{pin::Pin, task::{Context, Poll}};
+use tower::Service;
+use tracing::{debug, trace};
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Round robin load balance, services are picked in discover order.
+/// In least load mode, only ready services with the lowest load are candidates,
+/// ties are broken in the same round robin order.
+pub struct RoundRobinBalance<D, Req>
+where
+    D: Discover,
+    D::Key: Hash,
+{
+    discover: D,
+    services: ReadyCache<D::Key, D::Service, Req>,
+    keys: Vec<D::Key>,
+    cursor: usize,
+    least_load: bool,
+    ready_index: Option<usize>,
+    _req: PhantomData<Req>,
+}
+
+
+impl<D, Req> RoundRobinBalance<D, Req>
+where
+    D: Discover,
+    D::Key: Hash,
+    D::Service: Service<Req>,
+    <D::Service as Service<Req>>::Error: Into<BoxError>,
+{
+    /// Constructs a plain round robin load balancer.
+    pub fn new(discover: D) -> Self {
+        Self::with_mode(discover, false)
+    }
+
+    /// Constructs a load balancer picking the least loaded service, e.g. least connections.
+    pub fn least_load(discover: D) -> Self {
+        Self::with_mode(discover, true)
+    }
+
+    fn with_mode(discover: D, least_load: bool) -> Self {
+        Self {
+            discover,
+            services: ReadyCache::default(),
+            keys: Vec::new(),
+            cursor: 0,
+            least_load,
+            ready_index: None,
+            _req: PhantomData,
+        }
+    }
+}
+
+impl<D, Req> RoundRobinBalance<D, Req>
+where
+    D: Discover + Unpin,
+    D::Key: Hash + Clone,
+    D::Error: Into<BoxError>,
+    D::Service: Service<Req> + Load,
+    <D::Service as Load>::Metric: std::fmt::Debug + PartialOrd,
+    <D::Service as Service<Req>>::Error: Into<BoxError>,
+{
+    fn update_pending_from_discover(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<(), BoxError>>> {
+        debug!("updating from discover");
+        loop {
+            match ready!(Pin::new(&mut self.discover).poll_discover(cx))
+                .transpose()
+                .map_err(|e| e.into())?
+            {
+                None => return Poll::Ready(None),
+                Some(Change::Remove(key)) => {
+                    trace!("remove");
+                    self.services.evict(&key);
+                    self.keys.retain(|k| k != &key);
+                },
+                Some(Change::Insert(key, svc)) => {
+                    trace!("insert");
+                    self.keys.retain(|k| k != &key);
+                    self.keys.push(key.clone());
+                    self.services.push(key, svc);
+                },
+            }
+        }
+    }
+
+    fn promote_pending_to_ready(&mut self, cx: &mut Context<'_>) {
+        loop {
+            match self.services.poll_pending(cx) {
+                Poll::Ready(Ok(())) => {
+                    // There are no remaining pending services.
+                    debug_assert_eq!(self.services.pending_len(), 0);
+                    break;
+                }
+                Poll::Pending => {
+                    // None of the pending services are ready.
+                    debug_assert!(self.services.pending_len() > 0);
+                    break;
+                }
+                Poll::Ready(Err(error)) => {
+                    // An individual service was lost; continue processing
+                    // pending services.
+                    debug!(%error, "dropping failed endpoint");
+                }
+            }
+        }
+        trace!(
+            ready = %self.services.ready_len(),
+            pending = %self.services.pending_len(),
+            "poll_unready"
+        );
+    }
+
+    fn next_ready_index(&mut self) -> Option<usize> {
+        let total = self.keys.len();
+        // (position in keys, index in ready cache, load)
+        let mut chosen: Option<(usize, usize, <D::Service as Load>::Metric)> = None;
+        for offset in 0..total {
+            let pos = (self.cursor + offset) % total;
+            if let Some((index, _, svc)) = self.services.get_ready(&self.keys[pos]) {
+                if !self.least_load {
+                    chosen = Some((pos, index, svc.load()));
+                    break;
+                }
+                let load = svc.load();
+                let better = match &chosen {
+                    Some((_, _, best)) => load < *best,
+                    None => true,
+                };
+                if better {
+                    chosen = Some((pos, index, load));
+                }
+            }
+        }
+        chosen.map(|(pos, index, _)| {
+            self.cursor = (pos + 1) % total;
+            index
+        })
+    }
+
+}
+
+impl<D, Req> Service<Req> for RoundRobinBalance<D, Req>
+where
+    D: Discover + Unpin,
+    D::Key: Hash + Clone,
+    D::Error: Into<BoxError>,
+    D::Service: Service<Req> + Load,
+    <D::Service as Load>::Metric: std::fmt::Debug + PartialOrd,
+    <D::Service as Service<Req>>::Error: Into<BoxError>,
+{
+    type Response = <D::Service as Service<Req>>::Response;
+    type Error = BoxError;
+    type Future = future::MapErr<
+        <D::Service as Service<Req>>::Future,
+        fn(<D::Service as Service<Req>>::Error) -> BoxError,
+    >;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let _ = self.update_pending_from_discover(cx)?;
+        self.promote_pending_to_ready(cx);
+
+        loop {
+            if let Some(index) = self.ready_index.take() {
+                match self.services.check_ready_index(cx, index) {
+                    Ok(true) => {
+                        // The service remains ready.
+                        self.ready_index = Some(index);
+                        return Poll::Ready(Ok(()));
+                    }
+                    Ok(false) => {
+                        // The service is no longer ready. Try to find a new one.
+                        trace!("ready service became unavailable");
+                    }
+                    Err(Failed(_, error)) => {
+                        // The ready endpoint failed, so log the error and try
+                        // to find a new one.
+                        debug!(%error, "endpoint failed");
+                    }
+                }
+            }
+
+            self.ready_index = self.next_ready_index();
+            if self.ready_index.is_none() {
+                debug_assert_eq!(self.services.ready_len(), 0);
+                return Poll::Pending;
+            }
+        }
+    }
+
+    fn call(&mut self, request: Req) -> Self::Future {
+        let index = self.ready_index.take().expect("called before ready");
+        self.services
+            .call_ready_index(index, request)
+            .map_err(Into::into)
+    }
+}
@@ -0,0 +1,48 @@
+use crate::config::RouteRule;
+use glob::Pattern;
+use hyper::{Body, Request};
+use std::collections::HashSet;
+use std::time::Duration;
+
+/// Method and path of a route rule, path is matched after the service path like ACL
+#[derive(Debug)]
+pub struct RouteMatcher {
+    methods: Option<HashSet<String>>, // any method if None
+    pattern: Option<Pattern>,         // never matches if invalid
+    pub upstreams: HashSet<String>,
+    pub timeout: Option<Duration>, // of upstream requests of the route
+}
+
+impl RouteMatcher {
+    pub fn new(rule: &RouteRule) -> Self {
+        let methods = match rule.methods.trim() {
+            "" | "*" => None,
+            methods => Some(
+                methods
+                    .split(',')
+                    .map(|m| m.trim().to_ascii_uppercase())
+                    .collect(),
+            ),
+        };
+        RouteMatcher {
+            methods,
+            pattern: Pattern::new(&rule.path_pattern).ok(),
+            upstreams: rule.upstreams.iter().cloned().collect(),
+            timeout: rule.timeout.map(Duration::from_secs),
+        }
+    }
+
+    pub fn matches(&self, req: &Request<Body>) -> bool {
+        if let Some(methods) = &self.methods {
+            if !methods.contains(req.method().as_str()) {
+                return false;
+            }
+        }
+        let path = req.uri().path();
+        let path = path.strip_prefix('/').unwrap_or(path);
+        let path_left = path.find('/').map(|offset| &path[offset..]).unwrap_or("/");
+        self.pattern
+            .as_ref()
+            .is_some_and(|pattern| pattern.matches(path_left))
+    }
+}
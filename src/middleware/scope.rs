@@ -0,0 +1,156 @@
+use crate::auth::ClientScopes;
+use crate::config::{ConfigUpdate, FilterSetting, RequireScopesSetting};
+use crate::middleware::{
+    GatewayError, Middleware, MwNextAction, MwPostRequest, MwPreRequest, MwPreResponse,
+};
+use glob::Pattern;
+use hyper::{Body, Request};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tracing::{event, Level};
+
+/// Check the scopes granted by the client's token against the RequireScopes filters of
+/// the route, requests missing a scope are answered with 403
+#[derive(Default)]
+pub struct ScopeMiddleware {
+    scopes: HashMap<String, HashMap<RequireScopesSetting, Arc<ScopeRule>>>, // scopes[service_id][setting], compiled at config update
+}
+
+impl Middleware for ScopeMiddleware {
+    fn name() -> String {
+        "Scope".into()
+    }
+
+    fn post() -> bool {
+        false
+    }
+
+    fn request(&mut self, task: MwPreRequest) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let MwPreRequest {
+            context,
+            request,
+            service_filters,
+            client_filters,
+            result,
+        } = task;
+        let compiled = self.scopes.get(&context.service_id);
+        let granted = request
+            .extensions()
+            .get::<ClientScopes>()
+            .map(|s| s.0.as_slice())
+            .unwrap_or_default();
+        let missing = service_filters
+            .iter()
+            .chain(client_filters.iter())
+            .filter_map(|f| match f {
+                FilterSetting::RequireScopes(s) => compiled.and_then(|c| c.get(s)),
+                _ => None,
+            })
+            .filter(|rule| rule.matches(&request))
+            .find_map(|rule| rule.missing(granted));
+        if let Some(scope) = missing {
+            event!(
+                Level::INFO,
+                "{} {} of client {:?} denied, missing scope {}",
+                request.method(),
+                request.uri().path(),
+                context.client_id,
+                scope
+            );
+            let detail = format!("missing scope {}", scope);
+            let _ = result.send(Err(GatewayError::Forbidden(detail)));
+        } else {
+            let _ = result.send(Ok(MwPreResponse {
+                context,
+                next: MwNextAction::Next(request),
+            }));
+        }
+        Box::pin(async {})
+    }
+
+    fn response(&mut self, _task: MwPostRequest) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        panic!("never got here")
+    }
+
+    fn config_update(&mut self, update: ConfigUpdate) {
+        match update {
+            ConfigUpdate::ServiceUpdate(service) => {
+                let mut rules = HashMap::new();
+                let filters = service
+                    .filters
+                    .iter()
+                    .chain(service.sla.iter().flat_map(|sla| sla.filters.iter()));
+                for filter in filters {
+                    if let FilterSetting::RequireScopes(setting) = filter {
+                        match ScopeRule::new(setting) {
+                            Ok(rule) => {
+                                rules.insert(setting.clone(), Arc::new(rule));
+                            }
+                            Err(e) => {
+                                event!(
+                                    Level::ERROR,
+                                    "bad scope rule of {}: {}",
+                                    service.service_id,
+                                    e
+                                );
+                            }
+                        }
+                    }
+                }
+                self.scopes.insert(service.service_id.clone(), rules);
+            }
+            ConfigUpdate::ServiceRemove(service_id) => {
+                self.scopes.remove(&service_id);
+            }
+            _ => {}
+        }
+    }
+}
+
+// compiled RequireScopesSetting
+struct ScopeRule {
+    methods: HashSet<String>, // empty for all
+    pattern: Option<Pattern>,
+    scopes: Vec<String>,
+}
+
+impl ScopeRule {
+    fn new(setting: &RequireScopesSetting) -> Result<Self, String> {
+        let methods = setting
+            .methods
+            .split(',')
+            .map(|m| m.trim().to_ascii_uppercase())
+            .filter(|m| !m.is_empty() && m != "*")
+            .collect();
+        let pattern = match setting.path_pattern.as_str() {
+            "" => None,
+            p => Some(Pattern::new(p).map_err(|e| e.to_string())?),
+        };
+        Ok(ScopeRule {
+            methods,
+            pattern,
+            scopes: setting.scopes.clone(),
+        })
+    }
+
+    fn matches(&self, request: &Request<Body>) -> bool {
+        if !self.methods.is_empty() && !self.methods.contains(request.method().as_str()) {
+            return false;
+        }
+        // path after the service path, `/svc/users/1` is matched as `/users/1`
+        let path = request.uri().path();
+        let path = path.strip_prefix('/').unwrap_or(path);
+        let left = path.find('/').map(|offset| &path[offset..]).unwrap_or("/");
+        self.pattern.as_ref().is_none_or(|p| p.matches(left))
+    }
+
+    // first required scope not granted, all of them are required
+    fn missing(&self, granted: &[String]) -> Option<&str> {
+        self.scopes
+            .iter()
+            .find(|s| !granted.contains(s))
+            .map(String::as_str)
+    }
+}
@@ -0,0 +1,246 @@
+use crate::config::CoalesceSetting;
+use crate::middleware::json_transform::read_capped;
+use crate::middleware::upgrade::is_upgrade;
+use crate::middleware::{GatewayError, MwNextAction, MwPreRequest, MwPreResponse, RequestContext};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderName, HeaderValue, SET_COOKIE, VARY};
+use hyper::http::{StatusCode, Version};
+use hyper::{Body, HeaderMap, Method, Request, Response};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+
+const DEFAULT_MAX_BODY: usize = 1024 * 1024;
+
+lazy_static::lazy_static! {
+    static ref COALESCED_REQUESTS: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_coalesced_requests_total",
+        "Requests answered with the response of an identical request in flight",
+        &["service"]
+    ).unwrap();
+}
+
+// buffered leader response, with the leader request values of headers in its Vary
+#[derive(Debug, Clone)]
+struct SharedResponse {
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+    body: Bytes,
+    varied: Vec<(HeaderName, Option<HeaderValue>)>,
+}
+
+impl SharedResponse {
+    // None if the response differs by a header the waiter has another value of
+    fn response_for(&self, req: &Request<Body>) -> Option<Response<Body>> {
+        let same = self
+            .varied
+            .iter()
+            .all(|(name, value)| req.headers().get(name) == value.as_ref());
+        if !same {
+            return None;
+        }
+        let mut resp = Response::new(Body::from(self.body.clone()));
+        *resp.status_mut() = self.status;
+        *resp.version_mut() = self.version;
+        *resp.headers_mut() = self.headers.clone();
+        Some(resp)
+    }
+}
+
+type Waiter = oneshot::Sender<Option<SharedResponse>>;
+
+/// Identical requests in flight wait for the first one and share its response
+#[derive(Debug)]
+pub struct SingleFlight {
+    service_id: String,
+    vary: Vec<HeaderName>,
+    max_body: usize,
+    flights: Mutex<HashMap<String, Vec<Waiter>>>, // flights[key] = waiters of the leader
+}
+
+impl SingleFlight {
+    pub fn new(service_id: &str, setting: &CoalesceSetting) -> Self {
+        SingleFlight {
+            service_id: service_id.into(),
+            vary: setting
+                .vary
+                .iter()
+                .filter_map(|h| HeaderName::from_bytes(h.as_bytes()).ok())
+                .collect(),
+            max_body: match setting.max_body {
+                0 => DEFAULT_MAX_BODY,
+                n => n,
+            },
+            flights: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Key of a GET or HEAD request without body, other requests and upgrades are not coalesced
+    pub fn key(&self, context: &RequestContext, req: &Request<Body>) -> Option<String> {
+        if !matches!(*req.method(), Method::GET | Method::HEAD) || is_upgrade(req) {
+            return None;
+        }
+        if req.body().size_hint().exact() != Some(0) {
+            return None;
+        }
+        // responses may differ by client, they are never shared across
+        let mut key = format!("{} {} {}", req.method(), req.uri(), context.client_id);
+        for name in self.vary.iter() {
+            for value in req.headers().get_all(name) {
+                key.push('\n');
+                key.push_str(name.as_str());
+                key.push(':');
+                key.push_str(&String::from_utf8_lossy(value.as_bytes()));
+            }
+        }
+        Some(key)
+    }
+
+    /// Send `task` through `dispatch` if no identical request is in flight, otherwise wait for it.
+    /// Waiters of a failed or unshareable response are dispatched on their own.
+    pub async fn run<F, Fut>(&self, key: String, task: MwPreRequest, dispatch: F)
+    where
+        F: Fn(MwPreRequest) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let waiting = {
+            let mut flights = self.flights.lock().unwrap();
+            match flights.get_mut(&key) {
+                Some(waiters) => {
+                    let (tx, rx) = oneshot::channel();
+                    waiters.push(tx);
+                    Some(rx)
+                }
+                None => {
+                    flights.insert(key.clone(), Vec::new());
+                    None
+                }
+            }
+        };
+        let rx = match waiting {
+            Some(rx) => rx,
+            None => return self.lead(key, task, dispatch).await,
+        };
+        let shared = rx.await.ok().flatten();
+        match shared.and_then(|s| s.response_for(&task.request)) {
+            Some(resp) => {
+                COALESCED_REQUESTS
+                    .with_label_values(&[&self.service_id])
+                    .inc();
+                let _ = task.result.send(Ok(MwPreResponse {
+                    context: task.context,
+                    next: MwNextAction::Return(resp),
+                }));
+            }
+            None => dispatch(task).await,
+        }
+    }
+
+    async fn lead<F, Fut>(&self, key: String, task: MwPreRequest, dispatch: F)
+    where
+        F: Fn(MwPreRequest) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let MwPreRequest {
+            context,
+            request,
+            service_filters,
+            client_filters,
+            result,
+        } = task;
+        // waiters are released even if the leader is dropped midway
+        let mut flight = Flight {
+            owner: self,
+            key,
+            shared: None,
+        };
+        let leader_headers = request.headers().clone();
+        let (tx, rx) = oneshot::channel();
+        dispatch(MwPreRequest {
+            context,
+            request,
+            service_filters,
+            client_filters,
+            result: tx,
+        })
+        .await;
+        let outcome = rx
+            .await
+            .unwrap_or_else(|e| Err(GatewayError::ChannelRecvError(e.to_string())));
+
+        let outcome = match outcome {
+            Ok(MwPreResponse {
+                context,
+                next: MwNextAction::Return(resp),
+            }) if shareable(&resp) => {
+                let (parts, body) = resp.into_parts();
+                match read_capped(body, self.max_body).await {
+                    Ok(body) => {
+                        let varied = varied_headers(&parts.headers)
+                            .map(|name| {
+                                let value = leader_headers.get(&name).cloned();
+                                (name, value)
+                            })
+                            .collect();
+                        let shared = SharedResponse {
+                            status: parts.status,
+                            version: parts.version,
+                            headers: parts.headers.clone(),
+                            body: body.clone(),
+                            varied,
+                        };
+                        flight.shared = Some(shared);
+                        let resp = Response::from_parts(parts, Body::from(body));
+                        Ok(MwPreResponse {
+                            context,
+                            next: MwNextAction::Return(resp),
+                        })
+                    }
+                    Err(body) => Ok(MwPreResponse {
+                        context,
+                        next: MwNextAction::Return(Response::from_parts(parts, body)),
+                    }),
+                }
+            }
+            other => other,
+        };
+        drop(flight);
+        let _ = result.send(outcome);
+    }
+}
+
+// in flight request of a leader, its waiters get the shared response or None once dropped
+struct Flight<'a> {
+    owner: &'a SingleFlight,
+    key: String,
+    shared: Option<SharedResponse>,
+}
+
+impl Drop for Flight<'_> {
+    fn drop(&mut self) {
+        let waiters = self.owner.flights.lock().unwrap().remove(&self.key);
+        for waiter in waiters.unwrap_or_default() {
+            let _ = waiter.send(self.shared.clone());
+        }
+    }
+}
+
+// errors, Set-Cookie and `Vary: *` responses are for the leader only
+fn shareable(resp: &Response<Body>) -> bool {
+    let any_vary = resp.headers().get_all(VARY).iter().any(|v| {
+        v.as_bytes()
+            .split(|b| *b == b',')
+            .any(|h| h.trim_ascii() == b"*")
+    });
+    !resp.status().is_server_error() && !resp.headers().contains_key(SET_COOKIE) && !any_vary
+}
+
+fn varied_headers(headers: &HeaderMap) -> impl Iterator<Item = HeaderName> + '_ {
+    headers
+        .get_all(VARY)
+        .iter()
+        .flat_map(|v| v.as_bytes().split(|b| *b == b','))
+        .filter_map(|h| HeaderName::from_bytes(h.trim_ascii()).ok())
+}
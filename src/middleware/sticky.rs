@@ -0,0 +1,98 @@
+use crate::config::StickySetting;
+use base64::alphabet::URL_SAFE;
+use base64::engine::fast_portable::{FastPortable, NO_PAD};
+use hyper::header::{HeaderValue, COOKIE, SET_COOKIE};
+use hyper::{Body, Request, Response};
+use ring::hmac;
+
+const COOKIE_ENGINE: FastPortable = FastPortable::from(&URL_SAFE, NO_PAD);
+
+lazy_static::lazy_static! {
+    // signing key for services without a secret, cookies expire with the process
+    static ref PROCESS_KEY: [u8; 32] = rand::random();
+}
+
+/// Pin a client to the upstream named in a signed cookie
+#[derive(Debug)]
+pub struct StickySession {
+    service_id: String,
+    cookie: String,
+    path: String,
+    max_age: u64,
+    key: hmac::Key,
+}
+
+impl StickySession {
+    pub fn new(service_id: &str, path: &str, setting: &StickySetting) -> Self {
+        let key = if setting.secret.is_empty() {
+            hmac::Key::new(hmac::HMAC_SHA256, &PROCESS_KEY[..])
+        } else {
+            hmac::Key::new(hmac::HMAC_SHA256, setting.secret.as_bytes())
+        };
+        StickySession {
+            service_id: service_id.into(),
+            cookie: setting.cookie.clone(),
+            path: path.into(),
+            max_age: setting.max_age,
+            key,
+        }
+    }
+
+    /// Upstream id from a cookie with valid signature
+    pub fn pinned_upstream(&self, req: &Request<Body>) -> Option<String> {
+        req.headers()
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(';'))
+            .filter_map(|c| c.trim().split_once('='))
+            .find(|(name, _)| name.eq(&self.cookie))
+            .and_then(|(_, value)| self.verify(value))
+    }
+
+    /// Set cookie for the upstream that served the response, unless already pinned to it
+    pub fn set_cookie(&self, resp: &mut Response<Body>, pinned: Option<&str>) {
+        let upstream_id = match resp
+            .headers()
+            .get("X-UPSTREAM-ID")
+            .and_then(|h| h.to_str().ok())
+        {
+            Some(id) if pinned != Some(id) => id.to_string(),
+            _ => return,
+        };
+        let mut cookie = format!(
+            "{}={}; Path={}; HttpOnly",
+            self.cookie,
+            self.sign(&upstream_id),
+            self.path
+        );
+        if self.max_age > 0 {
+            cookie.push_str(&format!("; Max-Age={}", self.max_age));
+        }
+        if let Ok(value) = HeaderValue::from_str(&cookie) {
+            resp.headers_mut().append(SET_COOKIE, value);
+        }
+    }
+
+    fn message(&self, upstream_id: &str) -> String {
+        format!("{}/{}", self.service_id, upstream_id)
+    }
+
+    fn sign(&self, upstream_id: &str) -> String {
+        let tag = hmac::sign(&self.key, self.message(upstream_id).as_bytes());
+        format!(
+            "{}.{}",
+            base64::encode_engine(upstream_id, &COOKIE_ENGINE),
+            base64::encode_engine(tag.as_ref(), &COOKIE_ENGINE)
+        )
+    }
+
+    fn verify(&self, value: &str) -> Option<String> {
+        let (id, tag) = value.split_once('.')?;
+        let id = base64::decode_engine(id, &COOKIE_ENGINE).ok()?;
+        let id = String::from_utf8(id).ok()?;
+        let tag = base64::decode_engine(tag, &COOKIE_ENGINE).ok()?;
+        hmac::verify(&self.key, self.message(&id).as_bytes(), &tag).ok()?;
+        Some(id)
+    }
+}
@@ -0,0 +1,109 @@
+use crate::config::{TrafficSplit, VersionOverride};
+use hyper::header::{HeaderName, COOKIE};
+use hyper::{Body, Request};
+use rand::Rng;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use tracing::{event, Level};
+
+/// Pick an upstream version by percent of traffic, like 90% stable and 10% canary
+#[derive(Debug)]
+pub struct TrafficSplitter {
+    versions: Vec<(String, u32)>, // version and cumulative percent
+    sticky_client: bool,
+}
+
+impl TrafficSplitter {
+    pub fn new(split: &TrafficSplit) -> Self {
+        let mut total = 0;
+        let versions = split
+            .versions
+            .iter()
+            .map(|v| {
+                total += v.percent;
+                (v.version.clone(), total)
+            })
+            .collect();
+        TrafficSplitter {
+            versions,
+            sticky_client: split.sticky_client,
+        }
+    }
+
+    pub fn versions(&self) -> impl Iterator<Item = String> + '_ {
+        self.versions.iter().map(|(v, _)| v.clone())
+    }
+
+    /// Version for a request of client, hashed by client id if sticky
+    pub fn pick(&self, client_id: &str) -> &str {
+        let bucket = if self.sticky_client && !client_id.is_empty() {
+            let mut hasher = DefaultHasher::new();
+            client_id.hash(&mut hasher);
+            (hasher.finish() % 100) as u32
+        } else {
+            rand::thread_rng().gen_range(0..100)
+        };
+        self.versions
+            .iter()
+            .find(|(_, upto)| bucket < *upto)
+            .or_else(|| self.versions.last())
+            .map(|(v, _)| v.as_str())
+            .unwrap_or("")
+    }
+}
+
+/// Upstream version requested by header or cookie, for testing a version on purpose
+#[derive(Debug)]
+pub struct VersionOverrider {
+    header: Option<HeaderName>,
+    cookie: String,
+    clients: HashSet<String>, // "*" for any client
+}
+
+impl VersionOverrider {
+    pub fn new(setting: &VersionOverride) -> Self {
+        VersionOverrider {
+            header: HeaderName::from_bytes(setting.header.as_bytes()).ok(),
+            cookie: setting.cookie.clone(),
+            clients: setting.clients.iter().cloned().collect(),
+        }
+    }
+
+    /// Requested version, None if not requested or the client is not allowed to
+    pub fn requested(&self, req: &Request<Body>, client_id: &str) -> Option<String> {
+        let from_header = self
+            .header
+            .as_ref()
+            .and_then(|h| req.headers().get(h))
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim().to_string());
+        let version = from_header
+            .or_else(|| self.cookie_version(req))
+            .filter(|v| !v.is_empty())?;
+        if self.clients.contains("*") || self.clients.contains(client_id) {
+            Some(version)
+        } else {
+            event!(
+                Level::DEBUG,
+                "client {:?} not allowed to override version",
+                client_id
+            );
+            None
+        }
+    }
+
+    fn cookie_version(&self, req: &Request<Body>) -> Option<String> {
+        if self.cookie.is_empty() {
+            return None;
+        }
+        req.headers()
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(';'))
+            .filter_map(|c| c.trim().split_once('='))
+            .find(|(name, _)| name.eq(&self.cookie))
+            .map(|(_, value)| value.to_string())
+    }
+}
@@ -0,0 +1,117 @@
+use hyper::header::{CONNECTION, UPGRADE};
+use hyper::upgrade::OnUpgrade;
+use hyper::{Body, Request};
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+use tracing::{event, Level};
+
+pub const DEFAULT_IDLE_TIMEOUT: u64 = 300;
+
+const BUF_SIZE: usize = 16 * 1024;
+
+lazy_static::lazy_static! {
+    static ref UPGRADED_CONNECTIONS: prometheus::IntGaugeVec = prometheus::register_int_gauge_vec!(
+        "gateway_upgraded_connections",
+        "Open upgraded connections, like WebSocket tunnels",
+        &["service"]
+    ).unwrap();
+}
+
+/// Request switching protocols, like a WebSocket handshake
+pub fn is_upgrade<B>(req: &Request<B>) -> bool {
+    let connection_upgrade = req
+        .headers()
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case("upgrade"));
+    connection_upgrade && req.headers().contains_key(UPGRADE)
+}
+
+/// Upgrade of the client connection, taken before the request is proxied
+pub fn take_upgrade(req: &mut Request<Body>) -> Option<OnUpgrade> {
+    if is_upgrade(req) {
+        Some(hyper::upgrade::on(req))
+    } else {
+        None
+    }
+}
+
+/// Relay upgraded client and upstream connections once both are switched,
+/// both are closed after `idle` without data in either direction
+pub async fn tunnel(client: OnUpgrade, upstream: OnUpgrade, idle: Duration, service_id: String) {
+    let (client, upstream) = match tokio::try_join!(client, upstream) {
+        Ok(upgraded) => upgraded,
+        Err(e) => {
+            event!(
+                Level::WARN,
+                "upgrade of service {} failed: {}",
+                service_id,
+                e
+            );
+            return;
+        }
+    };
+    let open = UPGRADED_CONNECTIONS.with_label_values(&[&service_id]);
+    open.inc();
+    if let Err(e) = relay(client, upstream, idle).await {
+        event!(
+            Level::DEBUG,
+            "upgraded connection of {} closed: {}",
+            service_id,
+            e
+        );
+    }
+    open.dec();
+}
+
+/// Copy bytes both ways until both sides are closed, a write end is shut down once
+/// its peer read end is. Fails with `TimedOut` after `idle` without data.
+pub async fn relay<A, B>(mut a: A, mut b: B, idle: Duration) -> io::Result<()>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut a_buf = vec![0u8; BUF_SIZE];
+    let mut b_buf = vec![0u8; BUF_SIZE];
+    let (mut a_open, mut b_open) = (true, true);
+    let timer = tokio::time::sleep(idle);
+    tokio::pin!(timer);
+    while a_open || b_open {
+        tokio::select! {
+            n = a.read(&mut a_buf), if a_open => {
+                let n = n?;
+                a_open = n > 0;
+                forward(&mut b, &a_buf[..n], idle).await?;
+            }
+            n = b.read(&mut b_buf), if b_open => {
+                let n = n?;
+                b_open = n > 0;
+                forward(&mut a, &b_buf[..n], idle).await?;
+            }
+            _ = &mut timer => {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+        }
+        timer.as_mut().reset(Instant::now() + idle);
+    }
+    Ok(())
+}
+
+// write a chunk, or shut down on end of stream; a peer not reading for `idle` is idle too
+async fn forward<W: AsyncWrite + Unpin>(w: &mut W, data: &[u8], idle: Duration) -> io::Result<()> {
+    let write = async {
+        if data.is_empty() {
+            w.shutdown().await
+        } else {
+            w.write_all(data).await?;
+            w.flush().await
+        }
+    };
+    tokio::time::timeout(idle, write)
+        .await
+        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+}
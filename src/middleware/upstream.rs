@@ -1,136 +1,839 @@
-use crate::config::{ConfigUpdate, ServiceInfo};
-use crate::middleware::proxy::ProxyHandler;
-use crate::middleware::weighted::WeightedBalance;
+use crate::config::{ConfigUpdate, MaintenancePage, ServiceInfo, Upstream};
+use crate::middleware::body_limit::limit_body;
+use crate::middleware::consistent_hash::{HashRing, InFlight};
+use crate::middleware::idempotency::{Idempotency, IdempotencyStore};
+use crate::middleware::mirror::Mirror;
+use crate::middleware::outlier::{EjectionGroup, OutlierDetection};
+use crate::middleware::proxy::{ProxyHandler, RouteTimeout};
+use crate::middleware::retry::{ReplayRequest, RetryPolicy};
+use crate::middleware::round_robin::RoundRobinBalance;
+use crate::middleware::route::RouteMatcher;
+use crate::middleware::single_flight::SingleFlight;
+use crate::middleware::sticky::StickySession;
+use crate::middleware::traffic_split::{TrafficSplitter, VersionOverrider};
+use crate::middleware::upgrade::is_upgrade;
+use crate::middleware::weighted::{RuntimeWeight, SlowStart, WeightedBalance};
 use crate::middleware::{CircuitBreakerConfig, CircuitBreakerService};
 use crate::middleware::{
     GatewayError, Middleware, MwNextAction, MwPostRequest, MwPreRequest, MwPreResponse,
 };
-use hyper::header::HeaderValue;
+use futures::FutureExt;
+use hyper::header::{HeaderValue, CONTENT_TYPE, RETRY_AFTER};
 use hyper::{Body, Request, Response};
+use rustls::RootCertStore;
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::future::Future;
 use std::hash::{Hash, Hasher};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
-use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
 use tower::balance::p2c::Balance;
 use tower::discover::ServiceList;
 use tower::limit::concurrency::ConcurrencyLimit;
-use tower::load::{CompleteOnResponse, Constant, PeakEwmaDiscover, PendingRequestsDiscover};
+use tower::load::{CompleteOnResponse, PeakEwmaDiscover, PendingRequestsDiscover};
+use tower::load_shed::error::Overloaded;
 use tower::load_shed::LoadShed;
 use tower::steer::Steer;
 use tower::util::{BoxService, ServiceExt};
 use tower::Service;
 use tracing::{event, Level};
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct UpstreamMiddleware {
     pub worker_queues: HashMap<String, mpsc::Sender<MwPreRequest>>,
+    workers: HashMap<String, Worker>,
+    failover_queues: WorkerQueues, // copy of worker_queues read by workers failing over
+    idempotency_store: IdempotencyStore,
+    upstream_roots: Option<Arc<RootCertStore>>, // shared by service workers, native certs if None
 }
 
-impl Default for UpstreamMiddleware {
-    fn default() -> Self {
-        UpstreamMiddleware {
-            worker_queues: HashMap::new(),
+// config of a running worker, with upstream weights it reads on every pick
+#[derive(Debug)]
+struct Worker {
+    conf: ServiceInfo,
+    weights: Vec<Arc<AtomicU32>>,
+    slow_starts: Vec<Arc<SlowStart>>,
+    mirror: Option<Arc<Mirror>>,
+    coalesce: Option<Arc<SingleFlight>>,
+    idempotency: Option<Arc<Idempotency>>,
+    removed: oneshot::Sender<()>, // dropped without sending when worker is replaced
+}
+
+impl Worker {
+    // upstreams not in the previous config of the service, or drained in it, start slow
+    fn new(
+        conf: &ServiceInfo,
+        previous: Option<&ServiceInfo>,
+        removed: oneshot::Sender<()>,
+        idempotency_store: &IdempotencyStore,
+        roots: Option<&RootCertStore>,
+    ) -> Self {
+        let weights = conf
+            .upstreams
+            .iter()
+            .map(|u| Arc::new(AtomicU32::new(u.weight)))
+            .collect();
+        let window = Duration::from_secs(conf.slow_start);
+        let slow_starts = conf
+            .upstreams
+            .iter()
+            .map(|u| {
+                let cold = previous
+                    .is_some_and(|p| p.upstreams.iter().all(|pu| pu.id != u.id || pu.weight == 0));
+                Arc::new(SlowStart::new(window, cold))
+            })
+            .collect();
+        let mirror = conf
+            .mirror
+            .as_ref()
+            .and_then(|m| Mirror::new(conf, m, roots))
+            .map(Arc::new);
+        let coalesce = conf
+            .coalesce
+            .as_ref()
+            .map(|c| Arc::new(SingleFlight::new(&conf.service_id, c)));
+        let idempotency = conf.idempotency.as_ref().map(|i| {
+            let store = idempotency_store.clone();
+            Arc::new(Idempotency::new(&conf.service_id, i, conf.timeout, store))
+        });
+        Worker {
+            conf: conf.clone(),
+            weights,
+            slow_starts,
+            mirror,
+            coalesce,
+            idempotency,
+            removed,
+        }
+    }
+
+    // apply update in place if nothing but upstream weights or maintenance changed,
+    // draining an upstream or taking it back rebuilds balancers without or with it
+    fn update(&mut self, conf: &ServiceInfo) -> bool {
+        let mut same_weights = conf.clone();
+        if same_weights.upstreams.len() != self.conf.upstreams.len() {
+            return false;
+        }
+        for (u, current) in same_weights
+            .upstreams
+            .iter_mut()
+            .zip(self.conf.upstreams.iter())
+        {
+            if (u.weight == 0) != (current.weight == 0) {
+                return false;
+            }
+            u.weight = current.weight;
+        }
+        same_weights.maintenance = self.conf.maintenance;
+        same_weights.maintenance_page = self.conf.maintenance_page.clone();
+        same_weights.body_limit = self.conf.body_limit.clone();
+        if same_weights != self.conf {
+            return false;
+        }
+        for (w, u) in self.weights.iter().zip(conf.upstreams.iter()) {
+            w.store(u.weight, Ordering::Relaxed);
         }
+        self.conf = conf.clone();
+        true
     }
 }
 
 type BoxedHttpService =
     BoxService<Request<Body>, Response<Body>, Box<dyn std::error::Error + Send + Sync>>;
 
+type UpstreamService =
+    OutlierDetection<CircuitBreakerService<LoadShed<ConcurrencyLimit<ProxyHandler>>>>;
+
+// (route index, version), None for all routes or all versions
+type GroupKey = (Option<usize>, Option<String>);
+
+// upstream ids of a group and their balancer
+type UpstreamGroup = (HashSet<String>, BoxedHttpService);
+
+type WorkerQueues = Arc<RwLock<HashMap<String, mpsc::Sender<MwPreRequest>>>>;
+
+// queue of a service worker, outliving the worker if it panics
+struct WorkerQueue {
+    rx: mpsc::Receiver<MwPreRequest>,
+    removed: oneshot::Receiver<()>,
+    replaced: bool, // removed was dropped, no longer polled
+    started: bool,  // upstreams and balancers built, serving requests
+}
+
+// services a request failed over from, a chain never goes back to one of them
+struct FailedOver(Vec<String>);
+
+// upstream requests still running in spawned tasks
+static INFLIGHT_TASKS: AtomicUsize = AtomicUsize::new(0);
+
+// spawned task counted in INFLIGHT_TASKS and the service gauge, holding a permit of
+// the service's max_proxy_tasks until it ends
+struct InflightGuard {
+    tasks: prometheus::IntGauge,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl InflightGuard {
+    fn new(tasks: prometheus::IntGauge, permit: Option<OwnedSemaphorePermit>) -> Self {
+        INFLIGHT_TASKS.fetch_add(1, Ordering::SeqCst);
+        tasks.inc();
+        InflightGuard {
+            tasks,
+            _permit: permit,
+        }
+    }
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        INFLIGHT_TASKS.fetch_sub(1, Ordering::SeqCst);
+        self.tasks.dec();
+    }
+}
+
+lazy_static::lazy_static! {
+    // services with a running worker, mirrors `worker_queues` for readiness and admin api
+    static ref WORKER_IDS: RwLock<BTreeSet<String>> = RwLock::new(BTreeSet::new());
+
+    static ref QUEUED_REQUESTS: prometheus::IntGaugeVec = prometheus::register_int_gauge_vec!(
+        "gateway_service_queued_requests",
+        "Requests waiting for the service worker.",
+        &["service"]
+    ).unwrap();
+
+    static ref PROXY_TASKS: prometheus::IntGaugeVec = prometheus::register_int_gauge_vec!(
+        "gateway_service_proxy_tasks",
+        "Upstream calls running in tasks spawned by the service worker.",
+        &["service"]
+    ).unwrap();
+
+    static ref FAILOVER_REQUESTS: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_failover_requests_total",
+        "Requests sent to the failover service while no upstream of their service was ready.",
+        &["service", "failover"]
+    ).unwrap();
+
+    static ref CIRCUIT_OPEN_REQUESTS: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_circuit_open_requests_total",
+        "Requests answered with 503 and Retry-After while every upstream circuit was open.",
+        &["service"]
+    ).unwrap();
+
+    static ref WORKER_PANICS: prometheus::IntCounterVec = prometheus::register_int_counter_vec!(
+        "gateway_service_worker_panics_total",
+        "Service workers died of a panic, restarted or with their queue closed.",
+        &["service"]
+    ).unwrap();
+}
+
+const DEFAULT_QUEUE_DEPTH: usize = 10;
+
 impl UpstreamMiddleware {
-    async fn service_worker(mut rx: mpsc::Receiver<MwPreRequest>, conf: ServiceInfo) {
-        let mut service = Self::build_service(&conf);
-
-        while let Some(MwPreRequest {
-            context,
-            request,
-            result,
-            ..
-        }) = rx.recv().await
-        {
-            event!(Level::DEBUG, "request {:?}", request.uri());
-            if let Ok(px) = service.ready().await {
-                let f = px.call(request);
-                tokio::spawn(async move {
-                    let proxy_resp: Result<
-                        Response<Body>,
-                        Box<dyn std::error::Error + Send + Sync>,
-                    > = f.await;
-                    match proxy_resp {
-                        Ok(resp) => {
-                            let response = MwPreResponse {
-                                context,
-                                next: MwNextAction::Return(resp),
-                            };
-                            let _ = result.send(Ok(response));
-                        }
-                        Err(e) => {
-                            if let Some(err) = e.downcast_ref::<GatewayError>() {
-                                let _ = result.send(Err(err.clone()));
-                            } else {
-                                let msg = format!("Upstream error\n{:?}", e);
-                                let _ = result.send(Err(GatewayError::UpstreamError(msg)));
-                            }
-                        }
+    /// Responses of `Idempotency-Key` requests are shared across replicas through the redis
+    /// `idempotency_store`, and kept in memory of each gateway if None.
+    /// Https upstreams without their own ca_file trust `upstream_roots`, native certs if None.
+    pub fn new(
+        idempotency_store: Option<redis::Client>,
+        upstream_roots: Option<RootCertStore>,
+    ) -> Self {
+        UpstreamMiddleware {
+            idempotency_store: IdempotencyStore::new(idempotency_store),
+            upstream_roots: upstream_roots.map(Arc::new),
+            ..Default::default()
+        }
+    }
+
+    /// Whether any service worker is registered to proxy requests
+    pub fn has_workers() -> bool {
+        !WORKER_IDS.read().unwrap().is_empty()
+    }
+
+    /// Services with a running worker, sorted by service id
+    pub fn worker_ids() -> Vec<String> {
+        WORKER_IDS.read().unwrap().iter().cloned().collect()
+    }
+
+    fn sync_worker_ids(&self) {
+        *WORKER_IDS.write().unwrap() = self.worker_queues.keys().cloned().collect();
+        *self.failover_queues.write().unwrap() = self.worker_queues.clone();
+    }
+
+    /// Wait for spawned upstream requests to finish, false if still running at deadline
+    pub async fn wait_idle(deadline: Instant) -> bool {
+        while INFLIGHT_TASKS.load(Ordering::SeqCst) > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        true
+    }
+
+    // A worker dying of a panic is restarted with its config if it was serving requests.
+    // Panicking before that, the config can't be served at all, queued and later
+    // requests get ServiceNotFound until the service is updated.
+    async fn supervise(
+        queue: Arc<tokio::sync::Mutex<WorkerQueue>>,
+        conf: ServiceInfo,
+        weights: Vec<Arc<AtomicU32>>,
+        slow_starts: Vec<Arc<SlowStart>>,
+        failover_queues: WorkerQueues,
+        roots: Option<Arc<RootCertStore>>,
+    ) {
+        loop {
+            let worker = tokio::spawn(Self::service_worker(
+                queue.clone(),
+                conf.clone(),
+                weights.clone(),
+                slow_starts.clone(),
+                failover_queues.clone(),
+                roots.clone(),
+            ));
+            let panic = match worker.await {
+                Err(e) if e.is_panic() => e.into_panic(),
+                // stopped, or cancelled with the runtime
+                _ => return,
+            };
+            let message = panic
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            WORKER_PANICS.with_label_values(&[&conf.service_id]).inc();
+            let mut queue = queue.lock().await;
+            if queue.started {
+                event!(
+                    Level::ERROR,
+                    "Service worker {} panicked: {}, restarting",
+                    conf.service_id,
+                    message
+                );
+                queue.started = false;
+                continue;
+            }
+            event!(
+                Level::ERROR,
+                "Service worker {} panicked starting: {}, rejecting its requests",
+                conf.service_id,
+                message
+            );
+            let queued = QUEUED_REQUESTS.with_label_values(&[&conf.service_id]);
+            Self::drain_queue(&mut queue.rx, &conf.service_id, &queued).await;
+            return;
+        }
+    }
+
+    // Requests queued before a service is removed get ServiceNotFound,
+    // a replaced worker completes requests queued to it before exiting.
+    async fn service_worker(
+        queue: Arc<tokio::sync::Mutex<WorkerQueue>>,
+        conf: ServiceInfo,
+        weights: Vec<Arc<AtomicU32>>,
+        slow_starts: Vec<Arc<SlowStart>>,
+        failover_queues: WorkerQueues,
+        roots: Option<Arc<RootCertStore>>,
+    ) {
+        // released on panic for the supervisor to take over the queue
+        let mut queue = queue.lock_owned().await;
+        let queue = &mut *queue;
+        let queued = QUEUED_REQUESTS.with_label_values(&[&conf.service_id]);
+        let proxy_tasks = PROXY_TASKS.with_label_values(&[&conf.service_id]);
+        let upstreams = Self::build_upstreams(&conf, roots.as_deref());
+        // service level limit, shared by balanced and pinned requests
+        let limit = (conf.max_conn > 0).then(|| Arc::new(Semaphore::new(conf.max_conn as usize)));
+        // tasks of upstream calls, spawned before their responses come back
+        let task_limit =
+            (conf.max_proxy_tasks > 0).then(|| Arc::new(Semaphore::new(conf.max_proxy_tasks)));
+        let sticky = conf
+            .sticky
+            .as_ref()
+            .map(|s| Arc::new(StickySession::new(&conf.service_id, &conf.path, s)));
+        // pinned requests bypass the balancer, sharing circuit breaker and connection limit
+        let mut pinned_services: HashMap<String, BoxedHttpService> = HashMap::new();
+        if sticky.is_some() {
+            for (u, us) in conf.upstreams.iter().zip(upstreams.iter()) {
+                let pinned = BoxService::new(us.clone());
+                pinned_services.insert(u.id.clone(), Self::limit_service(pinned, &limit));
+            }
+        }
+        let matchers: Vec<RouteMatcher> = conf.routes.iter().map(RouteMatcher::new).collect();
+        let splitter = conf.traffic_split.as_ref().map(TrafficSplitter::new);
+        let overrider = conf.version_override.as_ref().map(VersionOverrider::new);
+        // balancer of each route and version, None for all routes or all versions
+        let route_keys: Vec<Option<usize>> = match matchers.len() {
+            0 => vec![None],
+            n => (0..n).map(Some).collect(),
+        };
+        // any upstream version may be requested by override
+        let mut version_keys: Vec<Option<String>> = std::iter::once(None)
+            .chain(splitter.iter().flat_map(|s| s.versions().map(Some)))
+            .collect();
+        if overrider.is_some() {
+            for u in conf.upstreams.iter() {
+                if !version_keys.iter().any(|v| v.as_ref() == Some(&u.version)) {
+                    version_keys.push(Some(u.version.clone()));
+                }
+            }
+        }
+        let mut groups: HashMap<GroupKey, UpstreamGroup> = HashMap::new();
+        for route in route_keys.iter() {
+            for version in version_keys.iter() {
+                let accept = |u: &Upstream| {
+                    route.is_none_or(|i| matchers[i].upstreams.contains(&u.id))
+                        && version.as_ref().is_none_or(|v| &u.version == v)
+                };
+                let group =
+                    Self::build_group(&conf, &upstreams, &weights, &slow_starts, &limit, accept);
+                if let Some(group) = group {
+                    groups.insert((*route, version.clone()), group);
+                }
+            }
+        }
+
+        let retry_policy = conf
+            .retry
+            .as_ref()
+            .map(|r| Arc::new(RetryPolicy::new(&conf.service_id, r)));
+        // retried requests with their attempt number, picked before new requests
+        let (retry_tx, mut retry_rx) = mpsc::unbounded_channel::<(MwPreRequest, u32)>();
+
+        queue.started = true;
+        // permit for the next spawned task, kept by requests answered without one
+        let mut permit: Option<OwnedSemaphorePermit> = None;
+        loop {
+            let ready = task_limit.is_none() || permit.is_some();
+            let (task, attempt) = tokio::select! {
+                biased;
+                res = &mut queue.removed, if !queue.replaced => {
+                    if res.is_ok() {
+                        Self::drain_retries(&mut retry_rx);
+                        Self::drain_queue(&mut queue.rx, &conf.service_id, &queued).await;
+                        return;
                     }
-                });
+                    queue.replaced = true;
+                    continue;
+                }
+                // at the cap requests wait in the bounded queue instead of in spawned tasks
+                Some(acquired) = async { task_limit.clone()?.acquire_owned().await.ok() }, if !ready => {
+                    permit = Some(acquired);
+                    continue;
+                }
+                Some(retry) = retry_rx.recv(), if ready => retry,
+                task = queue.rx.recv(), if ready => match task {
+                    Some(task) => {
+                        queued.dec();
+                        (task, 0)
+                    }
+                    None => break,
+                },
+            };
+            let MwPreRequest {
+                context,
+                mut request,
+                result,
+                ..
+            } = task;
+            // client got a response at its deadline while queued, don't call upstream for nothing
+            if result.is_closed() {
+                event!(Level::DEBUG, "drop abandoned request {:?}", request.uri());
+                continue;
+            }
+            let replay = match &retry_policy {
+                Some(policy) => {
+                    if attempt == 0 {
+                        policy.deposit();
+                    }
+                    ReplayRequest::of(&request)
+                }
+                None => None,
+            };
+            event!(Level::DEBUG, "request {:?}", request.uri());
+            let route = match matchers.iter().position(|m| m.matches(&request)) {
+                Some(i) => Some(i),
+                None if matchers.is_empty() => None,
+                None => {
+                    let err = GatewayError::ServiceNotFound("No route matched".into());
+                    let _ = result.send(Err(err));
+                    continue;
+                }
+            };
+            if let Some(timeout) = route.and_then(|i| matchers[i].timeout) {
+                request.extensions_mut().insert(RouteTimeout(timeout));
+            }
+            let requested = overrider
+                .as_ref()
+                .and_then(|o| o.requested(&request, &context.client_id));
+            let key = if let Some(version) = requested {
+                // requested version never falls back to others
+                let key = (route, Some(version));
+                if !groups.contains_key(&key) {
+                    let err = GatewayError::ServiceNotFound("No upstream of version".into());
+                    let _ = result.send(Err(err));
+                    continue;
+                }
+                key
+            } else {
+                // version picked before balancing, all versions if the route has none of it
+                let version = splitter
+                    .as_ref()
+                    .map(|s| s.pick(&context.client_id).to_string());
+                let key = (route, version);
+                if groups.contains_key(&key) {
+                    key
+                } else {
+                    (route, None)
+                }
+            };
+            // failover while every upstream of the group is circuit broken, ejected or drained
+            let failover = conf.failover_service_id.as_ref().filter(|target| {
+                let chain = request.extensions().get::<FailedOver>();
+                !chain.is_some_and(|c| c.0.contains(target))
+                    && groups
+                        .get(&key)
+                        .is_none_or(|(ids, _)| Self::all_down(&conf, &upstreams, ids))
+            });
+            let failover_queue =
+                failover.and_then(|t| failover_queues.read().unwrap().get(t).cloned());
+            if let (Some(target), Some(ch)) = (failover, failover_queue) {
+                event!(Level::DEBUG, "fail over {:?} to {}", request.uri(), target);
+                FAILOVER_REQUESTS
+                    .with_label_values(&[&conf.service_id, target])
+                    .inc();
+                let mut chain = request
+                    .extensions_mut()
+                    .remove::<FailedOver>()
+                    .unwrap_or(FailedOver(Vec::new()));
+                chain.0.push(conf.service_id.clone());
+                request.extensions_mut().insert(chain);
+                request.extensions_mut().remove::<RouteTimeout>();
+                let task = MwPreRequest {
+                    context,
+                    request,
+                    service_filters: Vec::new(),
+                    client_filters: Vec::new(),
+                    result,
+                };
+                tokio::spawn(Self::enqueue(ch, target.clone(), task, false));
+                continue;
+            }
+            // fail fast instead of waiting for the balancer while every circuit is open
+            let retry_in = groups
+                .get(&key)
+                .and_then(|(ids, _)| Self::circuit_retry_in(&conf, &upstreams, ids));
+            if let Some(retry_in) = retry_in {
+                CIRCUIT_OPEN_REQUESTS
+                    .with_label_values(&[&conf.service_id])
+                    .inc();
+                let secs = (retry_in.as_millis() as u64).div_ceil(1000).max(1);
+                let _ = result.send(Err(GatewayError::CircuitOpen(secs)));
+                continue;
+            }
+            let (group_ids, balancer) = match groups.get_mut(&key) {
+                Some(group) => group,
+                None => {
+                    let _ = result.send(Err(GatewayError::ServiceNotReady(
+                        "Service not ready".into(),
+                    )));
+                    continue;
+                }
+            };
+            // pinned upstream is only used if it is in the picked group, and not for retries
+            let pinned = sticky
+                .as_ref()
+                .and_then(|s| s.pinned_upstream(&request))
+                .filter(|id| attempt == 0 && group_ids.contains(id));
+            let pinned_px = pinned
+                .as_ref()
+                .and_then(|id| pinned_services.get_mut(id))
+                .and_then(|px| px.ready().now_or_never())
+                .and_then(Result::ok);
+            let f = if let Some(px) = pinned_px {
+                px.call(request)
+            } else if let Ok(px) = balancer.ready().await {
+                px.call(request)
             } else {
                 let _ = result.send(Err(GatewayError::ServiceNotReady(
                     "Service not ready".into(),
                 )));
+                continue;
+            };
+            let sticky = sticky.clone();
+            let retry = retry_policy
+                .clone()
+                .zip(replay)
+                .map(|(policy, replay)| (policy, replay, retry_tx.clone()));
+            let guard = InflightGuard::new(proxy_tasks.clone(), permit.take());
+            tokio::spawn(async move {
+                let _guard = guard;
+                let (mut context, mut result) = (context, result);
+                let proxy_resp: Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>> =
+                    f.await;
+                if let Some((policy, replay, retry_tx)) = retry {
+                    if policy.allow(attempt, &proxy_resp) {
+                        let task = MwPreRequest {
+                            context,
+                            request: replay.build(),
+                            service_filters: Vec::new(),
+                            client_filters: Vec::new(),
+                            result,
+                        };
+                        // worker stopped, answer with this attempt
+                        match retry_tx.send((task, attempt + 1)) {
+                            Ok(()) => return,
+                            Err(mpsc::error::SendError((task, _))) => {
+                                context = task.context;
+                                result = task.result;
+                            }
+                        }
+                    }
+                }
+                match proxy_resp {
+                    Ok(mut resp) => {
+                        if let Some(s) = sticky {
+                            s.set_cookie(&mut resp, pinned.as_deref());
+                        }
+                        let response = MwPreResponse {
+                            context,
+                            next: MwNextAction::Return(resp),
+                        };
+                        let _ = result.send(Ok(response));
+                    }
+                    Err(e) => {
+                        let err = if let Some(err) = e.downcast_ref::<GatewayError>() {
+                            err.clone()
+                        } else if e.is::<Overloaded>() {
+                            GatewayError::ServiceOverloaded("Service overloaded".into())
+                        } else {
+                            GatewayError::UpstreamError(format!("Upstream error: {}", e))
+                        };
+                        if !matches!(err, GatewayError::ServiceOverloaded(_)) {
+                            event!(
+                                Level::ERROR,
+                                request_id = context.request_id.as_str(),
+                                service = context.service_id.as_str(),
+                                "{}: {:?}",
+                                err,
+                                e
+                            );
+                        }
+                        let _ = result.send(Err(err));
+                    }
+                }
+            });
+        }
+        Self::drain_retries(&mut retry_rx);
+    }
+
+    // shed requests beyond service limit instead of queueing them
+    fn limit_service(
+        service: BoxedHttpService,
+        limit: &Option<Arc<Semaphore>>,
+    ) -> BoxedHttpService {
+        match limit {
+            Some(semaphore) => BoxService::new(LoadShed::new(ConcurrencyLimit::with_semaphore(
+                service,
+                semaphore.clone(),
+            ))),
+            None => service,
+        }
+    }
+
+    // no upstream of the group lets requests through
+    fn all_down(conf: &ServiceInfo, upstreams: &[UpstreamService], ids: &HashSet<String>) -> bool {
+        conf.upstreams
+            .iter()
+            .zip(upstreams)
+            .filter(|(u, _)| ids.contains(&u.id))
+            .all(|(_, us)| us.is_ejected() || us.get_ref().is_open())
+    }
+
+    // time until the first circuit of the group lets requests through, None if one is closed
+    fn circuit_retry_in(
+        conf: &ServiceInfo,
+        upstreams: &[UpstreamService],
+        ids: &HashSet<String>,
+    ) -> Option<Duration> {
+        conf.upstreams
+            .iter()
+            .zip(upstreams)
+            .filter(|(u, _)| ids.contains(&u.id))
+            .map(|(_, us)| us.get_ref().retry_in())
+            .try_fold(None, |min: Option<Duration>, retry_in| {
+                retry_in.map(|r| Some(min.map_or(r, |m| m.min(r))))
+            })
+            .flatten()
+    }
+
+    fn drain_retries(retry_rx: &mut mpsc::UnboundedReceiver<(MwPreRequest, u32)>) {
+        retry_rx.close();
+        while let Ok((task, _)) = retry_rx.try_recv() {
+            let err = GatewayError::ServiceNotFound("Service removed".into());
+            let _ = task.result.send(Err(err));
+        }
+    }
+
+    async fn drain_queue(
+        rx: &mut mpsc::Receiver<MwPreRequest>,
+        service_id: &str,
+        queued: &prometheus::IntGauge,
+    ) {
+        rx.close();
+        let mut rejected = 0;
+        while let Some(task) = rx.recv().await {
+            queued.dec();
+            let err = GatewayError::ServiceNotFound("Service removed".into());
+            let _ = task.result.send(Err(err));
+            rejected += 1;
+        }
+        event!(
+            Level::INFO,
+            "Service worker {} stopped, {} queued requests rejected",
+            service_id,
+            rejected
+        );
+    }
+
+    // queue closed by a worker that panicked starting, see supervise
+    fn remove_dead_worker(&mut self, service_id: &str) {
+        if self
+            .worker_queues
+            .get(service_id)
+            .is_some_and(|ch| ch.is_closed())
+        {
+            self.remove_worker(service_id);
+            self.sync_worker_ids();
+        }
+    }
+
+    fn remove_worker(&mut self, service_id: &str) {
+        self.worker_queues.remove(service_id);
+        if let Some(worker) = self.workers.remove(service_id) {
+            let _ = worker.removed.send(());
+        }
+    }
+
+    // balancer over upstreams accepted by filter, None if there is none.
+    // Upstreams of weight 0 are draining, they only complete requests already sent to them.
+    fn build_group(
+        conf: &ServiceInfo,
+        upstreams: &[UpstreamService],
+        weights: &[Arc<AtomicU32>],
+        slow_starts: &[Arc<SlowStart>],
+        limit: &Option<Arc<Semaphore>>,
+        accept: impl Fn(&Upstream) -> bool,
+    ) -> Option<UpstreamGroup> {
+        let mut group_conf = conf.clone();
+        group_conf.upstreams.clear();
+        let mut group_upstreams = Vec::new();
+        let mut group_weights = Vec::new();
+        let upstreams = conf.upstreams.iter().zip(upstreams);
+        for ((u, us), (w, ss)) in upstreams.zip(weights.iter().zip(slow_starts)) {
+            if u.weight > 0 && accept(u) {
+                group_conf.upstreams.push(u.clone());
+                group_upstreams.push(us.clone());
+                group_weights.push((w.clone(), ss.clone()));
+            }
+        }
+        if group_upstreams.is_empty() {
+            return None;
+        }
+        let ids = group_conf.upstreams.iter().map(|u| u.id.clone()).collect();
+        let service = Self::build_service(&group_conf, group_upstreams, group_weights);
+        Some((ids, Self::limit_service(service, limit)))
+    }
+
+    // queue task for the service worker, answered right away if queue is full and fail_fast
+    async fn enqueue(
+        ch: mpsc::Sender<MwPreRequest>,
+        service_id: String,
+        task: MwPreRequest,
+        fail_fast: bool,
+    ) {
+        let queued = QUEUED_REQUESTS.with_label_values(&[&service_id]);
+        queued.inc();
+        if !fail_fast {
+            if let Err(mpsc::error::SendError(task)) = ch.send(task).await {
+                queued.dec();
+                let err = GatewayError::ServiceNotFound("Service removed".into());
+                let _ = task.result.send(Err(err));
             }
+            return;
         }
+        let (task, err) = match ch.try_send(task) {
+            Ok(()) => return,
+            Err(TrySendError::Full(task)) => {
+                event!(Level::DEBUG, "queue of service {} is full", service_id);
+                (
+                    task,
+                    GatewayError::ServiceOverloaded("Service queue full".into()),
+                )
+            }
+            Err(TrySendError::Closed(task)) => (
+                task,
+                GatewayError::ServiceNotFound("Service removed".into()),
+            ),
+        };
+        queued.dec();
+        let _ = task.result.send(Err(err));
+    }
+
+    fn build_upstreams(conf: &ServiceInfo, roots: Option<&RootCertStore>) -> Vec<UpstreamService> {
+        let u = conf.upstreams.first().expect("Invalid upstream config");
+        let cb_config = CircuitBreakerConfig {
+            error_threshold: u.error_threshold,
+            error_reset: Duration::from_secs(u.error_reset),
+            retry_delay: Duration::from_secs(u.retry_delay),
+        };
+        let group = conf.outlier.as_ref().map(|o| {
+            let group = EjectionGroup::new(conf.upstreams.len(), o.max_ejection_percent);
+            (o, Arc::new(group))
+        });
+        conf.upstreams
+            .iter()
+            .map(|u| {
+                let us = ProxyHandler::new(conf, u, roots);
+                let limit = ConcurrencyLimit::new(us, u.max_conn as usize);
+                let cb = CircuitBreakerService::new(LoadShed::new(limit), cb_config);
+                match &group {
+                    Some((setting, group)) => {
+                        OutlierDetection::new(cb, &conf.service_id, &u.id, setting, group.clone())
+                    }
+                    None => OutlierDetection::disabled(cb),
+                }
+            })
+            .collect()
     }
 
-    fn build_service(conf: &ServiceInfo) -> BoxedHttpService {
-        match conf.upstreams.len() {
+    fn build_service(
+        conf: &ServiceInfo,
+        mut upstreams: Vec<UpstreamService>,
+        weights: Vec<(Arc<AtomicU32>, Arc<SlowStart>)>,
+    ) -> BoxedHttpService {
+        match upstreams.len() {
             0 => {
                 panic!("Invalid upstream config");
             }
             1 => {
-                let u = conf.upstreams.get(0).unwrap();
-                let cb_config = CircuitBreakerConfig {
-                    error_threshold: u.error_threshold,
-                    error_reset: Duration::from_secs(u.error_reset),
-                    retry_delay: Duration::from_secs(u.retry_delay),
-                };
-                let us = ProxyHandler::new(&conf.service_id, u, conf.timeout);
-                let limit = ConcurrencyLimit::new(us, u.max_conn as usize);
-                let cb = CircuitBreakerService::new(LoadShed::new(limit), cb_config);
+                let cb = upstreams.pop().unwrap();
                 BoxService::new(LoadShed::new(cb))
             }
             _ => {
-                let u = conf.upstreams.get(0).unwrap();
-                let cb_config = CircuitBreakerConfig {
-                    error_threshold: u.error_threshold,
-                    error_reset: Duration::from_secs(u.error_reset),
-                    retry_delay: Duration::from_secs(u.retry_delay),
-                };
-                let list: Vec<
-                    Constant<CircuitBreakerService<LoadShed<ConcurrencyLimit<ProxyHandler>>>, u32>,
-                > = conf
-                    .upstreams
-                    .iter()
-                    .map(|u| {
-                        let us = ProxyHandler::new(&conf.service_id, u, conf.timeout);
-                        let limit = ConcurrencyLimit::new(us, u.max_conn as usize);
-                        let cb = CircuitBreakerService::new(LoadShed::new(limit), cb_config);
-                        Constant::new(cb, u.weight)
+                let list: Vec<RuntimeWeight<UpstreamService>> = upstreams
+                    .into_iter()
+                    .zip(weights)
+                    .map(|(cb, (weight, slow_start))| {
+                        RuntimeWeight::new(cb, weight).with_slow_start(slow_start)
                     })
                     .collect();
 
                 if conf.load_balance.eq("hash") {
-                    let list: Vec<
-                        LoadShed<
-                            Constant<
-                                CircuitBreakerService<LoadShed<ConcurrencyLimit<ProxyHandler>>>,
-                                u32,
-                            >,
-                        >,
-                    > = list.into_iter().map(|s| LoadShed::new(s)).collect();
+                    let list: Vec<LoadShed<RuntimeWeight<UpstreamService>>> =
+                        list.into_iter().map(LoadShed::new).collect();
                     let balance = Steer::new(list, |req: &Request<_>, s: &[_]| {
                         let total = s.len();
                         let default = HeaderValue::from_static("empty");
@@ -140,10 +843,18 @@ impl UpstreamMiddleware {
                             .unwrap_or(&default)
                             .as_bytes();
                         let mut hasher = DefaultHasher::new();
-                        Hash::hash_slice(&client_id, &mut hasher);
+                        Hash::hash_slice(client_id, &mut hasher);
                         (hasher.finish() as usize) % total
                     });
                     BoxService::new(balance)
+                } else if conf.load_balance.eq("consistent_hash") {
+                    let nodes: Vec<String> = conf.upstreams.iter().map(|u| u.id.clone()).collect();
+                    let list: Vec<InFlight<LoadShed<RuntimeWeight<UpstreamService>>>> = list
+                        .into_iter()
+                        .map(|s| InFlight::new(LoadShed::new(s)))
+                        .collect();
+                    let balance = Steer::new(list, HashRing::new(&nodes, conf.hash_load_bound));
+                    BoxService::new(balance)
                 } else if conf.load_balance.eq("load") {
                     let discover = ServiceList::new(list);
                     let load = PeakEwmaDiscover::new(
@@ -160,7 +871,29 @@ impl UpstreamMiddleware {
                         PendingRequestsDiscover::new(discover, CompleteOnResponse::default());
                     let balance = Balance::new(load);
                     BoxService::new(balance)
+                } else if conf.load_balance.eq("round_robin") {
+                    let discover = ServiceList::new(list);
+                    let balance = RoundRobinBalance::new(discover);
+                    BoxService::new(balance)
+                } else if conf.load_balance.eq("weighted_round_robin") {
+                    let discover = ServiceList::new(list);
+                    let balance = WeightedBalance::smooth(discover);
+                    BoxService::new(balance)
+                } else if conf.load_balance.eq("least_conn") {
+                    let discover = ServiceList::new(list);
+                    let load =
+                        PendingRequestsDiscover::new(discover, CompleteOnResponse::default());
+                    let balance = RoundRobinBalance::least_load(load);
+                    BoxService::new(balance)
                 } else {
+                    if !conf.load_balance.eq("random") {
+                        event!(
+                            Level::WARN,
+                            "Unknown load_balance {:?} of service {}, fallback to weighted random",
+                            conf.load_balance,
+                            conf.service_id
+                        );
+                    }
                     // weighted random
                     let discover = ServiceList::new(list);
                     let balance = WeightedBalance::new(discover);
@@ -171,6 +904,20 @@ impl UpstreamMiddleware {
     }
 }
 
+fn maintenance_response(page: &MaintenancePage) -> Response<Body> {
+    let content_type = match page.content_type.as_str() {
+        "" => "text/html",
+        t => t,
+    };
+    let mut resp = Response::builder()
+        .status(503)
+        .header(CONTENT_TYPE, content_type);
+    if page.retry_after > 0 {
+        resp = resp.header(RETRY_AFTER, page.retry_after);
+    }
+    resp.body(Body::from(page.body.clone())).unwrap()
+}
+
 impl Middleware for UpstreamMiddleware {
     fn name() -> String {
         "Upstream".into()
@@ -184,12 +931,60 @@ impl Middleware for UpstreamMiddleware {
         false
     }
 
-    fn request(&mut self, task: MwPreRequest) -> Pin<Box<dyn Future<Output = ()> + Send>> {
-        if let Some(ch) = self.worker_queues.get_mut(&task.context.service_id) {
-            let task_ch = ch.clone();
-            Box::pin(async move {
-                let _ = task_ch.send(task).await;
-            })
+    fn request(&mut self, mut task: MwPreRequest) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let service_id = task.context.service_id.clone();
+        self.remove_dead_worker(&service_id);
+        if let Some(ch) = self.worker_queues.get(&service_id) {
+            let worker = self.workers.get(&service_id);
+            if let Some(conf) = worker.map(|w| &w.conf).filter(|c| c.maintenance) {
+                let result = match &conf.maintenance_page {
+                    Some(page) => Ok(MwPreResponse {
+                        context: task.context,
+                        next: MwNextAction::Return(maintenance_response(page)),
+                    }),
+                    None => Err(GatewayError::ServiceNotReady(
+                        "Service under maintenance".into(),
+                    )),
+                };
+                let _ = task.result.send(result);
+                return Box::pin(async {});
+            }
+            if let Some(limit) = worker.and_then(|w| w.conf.body_limit.as_ref()) {
+                if let Err(e) = limit_body(limit, &mut task.request) {
+                    let _ = task.result.send(Err(e));
+                    return Box::pin(async {});
+                }
+            }
+            let fail_fast = worker.map(|w| w.conf.queue_fail_fast).unwrap_or(false);
+            let mirror = worker
+                .and_then(|w| w.mirror.as_ref())
+                .filter(|m| !is_upgrade(&task.request) && m.sampled())
+                .cloned();
+            let flight = worker
+                .and_then(|w| w.coalesce.clone())
+                .and_then(|f| Some((f.key(&task.context, &task.request)?, f)));
+            let idempotent = worker
+                .and_then(|w| w.idempotency.clone())
+                .and_then(|i| Some((i.key(&task.context, &task.request)?, i)));
+            let ch = ch.clone();
+            let dispatch =
+                move |task| Self::enqueue(ch.clone(), service_id.clone(), task, fail_fast);
+            if mirror.is_some() || flight.is_some() || idempotent.is_some() {
+                // body is buffered and identical requests wait out of the middleware loop
+                tokio::spawn(async move {
+                    let task = match mirror {
+                        Some(mirror) => mirror.tee(task).await,
+                        None => task,
+                    };
+                    match (idempotent, flight) {
+                        (Some((key, idempotent)), _) => idempotent.run(key, task, dispatch).await,
+                        (None, Some((key, flight))) => flight.run(key, task, dispatch).await,
+                        (None, None) => dispatch(task).await,
+                    }
+                });
+                return Box::pin(async {});
+            }
+            Box::pin(dispatch(task))
         } else {
             Box::pin(async {
                 let _ = task.result.send(Err(GatewayError::ServiceNotFound(
@@ -206,21 +1001,63 @@ impl Middleware for UpstreamMiddleware {
     fn config_update(&mut self, update: ConfigUpdate) {
         match update {
             ConfigUpdate::ServiceUpdate(conf) => {
-                let (tx, rx) = mpsc::channel(10);
+                self.remove_dead_worker(&conf.service_id);
+                // weight changes keep the worker, with its warm connections
+                if let Some(current) = self.workers.get_mut(&conf.service_id) {
+                    if current.update(&conf) {
+                        event!(
+                            Level::INFO,
+                            "Update upstream weights and maintenance of service {}",
+                            conf.service_id
+                        );
+                        return;
+                    }
+                }
+                let depth = match conf.queue_depth {
+                    0 => DEFAULT_QUEUE_DEPTH,
+                    depth => depth,
+                };
+                let (tx, rx) = mpsc::channel(depth);
                 let service_id = conf.service_id.clone();
-                if (&conf.upstreams).len() > 0 {
-                    tokio::spawn(async move {
-                        Self::service_worker(rx, conf).await;
-                    });
-                    self.worker_queues.insert(service_id, tx);
+                if !conf.upstreams.is_empty() {
+                    let (removed_tx, removed_rx) = oneshot::channel();
+                    let previous = self.workers.get(&service_id).map(|w| &w.conf);
+                    let roots = self.upstream_roots.clone();
+                    let worker = Worker::new(
+                        &conf,
+                        previous,
+                        removed_tx,
+                        &self.idempotency_store,
+                        roots.as_deref(),
+                    );
+                    let weights = worker.weights.clone();
+                    let slow_starts = worker.slow_starts.clone();
+                    let queues = self.failover_queues.clone();
+                    let queue = Arc::new(tokio::sync::Mutex::new(WorkerQueue {
+                        rx,
+                        removed: removed_rx,
+                        replaced: false,
+                        started: false,
+                    }));
+                    tokio::spawn(Self::supervise(
+                        queue,
+                        conf,
+                        weights,
+                        slow_starts,
+                        queues,
+                        roots,
+                    ));
+                    self.worker_queues.insert(service_id.clone(), tx);
+                    self.workers.insert(service_id, worker);
                 } else {
-                    self.worker_queues.remove(&service_id);
+                    self.remove_worker(&service_id);
                 }
             }
             ConfigUpdate::ServiceRemove(sid) => {
-                self.worker_queues.remove(&sid);
+                self.remove_worker(&sid);
             }
-            _ => {}
+            _ => return,
         }
+        self.sync_worker_ids();
     }
 }
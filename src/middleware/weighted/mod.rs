@@ -1,4 +1,5 @@
 mod service;
+mod weight;
 
 pub use service::WeightedBalance;
-
+pub use weight::{RuntimeWeight, SlowStart};
@@ -1,18 +1,24 @@
-use tower::discover::{Change, Discover};
-use tower::load::Load;
-use tower::ready_cache::{error::Failed, ReadyCache};
-use futures_util::ready;
 use futures_util::future::{self, TryFutureExt};
+use futures_util::ready;
 use rand::{rngs::SmallRng, Rng, SeedableRng};
+use std::collections::HashMap;
 use std::hash::Hash;
 use std::marker::PhantomData;
-use std::{pin::Pin, task::{Context, Poll}};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tower::discover::{Change, Discover};
+use tower::load::Load;
+use tower::ready_cache::{error::Failed, ReadyCache};
 use tower::Service;
 use tracing::{debug, trace};
 
 pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
-/// Random load balance, use load as service weight
+/// Random load balance, use load as service weight.
+/// In smooth mode, ready services are picked in Nginx's smooth weighted round robin order,
+/// e.g. weights 5:1:1 give `a a b a c a a`.
 pub struct WeightedBalance<D, Req>
 where
     D: Discover,
@@ -22,10 +28,10 @@ where
     services: ReadyCache<D::Key, D::Service, Req>,
     ready_index: Option<usize>,
     rng: SmallRng,
+    smooth: Option<HashMap<D::Key, i64>>, // current weight of services
     _req: PhantomData<Req>,
 }
 
-
 impl<D, Req> WeightedBalance<D, Req>
 where
     D: Discover,
@@ -46,15 +52,29 @@ where
             discover,
             services: ReadyCache::default(),
             ready_index: None,
+            smooth: None,
             _req: PhantomData,
         })
     }
+
+    /// Constructs a load balancer with a fixed seed, picks are repeatable.
+    pub fn with_seed(discover: D, seed: u64) -> Self {
+        let rng = SmallRng::seed_from_u64(seed);
+        Self::from_rng(discover, rng).expect("SmallRng must be valid")
+    }
+
+    /// Constructs a smooth weighted round robin load balancer, picks are deterministic.
+    pub fn smooth(discover: D) -> Self {
+        let mut balance = Self::new(discover);
+        balance.smooth = Some(HashMap::new());
+        balance
+    }
 }
 
 impl<D, Req> WeightedBalance<D, Req>
 where
     D: Discover + Unpin,
-    D::Key: Hash + Clone,
+    D::Key: Hash + Eq + Clone,
     D::Error: Into<BoxError>,
     D::Service: Service<Req> + Load,
     <D::Service as Load>::Metric: std::fmt::Debug + Into<u32>,
@@ -74,11 +94,14 @@ where
                 Some(Change::Remove(key)) => {
                     trace!("remove");
                     self.services.evict(&key);
-                },
+                    if let Some(current) = self.smooth.as_mut() {
+                        current.remove(&key);
+                    }
+                }
                 Some(Change::Insert(key, svc)) => {
                     trace!("insert");
                     self.services.push(key, svc);
-                },
+                }
             }
         }
     }
@@ -111,6 +134,9 @@ where
     }
 
     fn random_ready_index(&mut self) -> Option<usize> {
+        if self.smooth.is_some() {
+            return self.smooth_ready_index();
+        }
         match self.services.ready_len() {
             0 => None,
             1 => Some(0),
@@ -121,12 +147,15 @@ where
                     weights.push(svc.load().into())
                 }
                 let total: u32 = weights.iter().sum();
+                if total == 0 {
+                    return Some(self.rng.gen_range(0..len));
+                }
                 let mut point = self.rng.gen_range(0..total);
-                for i in 0..weights.len() {
-                    if point <= weights[i] {
-                        return Some(i)
+                for (i, weight) in weights.iter().enumerate() {
+                    if point < *weight {
+                        return Some(i);
                     } else {
-                        point = point - weights[i] 
+                        point -= weight
                     }
                 }
                 Some(len - 1)
@@ -134,12 +163,34 @@ where
         }
     }
 
+    // each ready service gains its weight, the highest is picked and loses the total
+    fn smooth_ready_index(&mut self) -> Option<usize> {
+        let current = self.smooth.as_mut()?;
+        let mut total: i64 = 0;
+        let mut best: Option<(usize, i64)> = None;
+        for i in 0..self.services.ready_len() {
+            let (key, svc) = self.services.get_ready_index(i).expect("invalid index");
+            let weight: u32 = svc.load().into();
+            let value = current.entry(key.clone()).or_insert(0);
+            *value += weight as i64;
+            total += weight as i64;
+            if best.map(|(_, b)| *value > b).unwrap_or(true) {
+                best = Some((i, *value));
+            }
+        }
+        let (index, _) = best?;
+        let (key, _) = self.services.get_ready_index(index).expect("invalid index");
+        if let Some(value) = current.get_mut(key) {
+            *value -= total;
+        }
+        Some(index)
+    }
 }
 
 impl<D, Req> Service<Req> for WeightedBalance<D, Req>
 where
     D: Discover + Unpin,
-    D::Key: Hash + Clone,
+    D::Key: Hash + Eq + Clone,
     D::Error: Into<BoxError>,
     D::Service: Service<Req> + Load,
     <D::Service as Load>::Metric: std::fmt::Debug + Into<u32>,
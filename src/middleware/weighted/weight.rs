@@ -0,0 +1,126 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower::load::Load;
+use tower::Service;
+
+const WARM: u64 = u64::MAX;
+
+/// Linear ramp of an upstream weight from 10% to full over a window,
+/// for upstreams just added or back after being unavailable
+#[derive(Debug)]
+pub struct SlowStart {
+    window: Duration,
+    base: Instant,
+    started: AtomicU64, // millis since base, WARM if not ramping
+}
+
+impl SlowStart {
+    /// Ramp starting now if `cold`, a zero window never ramps
+    pub fn new(window: Duration, cold: bool) -> Self {
+        let ramp = SlowStart {
+            window,
+            base: Instant::now(),
+            started: AtomicU64::new(WARM),
+        };
+        if cold {
+            ramp.restart();
+        }
+        ramp
+    }
+
+    /// Start ramping again from 10%
+    pub fn restart(&self) {
+        if !self.window.is_zero() {
+            let now = self.base.elapsed().as_millis() as u64;
+            self.started.store(now, Ordering::Relaxed);
+        }
+    }
+
+    /// Effective weight now in hundredths, so a ramped weight of 1 still differs from full
+    pub fn weight(&self, weight: u32) -> u32 {
+        let full = weight.saturating_mul(100);
+        let started = self.started.load(Ordering::Relaxed);
+        if started == WARM {
+            return full;
+        }
+        let elapsed = (self.base.elapsed().as_millis() as u64).saturating_sub(started);
+        let window = self.window.as_millis() as u64;
+        if elapsed >= window {
+            let _ = self
+                .started
+                .compare_exchange(started, WARM, Ordering::Relaxed, Ordering::Relaxed);
+            return full;
+        }
+        let permille = 100 + 900 * elapsed / window;
+        (full as u64 * permille / 1000) as u32
+    }
+}
+
+/// Weight of an upstream as its load, shared so it can be changed without rebuilding the balancer.
+/// With slow start load is in hundredths of weight, balanced upstreams should all have one.
+#[derive(Debug, Clone)]
+pub struct RuntimeWeight<S> {
+    inner: S,
+    weight: Arc<AtomicU32>,
+    slow_start: Option<Arc<SlowStart>>,
+    unready: bool, // inner was not ready at last poll, like an open circuit breaker
+}
+
+impl<S> RuntimeWeight<S> {
+    pub fn new(inner: S, weight: Arc<AtomicU32>) -> Self {
+        RuntimeWeight {
+            inner,
+            weight,
+            slow_start: None,
+            unready: false,
+        }
+    }
+
+    /// Ramp weight by slow start, restarted when inner gets ready after being unready
+    pub fn with_slow_start(mut self, slow_start: Arc<SlowStart>) -> Self {
+        self.slow_start = Some(slow_start);
+        self
+    }
+}
+
+impl<S> Load for RuntimeWeight<S> {
+    type Metric = u32;
+
+    fn load(&self) -> u32 {
+        let weight = self.weight.load(Ordering::Relaxed);
+        match &self.slow_start {
+            Some(slow_start) => slow_start.weight(weight),
+            None => weight,
+        }
+    }
+}
+
+impl<S, Req> Service<Req> for RuntimeWeight<S>
+where
+    S: Service<Req>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let poll = self.inner.poll_ready(cx);
+        match (&poll, self.unready) {
+            (Poll::Pending, _) => self.unready = true,
+            (Poll::Ready(_), true) => {
+                self.unready = false;
+                if let Some(slow_start) = &self.slow_start {
+                    slow_start.restart();
+                }
+            }
+            (Poll::Ready(_), false) => {}
+        }
+        poll
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        self.inner.call(req)
+    }
+}
@@ -0,0 +1,193 @@
+use super::{ConfigSnapshot, DrainState, RequestHandler};
+use crate::config::{AuthSetting, ConfigReloader};
+use crate::middleware::{QuotaCounters, UpstreamMiddleware};
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response};
+use serde::Serialize;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tower::Service;
+use tracing::{event, Level};
+
+const REDACTED: &str = "******";
+// seconds between readiness failing and connections closing, for the LB to notice
+const DEFAULT_DRAIN_DELAY: u64 = 10;
+
+/// Operational endpoints served on a separate listener, kept off the public port.
+///
+/// The admin api under `/admin/` requires `Authorization: Bearer <token>`,
+/// and is disabled without a token. It is read-only but for `POST /admin/reload`, and
+/// `POST /admin/drain?delay=SECS` and `POST /admin/undrain` taking the instance out of
+/// a load balancer and back.
+#[derive(Debug, Clone)]
+pub struct AdminHandler {
+    pub metrics_path: String,
+    pub token: Option<String>,
+    pub config: Arc<RwLock<ConfigSnapshot>>,
+    // reload of file config sources, None for sources pushing their changes
+    pub reload: Option<ConfigReloader>,
+    pub drain: DrainState,
+    pub quota: QuotaCounters,
+}
+
+impl AdminHandler {
+    fn authorized(&self, req: &Request<Body>) -> bool {
+        let token = match &self.token {
+            Some(token) if !token.is_empty() => token,
+            _ => return false,
+        };
+        req.headers()
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(|t| ring::constant_time::verify_slices_are_equal(t.as_bytes(), token.as_bytes()))
+            .map(|r| r.is_ok())
+            .unwrap_or(false)
+    }
+
+    fn admin_api(&self, path: &str) -> Option<Response<Body>> {
+        let config = self.config.read().unwrap();
+        match path {
+            "/admin/services" => {
+                // secrets are not exposed
+                let services: Vec<_> = config
+                    .services
+                    .values()
+                    .cloned()
+                    .map(|mut s| {
+                        if let Some(sticky) = s.sticky.as_mut().filter(|s| !s.secret.is_empty()) {
+                            sticky.secret = REDACTED.into();
+                        }
+                        if let AuthSetting::Introspection(auth) = &mut s.auth {
+                            auth.client_secret = REDACTED.into();
+                        }
+                        for (_, value) in s.upstreams.iter_mut().flat_map(|u| &mut u.headers) {
+                            *value = REDACTED.into();
+                        }
+                        s
+                    })
+                    .collect();
+                Some(json_response(200, &services))
+            }
+            "/admin/clients" => {
+                let clients: Vec<_> = config
+                    .clients
+                    .values()
+                    .cloned()
+                    .map(|mut c| {
+                        c.app_key = REDACTED.into();
+                        c
+                    })
+                    .collect();
+                Some(json_response(200, &clients))
+            }
+            "/admin/workers" => Some(json_response(200, &UpstreamMiddleware::worker_ids())),
+            "/admin/quota" => Some(json_response(200, &self.quota.usage())),
+            _ => None,
+        }
+    }
+
+    // readiness fails now, connections close after the delay, a drain in progress keeps its timer
+    fn start_drain(&self, req: &Request<Body>) -> Response<Body> {
+        let delay = req
+            .uri()
+            .query()
+            .into_iter()
+            .flat_map(|q| q.split('&'))
+            .find_map(|p| p.strip_prefix("delay="))
+            .map(|v| v.parse::<u64>());
+        let delay = match delay {
+            None => DEFAULT_DRAIN_DELAY,
+            Some(Ok(delay)) => delay,
+            Some(Err(_)) => {
+                let msg = "delay should be seconds";
+                return json_response(400, &serde_json::json!({ "error": msg }));
+            }
+        };
+        if self.drain.drain(Duration::from_secs(delay)) {
+            event!(
+                Level::INFO,
+                "Draining, not ready and closing connections in {}s",
+                delay
+            );
+        }
+        json_response(200, &serde_json::json!({ "draining": true }))
+    }
+}
+
+// changes applied by the reload, 422 if the config was rejected
+async fn reload_config(reload: Option<ConfigReloader>) -> Response<Body> {
+    let reload = match reload {
+        Some(reload) => reload,
+        None => {
+            let msg = "Config source does not support reload";
+            return json_response(501, &serde_json::json!({ "error": msg }));
+        }
+    };
+    match reload.reload().await {
+        Some(summary) if summary.errors.is_empty() => json_response(200, &summary),
+        Some(summary) => json_response(422, &summary),
+        None => json_response(503, &serde_json::json!({ "error": "Config source closed" })),
+    }
+}
+
+fn json_response<T: Serialize>(status: u16, data: &T) -> Response<Body> {
+    let body = serde_json::to_vec(data).unwrap_or_default();
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn not_found() -> Response<Body> {
+    Response::builder()
+        .status(404)
+        .body("Not Found".into())
+        .unwrap()
+}
+
+impl Service<Request<Body>> for AdminHandler {
+    type Response = Response<Body>;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, _c: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let path = req.uri().path();
+        let admin = path.starts_with("/admin/") && self.token.is_some();
+        let resp = if admin && !self.authorized(&req) {
+            Response::builder()
+                .status(401)
+                .header(hyper::header::WWW_AUTHENTICATE, "Bearer")
+                .body("Unauthorized".into())
+                .unwrap()
+        } else if admin && path == "/admin/reload" && req.method() == Method::POST {
+            let reload = self.reload.clone();
+            return Box::pin(async move { Ok(reload_config(reload).await) });
+        } else if admin && path == "/admin/drain" && req.method() == Method::POST {
+            self.start_drain(&req)
+        } else if admin && path == "/admin/undrain" && req.method() == Method::POST {
+            if self.drain.undrain() {
+                event!(Level::INFO, "Drain aborted, ready again");
+            }
+            json_response(200, &serde_json::json!({ "draining": false }))
+        } else if req.method() != Method::GET {
+            not_found()
+        } else if path.eq(&self.metrics_path) {
+            RequestHandler::prometheus_endpoint(&req)
+        } else if admin {
+            self.admin_api(path).unwrap_or_else(not_found)
+        } else {
+            not_found()
+        };
+        Box::pin(async { Ok(resp) })
+    }
+}
@@ -0,0 +1,89 @@
+use crate::middleware::UpstreamMiddleware;
+use hyper::{Body, Method, Request, Response};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Liveness and readiness probes, answered by the gateway before auth and middlewares
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub liveness_path: String,
+    pub readiness_path: String,
+    pub drain: DrainState,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            liveness_path: "/healthz".into(),
+            readiness_path: "/readyz".into(),
+            drain: DrainState::default(),
+        }
+    }
+}
+
+impl HealthCheck {
+    /// Probe response if request is a health check, `status` is the gateway server status
+    pub fn probe(&self, req: &Request<Body>, status: u8) -> Option<Response<Body>> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return None;
+        }
+        let path = req.uri().path();
+        if path.eq(&self.liveness_path) {
+            Some(Self::response(200, "ok"))
+        } else if path.eq(&self.readiness_path) {
+            // config loaded, not shutting down or draining, and some service can be proxied
+            if status == 1 && !self.drain.is_draining() && UpstreamMiddleware::has_workers() {
+                Some(Self::response(200, "ready"))
+            } else {
+                Some(Self::response(503, "not ready"))
+            }
+        } else {
+            None
+        }
+    }
+
+    fn response(status: u16, msg: &'static str) -> Response<Body> {
+        Response::builder()
+            .status(status)
+            .header(hyper::header::CONTENT_TYPE, "text/plain")
+            .body(Body::from(msg))
+            .unwrap()
+    }
+}
+
+/// Drain of a gateway instance taken out of a load balancer, shared by the admin api and
+/// request handlers. Readiness fails at once, requests are still served, and after a delay
+/// HTTP/1 responses close their connection so clients reconnect to other instances.
+/// Unlike shutdown, listeners stay open and an undrain makes the instance ready again.
+#[derive(Debug, Clone, Default)]
+pub struct DrainState(Arc<RwLock<Option<Instant>>>); // Some(time connections start closing) while draining
+
+impl DrainState {
+    /// Start draining, connections closed after `delay`. False if already draining,
+    /// the running timer is kept.
+    pub fn drain(&self, delay: Duration) -> bool {
+        let mut state = self.0.write().unwrap();
+        if state.is_some() {
+            return false;
+        }
+        *state = Some(Instant::now() + delay);
+        true
+    }
+
+    /// Stop draining, false if not draining
+    pub fn undrain(&self) -> bool {
+        self.0.write().unwrap().take().is_some()
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.0.read().unwrap().is_some()
+    }
+
+    /// Draining and past the delay, connections should be closed after their response
+    pub fn closing_connections(&self) -> bool {
+        self.0
+            .read()
+            .unwrap()
+            .is_some_and(|at| Instant::now() >= at)
+    }
+}
@@ -1,6 +1,9 @@
+use arc_swap::ArcSwap;
 use futures::ready;
 use hyper::server::accept::Accept;
 use hyper::server::conn::{AddrIncoming, AddrStream};
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::future::Future;
 use std::io::{self, BufReader, Cursor, Read};
@@ -9,11 +12,15 @@ use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
 use tokio_rustls::rustls::{
-    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, NoClientAuth,
-    RootCertStore, ServerConfig, TLSError,
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, Certificate, ClientHello,
+    NoClientAuth, PrivateKey, ProtocolVersion, ResolvesServerCert, RootCertStore, ServerConfig,
+    SupportedCipherSuite, TLSError, ALL_CIPHERSUITES,
 };
+use tracing::{event, Level};
 
 pub trait Transport: AsyncRead + AsyncWrite {
     fn remote_addr(&self) -> Option<SocketAddr>;
@@ -39,6 +46,14 @@ pub enum TlsConfigError {
     EmptyKey,
     /// An error from an invalid key
     InvalidKey(TLSError),
+    /// An unknown or unsupported minimum TLS version
+    UnsupportedTlsVersion(String),
+    /// An unknown cipher suite name
+    UnknownCipherSuite(String),
+    /// No cipher suite left for the enabled TLS versions
+    NoCipherSuite,
+    /// An empty ALPN protocol name
+    InvalidAlpnProtocol,
 }
 
 impl std::fmt::Display for TlsConfigError {
@@ -50,6 +65,25 @@ impl std::fmt::Display for TlsConfigError {
             TlsConfigError::RsaParseError => write!(f, "rsa parse error"),
             TlsConfigError::EmptyKey => write!(f, "key contains no private key"),
             TlsConfigError::InvalidKey(err) => write!(f, "key contains an invalid key, {}", err),
+            TlsConfigError::UnsupportedTlsVersion(v) => {
+                write!(f, "unsupported TLS version {:?}, expect 1.2 or 1.3", v)
+            }
+            TlsConfigError::UnknownCipherSuite(name) => {
+                let known: Vec<String> = ALL_CIPHERSUITES
+                    .iter()
+                    .map(|s| format!("{:?}", s.suite))
+                    .collect();
+                write!(
+                    f,
+                    "unknown cipher suite {:?}, supported: {}",
+                    name,
+                    known.join(", ")
+                )
+            }
+            TlsConfigError::NoCipherSuite => {
+                write!(f, "no cipher suite usable with the enabled TLS versions")
+            }
+            TlsConfigError::InvalidAlpnProtocol => write!(f, "empty ALPN protocol name"),
         }
     }
 }
@@ -72,14 +106,30 @@ pub struct TlsConfigBuilder {
     key: Box<dyn Read + Send + Sync>,
     client_auth: TlsClientAuth,
     ocsp_resp: Vec<u8>,
+    sni: Vec<SniSource>,
+    min_version: Option<String>,
+    cipher_suites: Option<Vec<String>>,
+    alpn_protocols: Vec<String>,
 }
 
+type SniSource = (
+    String,
+    Box<dyn Read + Send + Sync>,
+    Box<dyn Read + Send + Sync>,
+);
+
 impl std::fmt::Debug for TlsConfigBuilder {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         f.debug_struct("TlsConfigBuilder").finish()
     }
 }
 
+impl Default for TlsConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TlsConfigBuilder {
     /// Create a new TlsConfigBuilder
     pub fn new() -> TlsConfigBuilder {
@@ -88,6 +138,10 @@ impl TlsConfigBuilder {
             cert: Box::new(io::empty()),
             client_auth: TlsClientAuth::Off,
             ocsp_resp: Vec::new(),
+            sni: Vec::new(),
+            min_version: None,
+            cipher_suites: None,
+            alpn_protocols: vec!["h2".into(), "http/1.1".into()],
         }
     }
 
@@ -173,42 +227,75 @@ impl TlsConfigBuilder {
         self
     }
 
-    pub fn build(mut self) -> Result<ServerConfig, TlsConfigError> {
-        let mut cert_rdr = BufReader::new(self.cert);
-        let cert = tokio_rustls::rustls::internal::pemfile::certs(&mut cert_rdr)
-            .map_err(|()| TlsConfigError::CertParseError)?;
+    /// Add certificate for a SNI hostname via file path, `*.example.com` matches any
+    /// direct subdomain. Clients without matching SNI get the default cert.
+    pub fn sni_cert_path(
+        mut self,
+        host: &str,
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+    ) -> Self {
+        let cert = Box::new(LazyFile {
+            path: cert_path.as_ref().into(),
+            file: None,
+        });
+        let key = Box::new(LazyFile {
+            path: key_path.as_ref().into(),
+            file: None,
+        });
+        self.sni.push((host.into(), cert, key));
+        self
+    }
 
-        let key = {
-            // convert it to Vec<u8> to allow reading it again if key is RSA
-            let mut key_vec = Vec::new();
-            self.key
-                .read_to_end(&mut key_vec)
-                .map_err(TlsConfigError::Io)?;
+    /// Add certificate for a SNI hostname via bytes slice
+    pub fn sni_cert(mut self, host: &str, cert: &[u8], key: &[u8]) -> Self {
+        let cert = Box::new(Cursor::new(Vec::from(cert)));
+        let key = Box::new(Cursor::new(Vec::from(key)));
+        self.sni.push((host.into(), cert, key));
+        self
+    }
 
-            if key_vec.is_empty() {
-                return Err(TlsConfigError::EmptyKey);
-            }
+    /// Minimum TLS version to accept, `1.2` (default) or `1.3`
+    pub fn min_tls_version(mut self, version: &str) -> Self {
+        self.min_version = Some(version.into());
+        self
+    }
 
-            let mut pkcs8 = tokio_rustls::rustls::internal::pemfile::pkcs8_private_keys(
-                &mut key_vec.as_slice(),
-            )
-            .map_err(|()| TlsConfigError::Pkcs8ParseError)?;
+    /// Restrict cipher suites by rustls name, like `TLS13_AES_256_GCM_SHA384`.
+    /// Suites not usable with the enabled TLS versions are ignored.
+    pub fn cipher_suites<S: AsRef<str>>(mut self, suites: &[S]) -> Self {
+        self.cipher_suites = Some(suites.iter().map(|s| s.as_ref().into()).collect());
+        self
+    }
 
-            if !pkcs8.is_empty() {
-                pkcs8.remove(0)
-            } else {
-                let mut rsa = tokio_rustls::rustls::internal::pemfile::rsa_private_keys(
-                    &mut key_vec.as_slice(),
-                )
-                .map_err(|()| TlsConfigError::RsaParseError)?;
+    /// ALPN protocols in order of preference, defaults to `h2` and `http/1.1`
+    pub fn alpn_protocols<S: AsRef<str>>(mut self, protocols: &[S]) -> Self {
+        self.alpn_protocols = protocols.iter().map(|p| p.as_ref().into()).collect();
+        self
+    }
 
-                if !rsa.is_empty() {
-                    rsa.remove(0)
-                } else {
-                    return Err(TlsConfigError::EmptyKey);
-                }
-            }
-        };
+    /// Apply version, cipher suite and ALPN settings, unset options keep their defaults
+    pub fn options(mut self, options: &TlsOptions) -> Self {
+        if let Some(version) = &options.min_version {
+            self = self.min_tls_version(version);
+        }
+        if let Some(suites) = &options.cipher_suites {
+            self = self.cipher_suites(suites);
+        }
+        if let Some(protocols) = &options.alpn_protocols {
+            self = self.alpn_protocols(protocols);
+        }
+        self
+    }
+
+    pub fn build(self) -> Result<ServerConfig, TlsConfigError> {
+        let versions = tls_versions(self.min_version.as_deref())?;
+        let suites = cipher_suites(self.cipher_suites.as_deref(), &versions)?;
+        if self.alpn_protocols.iter().any(|p| p.is_empty()) {
+            return Err(TlsConfigError::InvalidAlpnProtocol);
+        }
+        let cert = read_certs(self.cert)?;
+        let key = read_key(self.key)?;
 
         fn read_trust_anchor(
             trust_anchor: Box<dyn Read + Send + Sync>,
@@ -233,14 +320,188 @@ impl TlsConfigBuilder {
         };
 
         let mut config = ServerConfig::new(client_auth);
-        config
-            .set_single_cert_with_ocsp_and_sct(cert, key, self.ocsp_resp, Vec::new())
-            .map_err(|err| TlsConfigError::InvalidKey(err))?;
-        config.set_protocols(&["h2".into(), "http/1.1".into()]);
+        if self.sni.is_empty() {
+            config
+                .set_single_cert_with_ocsp_and_sct(cert, key, self.ocsp_resp, Vec::new())
+                .map_err(TlsConfigError::InvalidKey)?;
+        } else {
+            let mut default = certified_key(cert, &key)?;
+            if !self.ocsp_resp.is_empty() {
+                default.ocsp = Some(self.ocsp_resp);
+            }
+            let mut resolver = SniResolver::new(default);
+            for (host, cert, key) in self.sni {
+                let sni_key = certified_key(read_certs(cert)?, &read_key(key)?)?;
+                resolver.add(&host, sni_key);
+            }
+            config.cert_resolver = Arc::new(resolver);
+        }
+        config.versions = versions;
+        config.ciphersuites = suites;
+        config.alpn_protocols = self
+            .alpn_protocols
+            .into_iter()
+            .map(String::into_bytes)
+            .collect();
         Ok(config)
     }
 }
 
+/// TLS protocol settings reapplied on every certificate reload
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    pub min_version: Option<String>,
+    pub cipher_suites: Option<Vec<String>>,
+    pub alpn_protocols: Option<Vec<String>>,
+}
+
+fn tls_versions(min_version: Option<&str>) -> Result<Vec<ProtocolVersion>, TlsConfigError> {
+    let version = match min_version {
+        Some(v) => v.trim(),
+        None => "1.2",
+    };
+    let number = version
+        .strip_prefix("TLSv")
+        .or_else(|| version.strip_prefix("tlsv"))
+        .unwrap_or(version);
+    match number {
+        "1.2" => Ok(vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2]),
+        "1.3" => Ok(vec![ProtocolVersion::TLSv1_3]),
+        _ => Err(TlsConfigError::UnsupportedTlsVersion(version.into())),
+    }
+}
+
+fn cipher_suites(
+    names: Option<&[String]>,
+    versions: &[ProtocolVersion],
+) -> Result<Vec<&'static SupportedCipherSuite>, TlsConfigError> {
+    let suites = match names {
+        None => ALL_CIPHERSUITES.to_vec(),
+        Some(names) => names
+            .iter()
+            .map(|name| {
+                ALL_CIPHERSUITES
+                    .iter()
+                    .find(|s| format!("{:?}", s.suite).eq_ignore_ascii_case(name.trim()))
+                    .copied()
+                    .ok_or_else(|| TlsConfigError::UnknownCipherSuite(name.clone()))
+            })
+            .collect::<Result<_, _>>()?,
+    };
+    let usable: Vec<&'static SupportedCipherSuite> = suites
+        .into_iter()
+        .filter(|s| versions.iter().any(|v| s.usable_for_version(*v)))
+        .collect();
+    if usable.is_empty() {
+        return Err(TlsConfigError::NoCipherSuite);
+    }
+    Ok(usable)
+}
+
+fn read_certs(reader: Box<dyn Read + Send + Sync>) -> Result<Vec<Certificate>, TlsConfigError> {
+    let mut cert_rdr = BufReader::new(reader);
+    let cert = tokio_rustls::rustls::internal::pemfile::certs(&mut cert_rdr)
+        .map_err(|()| TlsConfigError::CertParseError)?;
+    if cert.is_empty() {
+        return Err(TlsConfigError::CertParseError);
+    }
+    Ok(cert)
+}
+
+fn read_key(mut reader: Box<dyn Read + Send + Sync>) -> Result<PrivateKey, TlsConfigError> {
+    // convert it to Vec<u8> to allow reading it again if key is RSA
+    let mut key_vec = Vec::new();
+    reader
+        .read_to_end(&mut key_vec)
+        .map_err(TlsConfigError::Io)?;
+
+    if key_vec.is_empty() {
+        return Err(TlsConfigError::EmptyKey);
+    }
+
+    let mut pkcs8 =
+        tokio_rustls::rustls::internal::pemfile::pkcs8_private_keys(&mut key_vec.as_slice())
+            .map_err(|()| TlsConfigError::Pkcs8ParseError)?;
+
+    if !pkcs8.is_empty() {
+        Ok(pkcs8.remove(0))
+    } else {
+        let mut rsa =
+            tokio_rustls::rustls::internal::pemfile::rsa_private_keys(&mut key_vec.as_slice())
+                .map_err(|()| TlsConfigError::RsaParseError)?;
+
+        if !rsa.is_empty() {
+            Ok(rsa.remove(0))
+        } else {
+            Err(TlsConfigError::EmptyKey)
+        }
+    }
+}
+
+/// Load a certificate chain and its private key from PEM files, like a client identity for upstreams
+pub fn load_cert_key(
+    cert: impl AsRef<Path>,
+    key: impl AsRef<Path>,
+) -> Result<(Vec<Certificate>, PrivateKey), TlsConfigError> {
+    let cert = read_certs(Box::new(File::open(cert).map_err(TlsConfigError::Io)?))?;
+    let key = read_key(Box::new(File::open(key).map_err(TlsConfigError::Io)?))?;
+    // reject keys rustls cannot sign with
+    certified_key(cert.clone(), &key)?;
+    Ok((cert, key))
+}
+
+fn certified_key(cert: Vec<Certificate>, key: &PrivateKey) -> Result<CertifiedKey, TlsConfigError> {
+    let key = sign::any_supported_type(key).map_err(|()| {
+        TlsConfigError::InvalidKey(TLSError::General("invalid private key".into()))
+    })?;
+    Ok(CertifiedKey::new(cert, Arc::new(key)))
+}
+
+/// Pick certificate by SNI hostname, exact names first, then wildcards
+struct SniResolver {
+    exact: HashMap<String, CertifiedKey>,
+    wildcard: HashMap<String, CertifiedKey>,
+    default: CertifiedKey,
+}
+
+impl SniResolver {
+    fn new(default: CertifiedKey) -> Self {
+        SniResolver {
+            exact: HashMap::new(),
+            wildcard: HashMap::new(),
+            default,
+        }
+    }
+
+    fn add(&mut self, host: &str, key: CertifiedKey) {
+        let host = host.to_ascii_lowercase();
+        match host.strip_prefix("*.") {
+            Some(domain) => self.wildcard.insert(domain.into(), key),
+            None => self.exact.insert(host, key),
+        };
+    }
+
+    fn find(&self, name: &str) -> Option<&CertifiedKey> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(key) = self.exact.get(&name) {
+            return Some(key);
+        }
+        // wildcard covers a single label, `*.example.com` matches `a.example.com` only
+        let (_, domain) = name.split_once('.')?;
+        self.wildcard.get(domain)
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        let key = client_hello
+            .server_name()
+            .and_then(|name| self.find(name.into()))
+            .unwrap_or(&self.default);
+        Some(key.clone())
+    }
+}
+
 struct LazyFile {
     path: PathBuf,
     file: Option<File>,
@@ -268,27 +529,27 @@ impl Read for LazyFile {
     }
 }
 
-impl Transport for TlsStream {
+impl<IO: Transport + Unpin> Transport for TlsStream<IO> {
     fn remote_addr(&self) -> Option<SocketAddr> {
-        Some(self.remote_addr)
+        self.remote_addr
     }
 }
 
-enum State {
-    Handshaking(tokio_rustls::Accept<AddrStream>),
-    Streaming(tokio_rustls::server::TlsStream<AddrStream>),
+enum State<IO> {
+    Handshaking(tokio_rustls::Accept<IO>),
+    Streaming(tokio_rustls::server::TlsStream<IO>),
 }
 
 // tokio_rustls::server::TlsStream doesn't expose constructor methods,
 // so we have to TlsAcceptor::accept and handshake to have access to it
 // TlsStream implements AsyncRead/AsyncWrite handshaking tokio_rustls::Accept first
-pub struct TlsStream {
-    state: State,
-    remote_addr: SocketAddr,
+pub struct TlsStream<IO = AddrStream> {
+    state: State<IO>,
+    remote_addr: Option<SocketAddr>,
 }
 
-impl TlsStream {
-    fn new(stream: AddrStream, config: Arc<ServerConfig>) -> TlsStream {
+impl<IO: Transport + Unpin> TlsStream<IO> {
+    fn new(stream: IO, config: Arc<ServerConfig>) -> TlsStream<IO> {
         let remote_addr = stream.remote_addr();
         let accept = tokio_rustls::TlsAcceptor::from(config).accept(stream);
         TlsStream {
@@ -298,7 +559,7 @@ impl TlsStream {
     }
 }
 
-impl AsyncRead for TlsStream {
+impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsStream<IO> {
     fn poll_read(
         self: Pin<&mut Self>,
         cx: &mut Context,
@@ -319,7 +580,7 @@ impl AsyncRead for TlsStream {
     }
 }
 
-impl AsyncWrite for TlsStream {
+impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsStream<IO> {
     fn poll_write(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -354,22 +615,136 @@ impl AsyncWrite for TlsStream {
     }
 }
 
-pub struct TlsAcceptor {
-    config: Arc<ServerConfig>,
-    incoming: AddrIncoming,
+pub struct TlsAcceptor<I = AddrIncoming> {
+    config: Arc<ArcSwap<ServerConfig>>,
+    incoming: I,
+}
+
+impl<I> TlsAcceptor<I> {
+    pub fn new(config: ServerConfig, incoming: I) -> TlsAcceptor<I> {
+        TlsAcceptor {
+            config: Arc::new(ArcSwap::from_pointee(config)),
+            incoming,
+        }
+    }
+}
+
+/// Reload cert and key files at runtime, new handshakes use the new certificate
+/// while existing connections are unaffected
+#[derive(Clone)]
+pub struct TlsReloader {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    sni: Vec<SniCert>,
+    options: TlsOptions,
+    config: Arc<ArcSwap<ServerConfig>>,
+}
+
+/// Cert and key files for a SNI hostname, see `TlsConfigBuilder::sni_cert_path`
+#[derive(Debug, Clone)]
+pub struct SniCert {
+    pub host: String,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
 }
 
-impl TlsAcceptor {
-    pub fn new(config: ServerConfig, incoming: AddrIncoming) -> TlsAcceptor {
+impl TlsReloader {
+    pub fn new(
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+        sni: Vec<SniCert>,
+        options: TlsOptions,
+    ) -> Result<TlsReloader, TlsConfigError> {
+        let cert_path: PathBuf = cert_path.as_ref().into();
+        let key_path: PathBuf = key_path.as_ref().into();
+        let config = Self::load(&cert_path, &key_path, &sni, &options)?;
+        Ok(TlsReloader {
+            cert_path,
+            key_path,
+            sni,
+            options,
+            config: Arc::new(ArcSwap::from_pointee(config)),
+        })
+    }
+
+    fn load(
+        cert_path: &Path,
+        key_path: &Path,
+        sni: &[SniCert],
+        options: &TlsOptions,
+    ) -> Result<ServerConfig, TlsConfigError> {
+        let mut builder = TlsConfigBuilder::new()
+            .cert_path(cert_path)
+            .key_path(key_path)
+            .options(options);
+        for c in sni.iter() {
+            builder = builder.sni_cert_path(&c.host, &c.cert_path, &c.key_path);
+        }
+        builder.build()
+    }
+
+    /// Read cert and key files again, keep current config if they are invalid
+    pub fn reload(&self) -> Result<(), TlsConfigError> {
+        let config = Self::load(&self.cert_path, &self.key_path, &self.sni, &self.options)?;
+        self.config.store(Arc::new(config));
+        Ok(())
+    }
+
+    /// Reload on changes of cert and key files, watching their directories so
+    /// replaced files and symlinks (like mounted k8s secrets) are picked up
+    pub fn watch(&self) -> notify::Result<()> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut watcher = notify::watcher(tx, Duration::from_secs(2))?;
+        let mut dirs = HashSet::new();
+        let sni_paths = self.sni.iter().flat_map(|c| [&c.cert_path, &c.key_path]);
+        for path in [&self.cert_path, &self.key_path]
+            .into_iter()
+            .chain(sni_paths)
+        {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            if dirs.insert(dir) {
+                watcher.watch(dir, RecursiveMode::NonRecursive)?;
+            }
+        }
+        let reloader = self.clone();
+        std::thread::spawn(move || {
+            let _watcher = watcher;
+            while let Ok(event) = rx.recv() {
+                match event {
+                    DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) => continue,
+                    DebouncedEvent::Error(e, _) => {
+                        event!(Level::WARN, "TLS certificate watch error: {}", e);
+                        continue;
+                    }
+                    _ => {}
+                }
+                match reloader.reload() {
+                    Ok(()) => event!(Level::INFO, "TLS certificate reloaded"),
+                    Err(e) => event!(Level::ERROR, "Fail to reload TLS certificate: {}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Accept TLS connections over a TCP or unix socket incoming
+    pub fn acceptor<I>(&self, incoming: I) -> TlsAcceptor<I> {
         TlsAcceptor {
-            config: Arc::new(config),
+            config: self.config.clone(),
             incoming,
         }
     }
 }
 
-impl Accept for TlsAcceptor {
-    type Conn = TlsStream;
+impl<I> Accept for TlsAcceptor<I>
+where
+    I: Accept<Error = io::Error> + Unpin,
+    I::Conn: Transport + Unpin,
+{
+    type Conn = TlsStream<I::Conn>;
     type Error = io::Error;
 
     fn poll_accept(
@@ -378,7 +753,7 @@ impl Accept for TlsAcceptor {
     ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
         let pin = self.get_mut();
         match ready!(Pin::new(&mut pin.incoming).poll_accept(cx)) {
-            Some(Ok(sock)) => Poll::Ready(Some(Ok(TlsStream::new(sock, pin.config.clone())))),
+            Some(Ok(sock)) => Poll::Ready(Some(Ok(TlsStream::new(sock, pin.config.load_full())))),
             Some(Err(e)) => Poll::Ready(Some(Err(e))),
             None => Poll::Ready(None),
         }
@@ -0,0 +1,205 @@
+use super::https::Transport;
+use hyper::server::accept::Accept;
+use hyper::server::conn::AddrIncoming;
+use hyper::server::Builder;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{event, Level};
+
+/// Listening address, `host:port` or `unix:/path/to.sock`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("empty unix socket path".into()),
+            Some(path) => Ok(ListenAddr::Unix(path.into())),
+            None => s
+                .parse()
+                .map(ListenAddr::Tcp)
+                .map_err(|e| format!("{}: {}", s, e)),
+        }
+    }
+}
+
+impl std::fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => addr.fmt(f),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A `--listen` value, `ADDR[,cert=FILE,key=FILE][,proxy_protocol]`,
+/// with an own certificate for HTTPS and PROXY protocol headers expected from a L4 load balancer
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listener {
+    pub addr: ListenAddr,
+    pub cert: Option<(PathBuf, PathBuf)>, // (cert file, key file)
+    pub proxy_protocol: bool,
+}
+
+impl FromStr for Listener {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let addr = parts.next().unwrap_or_default().parse()?;
+        let (mut cert, mut key) = (None, None);
+        let mut proxy_protocol = false;
+        for option in parts {
+            if option == "proxy_protocol" {
+                proxy_protocol = true;
+                continue;
+            }
+            match option.split_once('=') {
+                Some(("cert", path)) if !path.is_empty() => cert = Some(PathBuf::from(path)),
+                Some(("key", path)) if !path.is_empty() => key = Some(PathBuf::from(path)),
+                _ => return Err(format!("{}: unknown option {:?}", s, option)),
+            }
+        }
+        let cert = match (cert, key) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            (None, None) => None,
+            _ => return Err(format!("{}: cert and key are required together", s)),
+        };
+        Ok(Listener {
+            addr,
+            cert,
+            proxy_protocol,
+        })
+    }
+}
+
+// pending connections of a listener bound with SO_REUSEPORT
+const REUSE_PORT_BACKLOG: i32 = 1024;
+
+/// Bind a TCP address, with `reuse_port` several processes bind the same address
+/// and the kernel spreads accepted connections across them (SO_REUSEPORT, Linux 3.9+)
+pub fn bind_tcp(addr: &SocketAddr, reuse_port: bool) -> io::Result<AddrIncoming> {
+    let to_io = io::Error::other;
+    if !reuse_port {
+        return AddrIncoming::bind(addr).map_err(to_io);
+    }
+    let socket = Socket::new(
+        Domain::for_address(*addr),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&(*addr).into())?;
+    socket.listen(REUSE_PORT_BACKLOG)?;
+    let listener = tokio::net::TcpListener::from_std(socket.into())?;
+    AddrIncoming::from_listener(listener).map_err(to_io)
+}
+
+/// HTTP settings of client connections, defaults are hyper's
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectionSettings {
+    pub http1_keepalive: bool,  // false closes connections after each response
+    pub http1_half_close: bool, // keep responding after the client shuts down its write side
+    pub http2_max_concurrent_streams: Option<u32>, // unlimited if None
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        ConnectionSettings {
+            http1_keepalive: true,
+            http1_half_close: false,
+            http2_max_concurrent_streams: None,
+        }
+    }
+}
+
+impl ConnectionSettings {
+    /// Apply to the server of a listener
+    pub fn apply<I, E>(&self, builder: Builder<I, E>) -> Builder<I, E> {
+        builder
+            .http1_keepalive(self.http1_keepalive)
+            .http1_half_close(self.http1_half_close)
+            .http2_max_concurrent_streams(self.http2_max_concurrent_streams)
+    }
+}
+
+impl Transport for UnixStream {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// Incoming connections of a unix socket, socket file is removed on drop
+pub struct UnixIncoming {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixIncoming {
+    /// Bind the socket, replacing a stale socket file left by a previous process.
+    /// Fails if another process is still accepting on it, or the path is not a socket.
+    pub fn bind(path: impl AsRef<Path>) -> io::Result<UnixIncoming> {
+        let path = path.as_ref();
+        if let Ok(meta) = std::fs::symlink_metadata(path) {
+            if !meta.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ));
+            }
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is in use", path.display()),
+                ));
+            }
+            event!(Level::INFO, "Remove stale socket {}", path.display());
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        Ok(UnixIncoming {
+            listener,
+            path: path.into(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for UnixIncoming {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl Accept for UnixIncoming {
+    type Conn = UnixStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        match self.listener.poll_accept(cx) {
+            Poll::Ready(Ok((stream, _))) => Poll::Ready(Some(Ok(stream))),
+            Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
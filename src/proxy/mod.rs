@@ -1,8 +1,16 @@
 mod server;
 mod request_handler;
 pub mod https;
+mod health;
+mod admin;
+mod listener;
+mod proxy_protocol;
 
-pub use server::GatewayServer;
-pub use request_handler::RequestHandler;
-pub use https::{TlsAcceptor, TlsConfigBuilder};
+pub use server::{ConfigSnapshot, GatewayServer, GatewaySettings, StartupError};
+pub use request_handler::{HardenedHeaders, HeaderLimits, RequestHandler, ResponseHeaders};
+pub use health::{DrainState, HealthCheck};
+pub use admin::AdminHandler;
+pub use listener::{bind_tcp, ConnectionSettings, ListenAddr, Listener, UnixIncoming};
+pub use proxy_protocol::{parse_v1, parse_v2, read_proxy_header, ProxiedStream, ProxyProtocolAcceptor};
+pub use https::{load_cert_key, SniCert, TlsAcceptor, TlsConfigBuilder, TlsOptions, TlsReloader};
 
@@ -0,0 +1,212 @@
+use super::https::Transport;
+use futures::future::BoxFuture;
+use futures::stream::{FuturesUnordered, Stream};
+use hyper::server::accept::Accept;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tracing::{event, Level};
+
+// connections not sending a complete header in time are dropped
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+const V1_PREFIX: &[u8] = b"PROXY";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+fn malformed(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("malformed PROXY protocol header, {}", reason),
+    )
+}
+
+/// Read a PROXY protocol v1 or v2 header, leaving the stream at the first byte after it.
+/// Returns the client address, or None for LOCAL and UNKNOWN connections like LB health checks.
+pub async fn read_proxy_header<IO>(io: &mut IO) -> io::Result<Option<SocketAddr>>
+where
+    IO: AsyncRead + Unpin,
+{
+    let mut prefix = [0u8; 5];
+    io.read_exact(&mut prefix).await?;
+    if prefix == V1_PREFIX {
+        // read byte by byte, data after the header belongs to the connection
+        let mut line = prefix.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(malformed("v1 line too long"));
+            }
+            line.push(io.read_u8().await?);
+        }
+        parse_v1(&line)
+    } else if prefix == V2_SIGNATURE[..5] {
+        let mut header = [0u8; 16];
+        header[..5].copy_from_slice(&prefix);
+        io.read_exact(&mut header[5..]).await?;
+        let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+        let mut addrs = vec![0u8; len];
+        io.read_exact(&mut addrs).await?;
+        parse_v2(&header, &addrs)
+    } else {
+        Err(malformed("unknown signature"))
+    }
+}
+
+/// Parse a v1 line like `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`
+pub fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| malformed("v1 line is not ascii"))?;
+    let line = line
+        .strip_suffix("\r\n")
+        .ok_or_else(|| malformed("v1 line without CRLF"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", proto @ ("TCP4" | "TCP6"), src, _dst, sport, _dport] => {
+            let ip: IpAddr = src.parse().map_err(|_| malformed("v1 source address"))?;
+            let port: u16 = sport.parse().map_err(|_| malformed("v1 source port"))?;
+            if ip.is_ipv4() != (*proto == "TCP4") {
+                return Err(malformed("v1 address family mismatch"));
+            }
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(malformed("v1 line")),
+    }
+}
+
+/// Parse a v2 binary header, `header` is the fixed 16 bytes and `addrs` the rest
+pub fn parse_v2(header: &[u8; 16], addrs: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if &header[..12] != V2_SIGNATURE {
+        return Err(malformed("v2 signature"));
+    }
+    if header[12] >> 4 != 2 {
+        return Err(malformed("v2 version"));
+    }
+    match header[12] & 0x0f {
+        0 => return Ok(None), // LOCAL
+        1 => {}               // PROXY
+        _ => return Err(malformed("v2 command")),
+    }
+    match header[13] >> 4 {
+        // AF_INET
+        1 if addrs.len() >= 12 => {
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // AF_INET6
+        2 if addrs.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addrs[..16]);
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        }
+        1 | 2 => Err(malformed("v2 address too short")),
+        // AF_UNSPEC and AF_UNIX carry no usable client address
+        0 | 3 => Ok(None),
+        _ => Err(malformed("v2 address family")),
+    }
+}
+
+/// Connection with the client address from its PROXY protocol header
+pub struct ProxiedStream<IO> {
+    inner: IO,
+    remote_addr: Option<SocketAddr>,
+}
+
+impl<IO: Transport + Unpin> Transport for ProxiedStream<IO> {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr.or_else(|| self.inner.remote_addr())
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for ProxiedStream<IO> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for ProxiedStream<IO> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Accept connections behind a L4 load balancer sending PROXY protocol headers.
+/// Headers are read concurrently, connections with a malformed header are closed.
+pub struct ProxyProtocolAcceptor<I: Accept> {
+    incoming: I,
+    pending: FuturesUnordered<BoxFuture<'static, io::Result<ProxiedStream<I::Conn>>>>,
+}
+
+impl<I: Accept> ProxyProtocolAcceptor<I> {
+    pub fn new(incoming: I) -> Self {
+        ProxyProtocolAcceptor {
+            incoming,
+            pending: FuturesUnordered::new(),
+        }
+    }
+}
+
+impl<I> Accept for ProxyProtocolAcceptor<I>
+where
+    I: Accept<Error = io::Error> + Unpin,
+    I::Conn: Transport + Send + Unpin + 'static,
+{
+    type Conn = ProxiedStream<I::Conn>;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let pin = self.get_mut();
+        let mut closed = false;
+        loop {
+            match Pin::new(&mut pin.incoming).poll_accept(cx) {
+                Poll::Ready(Some(Ok(mut conn))) => pin.pending.push(Box::pin(async move {
+                    let header = tokio::time::timeout(HEADER_TIMEOUT, read_proxy_header(&mut conn));
+                    let remote_addr = header.await.map_err(|_| malformed("header timeout"))??;
+                    Ok(ProxiedStream {
+                        inner: conn,
+                        remote_addr,
+                    })
+                })),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => {
+                    closed = true;
+                    break;
+                }
+                Poll::Pending => break,
+            }
+        }
+        loop {
+            match Pin::new(&mut pin.pending).poll_next(cx) {
+                Poll::Ready(Some(Ok(stream))) => return Poll::Ready(Some(Ok(stream))),
+                Poll::Ready(Some(Err(e))) => {
+                    event!(Level::WARN, "Close connection: {}", e);
+                }
+                Poll::Ready(None) if closed => return Poll::Ready(None),
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
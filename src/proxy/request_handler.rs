@@ -1,24 +1,166 @@
+use super::HealthCheck;
 use crate::auth::AuthRequest;
-use crate::middleware::{middleware_chain, GatewayError, MiddlewareHandle, RequestContext};
-use hyper::{Body, Request, Response};
+use crate::middleware::{
+    accepts_openmetrics, encode_openmetrics, middleware_chain, observe_stage, service_stack,
+    AccessInfo, AccessLog, AccessRecord, Deadline, GatewayError, MiddlewareHandle, RequestContext,
+    StageTimings, UpstreamTime, OPENMETRICS_CONTENT_TYPE, REQUEST_ID_HEADER,
+};
+use hyper::header::{HeaderName, CONNECTION, SERVER};
+use hyper::http::HeaderValue;
+use hyper::{Body, HeaderMap, Request, Response};
 use prometheus::{Encoder, TextEncoder};
 use std::future::Future;
+use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::{mpsc, oneshot};
 use tower::Service;
 use tracing::{event, span, Instrument, Level};
 
+const GATEWAY_TIME_HEADER: &str = "x-gateway-time-ms";
+const SERVER_TIMING_HEADER: &str = "server-timing";
+
+type HandlerFuture = Pin<
+    Box<
+        dyn Future<Output = Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>>>
+            + Send
+            + 'static,
+    >,
+>;
+
+/// Headers set or stripped on every response, to hide gateway and upstream software
+#[derive(Debug, Clone, Default)]
+pub struct ResponseHeaders {
+    pub server: Option<String>, // Server header replacing the upstream one, removed if empty
+    pub removal: Vec<String>,   // upstream headers dropped, like X-Powered-By
+}
+
+/// Limits of request headers, larger requests are answered 431 before auth
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeaderLimits {
+    pub max_bytes: Option<usize>, // names and values of all headers
+    pub max_count: Option<usize>, // at most 100, hyper rejects more headers itself
+}
+
+impl HeaderLimits {
+    /// Read buffer of HTTP/1 connections for `max_bytes` of headers and the request line,
+    /// hyper answers 431 itself to heads not fitting in it
+    pub fn buf_size(&self) -> Option<usize> {
+        // hyper requires 8KB at least
+        self.max_bytes.map(|bytes| (bytes + 8192).max(8192))
+    }
+
+    // detail of the limit exceeded by headers, None if within limits
+    fn exceeded(&self, headers: &HeaderMap) -> Option<String> {
+        if let Some(max) = self.max_count.filter(|max| headers.len() > *max) {
+            return Some(format!("{} headers over {}", headers.len(), max));
+        }
+        let bytes: usize = headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        let max = self.max_bytes.filter(|max| bytes > *max)?;
+        Some(format!("{} bytes of headers over {}", bytes, max))
+    }
+}
+
+/// Validated `ResponseHeaders`, applied to every response of a handler
+#[derive(Debug, Clone, Default)]
+pub struct HardenedHeaders {
+    server: Option<HeaderValue>,
+    removal: Vec<HeaderName>,
+}
+
+impl HardenedHeaders {
+    /// Check the Server header and names of headers stripped from all responses
+    pub fn new(headers: ResponseHeaders) -> Result<Self, hyper::http::Error> {
+        let server = headers
+            .server
+            .map(|v| HeaderValue::from_str(&v))
+            .transpose()?;
+        let removal = headers
+            .removal
+            .iter()
+            .map(|name| HeaderName::from_bytes(name.as_bytes()))
+            .collect::<Result<_, _>>()?;
+        Ok(HardenedHeaders { server, removal })
+    }
+
+    fn apply(&self, resp: &mut Response<Body>) {
+        let headers = resp.headers_mut();
+        for name in &self.removal {
+            headers.remove(name);
+        }
+        match &self.server {
+            Some(server) if server.is_empty() => {
+                headers.remove(SERVER);
+            }
+            Some(server) => {
+                headers.insert(SERVER, server.clone());
+            }
+            None => {}
+        }
+    }
+}
+
 pub struct RequestHandler {
     pub stack: Vec<MiddlewareHandle>,
     pub auth: mpsc::Sender<AuthRequest>,
     pub ready: u8,
+    pub status: Arc<Mutex<u8>>,
+    pub health: HealthCheck,
+    pub metrics_path: Option<String>,
+    pub remote_addr: Option<SocketAddr>, // client address of the connection
+    pub request_timeout: Option<Duration>, // total budget of a request, from auth to response
+    pub header_limits: HeaderLimits,
+    pub access_log: AccessLog,
+    pub response_headers: HardenedHeaders,
+    pub verbose_errors: bool, // error detail in responses, for development only
+}
+
+// request line of a request and the log to write it to, if rejected before the middleware chain
+#[derive(Clone)]
+struct RejectLog {
+    log: AccessLog,
+    access: AccessInfo,
+}
+
+impl RejectLog {
+    fn write(&self, request_id: &str, resp: &Response<Body>, start_time: SystemTime) {
+        AccessRecord {
+            info: &self.access,
+            request_id: request_id.to_string(),
+            service_id: "",
+            client_id: "",
+            upstream_id: "",
+            status: resp.status().as_u16(),
+            latency_ms: start_time.elapsed().unwrap_or_default().as_millis(),
+            bytes_sent: hyper::body::HttpBody::size_hint(resp.body()).exact(),
+            sample: None,
+        }
+        .write(&self.log);
+    }
 }
 
 impl RequestHandler {
-    pub fn prometheus_endpoint(_req: &Request<Body>) -> Response<Body> {
-        let encoder = TextEncoder::new();
+    /// Metrics in OpenMetrics format with exemplars when asked by `Accept`, Prometheus text otherwise
+    pub fn prometheus_endpoint(req: &Request<Body>) -> Response<Body> {
         let metric_families = prometheus::gather();
+        let openmetrics = req
+            .headers()
+            .get(hyper::header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(accepts_openmetrics);
+        if openmetrics {
+            return Response::builder()
+                .status(200)
+                .header(hyper::header::CONTENT_TYPE, OPENMETRICS_CONTENT_TYPE)
+                .body(Body::from(encode_openmetrics(&metric_families)))
+                .unwrap();
+        }
+        let encoder = TextEncoder::new();
         let mut buffer = vec![];
         encoder.encode(&metric_families, &mut buffer).unwrap();
 
@@ -30,6 +172,56 @@ impl RequestHandler {
         response
     }
 
+    pub fn is_grpc(req: &Request<Body>) -> bool {
+        req.headers()
+            .get(hyper::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.starts_with("application/grpc"))
+            .unwrap_or(false)
+    }
+
+    // trailers-only response, so gRPC clients get a status instead of a broken stream
+    pub fn grpc_error(code: u32, msg: &str) -> Response<Body> {
+        Response::builder()
+            .status(200)
+            .header(hyper::header::CONTENT_TYPE, "application/grpc")
+            .header("grpc-status", code)
+            .header("grpc-message", msg)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    pub fn grpc_gateway_error(err: &GatewayError) -> Response<Body> {
+        match err {
+            GatewayError::AccessBlocked(_) => Self::grpc_error(5, "Not Found"),
+            GatewayError::Forbidden(_) => Self::grpc_error(7, "Permission denied"),
+            GatewayError::Unauthorized(_) => Self::grpc_error(16, "Unauthenticated"),
+            GatewayError::RateLimited(_) => Self::grpc_error(8, "Rate Limited"),
+            GatewayError::QuotaExceeded(_) => Self::grpc_error(8, "Quota Exceeded"),
+            GatewayError::GatewayInteralError(_) => Self::grpc_error(13, "Gateway Internal Error"),
+            GatewayError::ServiceNotReady(_) => Self::grpc_error(14, "Gateway server not ready"),
+            GatewayError::ServiceOverloaded(_) => Self::grpc_error(14, "Service overloaded"),
+            GatewayError::CircuitOpen(_) => Self::grpc_error(14, "Circuit breaker open"),
+            GatewayError::ServiceNotFound(_) => Self::grpc_error(12, "Service not found"),
+            GatewayError::MethodNotAllowed(_) => Self::grpc_error(12, "Method not allowed"),
+            GatewayError::HeaderTooLarge(_) => Self::grpc_error(8, "Header too large"),
+            GatewayError::PayloadTooLarge(_) => Self::grpc_error(8, "Payload too large"),
+            GatewayError::TimeoutError => Self::grpc_error(4, "Request Timeout"),
+            GatewayError::DeadlineExceeded => Self::grpc_error(4, "Deadline Exceeded"),
+            GatewayError::UpstreamError(_) | GatewayError::UpstreamConnectError(..) => {
+                Self::grpc_error(14, "Upstream Error")
+            }
+            GatewayError::ChannelRecvError(_) => Self::grpc_error(13, "Gateway Error"),
+            GatewayError::Unknown => Self::grpc_error(2, "Gateway Error"),
+        }
+    }
+
+    fn set_request_id(resp: &mut Response<Body>, request_id: &str) {
+        if let Ok(value) = HeaderValue::from_str(request_id) {
+            resp.headers_mut().insert(REQUEST_ID_HEADER, value);
+        }
+    }
+
     pub fn health_endpoint(_req: &Request<Body>) -> Response<Body> {
         let encoder = TextEncoder::new();
         let response = Response::builder()
@@ -44,14 +236,42 @@ impl RequestHandler {
 impl Service<Request<Body>> for RequestHandler {
     type Response = Response<Body>;
     type Error = Box<dyn std::error::Error + Send + Sync>;
-    type Future =
-        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+    type Future = HandlerFuture;
 
     fn poll_ready(&mut self, _c: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let http1 = req.version() < hyper::Version::HTTP_2;
+        let drain = self.health.drain.clone();
+        let response_headers = self.response_headers.clone();
+        let handled = self.dispatch(req);
+        Box::pin(async move {
+            let mut resp = handled.await?;
+            response_headers.apply(&mut resp);
+            // a draining instance sends keep-alive clients to other instances
+            if http1 && drain.closing_connections() {
+                resp.headers_mut()
+                    .insert(CONNECTION, HeaderValue::from_static("close"));
+            }
+            Ok(resp)
+        })
+    }
+}
+
+impl RequestHandler {
+    fn dispatch(&mut self, mut req: Request<Body>) -> HandlerFuture {
+        // probes and metrics bypass auth and middlewares, probes see the live server status
+        let status = { *self.status.lock().unwrap() };
+        if let Some(resp) = self.health.probe(&req, status) {
+            return Box::pin(async { Ok(resp) });
+        }
+        if self.metrics_path.as_deref() == Some(req.uri().path()) {
+            let resp = Self::prometheus_endpoint(&req);
+            return Box::pin(async { Ok(resp) });
+        }
+
         if self.ready == 0 {
             // starting
             return Box::pin(async { Ok(Response::new("Server is initializing...".into())) });
@@ -62,11 +282,35 @@ impl Service<Request<Body>> for RequestHandler {
             return Box::pin(async { Ok(Response::new("Server is closing...".into())) });
         }
 
+        if let Some(detail) = self.header_limits.exceeded(req.headers()) {
+            let mut resp = GatewayError::HeaderTooLarge(detail)
+                .response(Self::is_grpc(&req), self.verbose_errors);
+            let request_id = RequestContext::extract_request_id(&mut req);
+            let rejected = RejectLog {
+                log: self.access_log.clone(),
+                access: AccessInfo::from_request(&req),
+            };
+            rejected.write(&request_id, &resp, SystemTime::now());
+            Self::set_request_id(&mut resp, &request_id);
+            return Box::pin(async { Ok(resp) });
+        }
+
+        if let Some(addr) = self.remote_addr {
+            req.extensions_mut().insert(addr);
+        }
         let stack = self.stack.clone();
 
         let auth = self.auth.clone();
+        let access_log = self.access_log.clone();
+        let request_timeout = self.request_timeout;
+        let verbose = self.verbose_errors;
+        if let Some(timeout) = request_timeout {
+            req.extensions_mut()
+                .insert(Deadline(Instant::now() + timeout));
+        }
 
-        let span = span!(Level::DEBUG, "request");
+        let request_id = RequestContext::extract_request_id(&mut req);
+        let span = span!(Level::DEBUG, "request", request_id = request_id.as_str());
         event!(Level::DEBUG, "{:?} {:?}", req.method(), req.uri());
         Box::pin(
             async move {
@@ -74,73 +318,27 @@ impl Service<Request<Body>> for RequestHandler {
                     let resp = Self::health_endpoint(&req);
                     return Ok(resp);
                 }
-                // auth
-                let (tx, rx) = oneshot::channel();
-                let (head, body) = req.into_parts();
-                let auth_request = AuthRequest {
-                    head: head,
-                    result: tx,
+                let grpc = Self::is_grpc(&req);
+                let rejected = RejectLog {
+                    log: access_log,
+                    access: AccessInfo::from_request(&req),
                 };
-                let _ = auth.send(auth_request).await;
-                let auth_result = rx.await?;
-
-                // handle request
-                match auth_result {
-                    Ok((head_part, auth_resp)) => {
-                        let req = Request::from_parts(head_part, body);
-                        let context = RequestContext::new(&req, &auth_resp);
-
-                        // prometheus endpoint
-                        if context.service_path.eq("/metrics") {
-                            let resp = Self::prometheus_endpoint(&req);
-                            return Ok(resp);
-                        }
-
-                        // apply middleware chain
-                        let resp = middleware_chain(req, context, stack).await;
-                        match resp {
-                            Ok(resp) => Ok(resp),
-                            Err(err) => match err {
-                                GatewayError::AccessBlocked(_e) => {
-                                    let msg = format!("Not Found");
-                                    Ok(Response::builder().status(404).body(msg.into()).unwrap())
-                                }
-                                GatewayError::RateLimited(_e) => {
-                                    let msg = format!("Rate Limited");
-                                    Ok(Response::builder().status(429).body(msg.into()).unwrap())
-                                }
-                                GatewayError::GatewayInteralError(_e) => {
-                                    let msg = format!("Gateway Internal Error");
-                                    Ok(Response::builder().status(502).body(msg.into()).unwrap())
-                                }
-                                GatewayError::ServiceNotReady(_e) => {
-                                    let msg = format!("Gateway server not ready");
-                                    Ok(Response::builder().status(502).body(msg.into()).unwrap())
-                                }
-                                GatewayError::ServiceNotFound(_e) => {
-                                    let msg = format!("Service not found");
-                                    Ok(Response::builder().status(404).body(msg.into()).unwrap())
-                                }
-                                GatewayError::TimeoutError => {
-                                    let msg = format!("Request Timeout");
-                                    Ok(Response::builder().status(504).body(msg.into()).unwrap())
-                                }
-                                GatewayError::UpstreamError(msg) => {
-                                    Ok(Response::builder().status(502).body(msg.into()).unwrap())
-                                }
-                                GatewayError::ChannelRecvError(msg) => {
-                                    Ok(Response::builder().status(502).body(msg.into()).unwrap())
-                                }
-                                GatewayError::Unknown => Ok(Response::builder()
-                                    .status(502)
-                                    .body("Gateway Error".into())
-                                    .unwrap()),
-                            },
-                        }
-                    }
-                    Err(err) => {
-                        let msg = format!("Auth Error: {:?}", err);
-                        Ok(Response::builder().status(502).body(msg.into()).unwrap())
+                let start_time = SystemTime::now();
+                let id = request_id.clone();
+                let handled =
+                    Self::handle(req, auth, stack, verbose, rejected.clone(), id, start_time);
+                let timeout = match request_timeout {
+                    Some(timeout) => timeout,
+                    None => return handled.await,
+                };
+                match tokio::time::timeout(timeout, handled).await {
+                    Ok(resp) => resp,
+                    Err(_) => {
+                        // in-flight middleware results are dropped, their senders see a closed channel
+                        let mut resp = GatewayError::DeadlineExceeded.response(grpc, verbose);
+                        rejected.write(&request_id, &resp, start_time);
+                        Self::set_request_id(&mut resp, &request_id);
+                        Ok(resp)
                     }
                 }
             }
@@ -148,3 +346,66 @@ impl Service<Request<Body>> for RequestHandler {
         )
     }
 }
+
+impl RequestHandler {
+    // auth and middleware chain of a request
+    async fn handle(
+        req: Request<Body>,
+        auth: mpsc::Sender<AuthRequest>,
+        stack: Vec<MiddlewareHandle>,
+        verbose: bool,
+        rejected: RejectLog,
+        request_id: String,
+        start_time: SystemTime,
+    ) -> Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>> {
+        let grpc = Self::is_grpc(&req);
+        // auth
+        let auth_start = Instant::now();
+        let (tx, rx) = oneshot::channel();
+        let (head, body) = req.into_parts();
+        let auth_request = AuthRequest { head, result: tx };
+        let _ = auth.send(auth_request).await;
+        let auth_result = rx.await?;
+        let auth_spent = auth_start.elapsed();
+        observe_stage("Auth", auth_spent);
+
+        // handle request
+        match auth_result {
+            Ok((head_part, auth_resp)) => {
+                let req = Request::from_parts(head_part, body);
+                let mut context = RequestContext::new(&req, &auth_resp);
+                context.verbose_errors = verbose;
+
+                // apply middleware chain
+                let stack = service_stack(stack, &auth_resp.middlewares);
+                let resp = middleware_chain(req, context, stack).await;
+                let mut resp = match resp {
+                    Ok(resp) => resp,
+                    Err(err) => err.response(grpc, verbose),
+                };
+                Self::set_request_id(&mut resp, &request_id);
+                StageTimings::record(&mut resp, "Auth", auth_spent);
+                // upstream time is only set if the service opts in to timing headers
+                if resp.extensions().get::<UpstreamTime>().is_some() {
+                    let total = start_time.elapsed().unwrap_or_default().as_millis() as u64;
+                    resp.headers_mut().insert(GATEWAY_TIME_HEADER, total.into());
+                    let stages = resp
+                        .extensions()
+                        .get::<StageTimings>()
+                        .map(|t| t.header_value());
+                    if let Some(value) = stages.and_then(|v| HeaderValue::from_str(&v).ok()) {
+                        resp.headers_mut().insert(SERVER_TIMING_HEADER, value);
+                    }
+                }
+                Ok(resp)
+            }
+            Err(err) => {
+                let mut resp = GatewayError::from(err).response(grpc, verbose);
+                // rejected before middlewares, so logged here
+                rejected.write(&request_id, &resp, start_time);
+                Self::set_request_id(&mut resp, &request_id);
+                Ok(resp)
+            }
+        }
+    }
+}
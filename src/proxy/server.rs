@@ -1,57 +1,204 @@
-use super::RequestHandler;
-use crate::auth::{AuthRequest, AuthService};
-use crate::config::{ConfigSource, ConfigUpdate};
+use super::{ConnectionSettings, HardenedHeaders, HeaderLimits, HealthCheck, RequestHandler};
+use crate::auth::{AuthRequest, AuthService, PathMatching, TenantMatching};
+use crate::config::{ClientInfo, ConfigSource, ConfigUpdate, ServiceInfo};
 use crate::middleware::{
-    ACLMiddleware, HeaderMiddleware, LoggerMiddleware, Middleware, MiddlewareHandle,
-    RateLimitMiddleware, UpstreamMiddleware,
+    ACLMiddleware, AccessLog, Cidr, ErrorPageMiddleware, ErrorPages, FaultInjectionMiddleware,
+    HeaderMiddleware, JsonSchemaMiddleware, JsonTransformMiddleware, LoggerMiddleware, Middleware,
+    MiddlewareHandle, QuotaCounters, QuotaMiddleware, RateLimitMiddleware, ScopeMiddleware,
+    UpstreamMiddleware,
 };
 use crate::start_middleware_macro;
 use futures::StreamExt;
-use std::sync::{Arc, Mutex};
-use tokio::sync::{broadcast, mpsc};
+use rustls::RootCertStore;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::{broadcast, mpsc, watch};
 use tracing::{event, Level};
 
+/// Initial config failing the startup check
+#[derive(Error, Debug, PartialEq)]
+pub enum StartupError {
+    #[error("config source closed before the initial config was loaded")]
+    SourceClosed,
+
+    #[error("initial config not loaded in {0:?}")]
+    Timeout(Duration),
+
+    #[error("no valid service in the initial config, see errors above")]
+    NoService,
+}
+
+/// Config applied by the running gateway, for inspection by the admin api
+#[derive(Debug, Default)]
+pub struct ConfigSnapshot {
+    pub services: BTreeMap<String, ServiceInfo>,
+    pub clients: BTreeMap<String, ClientInfo>,
+}
+
+impl ConfigSnapshot {
+    fn apply(&mut self, update: &ConfigUpdate) {
+        match update {
+            ConfigUpdate::ServiceUpdate(s) => {
+                self.services.insert(s.service_id.clone(), s.clone());
+            }
+            ConfigUpdate::ServiceRemove(sid) => {
+                self.services.remove(sid);
+            }
+            ConfigUpdate::ClientUpdate(c) => {
+                self.clients.insert(c.client_id.clone(), c.clone());
+            }
+            ConfigUpdate::ClientRemove(cid) => {
+                self.clients.remove(cid);
+            }
+            ConfigUpdate::ConfigReady(_) => {}
+        }
+    }
+}
+
+/// Settings of a gateway taken by its middlewares and auth when they are started
+#[derive(Debug, Default)]
+pub struct GatewaySettings {
+    pub access_log: AccessLog,
+    pub error_pages: ErrorPages,
+    pub trusted_proxies: Vec<Cidr>,
+    // redis sharing quota usage across replicas, None to count in memory
+    pub quota_store: Option<redis::Client>,
+    // apply FaultInjection filters, ignored if not set
+    pub enable_fault_injection: bool,
+    // redis sharing Idempotency-Key responses across replicas, None to keep them in memory
+    pub idempotency_store: Option<redis::Client>,
+    // CA bundle trusted by https upstreams without their own ca_file, None for native certs
+    pub upstream_roots: Option<RootCertStore>,
+    // service of requests matching no service path
+    pub default_service: Option<String>,
+    pub path_matching: PathMatching,
+    pub tenant_matching: TenantMatching,
+    // error detail in client responses, for development only
+    pub verbose_errors: bool,
+}
+
 pub struct GatewayServer {
     pub service_stack: Vec<MiddlewareHandle>,
     pub auth_channel: mpsc::Sender<AuthRequest>,
     pub config_channel: broadcast::Sender<ConfigUpdate>,
     pub status: Arc<Mutex<u8>>,
+    pub config: Arc<RwLock<ConfigSnapshot>>,
+    pub quota: QuotaCounters,
+    pub health: HealthCheck,
+    // metrics on the public listener, None if served by the admin listener
+    pub metrics_path: Option<String>,
+    // total budget of each request, upstream timeouts apply within it
+    pub request_timeout: Option<Duration>,
+    pub header_limits: HeaderLimits,
+    // Server header and headers stripped from every response
+    pub response_headers: HardenedHeaders,
+    pub connection: ConnectionSettings,
+    access_log: AccessLog,
+    verbose_errors: bool,
+    // Some(true) once the initial config is applied, Some(false) if the source closed before
+    loaded: watch::Receiver<Option<bool>>,
 }
 
 impl GatewayServer {
-    pub fn new(mut config: ConfigSource) -> Self {
+    pub fn new(config: ConfigSource) -> Self {
+        Self::with_settings(config, GatewaySettings::default())
+    }
+
+    pub fn with_settings(mut config: ConfigSource, settings: GatewaySettings) -> Self {
         let mut stack = Vec::new();
         let (conf_tx, conf_rx) = broadcast::channel(16);
         let config_channel = conf_tx.clone();
 
         // start upstream middleware, last in stack run first
-        start_middleware_macro!(UpstreamMiddleware, stack, conf_tx);
+        start_middleware_macro!(
+            UpstreamMiddleware,
+            UpstreamMiddleware::new(settings.idempotency_store, settings.upstream_roots),
+            stack,
+            conf_tx
+        );
+        // start fault injection middleware, right before proxying
+        start_middleware_macro!(
+            FaultInjectionMiddleware,
+            FaultInjectionMiddleware::new(settings.enable_fault_injection),
+            stack,
+            conf_tx
+        );
+        // start json transform middleware, sees upstream response first
+        start_middleware_macro!(JsonTransformMiddleware, stack, conf_tx);
         // start header middleware
         start_middleware_macro!(HeaderMiddleware, stack, conf_tx);
+        // start quota middleware, counts requests let through by rate limits
+        let quota = QuotaCounters::default();
+        start_middleware_macro!(
+            QuotaMiddleware,
+            QuotaMiddleware::new(quota.clone(), settings.quota_store),
+            stack,
+            conf_tx
+        );
+        // start json schema middleware, after rate limits so invalid requests don't count to quota
+        start_middleware_macro!(JsonSchemaMiddleware, stack, conf_tx);
         // start ratelimit middleware
-        start_middleware_macro!(RateLimitMiddleware, stack, conf_tx);
+        start_middleware_macro!(
+            RateLimitMiddleware,
+            RateLimitMiddleware::new(settings.trusted_proxies),
+            stack,
+            conf_tx
+        );
+        // start scope middleware, after acl rules
+        start_middleware_macro!(ScopeMiddleware, stack, conf_tx);
         // start acl middleware
         start_middleware_macro!(ACLMiddleware, stack, conf_tx);
+        // start error page middleware, replaces error bodies before they are logged
+        start_middleware_macro!(
+            ErrorPageMiddleware,
+            ErrorPageMiddleware::new(settings.error_pages),
+            stack,
+            conf_tx
+        );
         // start log middleware
-        start_middleware_macro!(LoggerMiddleware, stack, conf_tx);
+        start_middleware_macro!(
+            LoggerMiddleware,
+            LoggerMiddleware::new(settings.access_log.clone()),
+            stack,
+            conf_tx
+        );
 
         let server_status = Arc::new(Mutex::new(0u8));
         let init_status = server_status.clone();
+        let snapshot = Arc::new(RwLock::new(ConfigSnapshot::default()));
+        let applied = snapshot.clone();
+        let (loaded_tx, loaded) = watch::channel(None);
         tokio::spawn(async move {
             event!(Level::INFO, "Watch Config Update");
             while let Some(config_update) = config.next().await {
                 event!(Level::INFO, "Receive Config Update: {:?}", config_update);
-                if let ConfigUpdate::ConfigReady(_) = config_update {
+                applied.write().unwrap().apply(&config_update);
+                let ready = matches!(config_update, ConfigUpdate::ConfigReady(_));
+                if ready {
                     let mut lock = init_status.lock().unwrap();
                     *lock = 1;
                 }
                 let _ = conf_tx.send(config_update);
+                if ready {
+                    let _ = loaded_tx.send(Some(true));
+                }
+            }
+            if loaded_tx.borrow().is_none() {
+                let _ = loaded_tx.send(Some(false));
             }
         });
         let (auth_tx, auth_rx) = mpsc::channel(16);
+        let default_service = settings.default_service;
+        let path_matching = settings.path_matching;
+        let tenant_matching = settings.tenant_matching;
         tokio::spawn(async move {
             event!(Level::INFO, "Start auth worker");
             let mut auth_service = AuthService::new(conf_rx, auth_rx);
+            auth_service.default_service = default_service;
+            auth_service.path_matching = path_matching;
+            auth_service.tenant_matching = tenant_matching;
             auth_service.start().await
         });
 
@@ -59,15 +206,74 @@ impl GatewayServer {
             service_stack: stack,
             auth_channel: auth_tx,
             status: server_status,
+            config: snapshot,
+            quota,
             config_channel,
+            health: HealthCheck::default(),
+            metrics_path: Some("/metrics".into()),
+            request_timeout: None,
+            header_limits: HeaderLimits::default(),
+            response_headers: HardenedHeaders::default(),
+            connection: ConnectionSettings::default(),
+            access_log: settings.access_log,
+            verbose_errors: settings.verbose_errors,
+            loaded,
         }
     }
 
+    /// Wait for the initial config, failing if it is not loaded within `timeout`
+    /// or has no service. Count of services and clients applied on success.
+    pub async fn wait_initial_config(
+        &self,
+        timeout: Duration,
+    ) -> Result<(usize, usize), StartupError> {
+        let mut loaded = self.loaded.clone();
+        let wait = async {
+            loop {
+                if let Some(loaded) = *loaded.borrow() {
+                    return loaded;
+                }
+                if loaded.changed().await.is_err() {
+                    return false;
+                }
+            }
+        };
+        match tokio::time::timeout(timeout, wait).await {
+            Ok(true) => {}
+            Ok(false) => return Err(StartupError::SourceClosed),
+            Err(_) => return Err(StartupError::Timeout(timeout)),
+        }
+        let config = self.config.read().unwrap();
+        if config.services.is_empty() {
+            return Err(StartupError::NoService);
+        }
+        Ok((config.services.len(), config.clients.len()))
+    }
+
+    // stop handling new requests, middlewares exit once all handlers are dropped
+    pub fn shutdown(&mut self) {
+        *self.status.lock().unwrap() = 2;
+        self.service_stack.clear();
+    }
+
     pub fn make_service(&self) -> RequestHandler {
         let lock = self.status.clone();
-        let ready = { lock.lock().unwrap().clone() };
+        let ready = { *lock.lock().unwrap() };
         let stack = self.service_stack.clone();
         let auth = self.auth_channel.clone();
-        RequestHandler { stack, auth, ready }
+        RequestHandler {
+            stack,
+            auth,
+            ready,
+            status: lock,
+            health: self.health.clone(),
+            metrics_path: self.metrics_path.clone(),
+            remote_addr: None,
+            request_timeout: self.request_timeout,
+            header_limits: self.header_limits,
+            response_headers: self.response_headers.clone(),
+            access_log: self.access_log.clone(),
+            verbose_errors: self.verbose_errors,
+        }
     }
 }
@@ -0,0 +1,86 @@
+use hyperapi::middleware::{AccessInfo, AccessLog, AccessLogFormat, AccessRecord};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn write_records(log: &AccessLog, info: &AccessInfo, status: u16, sample: Option<u32>) {
+    for i in 0..1000 {
+        AccessRecord {
+            info,
+            request_id: format!("request-{}", i),
+            service_id: "test/sample",
+            client_id: "",
+            upstream_id: "",
+            status,
+            latency_ms: 1,
+            bytes_sent: None,
+            sample,
+        }
+        .write(log);
+    }
+}
+
+fn take_lines(buffer: &Buffer) -> Vec<String> {
+    let data = std::mem::take(&mut *buffer.0.lock().unwrap());
+    String::from_utf8(data)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect()
+}
+
+#[test]
+fn test_access_log_sample() {
+    let buffer = Buffer::default();
+    let log = AccessLog::new(AccessLogFormat::Combined, Box::new(buffer.clone()), 1000);
+    let info = AccessInfo {
+        method: "GET".into(),
+        uri: "/sample".into(),
+        version: "HTTP/1.1".into(),
+        ..Default::default()
+    };
+
+    // every request is logged by default, not marked as sampled
+    write_records(&log, &info, 200, None);
+    let lines = take_lines(&buffer);
+    assert_eq!(lines.len(), 1000);
+    assert!(lines.iter().all(|l| l.ends_with(" -")));
+
+    // errors are always logged
+    let log = AccessLog::new(AccessLogFormat::Combined, Box::new(buffer.clone()), 100);
+    write_records(&log, &info, 502, None);
+    assert_eq!(take_lines(&buffer).len(), 1000);
+
+    // successful requests are sampled, the same request ids every time
+    write_records(&log, &info, 200, None);
+    let first = take_lines(&buffer);
+    assert!(first.len() > 50 && first.len() < 150, "{}", first.len());
+    assert!(first.iter().all(|l| l.ends_with(" sampled")));
+    write_records(&log, &info, 204, None);
+    let second = take_lines(&buffer);
+    let ids = |lines: &[String]| -> Vec<String> {
+        lines
+            .iter()
+            .map(|l| l.split(' ').rev().nth(1).unwrap().to_string())
+            .collect()
+    };
+    assert_eq!(ids(&first), ids(&second));
+
+    // service rate overrides global one
+    write_records(&log, &info, 200, Some(0));
+    assert!(take_lines(&buffer).is_empty());
+    write_records(&log, &info, 200, Some(1000));
+    assert_eq!(take_lines(&buffer).len(), 1000);
+}
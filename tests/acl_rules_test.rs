@@ -0,0 +1,142 @@
+use hyper::{Body, Request};
+use hyperapi::auth::{AuthResponse, ClientRoles};
+use hyperapi::config::{validate_service, ConfigError, ConfigUpdate, ServiceInfo};
+use hyperapi::middleware::{
+    ACLMiddleware, GatewayError, Middleware, MwNextAction, MwPreRequest, RequestContext,
+};
+use tokio::sync::oneshot;
+
+const SERVICE: &str = r#"
+service_id: acl
+path: /svc
+protocol: http
+auth:
+  type: None
+timeout: 1
+load_balance: random
+upstreams:
+  - id: "1"
+    target: "http://127.0.0.1:1/"
+    max_conn: 10
+    version: "1.0"
+    weight: 1
+    error_threshold: 0
+    error_reset: 60
+    retry_delay: 10
+filters:
+  - type: AclRules
+    setting:
+      rules:
+        - id: admin-write
+          roles: [admin]
+          methods: POST,PUT
+          path_regex: "^/admin/"
+          action: allow
+        - id: no-admin
+          path_regex: "^/admin/"
+          action: deny
+        - id: public-read
+          clients: [app1]
+          methods: GET
+          path_regex: "^/public/"
+          action: allow
+sla: []
+"#;
+
+// status of a request through the ACL middleware, 200 if passed
+async fn status(
+    mw: &mut ACLMiddleware,
+    method: &str,
+    path: &str,
+    client: &str,
+    roles: &[&str],
+) -> u16 {
+    let mut request = Request::builder()
+        .method(method)
+        .uri(path)
+        .body(Body::empty())
+        .unwrap();
+    if !roles.is_empty() {
+        let roles = roles.iter().map(|r| r.to_string()).collect();
+        request.extensions_mut().insert(ClientRoles(roles));
+    }
+    let service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    let auth = AuthResponse {
+        client_id: client.into(),
+        service_id: "acl".into(),
+        sla: String::new(),
+        service_filters: service.filters.clone(),
+        client_filters: Vec::new(),
+        middlewares: Vec::new(),
+    };
+    let (tx, rx) = oneshot::channel();
+    let task = MwPreRequest {
+        context: RequestContext::new(&request, &auth),
+        request,
+        service_filters: service.filters,
+        client_filters: Vec::new(),
+        result: tx,
+    };
+    mw.request(task).await;
+    match rx.await.unwrap() {
+        Ok(resp) => {
+            assert!(matches!(resp.next, MwNextAction::Next(_)));
+            200
+        }
+        Err(e) => {
+            assert!(matches!(&e, GatewayError::Forbidden(rule) if rule.contains("acl rule")));
+            e.status().0
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_acl_rules() {
+    let service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    validate_service(&service).unwrap();
+    let mut mw = ACLMiddleware::default();
+    mw.config_update(ConfigUpdate::ServiceUpdate(service.clone()));
+
+    assert_eq!(
+        status(&mut mw, "GET", "/svc/public/doc", "app1", &[]).await,
+        200
+    );
+    assert_eq!(
+        status(&mut mw, "POST", "/svc/public/doc", "app1", &[]).await,
+        403
+    );
+    assert_eq!(
+        status(&mut mw, "GET", "/svc/public/doc", "app2", &[]).await,
+        403
+    );
+    // first match wins, admins pass the rule denying everyone else
+    assert_eq!(
+        status(&mut mw, "POST", "/svc/admin/users", "app1", &[]).await,
+        403
+    );
+    assert_eq!(
+        status(&mut mw, "POST", "/svc/admin/users", "app2", &["admin"]).await,
+        200
+    );
+    assert_eq!(
+        status(&mut mw, "GET", "/svc/admin/users", "app2", &["admin"]).await,
+        403
+    );
+
+    let mut invalid = service.clone();
+    invalid.filters = serde_yaml::from_str(
+        r#"
+- type: AclRules
+  setting:
+    rules:
+      - id: bad
+        path_regex: "^/admin/("
+        action: deny
+"#,
+    )
+    .unwrap();
+    assert!(matches!(
+        validate_service(&invalid),
+        Err(ConfigError::InvalidAclRules(..))
+    ));
+}
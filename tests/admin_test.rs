@@ -0,0 +1,181 @@
+use futures::StreamExt;
+use hyper::{Body, Method, Request};
+use hyperapi::config::{ConfigSource, ConfigUpdate, ServiceInfo};
+use hyperapi::middleware::{Middleware, QuotaCounters, UpstreamMiddleware};
+use hyperapi::proxy::{AdminHandler, ConfigSnapshot, DrainState, HealthCheck};
+use serde_json::Value;
+use std::sync::{Arc, RwLock};
+use tower::Service;
+
+const SERVICES: &str = r#"
+clients: []
+services:
+  - service_id: admin
+    path: /admin
+    protocol: http
+    auth:
+      type: None
+    timeout: 3
+    load_balance: random
+    filters: []
+    sla: []
+    upstreams:
+      - id: admin1
+        target: "http://127.0.0.1:1/"
+        max_conn: 10
+        weight: 1
+        version: "1"
+        error_threshold: 0
+        error_reset: 60
+        retry_delay: 10
+"#;
+
+fn handler(source: &ConfigSource) -> AdminHandler {
+    AdminHandler {
+        metrics_path: "/metrics".into(),
+        token: Some("secret".into()),
+        config: Arc::new(RwLock::new(ConfigSnapshot::default())),
+        reload: source.reloader(),
+        drain: DrainState::default(),
+        quota: QuotaCounters::default(),
+    }
+}
+
+async fn post(admin: &mut AdminHandler, token: &str) -> (u16, Value) {
+    post_to(admin, "/admin/reload", token).await
+}
+
+async fn post_to(admin: &mut AdminHandler, uri: &str, token: &str) -> (u16, Value) {
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header("authorization", format!("Bearer {}", token))
+        .body(Body::empty())
+        .unwrap();
+    let resp = admin.call(req).await.unwrap();
+    let status = resp.status().as_u16();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+async fn get(admin: &mut AdminHandler, uri: &str) -> Value {
+    let req = Request::get(uri)
+        .header("authorization", "Bearer secret")
+        .body(Body::empty())
+        .unwrap();
+    let resp = admin.call(req).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+fn service(yaml: &str) -> ServiceInfo {
+    let services: Value = serde_yaml::from_str(yaml).unwrap();
+    serde_json::from_value(services["services"][0].clone()).unwrap()
+}
+
+#[tokio::test]
+async fn test_admin_reload() {
+    let path = std::env::temp_dir().join("hyperapi_admin_reload.yaml");
+    std::fs::write(&path, "clients: []\nservices: []\n").unwrap();
+    let source = ConfigSource::new(path.to_string_lossy().into());
+    let mut admin = handler(&source);
+    // updates are read by the gateway, the file is changed once the initial config is loaded
+    let (ready_tx, ready_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let mut source = source;
+        let mut ready_tx = Some(ready_tx);
+        while let Some(update) = source.next().await {
+            if let (ConfigUpdate::ConfigReady(_), Some(tx)) = (update, ready_tx.take()) {
+                let _ = tx.send(());
+            }
+        }
+    });
+    ready_rx.await.unwrap();
+
+    assert_eq!(post(&mut admin, "wrong").await.0, 401);
+
+    std::fs::write(&path, SERVICES).unwrap();
+    let (status, summary) = post(&mut admin, "secret").await;
+    assert_eq!(status, 200);
+    assert_eq!(summary["services_added"], serde_json::json!(["admin"]));
+
+    std::fs::write(&path, "services: [").unwrap();
+    let (status, summary) = post(&mut admin, "secret").await;
+    assert_eq!(status, 422);
+    assert_eq!(summary["errors"].as_array().unwrap().len(), 1);
+
+    // sources pushing their changes
+    let (_tx, source) = ConfigSource::channel();
+    assert_eq!(post(&mut handler(&source), "secret").await.0, 501);
+}
+
+#[tokio::test]
+async fn test_admin_drain() {
+    let (_tx, source) = ConfigSource::channel();
+    let mut admin = handler(&source);
+    let health = HealthCheck {
+        drain: admin.drain.clone(),
+        ..HealthCheck::default()
+    };
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service(SERVICES)));
+    let readyz = || {
+        let req = Request::get("/readyz").body(Body::empty()).unwrap();
+        health.probe(&req, 1).unwrap().status().as_u16()
+    };
+    assert_eq!(readyz(), 200);
+
+    assert_eq!(post_to(&mut admin, "/admin/drain", "wrong").await.0, 401);
+    assert_eq!(
+        post_to(&mut admin, "/admin/drain?delay=x", "secret")
+            .await
+            .0,
+        400
+    );
+    let (status, body) = post_to(&mut admin, "/admin/drain?delay=3600", "secret").await;
+    assert_eq!((status, body["draining"].as_bool()), (200, Some(true)));
+    // not ready at once, connections kept until the delay is over
+    assert_eq!(readyz(), 503);
+    assert!(!admin.drain.closing_connections());
+
+    assert_eq!(post_to(&mut admin, "/admin/undrain", "secret").await.0, 200);
+    assert_eq!(readyz(), 200);
+    post_to(&mut admin, "/admin/drain?delay=0", "secret").await;
+    assert!(admin.drain.closing_connections());
+    assert!(admin.drain.undrain());
+    assert!(!admin.drain.closing_connections());
+}
+
+#[tokio::test]
+async fn test_admin_services_redacted() {
+    let (_tx, source) = ConfigSource::channel();
+    let mut admin = handler(&source);
+    let yaml = SERVICES.replace(
+        "type: None",
+        "type: Introspection\n      url: \"http://127.0.0.1:1/introspect\"\n      client_id: gateway\n      client_secret: hunter2",
+    );
+    let yaml = yaml.replace(
+        "retry_delay: 10",
+        "retry_delay: 10\n        headers: [[\"X-Internal-Token\", \"tok3n\"]]",
+    );
+    let service = service(&yaml);
+    admin
+        .config
+        .write()
+        .unwrap()
+        .services
+        .insert(service.service_id.clone(), service);
+
+    let services = get(&mut admin, "/admin/services").await;
+    assert_eq!(services[0]["auth"]["client_id"], "gateway");
+    assert_eq!(services[0]["auth"]["client_secret"], "******");
+    assert!(!services.to_string().contains("hunter2"));
+    // upstream header values may be credentials, names are kept
+    let headers = &services[0]["upstreams"][0]["headers"];
+    assert_eq!(
+        headers,
+        &serde_json::json!([["X-Internal-Token", "******"]])
+    );
+    assert!(!services.to_string().contains("tok3n"));
+}
@@ -0,0 +1,78 @@
+use hyper::Request;
+use hyperapi::auth::{AuthRequest, AuthService, GatewayAuthError};
+use hyperapi::config::{validate_service, ConfigError, ConfigUpdate, ServiceInfo};
+use hyperapi::middleware::GatewayError;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+const SERVICE: &str = r#"
+service_id: methods
+path: /methods
+protocol: http
+auth:
+  type: AppKey
+allowed_methods: [GET, head]
+timeout: 1
+load_balance: random
+upstreams:
+  - id: "1"
+    target: "http://127.0.0.1:1/"
+    max_conn: 10
+    version: "1.0"
+    weight: 1
+    error_threshold: 0
+    error_reset: 60
+    retry_delay: 10
+filters: []
+sla: []
+"#;
+
+async fn auth(auth_tx: &mpsc::Sender<AuthRequest>, method: &str) -> GatewayAuthError {
+    let (head, _) = Request::builder()
+        .method(method)
+        .uri("/methods/x")
+        .body(())
+        .unwrap()
+        .into_parts();
+    let (tx, rx) = oneshot::channel();
+    let _ = auth_tx.send(AuthRequest { head, result: tx }).await;
+    rx.await.unwrap().unwrap_err()
+}
+
+#[tokio::test]
+async fn test_allowed_methods() {
+    let service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    validate_service(&service).unwrap();
+    let (conf_tx, conf_rx) = broadcast::channel(16);
+    let (auth_tx, auth_rx) = mpsc::channel(16);
+    tokio::spawn(async move { AuthService::new(conf_rx, auth_rx).start().await });
+    conf_tx
+        .send(ConfigUpdate::ServiceUpdate(service.clone()))
+        .unwrap();
+    // let auth service pick up the config
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // refused before the app key is checked or any middleware runs
+    let err = auth(&auth_tx, "POST").await;
+    assert!(
+        matches!(&err, GatewayAuthError::MethodNotAllowed(allow) if allow == "GET, HEAD"),
+        "{:?}",
+        err
+    );
+    let resp = GatewayError::from(err).response(false, false);
+    assert_eq!(resp.status(), 405);
+    assert_eq!(resp.headers()["allow"], "GET, HEAD");
+
+    // allowed methods go on to auth
+    for method in ["GET", "HEAD"] {
+        let err = auth(&auth_tx, method).await;
+        assert!(matches!(err, GatewayAuthError::TokenNotFound), "{:?}", err);
+    }
+
+    let mut invalid = service;
+    invalid.allowed_methods = vec!["GET".into(), "BAD METHOD".into()];
+    assert!(matches!(
+        validate_service(&invalid),
+        Err(ConfigError::InvalidMethod(..))
+    ));
+}
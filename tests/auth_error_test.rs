@@ -0,0 +1,139 @@
+use hyper::server::conn::AddrIncoming;
+use hyper::service::make_service_fn;
+use hyper::{Body, Client, Request, Response, Server};
+use hyperapi::config::ConfigSource;
+use hyperapi::proxy::{GatewayServer, GatewaySettings};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use serde_json::json;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+const CONFIG: &str = r#"
+clients:
+  - client_id: app1
+    app_key: key1
+    pub_key: secret
+    ip_whitelist: []
+    services:
+      other: Default
+  - client_id: app2
+    app_key: key2
+    pub_key: secret
+    ip_whitelist: []
+    services:
+      jwt: Default
+services:
+  - service_id: jwt
+    path: /jwt
+    protocol: http
+    auth:
+      type: JWT
+    timeout: 3
+    load_balance: random
+    filters: []
+    sla:
+      - name: Default
+        filters: []
+    upstreams:
+      - id: "1"
+        target: "http://127.0.0.1:1/"
+        max_conn: 10
+        weight: 1
+        version: "1"
+        error_threshold: 0
+        error_reset: 60
+        retry_delay: 10
+"#;
+
+async fn start_gateway(verbose_errors: bool) -> SocketAddr {
+    let path =
+        std::env::temp_dir().join(format!("hyperapi_auth_error_{}.yaml", std::process::id()));
+    std::fs::write(&path, CONFIG).unwrap();
+
+    let settings = GatewaySettings {
+        verbose_errors,
+        ..GatewaySettings::default()
+    };
+    let source = ConfigSource::new(path.to_string_lossy().into());
+    let gateway = GatewayServer::with_settings(source, settings);
+    while *gateway.status.lock().unwrap() == 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // let middleware workers pick up the config
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr();
+    let make_svc = make_service_fn(move |_| {
+        let handler = gateway.make_service();
+        async move { Ok::<_, Infallible>(handler) }
+    });
+    tokio::spawn(Server::builder(incoming).serve(make_svc));
+    addr
+}
+
+fn token(kid: &str, secret: &[u8]) -> String {
+    let header = Header {
+        kid: Some(kid.into()),
+        ..Default::default()
+    };
+    let claims = json!({ "exp": 4_000_000_000u64, "iat": 0, "iss": "test", "sub": kid });
+    encode(&header, &claims, &EncodingKey::from_secret(secret)).unwrap()
+}
+
+async fn call(gateway: SocketAddr, path: &str, token: Option<String>) -> Response<Body> {
+    let mut req = Request::get(format!("http://{}{}", gateway, path));
+    if let Some(token) = token {
+        req = req.header("authorization", format!("Bearer {}", token));
+    }
+    Client::new()
+        .request(req.body(Body::empty()).unwrap())
+        .await
+        .unwrap()
+}
+
+// status and json error code of a rejected request
+async fn rejected(resp: Response<Body>) -> (u16, String) {
+    let status = resp.status().as_u16();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(json.get("detail").is_none());
+    (
+        status,
+        json["code"].as_str().unwrap_or_default().to_string(),
+    )
+}
+
+#[tokio::test]
+async fn test_auth_error_status() {
+    let gateway = start_gateway(false).await;
+
+    let resp = call(gateway, "/jwt/x", None).await;
+    assert_eq!(rejected(resp).await, (401, "unauthorized".into()));
+
+    // signed with another key
+    let resp = call(gateway, "/jwt/x", Some(token("app2", b"wrong"))).await;
+    assert_eq!(rejected(resp).await, (401, "unauthorized".into()));
+
+    let resp = call(gateway, "/jwt/x", Some(token("nobody", b"secret"))).await;
+    assert_eq!(rejected(resp).await, (403, "forbidden".into()));
+
+    // client without an sla for the service
+    let resp = call(gateway, "/jwt/x", Some(token("app1", b"secret"))).await;
+    assert_eq!(rejected(resp).await, (403, "forbidden".into()));
+
+    let resp = call(gateway, "/nowhere", None).await;
+    assert_eq!(rejected(resp).await.0, 404);
+
+    // the auth error is only detailed with verbose errors
+    let verbose = start_gateway(true).await;
+    let resp = call(verbose, "/jwt/x", None).await;
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["detail"], "Auth token not found");
+
+    // accepted token reaches the unreachable upstream
+    let resp = call(gateway, "/jwt/x", Some(token("app2", b"secret"))).await;
+    assert!(resp.status().is_server_error());
+}
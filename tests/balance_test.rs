@@ -0,0 +1,150 @@
+use hyperapi::middleware::{RuntimeWeight, SlowStart, WeightedBalance};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tower::discover::ServiceList;
+use tower::load::Load;
+use tower::util::BoxService;
+use tower::{Service, ServiceExt};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// upstream answering with its index
+fn upstream(index: usize) -> BoxService<(), usize, BoxError> {
+    BoxService::new(tower::service_fn(move |_req: ()| async move {
+        Ok::<_, BoxError>(index)
+    }))
+}
+
+async fn picks<S>(balance: &mut S, upstreams: usize, total: usize) -> Vec<usize>
+where
+    S: Service<(), Response = usize, Error = BoxError>,
+{
+    let mut counter = vec![0; upstreams];
+    for _ in 0..total {
+        let index = balance.ready().await.unwrap().call(()).await.unwrap();
+        counter[index] += 1;
+    }
+    counter
+}
+
+#[tokio::test]
+async fn test_runtime_weight() {
+    let weights: Vec<Arc<AtomicU32>> = (0..2).map(|_| Arc::new(AtomicU32::new(1))).collect();
+    let list: Vec<_> = weights
+        .iter()
+        .enumerate()
+        .map(|(i, w)| RuntimeWeight::new(upstream(i), w.clone()))
+        .collect();
+    let mut balance = WeightedBalance::new(ServiceList::new(list));
+
+    let counter = picks(&mut balance, 2, 1000).await;
+    assert!(counter[0] > 400 && counter[1] > 400, "{:?}", counter);
+
+    // balancer picks up new weights without rebuilding
+    weights[0].store(9, Ordering::Relaxed);
+    let counter = picks(&mut balance, 2, 1000).await;
+    assert!(counter[0] > 850 && counter[1] > 50, "{:?}", counter);
+}
+
+type Upstream = RuntimeWeight<BoxService<(), usize, BoxError>>;
+
+fn weighted_list(weights: &[u32]) -> ServiceList<Vec<Upstream>> {
+    let list: Vec<_> = weights
+        .iter()
+        .enumerate()
+        .map(|(i, w)| RuntimeWeight::new(upstream(i), Arc::new(AtomicU32::new(*w))))
+        .collect();
+    ServiceList::new(list)
+}
+
+#[tokio::test]
+async fn test_seeded_weighted_random() {
+    let mut balance = WeightedBalance::with_seed(weighted_list(&[3, 1]), 42);
+    let counter = picks(&mut balance, 2, 1000).await;
+    assert!((700..=800).contains(&counter[0]), "{:?}", counter);
+
+    // same seed, same picks
+    let mut again = WeightedBalance::with_seed(weighted_list(&[3, 1]), 42);
+    assert_eq!(picks(&mut again, 2, 1000).await, counter);
+}
+
+#[tokio::test]
+async fn test_smooth_weighted_round_robin() {
+    let mut balance = WeightedBalance::smooth(weighted_list(&[3, 1]));
+    assert_eq!(picks(&mut balance, 2, 1000).await, vec![750, 250]);
+
+    // interleaved instead of bursts, 5:1:1 as in Nginx
+    let mut balance = WeightedBalance::smooth(weighted_list(&[5, 1, 1]));
+    let mut order = Vec::new();
+    for _ in 0..7 {
+        order.push(balance.ready().await.unwrap().call(()).await.unwrap());
+    }
+    // ties are broken in ready order, so b and c may swap
+    assert_eq!(
+        order.iter().map(|i| (*i == 0) as u8).collect::<Vec<_>>(),
+        vec![1, 1, 0, 1, 0, 1, 1]
+    );
+    assert_eq!(order[2] + order[4], 3);
+}
+
+#[test]
+fn test_slow_start_ramp() {
+    let ramp = SlowStart::new(Duration::from_millis(200), true);
+    assert_eq!(ramp.weight(100), 1000);
+    assert_eq!(ramp.weight(1), 10, "weight of 1 still ramps");
+    assert_eq!(ramp.weight(0), 0);
+    std::thread::sleep(Duration::from_millis(100));
+    let half = ramp.weight(100);
+    assert!((5000..=6500).contains(&half), "{}", half);
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(ramp.weight(100), 10000);
+
+    ramp.restart();
+    assert_eq!(ramp.weight(100), 1000);
+    assert_eq!(SlowStart::new(Duration::from_millis(200), false).weight(100), 10000);
+    let off = SlowStart::new(Duration::ZERO, true);
+    off.restart();
+    assert_eq!(off.weight(100), 10000);
+}
+
+// upstream not ready while its gate is closed, like an open circuit breaker
+struct Gated(Arc<AtomicBool>);
+
+impl Service<()> for Gated {
+    type Response = usize;
+    type Error = BoxError;
+    type Future = futures::future::Ready<Result<usize, BoxError>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        match self.0.load(Ordering::Relaxed) {
+            true => Poll::Ready(Ok(())),
+            false => Poll::Pending,
+        }
+    }
+
+    fn call(&mut self, _req: ()) -> Self::Future {
+        futures::future::ready(Ok(0))
+    }
+}
+
+#[test]
+fn test_slow_start_after_recovery() {
+    let open = Arc::new(AtomicBool::new(true));
+    let ramp = Arc::new(SlowStart::new(Duration::from_secs(60), false));
+    let weight = Arc::new(AtomicU32::new(100));
+    let mut upstream = RuntimeWeight::new(Gated(open.clone()), weight).with_slow_start(ramp);
+    let waker = futures::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+
+    assert!(upstream.poll_ready(&mut cx).is_ready());
+    assert_eq!(upstream.load(), 10000);
+    open.store(false, Ordering::Relaxed);
+    assert!(upstream.poll_ready(&mut cx).is_pending());
+    assert_eq!(upstream.load(), 10000);
+    // ready again after being unavailable, weight ramps from 10%
+    open.store(true, Ordering::Relaxed);
+    assert!(upstream.poll_ready(&mut cx).is_ready());
+    assert_eq!(upstream.load(), 1000);
+}
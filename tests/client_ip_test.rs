@@ -0,0 +1,79 @@
+use hyper::{Body, Request};
+use hyperapi::middleware::{client_ip, Cidr};
+use std::net::{IpAddr, SocketAddr};
+
+fn request(peer: &str, forwarded_for: &[&str]) -> Request<Body> {
+    let mut builder = Request::get("/public/");
+    for xff in forwarded_for {
+        builder = builder.header("x-forwarded-for", *xff);
+    }
+    let mut request = builder.body(Body::empty()).unwrap();
+    request
+        .extensions_mut()
+        .insert(peer.parse::<SocketAddr>().unwrap());
+    request
+}
+
+fn ip(s: &str) -> Option<IpAddr> {
+    Some(s.parse().unwrap())
+}
+
+#[test]
+fn test_cidr() {
+    let cidr: Cidr = "10.1.2.3/8".parse().unwrap();
+    assert!(cidr.contains("10.255.0.1".parse().unwrap()));
+    assert!(cidr.contains("::ffff:10.0.0.1".parse().unwrap()));
+    assert!(!cidr.contains("11.0.0.1".parse().unwrap()));
+    assert!(!cidr.contains("::a00:1".parse().unwrap()));
+    let cidr: Cidr = "2001:db8::/32".parse().unwrap();
+    assert!(cidr.contains("2001:db8:1::1".parse().unwrap()));
+    assert!(!cidr.contains("2001:db9::1".parse().unwrap()));
+    let single: Cidr = "192.0.2.1".parse().unwrap();
+    assert!(single.contains("192.0.2.1".parse().unwrap()));
+    assert!(!single.contains("192.0.2.2".parse().unwrap()));
+    assert_eq!(
+        "0.0.0.0/0".parse::<Cidr>().unwrap(),
+        "1.2.3.4/0".parse().unwrap()
+    );
+
+    assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    assert!("2001:db8::/129".parse::<Cidr>().is_err());
+    assert!("10.0.0/8".parse::<Cidr>().is_err());
+    assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+}
+
+#[test]
+fn test_client_ip_trusted_proxies() {
+    let trusted: Vec<Cidr> = vec![
+        "10.0.0.0/8".parse().unwrap(),
+        "2001:db8::/32".parse().unwrap(),
+    ];
+
+    // untrusted peer can't claim any address
+    let req = request("203.0.113.9:1000", &["1.1.1.1"]);
+    assert_eq!(client_ip(&req, &trusted), ip("203.0.113.9"));
+    let req = request("203.0.113.9:1000", &["10.0.0.2"]);
+    assert_eq!(client_ip(&req, &trusted), ip("203.0.113.9"));
+
+    // trusted hops are skipped from the right, spoofed entries left of the client ignored
+    let req = request("10.0.0.1:1000", &["1.1.1.1, 198.51.100.7, 10.0.0.3"]);
+    assert_eq!(client_ip(&req, &trusted), ip("198.51.100.7"));
+    let req = request("10.0.0.1:1000", &["1.1.1.1", "198.51.100.7", "10.0.0.3"]);
+    assert_eq!(client_ip(&req, &trusted), ip("198.51.100.7"));
+    let req = request("[2001:db8::1]:1000", &["198.51.100.7,2001:db8::2"]);
+    assert_eq!(client_ip(&req, &trusted), ip("198.51.100.7"));
+
+    // only trusted hops, the leftmost is the client
+    let req = request("10.0.0.1:1000", &["10.0.0.5, 10.0.0.3"]);
+    assert_eq!(client_ip(&req, &trusted), ip("10.0.0.5"));
+    // garbage stops the walk at the last valid hop
+    let req = request("10.0.0.1:1000", &["198.51.100.7, unknown, 10.0.0.3"]);
+    assert_eq!(client_ip(&req, &trusted), ip("10.0.0.3"));
+    // no header from a trusted peer
+    let req = request("[::ffff:10.0.0.1]:1000", &[]);
+    assert_eq!(client_ip(&req, &trusted), ip("10.0.0.1"));
+
+    // no client address without connection info
+    let req = Request::get("/").body(Body::empty()).unwrap();
+    assert_eq!(client_ip(&req, &trusted), None);
+}
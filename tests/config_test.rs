@@ -0,0 +1,804 @@
+use futures::StreamExt;
+use hyperapi::config::file_config::{interpolate_env, ConfigFormat, FileConfigError};
+use hyperapi::config::{
+    validate_update, ClientInfo, ConfigError, ConfigSource, ConfigUpdate, FaultInjectionSetting,
+    FilterSetting, MirrorSetting, OutlierSetting, PathRewrite, ReloadSummary, RetrySetting,
+    RouteRule, ServiceInfo, SyncState, TrafficSplit, VersionOverride, VersionShare,
+};
+use std::time::Duration;
+
+const CONFIG: &str = include_str!("invalid_config.yaml");
+
+fn sample() -> (ServiceInfo, ClientInfo) {
+    let config: serde_yaml::Value = serde_yaml::from_str(CONFIG).unwrap();
+    let service = serde_yaml::from_value(config["services"][0].clone()).unwrap();
+    let client = serde_yaml::from_value(config["clients"][0].clone()).unwrap();
+    (service, client)
+}
+
+fn check_service(service: ServiceInfo) -> Result<(), ConfigError> {
+    validate_update(&ConfigUpdate::ServiceUpdate(service))
+}
+
+#[test]
+fn test_valid_update() {
+    let (service, client) = sample();
+    assert_eq!(check_service(service), Ok(()));
+    assert_eq!(validate_update(&ConfigUpdate::ClientUpdate(client)), Ok(()));
+    assert_eq!(
+        validate_update(&ConfigUpdate::ServiceRemove("test/valid".into())),
+        Ok(())
+    );
+}
+
+#[test]
+fn test_invalid_service() {
+    let (base, _) = sample();
+    let sid = base.service_id.clone();
+
+    let mut s = base.clone();
+    s.service_id = String::new();
+    assert_eq!(check_service(s), Err(ConfigError::EmptyServiceId));
+
+    for path in ["", "/", "valid", "/a/b"] {
+        let mut s = base.clone();
+        s.path = path.into();
+        assert_eq!(
+            check_service(s),
+            Err(ConfigError::InvalidPath(sid.clone(), path.into()))
+        );
+    }
+
+    let mut s = base.clone();
+    s.upstreams.clear();
+    assert_eq!(check_service(s), Err(ConfigError::NoUpstream(sid.clone())));
+
+    let mut s = base.clone();
+    s.upstreams.push(s.upstreams[0].clone());
+    assert_eq!(
+        check_service(s),
+        Err(ConfigError::DuplicatedUpstream(sid.clone(), "1".into()))
+    );
+
+    for target in ["127.0.0.1:54320", "ftp://127.0.0.1/", "http://"] {
+        let mut s = base.clone();
+        s.upstreams[0].target = target.into();
+        assert_eq!(
+            check_service(s),
+            Err(ConfigError::InvalidTarget(
+                sid.clone(),
+                "1".into(),
+                target.into()
+            ))
+        );
+    }
+
+    let mut s = base.clone();
+    s.upstreams[0].max_conn = 0;
+    assert_eq!(
+        check_service(s),
+        Err(ConfigError::InvalidMaxConn(sid.clone(), "1".into()))
+    );
+
+    let mut s = base.clone();
+    s.upstreams[0].host_header = Some("api.internal".into());
+    s.upstreams[0].headers = vec![("X-Internal-Token".into(), "secret".into())];
+    assert_eq!(check_service(s.clone()), Ok(()));
+    s.upstreams[0].headers = vec![("X Token".into(), "secret".into())];
+    assert_eq!(
+        check_service(s.clone()),
+        Err(ConfigError::InvalidHeader(
+            sid.clone(),
+            "1".into(),
+            "X Token".into()
+        ))
+    );
+    s.upstreams[0].headers.clear();
+    s.upstreams[0].host_header = Some("api\ninternal".into());
+    assert_eq!(
+        check_service(s),
+        Err(ConfigError::InvalidHeader(
+            sid.clone(),
+            "1".into(),
+            "host".into()
+        ))
+    );
+
+    let mut s = base.clone();
+    let mut second = s.upstreams[0].clone();
+    second.id = "2".into();
+    s.upstreams.push(second);
+    s.upstreams.iter_mut().for_each(|u| u.weight = 0);
+    assert_eq!(check_service(s), Err(ConfigError::ZeroWeight(sid.clone())));
+
+    let mut s = base.clone();
+    s.rewrite = PathRewrite::Regex {
+        pattern: "^/valid/(".into(),
+        replacement: "/".into(),
+    };
+    assert_eq!(
+        check_service(s),
+        Err(ConfigError::InvalidRewrite(sid.clone(), "^/valid/(".into()))
+    );
+
+    let mut s = base.clone();
+    s.routes = vec![RouteRule {
+        methods: "GET".into(),
+        path_pattern: "/read/*".into(),
+        upstreams: vec!["1".into()],
+        timeout: Some(1),
+    }];
+    assert_eq!(check_service(s.clone()), Ok(()));
+    s.routes[0].timeout = Some(0);
+    assert!(matches!(
+        check_service(s.clone()),
+        Err(ConfigError::InvalidRoute(_, 0, _))
+    ));
+    s.routes[0].timeout = None;
+    s.routes[0].upstreams = vec!["2".into()];
+    assert!(matches!(
+        check_service(s.clone()),
+        Err(ConfigError::InvalidRoute(_, 0, _))
+    ));
+    s.routes[0].upstreams.clear();
+    assert!(matches!(
+        check_service(s.clone()),
+        Err(ConfigError::InvalidRoute(_, 0, _))
+    ));
+    s.routes[0].upstreams = vec!["1".into()];
+    s.routes[0].path_pattern = "/read/[".into();
+    assert!(matches!(
+        check_service(s),
+        Err(ConfigError::InvalidRoute(_, 0, _))
+    ));
+
+    let mut s = base.clone();
+    let version = s.upstreams[0].version.clone();
+    s.traffic_split = Some(TrafficSplit {
+        versions: vec![VersionShare {
+            version: version.clone(),
+            percent: 100,
+        }],
+        sticky_client: false,
+    });
+    assert_eq!(check_service(s.clone()), Ok(()));
+    let split = s.traffic_split.as_mut().unwrap();
+    split.versions[0].percent = 90;
+    assert!(matches!(
+        check_service(s.clone()),
+        Err(ConfigError::InvalidTrafficSplit(..))
+    ));
+    let split = s.traffic_split.as_mut().unwrap();
+    split.versions[0].percent = 50;
+    split.versions.push(VersionShare {
+        version: format!("{}-canary", version),
+        percent: 50,
+    });
+    assert!(matches!(
+        check_service(s),
+        Err(ConfigError::InvalidTrafficSplit(..))
+    ));
+
+    let mut s = base.clone();
+    s.version_override = Some(VersionOverride {
+        header: "X-Canary".into(),
+        cookie: String::new(),
+        clients: vec!["*".into()],
+    });
+    assert_eq!(check_service(s.clone()), Ok(()));
+    s.version_override.as_mut().unwrap().header = "X Canary".into();
+    assert!(matches!(
+        check_service(s.clone()),
+        Err(ConfigError::InvalidVersionOverride(..))
+    ));
+    s.version_override.as_mut().unwrap().header.clear();
+    assert!(matches!(
+        check_service(s),
+        Err(ConfigError::InvalidVersionOverride(..))
+    ));
+
+    let mut s = base.clone();
+    s.mirror = Some(MirrorSetting {
+        target: "http://shadow.internal:8080/".into(),
+        percent: 10,
+        max_body: 0,
+    });
+    assert_eq!(check_service(s.clone()), Ok(()));
+    s.mirror.as_mut().unwrap().percent = 101;
+    assert!(matches!(
+        check_service(s.clone()),
+        Err(ConfigError::InvalidMirror(..))
+    ));
+    s.mirror.as_mut().unwrap().percent = 10;
+    s.mirror.as_mut().unwrap().target = "shadow.internal".into();
+    assert!(matches!(
+        check_service(s),
+        Err(ConfigError::InvalidMirror(..))
+    ));
+
+    let fault = FaultInjectionSetting {
+        abort_percent: 10,
+        abort_status: 503,
+        delay_percent: 0,
+        delay_ms: 0,
+    };
+    let mut s = base.clone();
+    s.filters.push(FilterSetting::FaultInjection(fault.clone()));
+    assert_eq!(check_service(s), Ok(()));
+    let mut s = base.clone();
+    s.filters
+        .push(FilterSetting::FaultInjection(FaultInjectionSetting {
+            abort_status: 700,
+            ..fault.clone()
+        }));
+    assert!(matches!(
+        check_service(s),
+        Err(ConfigError::InvalidFaultInjection(..))
+    ));
+    let mut s = base.clone();
+    s.filters
+        .push(FilterSetting::FaultInjection(FaultInjectionSetting {
+            delay_percent: 150,
+            ..fault
+        }));
+    assert!(matches!(
+        check_service(s),
+        Err(ConfigError::InvalidFaultInjection(..))
+    ));
+
+    let mut s = base.clone();
+    s.retry = Some(RetrySetting {
+        attempts: 2,
+        budget_percent: Some(20),
+        min_retries: None,
+    });
+    assert_eq!(check_service(s.clone()), Ok(()));
+    s.retry.as_mut().unwrap().budget_percent = Some(200_000);
+    assert!(matches!(
+        check_service(s),
+        Err(ConfigError::InvalidRetry(..))
+    ));
+
+    let outlier = OutlierSetting {
+        error_percent: 50,
+        window: 10,
+        min_requests: 0,
+        cooldown: 30,
+        ramp_up: 0,
+        max_ejection_percent: 0,
+    };
+    let mut s = base.clone();
+    s.outlier = Some(outlier.clone());
+    assert_eq!(check_service(s), Ok(()));
+    for invalid in [
+        OutlierSetting {
+            error_percent: 0,
+            ..outlier.clone()
+        },
+        OutlierSetting {
+            window: 0,
+            ..outlier.clone()
+        },
+        OutlierSetting {
+            max_ejection_percent: 101,
+            ..outlier.clone()
+        },
+    ] {
+        let mut s = base.clone();
+        s.outlier = Some(invalid);
+        assert!(matches!(
+            check_service(s),
+            Err(ConfigError::InvalidOutlier(_, _))
+        ));
+    }
+}
+
+#[test]
+fn test_invalid_client() {
+    let (_, mut client) = sample();
+    client.client_id = String::new();
+    assert_eq!(
+        validate_update(&ConfigUpdate::ClientUpdate(client)),
+        Err(ConfigError::EmptyClientId)
+    );
+}
+
+// collect updates until config ready
+async fn load(mut source: ConfigSource) -> Vec<ConfigUpdate> {
+    load_next(&mut source).await
+}
+
+async fn load_next(source: &mut ConfigSource) -> Vec<ConfigUpdate> {
+    let loading = async {
+        let mut updates = Vec::new();
+        while let Some(u) = source.next().await {
+            if let ConfigUpdate::ConfigReady(_) = u {
+                break;
+            }
+            updates.push(u);
+        }
+        updates
+    };
+    tokio::time::timeout(Duration::from_secs(5), loading)
+        .await
+        .unwrap()
+}
+
+fn ids(updates: &[ConfigUpdate]) -> Vec<&str> {
+    updates
+        .iter()
+        .filter_map(|u| match u {
+            ConfigUpdate::ServiceUpdate(s) => Some(s.service_id.as_str()),
+            ConfigUpdate::ClientUpdate(c) => Some(c.client_id.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn entities(updates: &[ConfigUpdate]) -> (Vec<ServiceInfo>, Vec<ClientInfo>) {
+    let mut services = Vec::new();
+    let mut clients = Vec::new();
+    for u in updates {
+        match u {
+            ConfigUpdate::ServiceUpdate(s) => services.push(s.clone()),
+            ConfigUpdate::ClientUpdate(c) => clients.push(c.clone()),
+            _ => {}
+        }
+    }
+    (services, clients)
+}
+
+#[tokio::test]
+async fn test_source_rejects_invalid() {
+    let source = "file:///tests/invalid_config.yaml".to_string();
+
+    let updates = load(ConfigSource::new(source.clone())).await;
+    assert_eq!(ids(&updates), vec!["test/valid", "test/client"]);
+
+    let updates = load(ConfigSource::unvalidated(source)).await;
+    assert_eq!(
+        ids(&updates),
+        vec![
+            "test/valid",
+            "test/bad-path",
+            "test/no-upstream",
+            "test/client",
+            ""
+        ]
+    );
+}
+
+#[test]
+fn test_interpolate_env() {
+    std::env::set_var("HYPERAPI_TEST_INTERPOLATE", "secret");
+    std::env::set_var("HYPERAPI_TEST_INTERPOLATE_EMPTY", "");
+    std::env::remove_var("HYPERAPI_TEST_INTERPOLATE_UNSET");
+
+    let cases = [
+        ("plain", "plain"),
+        ("${HYPERAPI_TEST_INTERPOLATE}", "secret"),
+        ("a-${HYPERAPI_TEST_INTERPOLATE}-b", "a-secret-b"),
+        ("${HYPERAPI_TEST_INTERPOLATE:-default}", "secret"),
+        ("${HYPERAPI_TEST_INTERPOLATE_UNSET:-default}", "default"),
+        ("${HYPERAPI_TEST_INTERPOLATE_UNSET:-}", ""),
+        ("${HYPERAPI_TEST_INTERPOLATE_EMPTY:-default}", "default"),
+        ("${HYPERAPI_TEST_INTERPOLATE_EMPTY}", ""),
+        (
+            "$${HYPERAPI_TEST_INTERPOLATE}",
+            "${HYPERAPI_TEST_INTERPOLATE}",
+        ),
+        ("cost $5 $$", "cost $5 $"),
+    ];
+    for (input, expected) in cases {
+        assert_eq!(interpolate_env(input).unwrap(), expected, "{}", input);
+    }
+
+    match interpolate_env("${HYPERAPI_TEST_INTERPOLATE_UNSET}") {
+        Err(FileConfigError::MissingEnv(name)) => {
+            assert_eq!(name, "HYPERAPI_TEST_INTERPOLATE_UNSET")
+        }
+        r => panic!("unexpected {:?}", r),
+    }
+    assert!(matches!(
+        interpolate_env("${HYPERAPI_TEST_INTERPOLATE"),
+        Err(FileConfigError::UnclosedEnv(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_source_interpolates_env() {
+    std::env::set_var("HYPERAPI_TEST_APP_KEY", "env-app-key");
+    std::env::remove_var("HYPERAPI_TEST_UPSTREAM_HOST");
+
+    let updates = load(ConfigSource::new("file:///tests/env_config.yaml".into())).await;
+    match &updates[..] {
+        [ConfigUpdate::ServiceUpdate(s), ConfigUpdate::ClientUpdate(c)] => {
+            assert_eq!(s.upstreams[0].target, "http://127.0.0.1:54320/");
+            assert_eq!(s.upstreams[0].version, "${HYPERAPI_TEST_VERSION}");
+            assert_eq!(c.app_key, "env-app-key");
+        }
+        u => panic!("unexpected updates {:?}", u),
+    }
+}
+
+#[tokio::test]
+async fn test_source_sample_config() {
+    let updates = load(ConfigSource::new("file:///tests/sample_config.yaml".into())).await;
+    assert!(ids(&updates).contains(&"test/regex"));
+    assert!(ids(&updates).contains(&"test/client"));
+}
+
+#[test]
+fn test_format_from_path() {
+    assert_eq!(ConfigFormat::from_path("conf/a.json"), ConfigFormat::Json);
+    assert_eq!(ConfigFormat::from_path("conf/a.TOML"), ConfigFormat::Toml);
+    assert_eq!(ConfigFormat::from_path("conf/a.yml"), ConfigFormat::Yaml);
+    assert_eq!(ConfigFormat::from_path("conf/a.conf"), ConfigFormat::Yaml);
+    assert_eq!(ConfigFormat::from_path("conf/config"), ConfigFormat::Yaml);
+}
+
+#[tokio::test]
+async fn test_source_formats() {
+    let yaml = load(ConfigSource::new("file:///tests/format_config.yaml".into())).await;
+    assert_eq!(ids(&yaml), vec!["test/format", "test/format-client"]);
+    let expected = entities(&yaml);
+    assert_eq!(expected.0[0].upstreams[0].request_timeout, Some(2));
+    assert_eq!(expected.1[0].services["test/format"], "Default");
+    for source in ["tests/format_config.json", "tests/format_config.toml"] {
+        let updates = load(ConfigSource::new(format!("file:///{}", source))).await;
+        assert_eq!(entities(&updates), expected, "{}", source);
+    }
+}
+
+fn service_yaml(id: &str, timeout: u32, upstreams: &str) -> String {
+    format!(
+        r#"
+  - service_id: {id}
+    path: /{id}
+    protocol: http
+    auth:
+      type: AppKey
+    timeout: {timeout}
+    load_balance: conn
+    filters: []
+    sla: []
+    upstreams: {upstreams}"#,
+        id = id,
+        timeout = timeout,
+        upstreams = upstreams
+    )
+}
+
+const UPSTREAMS: &str = r#"
+      - id: "1"
+        target: "http://127.0.0.1:54320/"
+        max_conn: 100
+        weight: 100
+        version: "1.0"
+        error_threshold: 10
+        error_reset: 60
+        retry_delay: 10"#;
+
+const CLIENTS: &str = r#"
+clients:
+  - client_id: test/client
+    app_key: 9cf3319cbd254202cf882a79a755ba6e
+    pub_key: ""
+    ip_whitelist: []
+    services: {}
+"#;
+
+// updates within a debounced reload, none if reload is rejected
+async fn next_updates(source: &mut ConfigSource) -> Vec<ConfigUpdate> {
+    let mut updates = Vec::new();
+    while let Ok(Some(u)) = tokio::time::timeout(Duration::from_secs(3), source.next()).await {
+        updates.push(u);
+    }
+    updates
+}
+
+#[tokio::test]
+async fn test_file_reload() {
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("reload_config.yaml");
+    let write = |content: String| {
+        // save by rename like editors do
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, content).unwrap();
+        std::fs::rename(&tmp, &path).unwrap();
+    };
+    let config = |services: &[String]| format!("services:{}{}", services.concat(), CLIENTS);
+
+    write(config(&[
+        service_yaml("a", 3, UPSTREAMS),
+        service_yaml("b", 3, UPSTREAMS),
+    ]));
+    let mut source = ConfigSource::new(format!("file:///{}", path.display()));
+    let updates = load_next(&mut source).await;
+    assert_eq!(ids(&updates), vec!["a", "b", "test/client"]);
+
+    // partial write
+    std::fs::write(&path, "services:\n  - service_id: a\n    path: /a\n").unwrap();
+    assert!(next_updates(&mut source).await.is_empty());
+
+    // invalid service rejects the whole file
+    write(config(&[
+        service_yaml("a", 5, UPSTREAMS),
+        service_yaml("c", 3, "[]"),
+    ]));
+    assert!(next_updates(&mut source).await.is_empty());
+
+    // only changed entries
+    write(config(&[
+        service_yaml("a", 5, UPSTREAMS),
+        service_yaml("c", 3, UPSTREAMS),
+    ]));
+    let updates = next_updates(&mut source).await;
+    match &updates[..] {
+        [ConfigUpdate::ServiceUpdate(a), ConfigUpdate::ServiceUpdate(c), ConfigUpdate::ServiceRemove(b)] =>
+        {
+            assert_eq!((a.service_id.as_str(), a.timeout), ("a", 5));
+            assert_eq!(c.service_id, "c");
+            assert_eq!(b, "b");
+        }
+        u => panic!("unexpected updates {:?}", u),
+    }
+}
+
+#[tokio::test]
+async fn test_forced_reload() {
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("forced_config.yaml");
+    let config = |services: &[String]| format!("services:{}{}", services.concat(), CLIENTS);
+    std::fs::write(&path, config(&[service_yaml("a", 3, UPSTREAMS)])).unwrap();
+    let mut source = ConfigSource::new(format!("file:///{}", path.display()));
+    load_next(&mut source).await;
+    let reload = source.reloader().unwrap();
+
+    // unchanged file
+    assert_eq!(reload.reload().await.unwrap(), ReloadSummary::default());
+    assert!(next_updates(&mut source).await.is_empty());
+
+    // changes are emitted once, whether picked up by the reload or the watcher
+    std::fs::write(
+        &path,
+        config(&[
+            service_yaml("a", 5, UPSTREAMS),
+            service_yaml("b", 3, UPSTREAMS),
+        ]),
+    )
+    .unwrap();
+    let summary = reload.reload().await.unwrap();
+    assert_eq!(summary.services_updated, ["a"]);
+    assert_eq!(summary.services_added, ["b"]);
+    assert!(summary.errors.is_empty());
+    match &next_updates(&mut source).await[..] {
+        [ConfigUpdate::ServiceUpdate(a), ConfigUpdate::ServiceUpdate(b)] => {
+            assert_eq!((a.timeout, b.service_id.as_str()), (5, "b"))
+        }
+        u => panic!("unexpected updates {:?}", u),
+    }
+
+    // invalid file is reported, nothing changes
+    std::fs::write(&path, config(&[service_yaml("a", 5, "[]")])).unwrap();
+    let summary = reload.reload().await.unwrap();
+    assert_eq!(summary.errors.len(), 1, "{:?}", summary);
+    assert!(summary.services_removed.is_empty());
+    assert!(next_updates(&mut source).await.is_empty());
+
+    assert!(ConfigSource::channel().1.reloader().is_none());
+}
+
+#[tokio::test]
+async fn test_source_skips_unchanged_service() {
+    let (tx, mut source) = ConfigSource::channel();
+    let (service, _) = sample();
+    let mut changed = service.clone();
+    changed.timeout += 1;
+
+    let sent = vec![
+        ConfigUpdate::ServiceUpdate(service.clone()),
+        ConfigUpdate::ServiceUpdate(service.clone()),
+        ConfigUpdate::ServiceUpdate(changed.clone()),
+        ConfigUpdate::ServiceUpdate(changed),
+        ConfigUpdate::ServiceRemove("test/unknown".into()),
+        ConfigUpdate::ServiceRemove(service.service_id.clone()),
+        ConfigUpdate::ServiceUpdate(service),
+    ];
+    for u in sent {
+        tx.send(u).await.unwrap();
+    }
+    drop(tx);
+
+    let mut received = Vec::new();
+    while let Some(u) = source.next().await {
+        received.push(match u {
+            ConfigUpdate::ServiceUpdate(s) => format!("update {} {}", s.service_id, s.timeout),
+            ConfigUpdate::ServiceRemove(sid) => format!("remove {}", sid),
+            u => panic!("unexpected update {:?}", u),
+        });
+    }
+    assert_eq!(
+        received,
+        vec![
+            "update test/valid 3",
+            "update test/valid 4",
+            "remove test/valid",
+            "update test/valid 3",
+        ]
+    );
+}
+
+#[test]
+fn test_sync_state_resync() {
+    let (service, client) = sample();
+    let mut other = service.clone();
+    other.service_id = "test/other".into();
+    let mut state = SyncState::default();
+
+    state.start();
+    state.track(&ConfigUpdate::ServiceUpdate(service.clone()));
+    state.track(&ConfigUpdate::ServiceUpdate(other));
+    state.track(&ConfigUpdate::ClientUpdate(client));
+    assert!(state.finish().is_empty());
+    // watched removal is not removed again
+    state.track(&ConfigUpdate::ServiceRemove("test/other".into()));
+
+    // reconnected, client was deleted while disconnected
+    state.start();
+    state.track(&ConfigUpdate::ServiceUpdate(service));
+    let removed: Vec<_> = state
+        .finish()
+        .into_iter()
+        .map(|u| format!("{:?}", u))
+        .collect();
+    assert_eq!(removed, vec![r#"ClientRemove("test/client")"#]);
+
+    // no full sync in progress
+    assert!(state.finish().is_empty());
+}
+
+#[tokio::test]
+async fn test_ws_reconnect_resync() {
+    use async_tungstenite::tungstenite::Message;
+    use futures::SinkExt;
+
+    let (service, _) = sample();
+    let mut other = service.clone();
+    other.service_id = "test/other".into();
+    let rounds = vec![
+        vec![
+            ConfigUpdate::ServiceUpdate(service.clone()),
+            ConfigUpdate::ServiceUpdate(other),
+            ConfigUpdate::ConfigReady(true),
+        ],
+        vec![
+            ConfigUpdate::ServiceUpdate(service),
+            ConfigUpdate::ConfigReady(true),
+        ],
+    ];
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        // send a full config and close for each connection
+        for updates in rounds {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = async_tungstenite::tokio::accept_async(stream)
+                .await
+                .unwrap();
+            for u in updates {
+                let msg = serde_json::to_string(&u).unwrap();
+                ws.send(Message::Text(msg)).await.unwrap();
+            }
+            ws.close(None).await.unwrap();
+        }
+    });
+
+    let mut source = ConfigSource::new(format!("ws://{}/", addr));
+    let first = load_next(&mut source).await;
+    assert_eq!(ids(&first), vec!["test/valid", "test/other"]);
+    // unchanged service is skipped after reconnect, deleted one is removed
+    let second = load_next(&mut source).await;
+    assert_eq!(format!("{:?}", second), r#"[ServiceRemove("test/other")]"#);
+}
+
+type Headers = Vec<(String, String)>;
+
+// reject first connection with 401, then record handshake headers and send config ready
+#[allow(clippy::result_large_err)] // error response type of tungstenite callback
+async fn auth_server() -> (std::net::SocketAddr, tokio::sync::mpsc::Receiver<Headers>) {
+    use async_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+    use async_tungstenite::tungstenite::Message;
+    use futures::SinkExt;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = tokio::sync::mpsc::channel(4);
+    tokio::spawn(async move {
+        for round in 0.. {
+            let (stream, _) = listener.accept().await.unwrap();
+            let headers = tx.clone();
+            let callback = move |req: &Request, resp: Response| {
+                let h = req
+                    .headers()
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_str().unwrap().to_string()))
+                    .collect();
+                headers.try_send(h).unwrap();
+                if round == 0 {
+                    let mut err = ErrorResponse::new(None);
+                    *err.status_mut() = 401u16.try_into().unwrap();
+                    Err(err)
+                } else {
+                    Ok(resp)
+                }
+            };
+            let accepted = async_tungstenite::tokio::accept_hdr_async(stream, callback).await;
+            if let Ok(mut ws) = accepted {
+                let ready = serde_json::to_string(&ConfigUpdate::ConfigReady(true)).unwrap();
+                ws.send(Message::Text(ready)).await.unwrap();
+            }
+        }
+    });
+    (addr, rx)
+}
+
+fn header<'a>(headers: &'a Headers, name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+#[tokio::test]
+async fn test_ws_bearer_token() {
+    let (addr, mut handshakes) = auth_server().await;
+    let mut source = ConfigSource::new(format!("ws://:secret-token@{}/config", addr));
+    // retried after 401
+    assert!(load_next(&mut source).await.is_empty());
+    for _ in 0..2 {
+        let headers = handshakes.recv().await.unwrap();
+        assert_eq!(
+            header(&headers, "authorization"),
+            Some("Bearer secret-token")
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_ws_hmac_signature() {
+    use ring::hmac;
+
+    let (addr, mut handshakes) = auth_server().await;
+    let url = format!("ws://gateway-1:shared-secret@{}/config?auth=hmac", addr);
+    let mut source = ConfigSource::new(url);
+    assert!(load_next(&mut source).await.is_empty());
+
+    let headers = handshakes.recv().await.unwrap();
+    assert_eq!(header(&headers, "authorization"), None);
+    assert_eq!(header(&headers, "x-hyperapi-key"), Some("gateway-1"));
+    let timestamp = header(&headers, "x-hyperapi-timestamp").unwrap();
+    let signature = base64::decode(header(&headers, "x-hyperapi-signature").unwrap()).unwrap();
+    let key = hmac::Key::new(hmac::HMAC_SHA256, b"shared-secret");
+    let message = format!("{}\n/config?auth=hmac", timestamp);
+    assert!(hmac::verify(&key, message.as_bytes(), &signature).is_ok());
+}
+
+#[tokio::test]
+async fn test_source_resolves_dns_upstream() {
+    let (tx, mut source) = ConfigSource::channel();
+    let (mut service, _) = sample();
+    let id = service.upstreams[0].id.clone();
+    service.upstreams[0].target = "http://localhost:18999/api".into();
+    service.upstreams[0].dns_refresh = Some(60);
+    tx.send(ConfigUpdate::ServiceUpdate(service)).await.unwrap();
+
+    let resolved = match source.next().await {
+        Some(ConfigUpdate::ServiceUpdate(s)) => s,
+        u => panic!("unexpected update {:?}", u),
+    };
+    let upstream = resolved
+        .upstreams
+        .iter()
+        .find(|u| u.id == format!("{}-127.0.0.1:18999", id))
+        .expect("upstream of resolved address");
+    assert_eq!(upstream.target, "http://127.0.0.1:18999/api");
+}
@@ -0,0 +1,158 @@
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use hyperapi::config::{consul_config, ConfigUpdate, SyncState};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+// consul kv, blocking queries fail while `down` is set
+#[derive(Clone, Default)]
+struct FakeConsul {
+    kv: Arc<Mutex<BTreeMap<String, String>>>,
+    index: Arc<AtomicU64>,
+    down: Arc<AtomicBool>,
+}
+
+impl FakeConsul {
+    fn put(&self, key: &str, value: serde_json::Value) {
+        self.kv
+            .lock()
+            .unwrap()
+            .insert(key.into(), value.to_string());
+        self.index.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn delete(&self, key: &str) {
+        self.kv.lock().unwrap().remove(key);
+        self.index.fetch_add(1, Ordering::SeqCst);
+    }
+
+    async fn handle(self, req: Request<Body>) -> Response<Body> {
+        if self.down.load(Ordering::SeqCst) {
+            return Response::builder().status(500).body(Body::empty()).unwrap();
+        }
+        let blocking = req.uri().query().unwrap_or_default().contains("index=");
+        if blocking {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let prefix = req.uri().path().trim_start_matches("/v1/kv/").to_string();
+        let entries: Vec<_> = self
+            .kv
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, v)| json!({ "Key": k, "Value": base64::encode(v) }))
+            .collect();
+        Response::builder()
+            .header("X-Consul-Index", self.index.load(Ordering::SeqCst).max(1))
+            .body(Body::from(json!(entries).to_string()))
+            .unwrap()
+    }
+
+    async fn start(&self) -> u16 {
+        let consul = self.clone();
+        let make_svc = make_service_fn(move |_| {
+            let consul = consul.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let consul = consul.clone();
+                    async move { Ok::<_, Infallible>(consul.handle(req).await) }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let port = server.local_addr().port();
+        tokio::spawn(server);
+        port
+    }
+}
+
+fn service(id: &str) -> serde_json::Value {
+    json!({
+        "service_id": id,
+        "path": format!("/{}", id),
+        "protocol": "http",
+        "auth": { "type": "None" },
+        "timeout": 3,
+        "load_balance": "random",
+        "filters": [],
+        "sla": [],
+        "upstreams": [],
+    })
+}
+
+fn client(id: &str) -> serde_json::Value {
+    json!({
+        "client_id": id,
+        "app_key": "key",
+        "pub_key": "",
+        "ip_whitelist": [],
+        "services": {},
+    })
+}
+
+// updates up to the next ConfigReady, as "+service/a", "-client/c", sorted
+async fn next_sync(rx: &mut mpsc::Receiver<ConfigUpdate>) -> Vec<String> {
+    let mut updates = Vec::new();
+    loop {
+        let update = tokio::time::timeout(Duration::from_secs(3), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        updates.push(match update {
+            ConfigUpdate::ServiceUpdate(s) => format!("+service/{}", s.service_id),
+            ConfigUpdate::ServiceRemove(sid) => format!("-service/{}", sid),
+            ConfigUpdate::ClientUpdate(c) => format!("+client/{}", c.client_id),
+            ConfigUpdate::ClientRemove(cid) => format!("-client/{}", cid),
+            ConfigUpdate::ConfigReady(_) => break,
+        });
+    }
+    updates.sort();
+    updates
+}
+
+#[tokio::test]
+async fn test_consul_resync() {
+    let consul = FakeConsul::default();
+    consul.put("gw/services/a", service("a"));
+    consul.put("gw/services/b", service("b"));
+    consul.put("gw/clients/c", client("c"));
+    let port = consul.start().await;
+    let source = format!("consul://127.0.0.1:{}/gw", port);
+
+    let (tx, mut rx) = mpsc::channel(16);
+    let watch = {
+        let (source, tx) = (source.clone(), tx.clone());
+        tokio::spawn(async move {
+            let mut state = SyncState::default();
+            let result = consul_config::watch_config(source, tx, &mut state).await;
+            (result.is_ok(), state)
+        })
+    };
+    assert_eq!(
+        next_sync(&mut rx).await,
+        ["+client/c", "+service/a", "+service/b"]
+    );
+
+    // lost while entries are deleted
+    consul.down.store(true, Ordering::SeqCst);
+    let (loaded, mut state) = watch.await.unwrap();
+    assert!(loaded);
+    consul.delete("gw/services/a");
+    consul.delete("gw/clients/c");
+    consul.down.store(false, Ordering::SeqCst);
+
+    let watch = tokio::spawn(async move {
+        let _ = consul_config::watch_config(source, tx, &mut state).await;
+    });
+    assert_eq!(
+        next_sync(&mut rx).await,
+        ["+service/b", "-client/c", "-service/a"]
+    );
+    watch.abort();
+}
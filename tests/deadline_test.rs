@@ -0,0 +1,127 @@
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Request, Response, Server};
+use hyperapi::config::ConfigSource;
+use hyperapi::proxy::{GatewayServer, GatewaySettings};
+use serde_json::Value;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// upstream answering after the delay in path, like /delay/2000
+async fn start_upstream() -> SocketAddr {
+    let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr();
+    let make_svc = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+            let ms: u64 = req
+                .uri()
+                .path()
+                .rsplit('/')
+                .next()
+                .unwrap()
+                .parse()
+                .unwrap_or(0);
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+            Ok::<_, Infallible>(Response::new(Body::from("pong")))
+        }))
+    });
+    tokio::spawn(Server::builder(incoming).serve(make_svc));
+    addr
+}
+
+async fn start_gateway(upstream: SocketAddr, request_timeout: Duration) -> SocketAddr {
+    let service = |id: &str, filters: &str| {
+        format!(
+            r#"
+  - service_id: {id}
+    path: /{id}
+    protocol: http
+    auth:
+      type: None
+    timeout: 3
+    load_balance: random
+    filters: {filters}
+    sla: []
+    upstreams:
+      - id: {id}1
+        target: "http://{upstream}/"
+        max_conn: 10
+        weight: 1
+        version: "1"
+        error_threshold: 0
+        error_reset: 60
+        retry_delay: 10
+"#
+        )
+    };
+    let config = format!(
+        "clients: []\nservices:{}{}",
+        service("direct", "[]"),
+        service(
+            "delayed",
+            "[{type: FaultInjection, setting: {delay_percent: 100, delay_ms: 2000}}]"
+        ),
+    );
+    let path = std::env::temp_dir().join(format!("hyperapi_deadline_{}.yaml", upstream.port()));
+    std::fs::write(&path, config).unwrap();
+
+    let settings = GatewaySettings {
+        enable_fault_injection: true,
+        ..GatewaySettings::default()
+    };
+    let source = ConfigSource::new(path.to_string_lossy().into());
+    let mut gateway = GatewayServer::with_settings(source, settings);
+    gateway.request_timeout = Some(request_timeout);
+    while *gateway.status.lock().unwrap() == 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // let middleware workers pick up the config
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr();
+    let make_svc = make_service_fn(move |_| {
+        let handler = gateway.make_service();
+        async move { Ok::<_, Infallible>(handler) }
+    });
+    tokio::spawn(Server::builder(incoming).serve(make_svc));
+    addr
+}
+
+// status, error code and elapsed time of a request
+async fn call(gateway: SocketAddr, path: &str) -> (u16, Option<String>, Duration) {
+    let start = Instant::now();
+    let uri = format!("http://{}{}", gateway, path).parse().unwrap();
+    let resp = Client::new().get(uri).await.unwrap();
+    let status = resp.status().as_u16();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let code = serde_json::from_slice::<Value>(&body)
+        .ok()
+        .and_then(|v| v["code"].as_str().map(String::from));
+    (status, code, start.elapsed())
+}
+
+#[tokio::test]
+async fn test_request_deadline() {
+    let upstream = start_upstream().await;
+    let gateway = start_gateway(upstream, Duration::from_millis(500)).await;
+
+    let (status, _, _) = call(gateway, "/direct/delay/0").await;
+    assert_eq!(status, 200);
+
+    // well before the service timeout of 3s
+    let (status, _, elapsed) = call(gateway, "/direct/delay/2000").await;
+    assert_eq!(status, 504);
+    assert!(elapsed < Duration::from_millis(1500), "{:?}", elapsed);
+
+    // time spent before proxying counts too
+    let (status, code, elapsed) = call(gateway, "/delayed/delay/0").await;
+    assert_eq!(status, 504);
+    assert_eq!(code.as_deref(), Some("deadline_exceeded"));
+    assert!(elapsed < Duration::from_millis(1500), "{:?}", elapsed);
+
+    // middlewares keep serving after abandoned requests
+    let (status, _, _) = call(gateway, "/direct/delay/0").await;
+    assert_eq!(status, 200);
+}
@@ -0,0 +1,94 @@
+use hyper::Request;
+use hyperapi::auth::{AuthRequest, AuthService, GatewayAuthError};
+use hyperapi::config::{ConfigUpdate, ServiceInfo};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+fn service(id: &str) -> ServiceInfo {
+    let config = format!(
+        r#"
+service_id: {id}
+path: /{id}
+protocol: http
+auth:
+  type: None
+timeout: 1
+load_balance: random
+upstreams:
+  - id: "1"
+    target: "http://127.0.0.1:1/"
+    max_conn: 10
+    version: "1.0"
+    weight: 1
+    error_threshold: 0
+    error_reset: 60
+    retry_delay: 10
+filters: []
+sla: []
+rewrite:
+  mode: keep
+"#
+    );
+    serde_yaml::from_str(&config).unwrap()
+}
+
+// auth service routing unmatched paths to `default_service`
+fn start_auth(
+    conf_tx: &broadcast::Sender<ConfigUpdate>,
+    default_service: Option<&str>,
+) -> mpsc::Sender<AuthRequest> {
+    let (auth_tx, auth_rx) = mpsc::channel(16);
+    let mut auth = AuthService::new(conf_tx.subscribe(), auth_rx);
+    auth.default_service = default_service.map(String::from);
+    tokio::spawn(async move { auth.start().await });
+    auth_tx
+}
+
+async fn route(
+    auth_tx: &mpsc::Sender<AuthRequest>,
+    path: &'static str,
+) -> Result<String, GatewayAuthError> {
+    let (head, _) = Request::get(path).body(()).unwrap().into_parts();
+    let (tx, rx) = oneshot::channel();
+    let _ = auth_tx.send(AuthRequest { head, result: tx }).await;
+    rx.await.unwrap().map(|(_, auth)| auth.service_id)
+}
+
+// service id picked for each path
+#[tokio::test]
+async fn test_default_service() {
+    let (conf_tx, _) = broadcast::channel(16);
+    let without = start_auth(&conf_tx, None);
+    let fallback = start_auth(&conf_tx, Some("fallback"));
+    let missing = start_auth(&conf_tx, Some("missing"));
+    conf_tx
+        .send(ConfigUpdate::ServiceUpdate(service("api")))
+        .unwrap();
+    conf_tx
+        .send(ConfigUpdate::ServiceUpdate(service("fallback")))
+        .unwrap();
+    conf_tx
+        .send(ConfigUpdate::ServiceUpdate(service("removed")))
+        .unwrap();
+    conf_tx
+        .send(ConfigUpdate::ServiceRemove("removed".into()))
+        .unwrap();
+    // let auth services pick up the config
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert!(matches!(
+        route(&without, "/unknown/page").await,
+        Err(GatewayAuthError::UnknownService)
+    ));
+
+    assert_eq!(route(&fallback, "/api/users").await.unwrap(), "api");
+    assert_eq!(route(&fallback, "/unknown/page").await.unwrap(), "fallback");
+    assert_eq!(route(&fallback, "/").await.unwrap(), "fallback");
+    assert_eq!(route(&fallback, "/removed/page").await.unwrap(), "fallback");
+
+    // default service not configured
+    assert!(matches!(
+        route(&missing, "/unknown/page").await,
+        Err(GatewayAuthError::UnknownService)
+    ));
+}
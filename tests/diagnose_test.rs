@@ -0,0 +1,161 @@
+use hyperapi::diagnose::{diagnose, CheckStatus, DiagnoseReport};
+use std::net::SocketAddr;
+
+const CONFIG: &str = r#"
+clients: []
+services:
+  - service_id: test/diagnose
+    path: /diagnose
+    protocol: http
+    auth:
+      type: None
+    timeout: 3
+    load_balance: random
+    filters: []
+    sla: []
+    upstreams:
+      - id: diag1
+        target: "TARGET"
+        max_conn: 10
+        weight: 1
+        version: "1"
+        error_threshold: 0
+        error_reset: 60
+        retry_delay: 10
+UPSTREAM_EXTRA"#;
+
+const CA_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls/ca.pem");
+
+fn tls_upstream() -> SocketAddr {
+    let config = hyperapi::proxy::TlsConfigBuilder::new()
+        .cert(include_bytes!("tls/server.pem"))
+        .key(include_bytes!("tls/server.key"))
+        .build()
+        .unwrap();
+    let incoming =
+        hyper::server::conn::AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr();
+    let make_svc = hyper::service::make_service_fn(|_| async {
+        Ok::<_, std::convert::Infallible>(hyper::service::service_fn(|_req| async {
+            Ok::<_, std::convert::Infallible>(hyper::Response::new(hyper::Body::from("pong")))
+        }))
+    });
+    let acceptor = hyperapi::proxy::TlsAcceptor::new(config, incoming);
+    tokio::spawn(hyper::Server::builder(acceptor).serve(make_svc));
+    addr
+}
+
+// diagnose a config of one upstream, with extra upstream settings indented under it
+async fn run(name: &str, target: &str, extra: &str, ca_file: Option<&str>) -> DiagnoseReport {
+    let path = std::env::temp_dir().join(format!(
+        "hyperapi_diagnose_{}_{}.yaml",
+        name,
+        std::process::id()
+    ));
+    let extra: String = extra.lines().map(|l| format!("        {}\n", l)).collect();
+    let config = CONFIG
+        .replace("TARGET", target)
+        .replace("UPSTREAM_EXTRA", &extra);
+    std::fs::write(&path, config).unwrap();
+    let roots = ca_file.map(|f| hyperapi::middleware::load_ca_file(f).unwrap());
+    diagnose(path.to_str().unwrap().into(), roots.as_ref()).await
+}
+
+fn status(report: &DiagnoseReport, check: &str) -> Option<CheckStatus> {
+    report
+        .results
+        .iter()
+        .find(|r| r.subject == "service test/diagnose upstream diag1" && r.check == check)
+        .map(|r| r.status)
+}
+
+#[tokio::test]
+async fn test_diagnose_upstream_tls() {
+    let addr = tls_upstream();
+    let by_ip = format!("https://127.0.0.1:{}/", addr.port());
+    let by_name = format!("https://localhost:{}/", addr.port());
+    let ca_file = format!("ca_file: {}", CA_FILE);
+    let named = format!("{}\ntls_server_name: localhost", ca_file);
+
+    // certificate is for localhost, signed by the test CA
+    let cases = [
+        ("untrusted", &by_name, "", None, CheckStatus::Fail),
+        ("ca_file", &by_name, ca_file.as_str(), None, CheckStatus::Ok),
+        ("gateway_ca", &by_name, "", Some(CA_FILE), CheckStatus::Ok),
+        ("ip", &by_ip, ca_file.as_str(), None, CheckStatus::Fail),
+        ("server_name", &by_ip, named.as_str(), None, CheckStatus::Ok),
+        (
+            "insecure",
+            &by_name,
+            "insecure_skip_verify: true",
+            None,
+            CheckStatus::Ok,
+        ),
+    ];
+    for (name, target, extra, gateway_ca, tls) in cases {
+        let report = run(name, target, extra, gateway_ca).await;
+        assert_eq!(
+            status(&report, "tcp"),
+            Some(CheckStatus::Ok),
+            "{}\n{}",
+            name,
+            report
+        );
+        assert_eq!(status(&report, "tls"), Some(tls), "{}\n{}", name, report);
+        if tls == CheckStatus::Ok {
+            assert_eq!(status(&report, "cert"), Some(CheckStatus::Ok), "{}", report);
+        }
+    }
+
+    let report = run("bad_ca", &by_name, "ca_file: /nonexistent/ca.pem", None).await;
+    assert_eq!(
+        status(&report, "tls"),
+        Some(CheckStatus::Fail),
+        "{}",
+        report
+    );
+}
+
+#[tokio::test]
+async fn test_diagnose_upstream_tcp() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let open = listener.local_addr().unwrap();
+    let closed = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let report = run("open", &format!("http://{}/", open), "", None).await;
+    assert!(!report.has_failure(), "{}", report);
+    assert_eq!(status(&report, "dns"), Some(CheckStatus::Ok), "{}", report);
+    assert_eq!(status(&report, "tcp"), Some(CheckStatus::Ok), "{}", report);
+    // plain http upstreams get no tls check
+    assert_eq!(status(&report, "tls"), None, "{}", report);
+    let loaded = report.results.iter().find(|r| r.subject == "config");
+    assert_eq!(
+        loaded.map(|r| r.status),
+        Some(CheckStatus::Ok),
+        "{}",
+        report
+    );
+
+    let report = run("closed", &format!("http://{}/", closed), "", None).await;
+    assert_eq!(report.count(CheckStatus::Fail), 1, "{}", report);
+    assert_eq!(
+        status(&report, "tcp"),
+        Some(CheckStatus::Fail),
+        "{}",
+        report
+    );
+    assert!(report.to_string().ends_with("1 failures"), "{}", report);
+
+    let report = run("unresolved", "http://nonexistent.invalid/", "", None).await;
+    assert_eq!(
+        status(&report, "dns"),
+        Some(CheckStatus::Fail),
+        "{}",
+        report
+    );
+    assert_eq!(status(&report, "tcp"), None, "{}", report);
+    drop(listener);
+}
@@ -0,0 +1,138 @@
+use hyper::{Body, Request, Response};
+use hyperapi::auth::AuthResponse;
+use hyperapi::config::{validate_service, ConfigError, ConfigUpdate, ErrorPage, ServiceInfo};
+use hyperapi::middleware::{
+    ErrorPageMiddleware, ErrorPages, Middleware, MwPostRequest, RequestContext,
+};
+use tokio::sync::oneshot;
+
+const SERVICE: &str = r#"
+service_id: test/pages
+path: /pages
+protocol: http
+auth:
+  type: None
+timeout: 3
+load_balance: random
+upstreams:
+  - id: "1"
+    target: "http://127.0.0.1:1/"
+    max_conn: 10
+    version: "1.0"
+    weight: 1
+    error_threshold: 0
+    error_reset: 60
+    retry_delay: 10
+error_pages:
+  - status: 503
+    body: "<h1>Unavailable</h1>"
+  - status: 404
+    content_type: application/problem+json
+    body: '{"title":"Not Found"}'
+filters: []
+sla: []
+"#;
+
+fn page(status: u16, content_type: &str, body: &str) -> ErrorPage {
+    ErrorPage {
+        status,
+        content_type: content_type.into(),
+        body: body.into(),
+        file: String::new(),
+    }
+}
+
+// body and content type after the middleware
+async fn serve(mw: &mut ErrorPageMiddleware, accept: &str, status: u16) -> (String, String) {
+    let mut request = Request::get("/pages/a").body(Body::empty()).unwrap();
+    if !accept.is_empty() {
+        request
+            .headers_mut()
+            .insert("accept", accept.parse().unwrap());
+    }
+    let auth = AuthResponse {
+        client_id: String::new(),
+        service_id: "test/pages".into(),
+        sla: String::new(),
+        service_filters: Vec::new(),
+        client_filters: Vec::new(),
+        middlewares: Vec::new(),
+    };
+    let response = Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(r#"{"error":"upstream"}"#))
+        .unwrap();
+    let (tx, rx) = oneshot::channel();
+    let task = MwPostRequest {
+        context: RequestContext::new(&request, &auth),
+        response,
+        service_filters: Vec::new(),
+        client_filters: Vec::new(),
+        result: tx,
+    };
+    mw.response(task).await;
+    let response = rx.await.unwrap().unwrap().response;
+    assert_eq!(response.status(), status);
+    let content_type = response.headers()["content-type"].to_str().unwrap().into();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (content_type, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn test_error_page_negotiation() {
+    let service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    validate_service(&service).unwrap();
+    let pages = ErrorPages::load(&[page(502, "", "<h1>Bad Gateway</h1>")]).unwrap();
+    let mut mw = ErrorPageMiddleware::new(pages);
+    mw.config_update(ConfigUpdate::ServiceUpdate(service));
+    let browser = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+
+    let (content_type, body) = serve(&mut mw, browser, 503).await;
+    assert_eq!(content_type, "text/html");
+    assert_eq!(body, "<h1>Unavailable</h1>");
+    assert_eq!(
+        serve(&mut mw, "text/*", 503).await.1,
+        "<h1>Unavailable</h1>"
+    );
+
+    // API clients keep the json error
+    for accept in ["", "*/*", "application/json", "text/html;q=0"] {
+        let (_, body) = serve(&mut mw, accept, 503).await;
+        assert_eq!(body, r#"{"error":"upstream"}"#, "{:?}", accept);
+    }
+    // json pages serve any client
+    let (content_type, body) = serve(&mut mw, "*/*", 404).await;
+    assert_eq!(content_type, "application/problem+json");
+    assert_eq!(body, r#"{"title":"Not Found"}"#);
+    assert_eq!(
+        serve(&mut mw, browser, 200).await.1,
+        r#"{"error":"upstream"}"#
+    );
+
+    // global pages for statuses without a service page
+    assert_eq!(serve(&mut mw, browser, 502).await.1, "<h1>Bad Gateway</h1>");
+    assert_eq!(serve(&mut mw, browser, 503).await.1, "<h1>Unavailable</h1>");
+    let mut without = ErrorPageMiddleware::default();
+    assert_eq!(
+        serve(&mut without, browser, 502).await.1,
+        r#"{"error":"upstream"}"#
+    );
+}
+
+#[test]
+fn test_invalid_error_page() {
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.error_pages = vec![page(302, "", "moved")];
+    assert!(matches!(
+        validate_service(&service),
+        Err(ConfigError::InvalidErrorPage(_, 302, _))
+    ));
+    let mut missing = page(500, "", "");
+    missing.file = "/nonexistent/500.html".into();
+    service.error_pages = vec![missing];
+    assert!(matches!(
+        validate_service(&service),
+        Err(ConfigError::InvalidErrorPage(_, 500, _))
+    ));
+}
@@ -0,0 +1,97 @@
+use hyper::{Body, Request};
+use hyperapi::auth::AuthResponse;
+use hyperapi::config::{ConfigUpdate, FaultInjectionSetting, FilterSetting, ServiceInfo};
+use hyperapi::middleware::{
+    FaultInjectionMiddleware, GatewayError, Middleware, MwNextAction, MwPreRequest, MwPreResponse,
+    RequestContext,
+};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+const SERVICE: &str = r#"
+service_id: test/fault
+path: /fault
+protocol: http
+auth:
+  type: None
+timeout: 1
+load_balance: random
+upstreams: []
+filters: []
+sla: []
+"#;
+
+async fn inject(
+    mw: &mut FaultInjectionMiddleware,
+    setting: FaultInjectionSetting,
+) -> Result<MwPreResponse, GatewayError> {
+    let request = Request::get("/fault/").body(Body::empty()).unwrap();
+    let auth = AuthResponse {
+        client_id: String::new(),
+        service_id: "test/fault".into(),
+        sla: String::new(),
+        service_filters: Vec::new(),
+        client_filters: Vec::new(),
+        middlewares: Vec::new(),
+    };
+    let (tx, rx) = oneshot::channel();
+    let task = MwPreRequest {
+        context: RequestContext::new(&request, &auth),
+        request,
+        service_filters: vec![FilterSetting::FaultInjection(setting)],
+        client_filters: Vec::new(),
+        result: tx,
+    };
+    mw.request(task).await;
+    rx.await.unwrap()
+}
+
+fn abort(status: u16) -> FaultInjectionSetting {
+    FaultInjectionSetting {
+        abort_percent: 100,
+        abort_status: status,
+        delay_percent: 0,
+        delay_ms: 0,
+    }
+}
+
+fn delay(ms: u64) -> FaultInjectionSetting {
+    FaultInjectionSetting {
+        abort_percent: 0,
+        abort_status: 0,
+        delay_percent: 100,
+        delay_ms: ms,
+    }
+}
+
+#[tokio::test]
+async fn test_fault_injection() {
+    let service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    let mut mw = FaultInjectionMiddleware::default();
+    mw.config_update(ConfigUpdate::ServiceUpdate(service.clone()));
+
+    // filters do nothing unless enabled
+    let resp = inject(&mut mw, abort(500)).await.unwrap();
+    assert!(matches!(resp.next, MwNextAction::Next(_)));
+    let mut mw = FaultInjectionMiddleware::new(true);
+    mw.config_update(ConfigUpdate::ServiceUpdate(service));
+
+    match inject(&mut mw, abort(0)).await.unwrap().next {
+        MwNextAction::Return(resp) => {
+            assert_eq!(resp.status(), 503);
+            assert_eq!(resp.headers()["x-fault-injected"], "abort");
+        }
+        MwNextAction::Next(_) => panic!("request not aborted"),
+    }
+
+    let start = Instant::now();
+    let resp = inject(&mut mw, delay(200)).await.unwrap();
+    assert!(matches!(resp.next, MwNextAction::Next(_)));
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    // delay over the service timeout times out at the timeout
+    let start = Instant::now();
+    let resp = inject(&mut mw, delay(60_000)).await;
+    assert!(matches!(resp, Err(GatewayError::TimeoutError)));
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
@@ -7,6 +7,8 @@ import asyncio
 
 gateway_port = 54321
 mock_port = 54320
+admin_port = 54322
+admin_token = "test-admin-token"
 
 
 @app.get("/test1")
@@ -28,6 +30,15 @@ async def test_appkey_service():
         request_header = received.headers
         assert request_header.get('X-TEST') == 'test-header'
         assert request_header.get('Authorization') is None
+        request_id = resp.headers.get('X-Request-Id')
+        assert request_id and request_header.get('X-Request-Id') == request_id
+        queue.task_done()
+
+        # test request id propagation
+        resp = await ac.get(url, headers={**headers, 'X-Request-Id': 'test-request-1'})
+        assert resp.headers.get('X-Request-Id') == 'test-request-1'
+        received = await queue.get()
+        assert received.headers.get('X-Request-Id') == 'test-request-1'
         queue.task_done()
 
         # test acl
@@ -58,6 +69,22 @@ async def test_appkey_service():
         resp = await ac.get(url, headers=headers)
         assert resp.status_code == 429
 
+        print('------------test path rewrite------------')
+        cases = [
+            ("/echo", "/echo/", ""),
+            ("/echo/", "/echo/", ""),
+            ("/echo?q=1", "/echo/", "q=1"),
+            ("/echo/a/b?q=1", "/echo/a/b", "q=1"),
+            ("/keep", "/echo/keep", ""),
+            ("/keep/a/b?q=1", "/echo/keep/a/b", "q=1"),
+            ("/regex/v1/a?q=1", "/echo/v2/a", "from=regex&q=1"),
+            ("/regex/other", "/echo/regex/other", ""),
+        ]
+        for url, path, query in cases:
+            resp = await ac.get(url, headers=headers)
+            assert resp.status_code == 200
+            assert resp.json() == {"path": path, "query": query}
+
     return {"result": "Pass"}
 
 
@@ -93,7 +120,9 @@ async def test_jwt_service():
             print(resp.headers)
         resp = await ac.post(url, headers=headers)  # CB is OPEN
         print(resp.headers)
-        assert resp.status_code == 502
+        assert resp.status_code == 503
+        assert resp.json() == {"error": "Service unavailable", "code": "circuit_open"}
+        assert 1 <= int(resp.headers["retry-after"]) <= 4
 
         print('wait retry delay, and failed')
         await asyncio.sleep(4)  # retry delay
@@ -103,7 +132,7 @@ async def test_jwt_service():
         print('go back to OPEN state')
         resp = await ac.post(url, headers=headers)
         print(resp.headers)
-        assert resp.status_code == 502
+        assert resp.status_code == 503
 
         print('wait retry delay, and success')
         await asyncio.sleep(4)  # retry delay
@@ -122,7 +151,7 @@ async def test_jwt_service():
         resps = await asyncio.gather(*reqs)
         print([r.content for r in resps])
         assert len([s for s in resps if s.status_code == 200]) == 10
-        assert len([s for s in resps if s.status_code == 502]) == 10
+        assert len([s for s in resps if s.status_code == 503]) == 10
 
     return {"result": "Pass"}
 
@@ -161,6 +190,93 @@ async def test_load_balance():
         print("all traffic goes to one upstream")
         assert counter.get('22') is None or counter.get('21') is None
 
+        print('------------test round robin lb------------')
+        url = "/lb_rr/error/200"
+        picked = []
+        for i in range(20):
+            resp = await ac.get(url, headers=headers)
+            assert resp.status_code == 200
+            picked.append(resp.headers.get('x-upstream-id'))
+        print(picked)
+        print("upstreams are picked in turn regardless of weight")
+        assert all(picked[i] != picked[i + 1] for i in range(len(picked) - 1))
+        assert picked.count('51') == picked.count('52') == 10
+
+        print('------------test smooth weighted round robin lb------------')
+        url = "/lb_wrr/error/200"
+        picked = []
+        for i in range(20):
+            resp = await ac.get(url, headers=headers)
+            assert resp.status_code == 200
+            picked.append(resp.headers.get('x-upstream-id'))
+        print(picked)
+        print("3:1 split, never the light upstream twice in a row")
+        assert picked.count('71') == 15 and picked.count('72') == 5
+        assert all(picked[i:i + 2] != ['72', '72'] for i in range(len(picked) - 1))
+
+        print('------------test json transform------------')
+        resp = await ac.get("/json/echo/users?page=1", headers=headers)
+        assert resp.status_code == 200
+        print(resp.json())
+        print("query dropped, path renamed to url")
+        assert resp.json() == {"url": "/echo/users"}
+        print("no route for DELETE")
+        resp = await ac.delete("/json/echo/users", headers=headers)
+        assert resp.status_code == 404
+
+        print('------------test traffic split------------')
+        for i in range(10):
+            resp = await ac.get("/canary/error/200", headers=headers)
+            assert resp.status_code == 200
+            assert resp.headers.get('x-upstream-version') == '2.0'
+            assert int(resp.headers['x-gateway-time-ms']) >= int(resp.headers['x-upstream-time-ms'])
+            assert resp.headers['server-timing'].startswith('Auth;dur=')
+        print("version forced by header, unknown version not found")
+        resp = await ac.get("/canary/error/200", headers={**headers, "X-Canary": "1.0"})
+        assert resp.status_code == 200
+        assert resp.headers.get('x-upstream-version') == '1.0'
+        resp = await ac.get("/canary/error/200", headers={**headers, "X-Canary": "9.9"})
+        assert resp.status_code == 404
+
+        print('------------test fault injection------------')
+        resp = await ac.get("/fault/error/200", headers=headers)
+        assert resp.status_code == 418
+        assert 'x-upstream-time-ms' not in resp.headers
+        assert resp.headers.get('x-fault-injected') == 'abort'
+        html = dict(headers, accept='text/html')
+        resp = await ac.get("/fault/error/200", headers=html)
+        assert resp.status_code == 418
+        assert resp.headers.get('content-type') == 'text/html'
+        assert resp.text == "<h1>I'm a teapot</h1>"
+
+        print('------------test maintenance------------')
+        resp = await ac.get("/maintenance/error/200", headers=headers)
+        assert resp.status_code == 503
+        assert resp.headers.get('retry-after') == '300'
+        assert 'Down for maintenance' in resp.text
+
+        print('------------test ip rate limit------------')
+        for i in range(5):
+            resp = await ac.get("/public/error/200")
+            assert resp.status_code == 200
+        resp = await ac.get("/public/error/200")
+        assert resp.status_code == 429
+        assert int(resp.headers.get('retry-after')) > 0
+
+        print('------------test sticky session------------')
+        url = "/lb_sticky/error/200"
+        resp = await ac.get(url, headers=headers)
+        assert resp.status_code == 200
+        assert 'set-cookie' in resp.headers
+        pinned = resp.headers.get('x-upstream-id')
+        for i in range(10):
+            resp = await ac.get(url, headers=headers)
+            assert resp.status_code == 200
+            assert resp.headers.get('x-upstream-id') == pinned
+            assert 'set-cookie' not in resp.headers
+        print("requests with cookie stay on upstream", pinned)
+        ac.cookies.clear()
+
         print('------------test connection based lb------------')
         url = "/lb_conn"
         concurrent = [runner(ac, url, headers, 50) for i in range(10)]
@@ -203,15 +319,84 @@ async def runner(ac, url, headers, counts):
     return counter
 
 
+def test_health_probe():
+    print("=============TESTING HEALTH PROBES=========================")
+    resp = httpx.get(f"http://localhost:{gateway_port}/healthz")
+    assert resp.status_code == 200
+    resp = httpx.get(f"http://localhost:{gateway_port}/readyz")
+    assert resp.status_code == 200
+    assert resp.text == "ready"
+    # probes are not proxied, no app key needed
+    resp = httpx.post(f"http://localhost:{gateway_port}/readyz")
+    assert resp.status_code != 200
+
+
+def test_metrics():
+    print("=============TESTING METRICS=========================")
+    resp = httpx.get(f"http://localhost:{admin_port}/metrics")
+    assert resp.status_code == 200
+    assert "gateway_requests_total" in resp.text
+    assert "gateway_request_duration_seconds_bucket" in resp.text
+    assert "gateway_requests_in_progress" in resp.text
+    assert "gateway_upstream_duration_seconds_bucket" in resp.text
+    assert "gateway_upstream_responses_total" in resp.text
+    assert "gateway_mirrored_requests_total" in resp.text
+
+
+def test_admin_api():
+    print("=============TESTING ADMIN API=========================")
+    url = f"http://localhost:{admin_port}/admin/services"
+    resp = httpx.get(url)
+    assert resp.status_code == 401
+    headers = {'Authorization': f"Bearer {admin_token}"}
+    resp = httpx.get(url, headers=headers)
+    assert resp.status_code == 200
+    assert "test/mws" in [s['service_id'] for s in resp.json()]
+    resp = httpx.get(f"http://localhost:{admin_port}/admin/clients", headers=headers)
+    assert resp.status_code == 200
+    client = [c for c in resp.json() if c['client_id'] == 'test/client'][0]
+    assert client['app_key'] == "******"
+    resp = httpx.get(f"http://localhost:{admin_port}/admin/workers", headers=headers)
+    assert resp.status_code == 200
+    assert "test/mws" in resp.json()
+    # metrics and admin api are not exposed on the public port
+    resp = httpx.get(f"http://localhost:{gateway_port}/metrics")
+    assert resp.status_code != 200
+
+
+def test_diagnose():
+    import subprocess
+
+    print("=============TESTING DIAGNOSE=========================")
+    result = subprocess.run(["../target/debug/hyperapi", "diagnose", "--config", "sample_config.yaml"],
+                            capture_output=True, text=True)
+    print(result.stdout)
+    assert result.returncode == 0
+    assert "0 failures" in result.stdout
+
+    result = subprocess.run(["../target/debug/hyperapi", "diagnose", "--config", "diagnose_config.yaml"],
+                            capture_output=True, text=True)
+    print(result.stdout)
+    assert result.returncode == 1
+    assert "[ OK ] service test/reachable upstream 1 tcp" in result.stdout
+    assert "[FAIL] service test/unreachable upstream 2 tcp" in result.stdout
+    assert "1 failures" in result.stdout
+
+
 def run_test():
     import subprocess
     import time
 
-    gateway = subprocess.Popen(["../target/debug/hyperapi", "--listen", f"127.0.0.1:{gateway_port}", "--config", "sample_config.yaml"])
+    gateway = subprocess.Popen(["../target/debug/hyperapi", "--listen", f"127.0.0.1:{gateway_port}", "--config", "sample_config.yaml",
+                                "--admin_listen", f"127.0.0.1:{admin_port}", "--admin_token", admin_token,
+                                "--fault_injection"])
     fastapi = subprocess.Popen(["uvicorn", "--port", f"{mock_port}", "gateway_test:app"])
     time.sleep(3)
     
     try:
+        print("check liveness and readiness probes")
+        test_health_probe()
+
         print("request test endpoint, middleware test, appkey auth")
         resp = httpx.get(f"http://localhost:{mock_port}/test1", timeout=None)
         assert resp.status_code == 200
@@ -223,6 +408,15 @@ def run_test():
         print("request test endpoint, load balance test, appkey auth")
         resp = httpx.get(f"http://localhost:{mock_port}/test3", timeout=None)
         assert resp.status_code == 200
+
+        print("scrape prometheus metrics without auth")
+        test_metrics()
+
+        print("inspect live config through admin api")
+        test_admin_api()
+
+        print("run diagnose against reachable and unreachable upstreams")
+        test_diagnose()
     finally:
         gateway.kill()
         fastapi.kill()
@@ -0,0 +1,149 @@
+use hyper::body::HttpBody;
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, HeaderMap, Request, Response, Server};
+use hyperapi::config::ConfigSource;
+use hyperapi::proxy::GatewayServer;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+// minimal gRPC-like upstream, replies with trailers or trailers-only error
+async fn grpc_upstream(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let builder = Response::builder().header("content-type", "application/grpc");
+    if req.uri().path().ends_with("/Fail") {
+        let resp = builder
+            .header("grpc-status", "5")
+            .header("grpc-message", "not found")
+            .body(Body::empty())
+            .unwrap();
+        return Ok(resp);
+    }
+    let (mut tx, body) = Body::channel();
+    tokio::spawn(async move {
+        let _ = tx.send_data(vec![0u8, 0, 0, 0, 2, 8, 1].into()).await;
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", "0".parse().unwrap());
+        trailers.insert("grpc-message", "ok".parse().unwrap());
+        let _ = tx.send_trailers(trailers).await;
+    });
+    Ok(builder.body(body).unwrap())
+}
+
+async fn start_upstream() -> SocketAddr {
+    let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr();
+    let make_svc = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(grpc_upstream)) });
+    let server = Server::builder(incoming).http2_only(true).serve(make_svc);
+    tokio::spawn(server);
+    addr
+}
+
+async fn start_gateway(upstream: SocketAddr) -> SocketAddr {
+    let config = format!(
+        r#"
+clients: []
+services:
+  - service_id: grpc
+    path: /pkg.Echo
+    protocol: http
+    auth:
+      type: None
+    timeout: 3
+    load_balance: random
+    filters: []
+    sla: []
+    upstreams:
+      - id: grpc1
+        target: "http://{}/pkg.Echo"
+        max_conn: 10
+        weight: 1
+        version: "1"
+        http2_only: true
+        error_threshold: 0
+        error_reset: 60
+        retry_delay: 10
+  - service_id: grpc_down
+    path: /pkg.Down
+    protocol: http
+    auth:
+      type: None
+    timeout: 3
+    load_balance: random
+    filters: []
+    sla: []
+    upstreams:
+      - id: down1
+        target: "http://127.0.0.1:1/"
+        max_conn: 10
+        weight: 1
+        version: "1"
+        http2_only: true
+        error_threshold: 0
+        error_reset: 60
+        retry_delay: 10
+"#,
+        upstream
+    );
+    let path = std::env::temp_dir().join(format!("hyperapi_grpc_{}.yaml", upstream.port()));
+    std::fs::write(&path, config).unwrap();
+
+    let gateway = GatewayServer::new(ConfigSource::new(path.to_string_lossy().into()));
+    while *gateway.status.lock().unwrap() == 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // let middleware workers pick up the config
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr();
+    let make_svc = make_service_fn(move |_| {
+        let handler = gateway.make_service();
+        async move { Ok::<_, Infallible>(handler) }
+    });
+    tokio::spawn(Server::builder(incoming).serve(make_svc));
+    addr
+}
+
+async fn grpc_call(gateway: SocketAddr, path: &str) -> (HeaderMap, Vec<u8>, Option<HeaderMap>) {
+    let client = Client::builder().http2_only(true).build_http::<Body>();
+    let req = Request::post(format!("http://{}{}", gateway, path))
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .body(Body::from(vec![0u8, 0, 0, 0, 0]))
+        .unwrap();
+    let resp = client.request(req).await.unwrap();
+    let headers = resp.headers().clone();
+    let mut body = resp.into_body();
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        data.extend_from_slice(&chunk.unwrap());
+    }
+    let trailers = body.trailers().await.unwrap();
+    (headers, data, trailers)
+}
+
+#[tokio::test]
+async fn test_grpc_trailers() {
+    let upstream = start_upstream().await;
+    let gateway = start_gateway(upstream).await;
+
+    let (headers, data, trailers) = grpc_call(gateway, "/pkg.Echo/Echo").await;
+    assert_eq!(headers["content-type"], "application/grpc");
+    assert_eq!(data, vec![0u8, 0, 0, 0, 2, 8, 1]);
+    let trailers = trailers.expect("trailers are forwarded");
+    assert_eq!(trailers["grpc-status"], "0");
+    assert_eq!(trailers["grpc-message"], "ok");
+
+    // trailers-only response carries status in headers
+    let (headers, data, _) = grpc_call(gateway, "/pkg.Echo/Fail").await;
+    assert_eq!(headers["content-type"], "application/grpc");
+    assert_eq!(headers["grpc-status"], "5");
+    assert_eq!(headers["grpc-message"], "not found");
+    assert!(data.is_empty());
+
+    // gateway errors are reported as gRPC status
+    let (headers, _, _) = grpc_call(gateway, "/pkg.Down/Echo").await;
+    assert_eq!(headers["content-type"], "application/grpc");
+    assert_eq!(headers["grpc-status"], "14");
+}
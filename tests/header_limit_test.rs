@@ -0,0 +1,120 @@
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Request, Response, Server};
+use hyperapi::config::ConfigSource;
+use hyperapi::proxy::{GatewayServer, HeaderLimits};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+async fn start_upstream() -> SocketAddr {
+    let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr();
+    let make_svc = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|_req: Request<Body>| async {
+            Ok::<_, Infallible>(Response::new(Body::from("pong")))
+        }))
+    });
+    tokio::spawn(Server::builder(incoming).serve(make_svc));
+    addr
+}
+
+async fn start_gateway(upstream: SocketAddr, limits: HeaderLimits) -> SocketAddr {
+    let config = format!(
+        r#"
+clients: []
+services:
+  - service_id: limited
+    path: /limited
+    protocol: http
+    auth:
+      type: None
+    timeout: 3
+    load_balance: random
+    filters: []
+    sla: []
+    upstreams:
+      - id: "1"
+        target: "http://{}/"
+        max_conn: 10
+        weight: 1
+        version: "1"
+        error_threshold: 0
+        error_reset: 60
+        retry_delay: 10
+"#,
+        upstream
+    );
+    let path = std::env::temp_dir().join(format!("hyperapi_limits_{}.yaml", upstream.port()));
+    std::fs::write(&path, config).unwrap();
+
+    let mut gateway = GatewayServer::new(ConfigSource::new(path.to_string_lossy().into()));
+    gateway.header_limits = limits;
+    while *gateway.status.lock().unwrap() == 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // let middleware workers pick up the config
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr();
+    let make_svc = make_service_fn(move |_| {
+        let handler = gateway.make_service();
+        async move { Ok::<_, Infallible>(handler) }
+    });
+    let mut builder = Server::builder(incoming);
+    if let Some(size) = limits.buf_size() {
+        builder = builder.http1_max_buf_size(size);
+    }
+    tokio::spawn(builder.serve(make_svc));
+    addr
+}
+
+async fn call(gateway: SocketAddr, headers: &[(String, String)]) -> Response<Body> {
+    let mut req = Request::get(format!("http://{}/limited/", gateway));
+    for (name, value) in headers {
+        req = req.header(name.as_str(), value.as_str());
+    }
+    Client::new()
+        .request(req.body(Body::empty()).unwrap())
+        .await
+        .unwrap()
+}
+
+async fn error_code(resp: Response<Body>) -> String {
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    json["code"].as_str().unwrap_or_default().to_string()
+}
+
+#[tokio::test]
+async fn test_header_limits() {
+    let upstream = start_upstream().await;
+    let limits = HeaderLimits {
+        max_bytes: Some(1024),
+        max_count: Some(20),
+    };
+    let gateway = start_gateway(upstream, limits).await;
+
+    let resp = call(gateway, &[("x-trace".into(), "1".into())]).await;
+    assert_eq!(resp.status(), 200);
+
+    // too many headers
+    let many: Vec<_> = (0..30)
+        .map(|i| (format!("x-extra-{}", i), "1".to_string()))
+        .collect();
+    let resp = call(gateway, &many).await;
+    assert_eq!(resp.status(), 431);
+    assert_eq!(error_code(resp).await, "header_too_large");
+
+    // one header over the total size
+    let cookie = vec![("cookie".to_string(), "a".repeat(2000))];
+    let resp = call(gateway, &cookie).await;
+    assert_eq!(resp.status(), 431);
+    assert_eq!(error_code(resp).await, "header_too_large");
+
+    // heads over the read buffer are refused by hyper
+    let huge = vec![("cookie".to_string(), "a".repeat(64 * 1024))];
+    let resp = call(gateway, &huge).await;
+    assert_eq!(resp.status(), 431);
+}
@@ -0,0 +1,190 @@
+use hyper::{Body, Method, Request};
+use hyperapi::auth::AuthResponse;
+use hyperapi::config::{ConfigUpdate, ServiceInfo};
+use hyperapi::middleware::{
+    GatewayError, Middleware, MwNextAction, MwPreRequest, MwPreResponse, RequestContext,
+    UpstreamMiddleware, IDEMPOTENT_REPLAYED_HEADER,
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+const SERVICE: &str = r#"
+service_id: test/idempotency_store
+path: /orders
+protocol: http
+auth:
+  type: AppKey
+timeout: 3
+load_balance: random
+upstreams:
+  - id: "1"
+    target: "http://127.0.0.1:1/"
+    max_conn: 10
+    version: "1.0"
+    weight: 1
+    error_threshold: 0
+    error_reset: 60
+    retry_delay: 10
+filters: []
+sla: []
+idempotency:
+  ttl: 60
+"#;
+
+type Store = Arc<Mutex<HashMap<String, String>>>;
+
+// redis server knowing SET [NX], GET and DEL only, expiry is ignored
+async fn start_redis(store: Store) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let store = store.clone();
+            tokio::spawn(async move {
+                let (read, mut write) = stream.into_split();
+                let mut lines = BufReader::new(read).lines();
+                while let Ok(Some(header)) = lines.next_line().await {
+                    // *<n>, then $<len> and the value for each argument
+                    let n: usize = header.trim_start_matches('*').parse().unwrap();
+                    let mut args = Vec::new();
+                    for _ in 0..n {
+                        lines.next_line().await.unwrap();
+                        args.push(lines.next_line().await.unwrap().unwrap());
+                    }
+                    let reply = {
+                        let mut store = store.lock().unwrap();
+                        match args[0].to_uppercase().as_str() {
+                            "SET"
+                                if args.iter().any(|a| a == "NX")
+                                    && store.contains_key(&args[1]) =>
+                            {
+                                "$-1\r\n".into()
+                            }
+                            "SET" => {
+                                store.insert(args[1].clone(), args[2].clone());
+                                "+OK\r\n".into()
+                            }
+                            "GET" => match store.get(&args[1]) {
+                                Some(v) => format!("${}\r\n{}\r\n", v.len(), v),
+                                None => "$-1\r\n".into(),
+                            },
+                            "DEL" => format!(":{}\r\n", store.remove(&args[1]).is_some() as u8),
+                            _ => "-ERR unknown command\r\n".into(),
+                        }
+                    };
+                    write.write_all(reply.as_bytes()).await.unwrap();
+                }
+            });
+        }
+    });
+    addr
+}
+
+// upstream counting its calls, slow enough for requests on other replicas to wait
+fn counting_upstream(calls: Arc<AtomicUsize>) -> SocketAddr {
+    let make_svc = hyper::service::make_service_fn(move |_| {
+        let calls = calls.clone();
+        async move {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |_req| {
+                let n = calls.fetch_add(1, Ordering::SeqCst) + 1;
+                async move {
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                    let resp = hyper::Response::builder().status(201);
+                    Ok::<_, std::convert::Infallible>(resp.body(Body::from(n.to_string())).unwrap())
+                }
+            }))
+        }
+    });
+    let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+async fn send(
+    upstream: &mut UpstreamMiddleware,
+    key: &str,
+) -> oneshot::Receiver<Result<MwPreResponse, GatewayError>> {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri("/orders/")
+        .header("idempotency-key", key)
+        .body(Body::from("{}"))
+        .unwrap();
+    let auth = AuthResponse {
+        client_id: "app1".into(),
+        service_id: "test/idempotency_store".into(),
+        sla: String::new(),
+        service_filters: Vec::new(),
+        client_filters: Vec::new(),
+        middlewares: Vec::new(),
+    };
+    let (tx, rx) = oneshot::channel();
+    upstream
+        .request(MwPreRequest {
+            context: RequestContext::new(&request, &auth),
+            request,
+            service_filters: Vec::new(),
+            client_filters: Vec::new(),
+            result: tx,
+        })
+        .await;
+    rx
+}
+
+// body and whether the response was replayed
+async fn answer(rx: oneshot::Receiver<Result<MwPreResponse, GatewayError>>) -> (String, bool) {
+    match rx.await.unwrap() {
+        Ok(MwPreResponse {
+            next: MwNextAction::Return(resp),
+            ..
+        }) => {
+            assert_eq!(resp.status(), 201);
+            let replayed = resp.headers().contains_key(IDEMPOTENT_REPLAYED_HEADER);
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            (String::from_utf8(body.to_vec()).unwrap(), replayed)
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_idempotency_shared_by_replicas() {
+    let store: Store = Arc::new(Mutex::new(HashMap::new()));
+    let addr = start_redis(store.clone()).await;
+    let redis = redis::Client::open(format!("redis://{}/", addr)).unwrap();
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.upstreams[0].target = format!("http://{}/", counting_upstream(calls.clone()));
+    let mut replicas = [
+        UpstreamMiddleware::new(Some(redis.clone()), None),
+        UpstreamMiddleware::new(Some(redis), None),
+    ];
+    for replica in replicas.iter_mut() {
+        replica.config_update(ConfigUpdate::ServiceUpdate(service.clone()));
+    }
+
+    // second replica waits for the request in flight on the first one
+    let first = send(&mut replicas[0], "k1").await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let retry = send(&mut replicas[1], "k1").await;
+    assert_eq!(answer(first).await, ("1".into(), false));
+    assert_eq!(answer(retry).await, ("1".into(), true));
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    let stored =
+        store.lock().unwrap()["hyperapi:idempotency:test/idempotency_store:app1:k1"].clone();
+    assert!(stored.contains("\"status\":201"), "{}", stored);
+
+    let retry = send(&mut replicas[1], "k1").await;
+    assert_eq!(answer(retry).await, ("1".into(), true));
+    let other = send(&mut replicas[1], "k2").await;
+    assert_eq!(answer(other).await, ("2".into(), false));
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
@@ -0,0 +1,209 @@
+use hyper::{Body, Method, Request};
+use hyperapi::auth::AuthResponse;
+use hyperapi::config::{validate_service, ConfigUpdate, IdempotencySetting, ServiceInfo};
+use hyperapi::middleware::{
+    GatewayError, Middleware, MwNextAction, MwPreRequest, MwPreResponse, RequestContext,
+    UpstreamMiddleware, IDEMPOTENT_REPLAYED_HEADER,
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+const SERVICE: &str = r#"
+service_id: test/idempotency
+path: /orders
+protocol: http
+auth:
+  type: AppKey
+timeout: 3
+load_balance: random
+upstreams:
+  - id: "1"
+    target: "http://127.0.0.1:1/"
+    max_conn: 10
+    version: "1.0"
+    weight: 1
+    error_threshold: 0
+    error_reset: 60
+    retry_delay: 10
+filters: []
+sla: []
+idempotency:
+  ttl: 1
+"#;
+
+type PreResult = oneshot::Receiver<Result<MwPreResponse, GatewayError>>;
+
+// upstream counting its calls, slow enough for retries to overlap, 500 on /fail
+fn counting_upstream(calls: Arc<AtomicUsize>) -> std::net::SocketAddr {
+    let make_svc = hyper::service::make_service_fn(move |_| {
+        let calls = calls.clone();
+        async move {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(
+                move |req: Request<Body>| {
+                    let n = calls.fetch_add(1, Ordering::SeqCst) + 1;
+                    async move {
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+                        let status = if req.uri().path() == "/fail" {
+                            500
+                        } else {
+                            201
+                        };
+                        let resp = hyper::Response::builder()
+                            .status(status)
+                            .header("location", format!("/orders/{}", n));
+                        Ok::<_, std::convert::Infallible>(
+                            resp.body(Body::from(n.to_string())).unwrap(),
+                        )
+                    }
+                },
+            ))
+        }
+    });
+    let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+async fn send(
+    upstream: &mut UpstreamMiddleware,
+    client_id: &str,
+    path: &str,
+    key: Option<&str>,
+) -> PreResult {
+    let mut request = Request::builder().method(Method::POST).uri(path);
+    if let Some(key) = key {
+        request = request.header("idempotency-key", key);
+    }
+    let request = request.body(Body::from("{}")).unwrap();
+    let auth = AuthResponse {
+        client_id: client_id.into(),
+        service_id: "test/idempotency".into(),
+        sla: String::new(),
+        service_filters: Vec::new(),
+        client_filters: Vec::new(),
+        middlewares: Vec::new(),
+    };
+    let (tx, rx) = oneshot::channel();
+    upstream
+        .request(MwPreRequest {
+            context: RequestContext::new(&request, &auth),
+            request,
+            service_filters: Vec::new(),
+            client_filters: Vec::new(),
+            result: tx,
+        })
+        .await;
+    rx
+}
+
+// status, body and whether the response was replayed
+async fn answer(rx: PreResult) -> (u16, String, bool) {
+    match rx.await.unwrap() {
+        Ok(MwPreResponse {
+            next: MwNextAction::Return(resp),
+            ..
+        }) => {
+            let status = resp.status().as_u16();
+            let replayed = resp.headers().contains_key(IDEMPOTENT_REPLAYED_HEADER);
+            assert_eq!(resp.headers()["location"], "/orders/1");
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            (status, String::from_utf8(body.to_vec()).unwrap(), replayed)
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_idempotency_key() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.upstreams[0].target = format!("http://{}/", counting_upstream(calls.clone()));
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service.clone()));
+
+    // retries in flight wait for the first request
+    let mut pending = Vec::new();
+    for _ in 0..3 {
+        pending.push(send(&mut upstream, "app1", "/orders/", Some("k1")).await);
+    }
+    let mut answers = Vec::new();
+    for rx in pending {
+        answers.push(answer(rx).await);
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert_eq!(answers[0], (201, "1".into(), false));
+    assert_eq!(
+        answers[1..],
+        [(201, "1".into(), true), (201, "1".into(), true)]
+    );
+
+    // later retry is replayed, keys are per client
+    let rx = send(&mut upstream, "app1", "/orders/", Some("k1")).await;
+    assert_eq!(answer(rx).await, (201, "1".into(), true));
+    let rx = send(&mut upstream, "app2", "/orders/", Some("k1")).await;
+    assert!(rx.await.unwrap().is_ok());
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    // responses are kept by each gateway without a store
+    let mut other = UpstreamMiddleware::default();
+    other.config_update(ConfigUpdate::ServiceUpdate(service));
+    let rx = send(&mut other, "app1", "/orders/", Some("k1")).await;
+    assert!(rx.await.unwrap().is_ok());
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+    // without a key, or from an anonymous client
+    for (client, key) in [("app1", None), ("", Some("k1"))] {
+        let rx = send(&mut upstream, client, "/orders/", key).await;
+        assert!(rx.await.unwrap().is_ok());
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 5);
+
+    // stored until the ttl is over
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let rx = send(&mut upstream, "app1", "/orders/", Some("k1")).await;
+    assert!(rx.await.unwrap().is_ok());
+    assert_eq!(calls.load(Ordering::SeqCst), 6);
+}
+
+#[tokio::test]
+async fn test_idempotency_server_error() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.upstreams[0].target = format!("http://{}/", counting_upstream(calls.clone()));
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+
+    // failed requests are not stored, waiters are sent one after another
+    let mut pending = Vec::new();
+    for _ in 0..2 {
+        pending.push(send(&mut upstream, "app1", "/orders/fail", Some("k2")).await);
+    }
+    for rx in pending {
+        match rx.await.unwrap() {
+            Ok(MwPreResponse {
+                next: MwNextAction::Return(resp),
+                ..
+            }) => assert_eq!(resp.status(), 500),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_idempotency_validation() {
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    validate_service(&service).unwrap();
+    service.idempotency = Some(IdempotencySetting {
+        ttl: 0,
+        max_body: 0,
+    });
+    let err = validate_service(&service).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "service test/idempotency: invalid idempotency, zero ttl"
+    );
+}
@@ -0,0 +1,159 @@
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use hyperapi::auth::{AuthProvider, GatewayAuthError, IntrospectionAuthProvider};
+use hyperapi::config::{validate_service, ClientInfo, ConfigError, ConfigUpdate, ServiceInfo};
+use serde_json::json;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+const SERVICE: &str = r#"
+service_id: opaque
+path: /svc
+protocol: http
+auth:
+  type: Introspection
+  url: "http://127.0.0.1:PORT/introspect"
+  client_id: gateway
+  client_secret: secret
+  cache_ttl: 60
+timeout: 1
+load_balance: random
+upstreams:
+  - id: "1"
+    target: "http://127.0.0.1:1/"
+    max_conn: 10
+    version: "1.0"
+    weight: 1
+    error_threshold: 0
+    error_reset: 60
+    retry_delay: 10
+filters: []
+sla: []
+"#;
+
+// introspection endpoint accepting the gateway's credentials, counting calls
+async fn start_endpoint(calls: Arc<AtomicUsize>) -> u16 {
+    let make_svc = make_service_fn(move |_| {
+        let calls = calls.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let calls = calls.clone();
+                async move {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    let authorized = req
+                        .headers()
+                        .get("authorization")
+                        .is_some_and(|v| v == "Basic Z2F0ZXdheTpzZWNyZXQ=");
+                    let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                    let form: HashMap<String, String> =
+                        serde_urlencoded::from_bytes(&body).unwrap();
+                    let result = match form["token"].as_str() {
+                        _ if !authorized => {
+                            return Ok::<_, Infallible>(
+                                Response::builder().status(401).body(Body::empty()).unwrap(),
+                            )
+                        }
+                        "good" => json!({
+                            "active": true,
+                            "client_id": "app1",
+                            "scope": "read write",
+                            "exp": 4_000_000_000u64,
+                        }),
+                        "stranger" => json!({ "active": true, "client_id": "nobody" }),
+                        "expired" => json!({ "active": true, "client_id": "app1", "exp": 1 }),
+                        _ => json!({ "active": false }),
+                    };
+                    Ok(Response::new(Body::from(result.to_string())))
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+    let port = server.local_addr().port();
+    tokio::spawn(server);
+    port
+}
+
+fn identify(
+    provider: &IntrospectionAuthProvider,
+    token: &str,
+) -> Result<(String, Vec<String>), GatewayAuthError> {
+    let (head, _) = Request::get("/svc/x")
+        .header("authorization", format!("Bearer {}", token))
+        .body(())
+        .unwrap()
+        .into_parts();
+    let (_, result) = provider.identify_client(head, "opaque")?;
+    Ok((result.client_id, result.scopes))
+}
+
+// the provider blocks while the endpoint answers, which runs on another worker
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_introspection() {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let port = start_endpoint(calls.clone()).await;
+    let service: ServiceInfo =
+        serde_yaml::from_str(&SERVICE.replace("PORT", &port.to_string())).unwrap();
+    validate_service(&service).unwrap();
+
+    let mut provider = IntrospectionAuthProvider::new();
+    provider.update_config(ConfigUpdate::ServiceUpdate(service.clone()));
+    provider.update_config(ConfigUpdate::ClientUpdate(ClientInfo {
+        client_id: "app1".into(),
+        app_key: "key1".into(),
+        pub_key: String::new(),
+        ip_whitelist: Vec::new(),
+        services: [("opaque".to_string(), "Default".to_string())].into(),
+        roles: Vec::new(),
+    }));
+
+    let app1 = (
+        "app1".to_string(),
+        vec!["read".to_string(), "write".to_string()],
+    );
+    assert_eq!(identify(&provider, "good").unwrap(), app1);
+    // cached for the ttl
+    assert_eq!(identify(&provider, "good").unwrap(), app1);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    assert!(matches!(
+        identify(&provider, "revoked"),
+        Err(GatewayAuthError::InvalidToken)
+    ));
+    assert!(matches!(
+        identify(&provider, "expired"),
+        Err(GatewayAuthError::InvalidToken)
+    ));
+    assert!(matches!(
+        identify(&provider, "stranger"),
+        Err(GatewayAuthError::UnknownClient)
+    ));
+
+    // endpoint refusing the gateway's credentials
+    let mut wrong = service.clone();
+    if let hyperapi::config::AuthSetting::Introspection(auth) = &mut wrong.auth {
+        auth.client_secret = "wrong".into();
+    }
+    provider.update_config(ConfigUpdate::ServiceUpdate(wrong));
+    assert!(matches!(
+        identify(&provider, "other"),
+        Err(GatewayAuthError::Unknown)
+    ));
+}
+
+#[test]
+fn test_invalid_introspection() {
+    let service = SERVICE.replace("PORT", "8080");
+    for (from, to) in [
+        ("\"http://127.0.0.1:8080/introspect\"", "/introspect"),
+        ("client_id: gateway", "client_id: \"\""),
+    ] {
+        let broken: ServiceInfo = serde_yaml::from_str(&service.replace(from, to)).unwrap();
+        assert!(matches!(
+            validate_service(&broken),
+            Err(ConfigError::InvalidIntrospection(..))
+        ));
+    }
+}
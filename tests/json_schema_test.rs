@@ -0,0 +1,149 @@
+use hyper::body::to_bytes;
+use hyper::{Body, Request};
+use hyperapi::auth::AuthResponse;
+use hyperapi::config::{validate_service, ConfigError, ConfigUpdate, FilterSetting, ServiceInfo};
+use hyperapi::middleware::{
+    GatewayError, JsonSchemaMiddleware, Middleware, MwNextAction, MwPreRequest, RequestContext,
+};
+use serde_json::Value;
+use tokio::sync::oneshot;
+
+const SERVICE: &str = r#"
+service_id: test/schema
+path: /schema
+protocol: http
+auth:
+  type: None
+timeout: 1
+load_balance: random
+upstreams:
+  - id: "1"
+    target: "http://127.0.0.1:1/"
+    max_conn: 10
+    version: "1.0"
+    weight: 1
+    error_threshold: 0
+    error_reset: 60
+    retry_delay: 10
+filters:
+  - type: JsonSchema
+    setting:
+      methods: POST,PUT
+      path_pattern: /users*
+      max_body: 64
+      schema:
+        type: object
+        required: [name]
+        properties:
+          name: {type: string}
+          age: {type: integer, minimum: 0}
+sla: []
+"#;
+
+// status and body of the answer, or the body passed on
+async fn call(
+    mw: &mut JsonSchemaMiddleware,
+    filters: &[FilterSetting],
+    request: Request<Body>,
+) -> Result<(u16, Value), GatewayError> {
+    let auth = AuthResponse {
+        client_id: String::new(),
+        service_id: "test/schema".into(),
+        sla: String::new(),
+        service_filters: Vec::new(),
+        client_filters: Vec::new(),
+        middlewares: Vec::new(),
+    };
+    let (tx, rx) = oneshot::channel();
+    let task = MwPreRequest {
+        context: RequestContext::new(&request, &auth),
+        request,
+        service_filters: filters.to_vec(),
+        client_filters: Vec::new(),
+        result: tx,
+    };
+    mw.request(task).await;
+    let (status, body) = match rx.await.unwrap()?.next {
+        MwNextAction::Next(req) => (0, req.into_body()),
+        MwNextAction::Return(resp) => (resp.status().as_u16(), resp.into_body()),
+    };
+    let body = to_bytes(body).await.unwrap();
+    Ok((status, serde_json::from_slice(&body).unwrap_or(Value::Null)))
+}
+
+fn post(path: &str, body: &str) -> Request<Body> {
+    Request::post(path)
+        .header("content-type", "application/json; charset=utf-8")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_json_schema() {
+    let service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    validate_service(&service).unwrap();
+    let filters = service.filters.clone();
+    let mut mw = JsonSchemaMiddleware::default();
+    mw.config_update(ConfigUpdate::ServiceUpdate(service));
+
+    // valid body passed unchanged
+    let (status, body) = call(&mut mw, &filters, post("/schema/users", r#"{"name":"a"}"#))
+        .await
+        .unwrap();
+    assert_eq!((status, body), (0, serde_json::json!({"name": "a"})));
+
+    let (status, body) = call(&mut mw, &filters, post("/schema/users", r#"{"age":-1}"#))
+        .await
+        .unwrap();
+    assert_eq!(status, 400);
+    assert_eq!(body["code"], "invalid_body");
+    let paths: Vec<&str> = body["details"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|d| d["path"].as_str().unwrap())
+        .collect();
+    assert!(paths.contains(&"") && paths.contains(&"/age"));
+
+    let (status, _) = call(&mut mw, &filters, post("/schema/users", "{"))
+        .await
+        .unwrap();
+    assert_eq!(status, 400);
+
+    // other routes, methods and content types are not validated
+    let (status, _) = call(&mut mw, &filters, post("/schema/orders", "{}"))
+        .await
+        .unwrap();
+    assert_eq!(status, 0);
+    let get = Request::get("/schema/users").body(Body::empty()).unwrap();
+    assert_eq!(call(&mut mw, &filters, get).await.unwrap().0, 0);
+    let text = Request::post("/schema/users")
+        .header("content-type", "text/plain")
+        .body(Body::from("hello"))
+        .unwrap();
+    assert_eq!(call(&mut mw, &filters, text).await.unwrap().0, 0);
+
+    // over max_body by content-length or chunked
+    let large = format!(r#"{{"name":"{}"}}"#, "x".repeat(100));
+    let err = call(&mut mw, &filters, post("/schema/users", &large)).await;
+    assert!(matches!(err, Err(GatewayError::PayloadTooLarge(_))));
+    let chunks = vec![Ok::<_, std::io::Error>(large.clone())];
+    let chunked = Request::post("/schema/users")
+        .header("content-type", "application/json")
+        .body(Body::wrap_stream(futures::stream::iter(chunks)))
+        .unwrap();
+    let err = call(&mut mw, &filters, chunked).await;
+    assert!(matches!(err, Err(GatewayError::PayloadTooLarge(_))));
+}
+
+#[test]
+fn test_invalid_schema() {
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    if let FilterSetting::JsonSchema(s) = &mut service.filters[0] {
+        s.schema.0["type"] = "no-such-type".into();
+    }
+    assert!(matches!(
+        validate_service(&service),
+        Err(ConfigError::InvalidJsonSchema(..))
+    ));
+}
@@ -0,0 +1,90 @@
+use hyper::{Body, Request, Response};
+use hyperapi::auth::AuthResponse;
+use hyperapi::config::{FilterSetting, JsonTransformSetting};
+use hyperapi::middleware::{JsonTransformMiddleware, Middleware, MwPostRequest, RequestContext};
+use serde_json::{json, Value};
+use tokio::sync::oneshot;
+
+const USER: &str = r#"{"data":{"users":[{"id":1,"name":"a","email":"a@example.com","password":"x"},{"id":2,"name":"b","email":"b@example.com","password":"y"}]},"debug":{"host":"10.0.0.1"}}"#;
+
+fn setting() -> JsonTransformSetting {
+    JsonTransformSetting {
+        removal: vec!["debug".into(), "data.users.*.password".into()],
+        rename: vec![("data.users.*.name".into(), "nickname".into())],
+        redaction: vec!["data.users.*.email".into()],
+        max_body: 0,
+    }
+}
+
+async fn transform(
+    setting: JsonTransformSetting,
+    content_type: &str,
+    body: &'static str,
+) -> Response<Body> {
+    let request = Request::get("/json/users").body(Body::empty()).unwrap();
+    let auth = AuthResponse {
+        client_id: String::new(),
+        service_id: "test/json".into(),
+        sla: String::new(),
+        service_filters: Vec::new(),
+        client_filters: Vec::new(),
+        middlewares: Vec::new(),
+    };
+    let response = Response::builder()
+        .header("content-type", content_type)
+        .header("content-length", body.len())
+        .body(Body::from(body))
+        .unwrap();
+    let (tx, rx) = oneshot::channel();
+    let task = MwPostRequest {
+        context: RequestContext::new(&request, &auth),
+        response,
+        service_filters: vec![FilterSetting::JsonTransform(setting)],
+        client_filters: Vec::new(),
+        result: tx,
+    };
+    let mut mw = JsonTransformMiddleware::default();
+    mw.response(task).await;
+    rx.await.unwrap().unwrap().response
+}
+
+async fn body_of(response: Response<Body>) -> Vec<u8> {
+    let length: usize = response.headers()["content-length"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(body.len(), length);
+    body.to_vec()
+}
+
+#[tokio::test]
+async fn test_json_transform() {
+    let response = transform(setting(), "application/json; charset=utf-8", USER).await;
+    let value: Value = serde_json::from_slice(&body_of(response).await).unwrap();
+    assert_eq!(
+        value,
+        json!({"data": {"users": [
+            {"id": 1, "nickname": "a", "email": "***"},
+            {"id": 2, "nickname": "b", "email": "***"},
+        ]}})
+    );
+}
+
+#[tokio::test]
+async fn test_json_transform_pass_through() {
+    // not json
+    let response = transform(setting(), "text/plain", USER).await;
+    assert_eq!(body_of(response).await, USER.as_bytes());
+
+    // invalid json
+    let response = transform(setting(), "application/json", "{\"debug\":").await;
+    assert_eq!(body_of(response).await, b"{\"debug\":");
+
+    // larger than buffer limit
+    let mut small = setting();
+    small.max_body = 16;
+    let response = transform(small, "application/problem+json", USER).await;
+    assert_eq!(body_of(response).await, USER.as_bytes());
+}
@@ -0,0 +1,55 @@
+use hyper::{Body, Request, Response};
+use hyperapi::auth::AuthResponse;
+use hyperapi::middleware::{
+    set_latency_buckets, LoggerMiddleware, Middleware, MwPostRequest, RequestContext,
+};
+use tokio::sync::oneshot;
+
+// upper bounds of the request latency histogram of service test/buckets
+fn bucket_bounds() -> Vec<f64> {
+    prometheus::gather()
+        .iter()
+        .filter(|family| family.get_name() == "gateway_request_duration_seconds")
+        .flat_map(|family| family.get_metric())
+        .filter(|m| {
+            m.get_label()
+                .iter()
+                .any(|l| l.get_name() == "service" && l.get_value() == "test/buckets")
+        })
+        .flat_map(|m| m.get_histogram().get_bucket())
+        .map(|b| b.get_upper_bound())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_latency_buckets() {
+    assert!(set_latency_buckets(vec![]).is_err());
+    assert!(set_latency_buckets(vec![0.5, 0.1]).is_err());
+    assert!(set_latency_buckets(vec![0.0, 0.1]).is_err());
+    assert!(set_latency_buckets(vec![0.1, f64::INFINITY]).is_err());
+    set_latency_buckets(vec![0.05, 0.5, 5.0]).unwrap();
+
+    let request = Request::get("/buckets/").body(Body::empty()).unwrap();
+    let auth = AuthResponse {
+        client_id: "app1".into(),
+        service_id: "test/buckets".into(),
+        sla: "Default".into(),
+        service_filters: Vec::new(),
+        client_filters: Vec::new(),
+        middlewares: Vec::new(),
+    };
+    let (tx, rx) = oneshot::channel();
+    let task = MwPostRequest {
+        context: RequestContext::new(&request, &auth),
+        response: Response::new(Body::from("hello")),
+        service_filters: Vec::new(),
+        client_filters: Vec::new(),
+        result: tx,
+    };
+    LoggerMiddleware::default().response(task).await;
+    rx.await.unwrap().unwrap();
+
+    assert_eq!(bucket_bounds(), vec![0.05, 0.5, 5.0]);
+    // histograms keep the buckets they are registered with
+    assert!(set_latency_buckets(vec![1.0]).is_err());
+}
@@ -0,0 +1,162 @@
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use hyperapi::proxy::{bind_tcp, ConnectionSettings, ListenAddr, Listener, UnixIncoming};
+use std::convert::Infallible;
+use std::path::PathBuf;
+use tokio::net::UnixStream;
+
+fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("hyperapi-{}-{}.sock", name, std::process::id()))
+}
+
+#[test]
+fn test_parse_listen_addr() {
+    let addr: ListenAddr = "127.0.0.1:8888".parse().unwrap();
+    assert_eq!(addr, ListenAddr::Tcp("127.0.0.1:8888".parse().unwrap()));
+    let addr: ListenAddr = "unix:/run/hyperapi.sock".parse().unwrap();
+    assert_eq!(addr, ListenAddr::Unix("/run/hyperapi.sock".into()));
+    assert!("unix:".parse::<ListenAddr>().is_err());
+    assert!("localhost".parse::<ListenAddr>().is_err());
+    assert_eq!(addr.to_string(), "unix:/run/hyperapi.sock");
+}
+
+#[test]
+fn test_parse_listener() {
+    let listener: Listener = "0.0.0.0:80".parse().unwrap();
+    assert_eq!(
+        listener.addr,
+        ListenAddr::Tcp("0.0.0.0:80".parse().unwrap())
+    );
+    assert_eq!(listener.cert, None);
+    let listener: Listener = "0.0.0.0:443,cert=a.pem,key=a.key".parse().unwrap();
+    assert_eq!(listener.cert, Some(("a.pem".into(), "a.key".into())));
+    let listener: Listener = "unix:/run/gw.sock,key=a.key,cert=a.pem".parse().unwrap();
+    assert_eq!(listener.addr, ListenAddr::Unix("/run/gw.sock".into()));
+    assert!(listener.cert.is_some());
+    assert!("0.0.0.0:443,cert=a.pem".parse::<Listener>().is_err());
+    assert!("0.0.0.0:443,tls".parse::<Listener>().is_err());
+}
+
+#[tokio::test]
+async fn test_unix_socket_serve() {
+    let path = socket_path("serve");
+    // stale socket file left without a listener
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+
+    let incoming = UnixIncoming::bind(&path).unwrap();
+    assert!(UnixIncoming::bind(&path).is_err(), "socket in use is kept");
+    let make_svc = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|_req| async {
+            Ok::<_, Infallible>(Response::new(Body::from("pong")))
+        }))
+    });
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(
+        Server::builder(incoming)
+            .serve(make_svc)
+            .with_graceful_shutdown(async {
+                let _ = stop_rx.await;
+            }),
+    );
+
+    let stream = UnixStream::connect(&path).await.unwrap();
+    let (mut sender, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+    tokio::spawn(conn);
+    let req = Request::get("/ping").body(Body::empty()).unwrap();
+    let resp = sender.send_request(req).await.unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(&body[..], b"pong");
+    drop(sender);
+
+    // socket file is removed with the server
+    let _ = stop_tx.send(());
+    server.await.unwrap().unwrap();
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_unix_socket_not_socket() {
+    let path = socket_path("file");
+    std::fs::write(&path, b"data").unwrap();
+    assert!(UnixIncoming::bind(&path).is_err());
+    assert!(path.exists(), "regular file is not removed");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_reuse_port() {
+    let first = bind_tcp(&"127.0.0.1:0".parse().unwrap(), true).unwrap();
+    let addr = first.local_addr();
+    assert!(
+        bind_tcp(&addr, false).is_err(),
+        "port in use without reuse_port"
+    );
+
+    // both processes, here servers, accept on the same port
+    let second = bind_tcp(&addr, true).unwrap();
+    assert_eq!(second.local_addr(), addr);
+    for (incoming, name) in [(first, "first"), (second, "second")] {
+        let make_svc = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |_req| async move {
+                Ok::<_, Infallible>(Response::new(Body::from(name)))
+            }))
+        });
+        tokio::spawn(Server::builder(incoming).serve(make_svc));
+    }
+    let uri: hyper::Uri = format!("http://{}/", addr).parse().unwrap();
+    let resp = hyper::Client::new().get(uri).await.unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert!(&body[..] == b"first" || &body[..] == b"second");
+}
+
+// raw HTTP/1 exchange, the response head and whether the server closed the connection after it
+async fn exchange(addr: std::net::SocketAddr, request: &str) -> (String, bool) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut buf = vec![0; 1024];
+    let n = stream.read(&mut buf).await.unwrap();
+    let head = String::from_utf8_lossy(&buf[..n]).to_ascii_lowercase();
+    let closed = tokio::time::timeout(std::time::Duration::from_millis(500), stream.read(&mut buf))
+        .await
+        .is_ok_and(|r| r.is_ok_and(|n| n == 0));
+    (head, closed)
+}
+
+#[tokio::test]
+async fn test_connection_settings() {
+    let mut addrs = Vec::new();
+    for keepalive in [true, false] {
+        let settings = ConnectionSettings {
+            http1_keepalive: keepalive,
+            ..ConnectionSettings::default()
+        };
+        let incoming = bind_tcp(&"127.0.0.1:0".parse().unwrap(), false).unwrap();
+        addrs.push(incoming.local_addr());
+        let make_svc = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_req| async {
+                Ok::<_, Infallible>(Response::new(Body::from("pong")))
+            }))
+        });
+        tokio::spawn(settings.apply(Server::builder(incoming)).serve(make_svc));
+    }
+    let (keepalive, disabled) = (addrs[0], addrs[1]);
+
+    let http11 = "GET / HTTP/1.1\r\nhost: gateway\r\n\r\n";
+    let (head, closed) = exchange(keepalive, http11).await;
+    assert!(head.starts_with("http/1.1 200"), "{}", head);
+    assert!(!closed);
+    let (head, closed) = exchange(disabled, http11).await;
+    assert!(head.starts_with("http/1.1 200"), "{}", head);
+    assert!(closed);
+
+    // legacy clients asking for keep-alive
+    let http10 = "GET / HTTP/1.0\r\nconnection: keep-alive\r\n\r\n";
+    let (head, closed) = exchange(keepalive, http10).await;
+    assert!(head.starts_with("http/1.0 200"), "{}", head);
+    assert!(head.contains("connection: keep-alive"), "{}", head);
+    assert!(!closed);
+    let (_, closed) = exchange(disabled, http10).await;
+    assert!(closed);
+}
@@ -0,0 +1,77 @@
+use hyper::body::Bytes;
+use hyper::{Body, Request, Response};
+use hyperapi::auth::AuthResponse;
+use hyperapi::middleware::{
+    AccessLog, LoggerMiddleware, Middleware, MwPostRequest, RequestContext,
+};
+use tokio::sync::oneshot;
+
+// post-response pass of the logger, returns the body as sent to the client
+async fn respond(mw: &mut LoggerMiddleware, client_id: &str, body: Body) -> Bytes {
+    let request = Request::get("/metrics/").body(Body::empty()).unwrap();
+    let auth = AuthResponse {
+        client_id: client_id.into(),
+        service_id: "test/metrics".into(),
+        sla: "Default".into(),
+        service_filters: Vec::new(),
+        client_filters: Vec::new(),
+        middlewares: Vec::new(),
+    };
+    let (tx, rx) = oneshot::channel();
+    let task = MwPostRequest {
+        context: RequestContext::new(&request, &auth),
+        response: Response::new(body),
+        service_filters: Vec::new(),
+        client_filters: Vec::new(),
+        result: tx,
+    };
+    mw.response(task).await;
+    let response = rx.await.unwrap().unwrap().response;
+    hyper::body::to_bytes(response.into_body()).await.unwrap()
+}
+
+// value of a counter of service test/metrics for the client label
+fn counter(name: &str, client: &str) -> u64 {
+    prometheus::gather()
+        .iter()
+        .filter(|family| family.get_name() == name)
+        .flat_map(|family| family.get_metric())
+        .filter(|m| {
+            let labels = m.get_label();
+            let label = |n: &str| {
+                labels
+                    .iter()
+                    .find(|l| l.get_name() == n)
+                    .map(|l| l.get_value())
+            };
+            label("service") == Some("test/metrics") && label("client") == Some(client)
+        })
+        .map(|m| m.get_counter().get_value() as u64)
+        .sum()
+}
+
+#[tokio::test]
+async fn test_client_metrics() {
+    let access_log = AccessLog::default().with_metrics_clients(vec!["app1".into()]);
+    let mut mw = LoggerMiddleware::new(access_log);
+
+    respond(&mut mw, "app1", Body::from("hello")).await;
+    // streamed bodies are counted as they are sent
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        sender.send_data(Bytes::from("chunk1")).await.unwrap();
+        sender.send_data(Bytes::from("chunk2")).await.unwrap();
+    });
+    assert_eq!(respond(&mut mw, "app1", body).await, "chunk1chunk2");
+    // clients out of the allowlist share a label, anonymous requests keep theirs
+    respond(&mut mw, "app2", Body::from("hi")).await;
+    respond(&mut mw, "app3", Body::from("hi")).await;
+    respond(&mut mw, "", Body::from("hi")).await;
+
+    assert_eq!(counter("gateway_client_requests_total", "app1"), 2);
+    assert_eq!(counter("gateway_client_response_bytes_total", "app1"), 17);
+    assert_eq!(counter("gateway_client_requests_total", "other"), 2);
+    assert_eq!(counter("gateway_client_response_bytes_total", "other"), 4);
+    assert_eq!(counter("gateway_client_requests_total", ""), 1);
+    assert_eq!(counter("gateway_client_requests_total", "app2"), 0);
+}
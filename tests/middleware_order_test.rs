@@ -0,0 +1,250 @@
+use hyper::{Body, Request, Response};
+use hyperapi::auth::{AuthRequest, AuthResponse, AuthService, GatewayAuthError};
+use hyperapi::config::{validate_service, ConfigUpdate, ServiceInfo};
+use hyperapi::middleware::{
+    middleware_chain, service_chain, service_stack, MiddlewareHandle, MiddlewareRequest,
+    MwNextAction, MwPostResponse, MwPreResponse, RequestContext,
+};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+const SERVICE: &str = r#"
+service_id: ordered
+path: /ordered
+protocol: http
+auth:
+  type: None
+timeout: 1
+load_balance: random
+upstreams:
+  - id: "1"
+    target: "http://127.0.0.1:1/"
+    max_conn: 10
+    version: "1.0"
+    weight: 1
+    error_threshold: 0
+    error_reset: 60
+    retry_delay: 10
+filters:
+  - type: ACL
+    setting:
+      access_control: allow
+      paths: []
+sla:
+  - name: Default
+    filters:
+      - type: RateLimit
+        setting:
+          interval: 1
+          limit: 100
+          burst: 100
+middlewares: [Logger, RateLimit, ACL, Upstream]
+"#;
+
+// middleware recording its pre and post calls, Upstream answers the request
+fn recorder(name: &str, calls: Arc<Mutex<Vec<String>>>) -> MiddlewareHandle {
+    let (tx, mut rx) = mpsc::channel(16);
+    let mw = name.to_string();
+    tokio::spawn(async move {
+        while let Some(task) = rx.recv().await {
+            match task {
+                MiddlewareRequest::Request(t) => {
+                    calls.lock().unwrap().push(format!("pre {}", mw));
+                    let next = if mw == "Upstream" {
+                        MwNextAction::Return(Response::new(Body::empty()))
+                    } else {
+                        MwNextAction::Next(t.request)
+                    };
+                    let _ = t.result.send(Ok(MwPreResponse {
+                        context: t.context,
+                        next,
+                    }));
+                }
+                MiddlewareRequest::Response(t) => {
+                    calls.lock().unwrap().push(format!("post {}", mw));
+                    let _ = t.result.send(Ok(MwPostResponse {
+                        context: t.context,
+                        response: t.response,
+                    }));
+                }
+            }
+        }
+    });
+    MiddlewareHandle {
+        name: name.into(),
+        pre: true,
+        post: name != "Upstream",
+        require_setting: false,
+        chan: tx,
+    }
+}
+
+async fn run_chain(order: &[&str]) -> Vec<String> {
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    // gateway stack, last in stack run first
+    let stack: Vec<MiddlewareHandle> = ["Upstream", "ACL", "RateLimit", "Logger"]
+        .iter()
+        .map(|name| recorder(name, calls.clone()))
+        .collect();
+    let order: Vec<String> = order.iter().map(|n| n.to_string()).collect();
+
+    let request = Request::get("/ordered/").body(Body::empty()).unwrap();
+    let auth = AuthResponse {
+        client_id: String::new(),
+        service_id: "ordered".into(),
+        sla: String::new(),
+        service_filters: Vec::new(),
+        client_filters: Vec::new(),
+        middlewares: order.clone(),
+    };
+    let context = RequestContext::new(&request, &auth);
+    middleware_chain(request, context, service_stack(stack, &order))
+        .await
+        .unwrap();
+    let calls = calls.lock().unwrap().clone();
+    calls
+}
+
+#[tokio::test]
+async fn test_service_middleware_order() {
+    assert_eq!(
+        run_chain(&[]).await,
+        [
+            "pre Logger",
+            "pre RateLimit",
+            "pre ACL",
+            "pre Upstream",
+            "post ACL",
+            "post RateLimit",
+            "post Logger"
+        ]
+    );
+    assert_eq!(
+        run_chain(&["ACL", "Logger", "RateLimit", "Upstream"]).await,
+        [
+            "pre ACL",
+            "pre Logger",
+            "pre RateLimit",
+            "pre Upstream",
+            "post RateLimit",
+            "post Logger",
+            "post ACL"
+        ]
+    );
+    // unlisted middlewares are left out
+    assert_eq!(
+        run_chain(&["Logger", "Upstream"]).await,
+        ["pre Logger", "pre Upstream", "post Logger"]
+    );
+}
+
+#[test]
+fn test_middleware_order_validation() {
+    let service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    validate_service(&service).unwrap();
+    let default = SERVICE.replace("middlewares: [Logger, RateLimit, ACL, Upstream]", "");
+    let service: ServiceInfo = serde_yaml::from_str(&default).unwrap();
+    validate_service(&service).unwrap();
+
+    for order in [
+        "[Logger, Cors, Upstream]",
+        "[Logger, Logger, Upstream]",
+        "[Upstream, Logger]",
+        "[Logger, RateLimit]",
+    ] {
+        let config = SERVICE.replace("[Logger, RateLimit, ACL, Upstream]", order);
+        let service: ServiceInfo = serde_yaml::from_str(&config).unwrap();
+        let err = validate_service(&service).unwrap_err();
+        assert!(err.to_string().contains("invalid middlewares"), "{}", err);
+    }
+}
+
+#[tokio::test]
+async fn test_disabled_middlewares() {
+    let config = SERVICE.replace("sla:\n", "disabled_middlewares: [RateLimit]\nsla:\n");
+    let service: ServiceInfo = serde_yaml::from_str(&config).unwrap();
+    validate_service(&service).unwrap();
+    let chain = service_chain(&service);
+    assert_eq!(chain, ["Logger", "ACL", "Upstream"]);
+    let order: Vec<&str> = chain.iter().map(String::as_str).collect();
+    assert_eq!(
+        run_chain(&order).await,
+        [
+            "pre Logger",
+            "pre ACL",
+            "pre Upstream",
+            "post ACL",
+            "post Logger"
+        ]
+    );
+
+    // disabled from the default chain
+    let config = config.replace("middlewares: [Logger, RateLimit, ACL, Upstream]\n", "");
+    let service: ServiceInfo = serde_yaml::from_str(&config).unwrap();
+    let chain = service_chain(&service);
+    assert!(!chain.iter().any(|n| n == "RateLimit"));
+    assert_eq!(chain.len(), hyperapi::middleware::DEFAULT_CHAIN.len() - 1);
+
+    for disabled in ["[Upstream]", "[Cors]"] {
+        let config = SERVICE.replace(
+            "sla:\n",
+            &format!("disabled_middlewares: {}\nsla:\n", disabled),
+        );
+        let service: ServiceInfo = serde_yaml::from_str(&config).unwrap();
+        assert!(validate_service(&service).is_err());
+    }
+}
+
+// client id of a request to /ordered/ without credentials
+async fn identify(disabled: &str) -> Result<String, GatewayAuthError> {
+    let config = SERVICE.replace("type: None", "type: AppKey").replace(
+        "sla:\n",
+        &format!("disabled_middlewares: {}\nsla:\n", disabled),
+    );
+    let service: ServiceInfo = serde_yaml::from_str(&config).unwrap();
+    let (conf_tx, conf_rx) = broadcast::channel(16);
+    let (auth_tx, auth_rx) = mpsc::channel(16);
+    tokio::spawn(async move { AuthService::new(conf_rx, auth_rx).start().await });
+    conf_tx.send(ConfigUpdate::ServiceUpdate(service)).unwrap();
+    // let auth service pick up the config
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    let (head, _) = Request::get("/ordered/").body(()).unwrap().into_parts();
+    let (tx, rx) = oneshot::channel();
+    let _ = auth_tx.send(AuthRequest { head, result: tx }).await;
+    let result = rx.await.unwrap().map(|(_, auth)| auth.client_id);
+    drop(conf_tx);
+    result
+}
+
+#[tokio::test]
+async fn test_disabled_auth() {
+    assert!(matches!(
+        identify("[]").await,
+        Err(GatewayAuthError::TokenNotFound)
+    ));
+    // anonymous client, as with auth type None
+    assert_eq!(identify("[Auth]").await.unwrap(), "");
+}
+
+#[test]
+fn test_middleware_setting_required() {
+    // listed, but skipped at runtime without filters
+    let config = SERVICE.replace(
+        "[Logger, RateLimit, ACL, Upstream]",
+        "[Logger, Header, Upstream]",
+    );
+    let service: ServiceInfo = serde_yaml::from_str(&config).unwrap();
+    let err = validate_service(&service).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "service ordered: middleware Header needs a Header filter in filters or sla to work"
+    );
+    // client filters of sla count, middlewares without settings don't need any
+    let config = SERVICE.replace(
+        "[Logger, RateLimit, ACL, Upstream]",
+        "[Logger, ErrorPage, RateLimit, Upstream]",
+    );
+    let service: ServiceInfo = serde_yaml::from_str(&config).unwrap();
+    validate_service(&service).unwrap();
+}
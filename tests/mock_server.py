@@ -24,6 +24,11 @@ async def api_endpoint(req: Request, api: str):
     return {"api": api}
 
 
+@app.api_route("/echo/{path:path}", methods=['POST', 'GET', 'PUT', 'DELETE'])
+async def echo_endpoint(req: Request, path: str):
+    return {"path": req.url.path, "query": req.url.query}
+
+
 @app.api_route("/error/{code}", methods=['POST', 'GET', 'PUT', 'DELETE'])
 async def error_endpoint(req: Request, code: int=Path(default=500)):
     return Response(status_code=int(code))
@@ -0,0 +1,74 @@
+use hyper::{Body, Request, Response};
+use hyperapi::auth::AuthResponse;
+use hyperapi::middleware::{
+    accepts_openmetrics, LoggerMiddleware, Middleware, MwPostRequest, RequestContext,
+};
+use hyperapi::proxy::RequestHandler;
+use tokio::sync::oneshot;
+
+// scrape the metrics endpoint, returns content type and body
+async fn scrape(accept: Option<&str>) -> (String, String) {
+    let mut request = Request::get("/metrics");
+    if let Some(accept) = accept {
+        request = request.header("accept", accept);
+    }
+    let response = RequestHandler::prometheus_endpoint(&request.body(Body::empty()).unwrap());
+    let content_type = response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (content_type, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn test_openmetrics_exemplars() {
+    assert!(accepts_openmetrics(
+        "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5"
+    ));
+    assert!(!accepts_openmetrics(
+        "application/openmetrics-text;q=0,text/plain"
+    ));
+    assert!(!accepts_openmetrics("*/*"));
+
+    let request = Request::get("/exemplar/")
+        .header("x-request-id", "trace-1")
+        .body(Body::empty())
+        .unwrap();
+    let auth = AuthResponse {
+        client_id: String::new(),
+        service_id: "test/exemplar".into(),
+        sla: "Default".into(),
+        service_filters: Vec::new(),
+        client_filters: Vec::new(),
+        middlewares: Vec::new(),
+    };
+    let (tx, rx) = oneshot::channel();
+    let task = MwPostRequest {
+        context: RequestContext::new(&request, &auth),
+        response: Response::new(Body::from("hello")),
+        service_filters: Vec::new(),
+        client_filters: Vec::new(),
+        result: tx,
+    };
+    LoggerMiddleware::default().response(task).await;
+    rx.await.unwrap().unwrap();
+
+    let (content_type, body) = scrape(Some("application/openmetrics-text")).await;
+    assert!(content_type.starts_with("application/openmetrics-text"));
+    assert!(body.ends_with("# EOF\n"));
+    assert!(body.contains("# TYPE gateway_client_requests counter\n"));
+    // the request falls in one bucket, the only one with its exemplar
+    let exemplars: Vec<&str> = body
+        .lines()
+        .filter(|l| l.starts_with("gateway_request_duration_seconds_bucket{"))
+        .filter(|l| l.contains("service=\"test/exemplar\""))
+        .filter(|l| l.contains(" # {trace_id=\"trace-1\"} "))
+        .collect();
+    assert_eq!(exemplars.len(), 1);
+
+    let (content_type, body) = scrape(None).await;
+    assert!(content_type.starts_with("text/plain"));
+    assert!(body.contains("gateway_request_duration_seconds_bucket{"));
+    assert!(!body.contains("trace_id") && !body.contains("# EOF"));
+}
@@ -0,0 +1,138 @@
+use futures::FutureExt;
+use hyper::{Body, Request, Response};
+use hyperapi::config::OutlierSetting;
+use hyperapi::middleware::{EjectionGroup, OutlierDetection};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tower::{Service, ServiceExt};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// upstream answering with the status currently set
+fn upstream(
+    status: Arc<AtomicU16>,
+) -> impl Service<Request<Body>, Response = Response<Body>, Error = BoxError, Future = impl Send> + Clone
+{
+    tower::service_fn(move |_req: Request<Body>| {
+        let status = status.load(Ordering::SeqCst);
+        async move {
+            let resp = Response::builder()
+                .status(status)
+                .body(Body::empty())
+                .unwrap();
+            Ok::<_, BoxError>(resp)
+        }
+    })
+}
+
+fn setting() -> OutlierSetting {
+    OutlierSetting {
+        error_percent: 50,
+        window: 10,
+        min_requests: 4,
+        cooldown: 1,
+        ramp_up: 0,
+        max_ejection_percent: 0,
+    }
+}
+
+async fn send<S>(service: &mut S) -> u16
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = BoxError>,
+{
+    let resp = service.ready().await.unwrap().call(Request::default());
+    resp.await.unwrap().status().as_u16()
+}
+
+fn is_ready<S>(service: &mut S) -> bool
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = BoxError>,
+{
+    service.ready().now_or_never().is_some()
+}
+
+#[tokio::test]
+async fn test_outlier_ejection() {
+    let status = Arc::new(AtomicU16::new(200));
+    let group = Arc::new(EjectionGroup::new(2, 0));
+    let mut service = OutlierDetection::new(
+        upstream(status.clone()),
+        "test/outlier",
+        "1",
+        &setting(),
+        group,
+    );
+
+    // below min_requests errors are not judged
+    status.store(500, Ordering::SeqCst);
+    for _ in 0..3 {
+        assert_eq!(send(&mut service).await, 500);
+    }
+    assert!(is_ready(&mut service));
+
+    // error rate above 50%, ejected for cooldown
+    assert_eq!(send(&mut service).await, 500);
+    assert!(!is_ready(&mut service));
+
+    // back after cooldown, with fresh statistics
+    status.store(200, Ordering::SeqCst);
+    tokio::time::timeout(Duration::from_secs(3), service.ready())
+        .await
+        .expect("upstream is not back after cooldown")
+        .unwrap();
+    assert_eq!(send(&mut service).await, 200);
+    status.store(500, Ordering::SeqCst);
+    assert_eq!(send(&mut service).await, 500);
+    assert!(is_ready(&mut service));
+}
+
+#[tokio::test]
+async fn test_outlier_max_ejection() {
+    let status = Arc::new(AtomicU16::new(500));
+    let group = Arc::new(EjectionGroup::new(2, 0));
+    let mut first = OutlierDetection::new(
+        upstream(status.clone()),
+        "test/outlier_max",
+        "1",
+        &setting(),
+        group.clone(),
+    );
+    let mut second = OutlierDetection::new(
+        upstream(status.clone()),
+        "test/outlier_max",
+        "2",
+        &setting(),
+        group,
+    );
+    for _ in 0..4 {
+        send(&mut first).await;
+        send(&mut second).await;
+    }
+    // only half of upstreams are ejected
+    assert!(!is_ready(&mut first));
+    assert!(is_ready(&mut second));
+}
+
+#[tokio::test]
+async fn test_outlier_ramp_up() {
+    let status = Arc::new(AtomicU16::new(500));
+    let group = Arc::new(EjectionGroup::new(1, 100));
+    let mut setting = setting();
+    setting.ramp_up = 60;
+    let mut service = OutlierDetection::new(
+        upstream(status.clone()),
+        "test/ramp_up",
+        "1",
+        &setting,
+        group,
+    );
+    for _ in 0..4 {
+        send(&mut service).await;
+    }
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+
+    // about 10% of traffic right after cooldown
+    let admitted = (0..1000).filter(|_| is_ready(&mut service)).count();
+    assert!(admitted > 40 && admitted < 200, "{}", admitted);
+}
@@ -0,0 +1,96 @@
+use hyper::Request;
+use hyperapi::auth::{AuthRequest, AuthService, GatewayAuthError, PathMatching};
+use hyperapi::config::{ConfigUpdate, ServiceInfo};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+const SERVICE: &str = r#"
+service_id: svc
+path: /svc
+protocol: http
+auth:
+  type: None
+timeout: 1
+load_balance: random
+upstreams:
+  - id: "1"
+    target: "http://127.0.0.1:1/"
+    max_conn: 10
+    version: "1.0"
+    weight: 1
+    error_threshold: 0
+    error_reset: 60
+    retry_delay: 10
+filters: []
+sla: []
+"#;
+
+// auth service normalizing paths by `matching`
+fn start_auth(
+    conf_tx: &broadcast::Sender<ConfigUpdate>,
+    matching: PathMatching,
+) -> mpsc::Sender<AuthRequest> {
+    let (auth_tx, auth_rx) = mpsc::channel(16);
+    let mut auth = AuthService::new(conf_tx.subscribe(), auth_rx);
+    auth.path_matching = matching;
+    tokio::spawn(async move { auth.start().await });
+    auth_tx
+}
+
+// service id and the request path seen by middlewares
+async fn route(
+    auth_tx: &mpsc::Sender<AuthRequest>,
+    path: &str,
+) -> Result<(String, String), GatewayAuthError> {
+    let (head, _) = Request::get(path).body(()).unwrap().into_parts();
+    let (tx, rx) = oneshot::channel();
+    let _ = auth_tx.send(AuthRequest { head, result: tx }).await;
+    rx.await
+        .unwrap()
+        .map(|(head, auth)| (auth.service_id, head.uri.to_string()))
+}
+
+#[tokio::test]
+async fn test_path_matching() {
+    let (conf_tx, _) = broadcast::channel(16);
+    let exact = start_auth(&conf_tx, PathMatching::default());
+    let auth_tx = start_auth(
+        &conf_tx,
+        PathMatching {
+            collapse_slashes: true,
+            ignore_case: true,
+        },
+    );
+    let case_sensitive = start_auth(
+        &conf_tx,
+        PathMatching {
+            collapse_slashes: true,
+            ignore_case: false,
+        },
+    );
+    let service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    conf_tx.send(ConfigUpdate::ServiceUpdate(service)).unwrap();
+    // let auth services pick up the config
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // exact match by default
+    let unknown = |r| matches!(r, Err(GatewayAuthError::UnknownService));
+    assert!(unknown(route(&exact, "//svc/x").await));
+    assert!(unknown(route(&exact, "/Svc/x").await));
+
+    let svc = |path: &str| ("svc".to_string(), path.to_string());
+    assert_eq!(route(&auth_tx, "//Svc/x").await.unwrap(), svc("/svc/x"));
+    assert_eq!(
+        route(&auth_tx, "///svc/x?q=A").await.unwrap(),
+        svc("/svc/x?q=A")
+    );
+    // only the service path changes case
+    assert_eq!(
+        route(&auth_tx, "/SVC/Users").await.unwrap(),
+        svc("/svc/Users")
+    );
+    assert_eq!(route(&auth_tx, "/svc").await.unwrap(), svc("/svc"));
+    assert!(unknown(route(&auth_tx, "/svc2/x").await));
+
+    assert!(unknown(route(&case_sensitive, "//Svc/x").await));
+}
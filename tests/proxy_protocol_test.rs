@@ -0,0 +1,110 @@
+use hyper::server::accept::Accept;
+use hyper::server::conn::AddrIncoming;
+use hyperapi::proxy::https::Transport;
+use hyperapi::proxy::{parse_v1, read_proxy_header, Listener, ProxyProtocolAcceptor};
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+fn v2_header(command: u8, family: u8, addrs: &[u8]) -> Vec<u8> {
+    let mut header = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+    header.push(0x20 | command);
+    header.push(family);
+    header.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+    header.extend_from_slice(addrs);
+    header
+}
+
+#[test]
+fn test_parse_v1() {
+    let addr = parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n").unwrap();
+    assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+    let addr = parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").unwrap();
+    assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+    assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+
+    assert!(parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n").is_err());
+    assert!(parse_v1(b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 443\r\n").is_err());
+    assert!(parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 99999 443\r\n").is_err());
+    assert!(parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\n").is_err());
+}
+
+#[tokio::test]
+async fn test_read_v2_header() {
+    let addrs = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+    let mut data = v2_header(1, 0x11, &addrs);
+    data.extend_from_slice(b"GET / HTTP/1.1\r\n");
+    let mut reader = &data[..];
+    let addr = read_proxy_header(&mut reader).await.unwrap();
+    assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+    // stream is left at the request
+    assert_eq!(reader, b"GET / HTTP/1.1\r\n");
+
+    let mut addrs = vec![0u8; 36];
+    addrs[15] = 1;
+    addrs[32..34].copy_from_slice(&8080u16.to_be_bytes());
+    let data = v2_header(1, 0x21, &addrs);
+    let addr = read_proxy_header(&mut &data[..]).await.unwrap();
+    assert_eq!(addr, Some("[::1]:8080".parse().unwrap()));
+
+    // LOCAL health check of the load balancer
+    let data = v2_header(0, 0x00, &[]);
+    assert_eq!(read_proxy_header(&mut &data[..]).await.unwrap(), None);
+
+    let data = v2_header(1, 0x11, &addrs[..4]);
+    assert!(read_proxy_header(&mut &data[..]).await.is_err());
+    let data = b"GET / HTTP/1.1\r\n";
+    assert!(read_proxy_header(&mut &data[..]).await.is_err());
+    let data = [b"PROXY TCP4 ".to_vec(), vec![b'1'; 120]].concat();
+    assert!(read_proxy_header(&mut &data[..]).await.is_err());
+}
+
+#[test]
+fn test_listener_proxy_protocol() {
+    let listener: Listener = "0.0.0.0:80,proxy_protocol".parse().unwrap();
+    assert!(listener.proxy_protocol);
+    let listener: Listener = "0.0.0.0:80".parse().unwrap();
+    assert!(!listener.proxy_protocol);
+}
+
+#[tokio::test]
+async fn test_proxy_protocol_acceptor() {
+    let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr();
+    let mut acceptor = ProxyProtocolAcceptor::new(incoming);
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Option<SocketAddr>>(4);
+    tokio::spawn(async move {
+        while let Some(Ok(mut conn)) =
+            futures::future::poll_fn(|cx| std::pin::Pin::new(&mut acceptor).poll_accept(cx)).await
+        {
+            let _ = tx.send(conn.remote_addr()).await;
+            let mut buf = [0u8; 4];
+            if conn.read_exact(&mut buf).await.is_ok() {
+                let _ = conn.write_all(&buf).await;
+            }
+        }
+    });
+
+    // malformed header closes the connection, without blocking others
+    let mut bad = TcpStream::connect(addr).await.unwrap();
+    bad.write_all(b"HELLO WORLD\r\n").await.unwrap();
+    let mut silent = TcpStream::connect(addr).await.unwrap();
+
+    let mut good = TcpStream::connect(addr).await.unwrap();
+    good.write_all(b"PROXY TCP4 203.0.113.7 127.0.0.1 40000 80\r\nping")
+        .await
+        .unwrap();
+    assert_eq!(
+        rx.recv().await.unwrap(),
+        Some("203.0.113.7:40000".parse().unwrap())
+    );
+    let mut buf = [0u8; 4];
+    good.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+    assert_eq!(bad.read(&mut buf).await.unwrap_or(0), 0);
+
+    // unknown source falls back to the peer address
+    silent.write_all(b"PROXY UNKNOWN\r\n").await.unwrap();
+    let peer = rx.recv().await.unwrap().unwrap();
+    assert_eq!(peer, silent.local_addr().unwrap());
+}
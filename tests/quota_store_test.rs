@@ -0,0 +1,99 @@
+use hyper::{Body, Request};
+use hyperapi::auth::AuthResponse;
+use hyperapi::config::{FilterSetting, QuotaPeriod, QuotaSetting};
+use hyperapi::middleware::{
+    period_of, Middleware, MwNextAction, MwPreRequest, QuotaCounters, QuotaMiddleware,
+    RequestContext,
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time::OffsetDateTime;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+type Store = Arc<Mutex<HashMap<String, i64>>>;
+
+// redis server knowing INCRBY and EXPIRE only
+async fn start_redis(store: Store) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let store = store.clone();
+            tokio::spawn(async move {
+                let (read, mut write) = stream.into_split();
+                let mut lines = BufReader::new(read).lines();
+                while let Ok(Some(header)) = lines.next_line().await {
+                    // *<n>, then $<len> and the value for each argument
+                    let n: usize = header.trim_start_matches('*').parse().unwrap();
+                    let mut args = Vec::new();
+                    for _ in 0..n {
+                        lines.next_line().await.unwrap();
+                        args.push(lines.next_line().await.unwrap().unwrap());
+                    }
+                    let reply = match args[0].to_uppercase().as_str() {
+                        "INCRBY" => {
+                            let mut store = store.lock().unwrap();
+                            let value = store.entry(args[1].clone()).or_insert(0);
+                            *value += args[2].parse::<i64>().unwrap();
+                            format!(":{}\r\n", value)
+                        }
+                        "EXPIRE" => ":1\r\n".into(),
+                        _ => "-ERR unknown command\r\n".into(),
+                    };
+                    write.write_all(reply.as_bytes()).await.unwrap();
+                }
+            });
+        }
+    });
+    addr
+}
+
+async fn passed(mw: &mut QuotaMiddleware) -> bool {
+    let request = Request::get("/quota/").body(Body::empty()).unwrap();
+    let auth = AuthResponse {
+        client_id: "app1".into(),
+        service_id: "test/quota".into(),
+        sla: "Default".into(),
+        service_filters: Vec::new(),
+        client_filters: Vec::new(),
+        middlewares: Vec::new(),
+    };
+    let quota = FilterSetting::Quota(QuotaSetting {
+        limit: 7,
+        period: QuotaPeriod::Monthly,
+    });
+    let (tx, rx) = oneshot::channel();
+    let task = MwPreRequest {
+        context: RequestContext::new(&request, &auth),
+        request,
+        service_filters: Vec::new(),
+        client_filters: vec![quota],
+        result: tx,
+    };
+    mw.request(task).await;
+    matches!(rx.await.unwrap().unwrap().next, MwNextAction::Next(_))
+}
+
+#[tokio::test]
+async fn test_quota_shared_by_replicas() {
+    let (period, _) = period_of(QuotaPeriod::Monthly, OffsetDateTime::now_utc());
+    let key = format!("hyperapi:quota:test/quota:app1:{}", period);
+    // used by other replicas, or before a restart
+    let store: Store = Arc::new(Mutex::new(HashMap::from([(key.clone(), 5)])));
+    let addr = start_redis(store.clone()).await;
+    let client = redis::Client::open(format!("redis://{}/", addr)).unwrap();
+
+    let mut mw = QuotaMiddleware::new(QuotaCounters::default(), Some(client));
+    assert!(passed(&mut mw).await);
+    // usage of other replicas is read back with the flush
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert!(passed(&mut mw).await);
+    assert!(!passed(&mut mw).await);
+
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(store.lock().unwrap()[&key], 7);
+}
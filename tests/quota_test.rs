@@ -0,0 +1,139 @@
+use hyper::{Body, Request};
+use hyperapi::auth::AuthResponse;
+use hyperapi::config::{validate_service, FilterSetting, QuotaPeriod, QuotaSetting, ServiceInfo};
+use hyperapi::middleware::{
+    period_of, Middleware, MwNextAction, MwPreRequest, QuotaCounters, QuotaMiddleware,
+    RequestContext,
+};
+use serde_json::Value;
+use time::{Date, Month, OffsetDateTime};
+use tokio::sync::oneshot;
+
+// status, error code and Retry-After of a rejected request, None if passed
+async fn call(
+    mw: &mut QuotaMiddleware,
+    service_id: &str,
+    client_id: &str,
+    limit: u64,
+) -> Option<(u16, String, u64)> {
+    let request = Request::get("/quota/").body(Body::empty()).unwrap();
+    let auth = AuthResponse {
+        client_id: client_id.into(),
+        service_id: service_id.into(),
+        sla: "Default".into(),
+        service_filters: Vec::new(),
+        client_filters: Vec::new(),
+        middlewares: Vec::new(),
+    };
+    let quota = FilterSetting::Quota(QuotaSetting {
+        limit,
+        period: QuotaPeriod::Monthly,
+    });
+    let (tx, rx) = oneshot::channel();
+    let task = MwPreRequest {
+        context: RequestContext::new(&request, &auth),
+        request,
+        service_filters: Vec::new(),
+        client_filters: vec![quota],
+        result: tx,
+    };
+    mw.request(task).await;
+    match rx.await.unwrap().unwrap().next {
+        MwNextAction::Next(_) => None,
+        MwNextAction::Return(resp) => {
+            let status = resp.status().as_u16();
+            let retry_after = resp.headers()["retry-after"]
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap();
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            Some((status, body["code"].as_str().unwrap().into(), retry_after))
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_quota() {
+    let counters = QuotaCounters::default();
+    let mut mw = QuotaMiddleware::new(counters.clone(), None);
+    assert_eq!(call(&mut mw, "test/quota", "app1", 2).await, None);
+    assert_eq!(call(&mut mw, "test/quota", "app1", 2).await, None);
+    let (status, code, retry_after) = call(&mut mw, "test/quota", "app1", 2).await.unwrap();
+    assert_eq!((status, code.as_str()), (429, "quota_exceeded"));
+    // until the start of next month
+    assert!((1..=31 * 86400).contains(&retry_after));
+
+    // counted per client and service
+    assert_eq!(call(&mut mw, "test/quota", "app2", 2).await, None);
+    assert_eq!(call(&mut mw, "test/other", "app1", 2).await, None);
+    // raised cap applies to the current period
+    assert_eq!(call(&mut mw, "test/quota", "app1", 3).await, None);
+
+    let usage: Vec<_> = counters
+        .usage()
+        .into_iter()
+        .filter(|u| u.service_id == "test/quota")
+        .map(|u| (u.client_id, u.used, u.limit))
+        .collect();
+    assert_eq!(usage, [("app1".into(), 3, 3), ("app2".into(), 1, 2)]);
+}
+
+fn utc(year: i32, month: Month, day: u8, hour: u8) -> OffsetDateTime {
+    let date = Date::from_calendar_date(year, month, day).unwrap();
+    date.with_hms(hour, 0, 0).unwrap().assume_utc()
+}
+
+#[test]
+fn test_quota_period() {
+    let now = utc(2021, Month::December, 31, 23);
+    assert_eq!(
+        period_of(QuotaPeriod::Monthly, now),
+        ("2021-12".into(), 3600)
+    );
+    assert_eq!(
+        period_of(QuotaPeriod::Daily, now),
+        ("2021-12-31".into(), 3600)
+    );
+    let now = utc(2021, Month::February, 1, 0);
+    assert_eq!(
+        period_of(QuotaPeriod::Monthly, now),
+        ("2021-02".into(), 28 * 86400)
+    );
+}
+
+#[test]
+fn test_quota_validation() {
+    let service = r#"
+service_id: test/quota
+path: /quota
+protocol: http
+auth:
+  type: AppKey
+timeout: 1
+load_balance: random
+upstreams:
+  - id: "1"
+    target: "http://127.0.0.1:1/"
+    max_conn: 10
+    version: "1.0"
+    weight: 1
+    error_threshold: 0
+    error_reset: 60
+    retry_delay: 10
+filters: []
+sla:
+  - name: Default
+    filters:
+      - type: Quota
+        setting:
+          limit: 10000
+          period: daily
+"#;
+    let parsed: ServiceInfo = serde_yaml::from_str(service).unwrap();
+    validate_service(&parsed).unwrap();
+    let parsed: ServiceInfo =
+        serde_yaml::from_str(&service.replace("limit: 10000", "limit: 0")).unwrap();
+    assert!(validate_service(&parsed).is_err());
+}
@@ -0,0 +1,134 @@
+use hyper::{Body, Request};
+use hyperapi::auth::AuthResponse;
+use hyperapi::config::{validate_service, ConfigUpdate, ServiceInfo};
+use hyperapi::middleware::{
+    Middleware, MwNextAction, MwPreRequest, RateLimitMiddleware, RequestContext,
+};
+use std::net::SocketAddr;
+use tokio::sync::oneshot;
+
+const SERVICE: &str = r#"
+service_id: test/public
+path: /public
+protocol: http
+auth:
+  type: None
+timeout: 1
+load_balance: random
+upstreams:
+  - id: "1"
+    target: "http://127.0.0.1:1/"
+    max_conn: 10
+    version: "1.0"
+    weight: 1
+    error_threshold: 0
+    error_reset: 60
+    retry_delay: 10
+filters:
+  - type: IpRateLimit
+    setting:
+      interval: 60
+      limit: 2
+      burst: 2
+      ipv4_prefix: 24
+sla: []
+"#;
+
+// status and Retry-After of a limited request, None if passed
+async fn call(
+    mw: &mut RateLimitMiddleware,
+    peer: &str,
+    forwarded_for: Option<&str>,
+) -> Option<(u16, String)> {
+    let mut request = Request::get("/public/").body(Body::empty()).unwrap();
+    request
+        .extensions_mut()
+        .insert(peer.parse::<SocketAddr>().unwrap());
+    if let Some(xff) = forwarded_for {
+        request
+            .headers_mut()
+            .insert("x-forwarded-for", xff.parse().unwrap());
+    }
+    let auth = AuthResponse {
+        client_id: String::new(),
+        service_id: "test/public".into(),
+        sla: String::new(),
+        service_filters: Vec::new(),
+        client_filters: Vec::new(),
+        middlewares: Vec::new(),
+    };
+    let (tx, rx) = oneshot::channel();
+    let task = MwPreRequest {
+        context: RequestContext::new(&request, &auth),
+        request,
+        service_filters: Vec::new(),
+        client_filters: Vec::new(),
+        result: tx,
+    };
+    mw.request(task).await;
+    match rx.await.unwrap().unwrap().next {
+        MwNextAction::Next(_) => None,
+        MwNextAction::Return(resp) => {
+            let retry_after = resp.headers()["retry-after"].to_str().unwrap().into();
+            Some((resp.status().as_u16(), retry_after))
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_ip_rate_limit() {
+    let mut mw = RateLimitMiddleware::default();
+    let service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    mw.config_update(ConfigUpdate::ServiceUpdate(service));
+
+    assert_eq!(call(&mut mw, "192.0.2.1:1000", None).await, None);
+    // same /24 shares the bucket
+    assert_eq!(call(&mut mw, "192.0.2.200:1000", None).await, None);
+    let (status, retry_after) = call(&mut mw, "192.0.2.1:1001", None).await.unwrap();
+    assert_eq!(status, 429);
+    let retry_after: u64 = retry_after.parse().unwrap();
+    assert!((1..=60).contains(&retry_after));
+    // IPv4 mapped address is the same client
+    assert!(call(&mut mw, "[::ffff:192.0.2.9]:1000", None)
+        .await
+        .is_some());
+
+    assert_eq!(call(&mut mw, "198.51.100.1:1000", None).await, None);
+    // peer is not a trusted proxy, header is ignored
+    let limited = call(&mut mw, "192.0.2.1:1000", Some("203.0.113.1")).await;
+    assert!(limited.is_some());
+}
+
+#[tokio::test]
+async fn test_ip_rate_limit_trusted_proxy() {
+    let mut mw = RateLimitMiddleware::new(vec!["10.0.0.0/8".parse().unwrap()]);
+    let config = SERVICE.replace("ipv4_prefix: 24", "ipv4_prefix: 32");
+    let service: ServiceInfo = serde_yaml::from_str(&config).unwrap();
+    mw.config_update(ConfigUpdate::ServiceUpdate(service));
+
+    let proxy = "10.0.0.1:1000";
+    // last entry is the one added by the proxy, earlier ones are client supplied
+    for spoofed in ["1.1.1.1", "2.2.2.2"] {
+        let xff = format!("{}, 203.0.113.1", spoofed);
+        assert_eq!(call(&mut mw, proxy, Some(&xff)).await, None);
+    }
+    assert!(call(&mut mw, proxy, Some("3.3.3.3, 203.0.113.1"))
+        .await
+        .is_some());
+    assert_eq!(call(&mut mw, proxy, Some("203.0.113.2")).await, None);
+    // without header the proxy is the client
+    assert_eq!(call(&mut mw, proxy, None).await, None);
+}
+
+#[test]
+fn test_ip_rate_limit_validation() {
+    let service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    validate_service(&service).unwrap();
+    for (from, to) in [
+        ("ipv4_prefix: 24", "ipv4_prefix: 33"),
+        ("interval: 60", "interval: 0"),
+    ] {
+        let service: ServiceInfo = serde_yaml::from_str(&SERVICE.replace(from, to)).unwrap();
+        assert!(validate_service(&service).is_err());
+    }
+}
@@ -0,0 +1,255 @@
+use hyperapi::config::{redis_config, ConfigUpdate, SyncState};
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+
+// in memory redis speaking the commands used by the config source
+#[derive(Clone)]
+struct FakeRedis {
+    keys: Arc<Mutex<HashMap<String, String>>>,
+    flags: Arc<Mutex<String>>,         // notify-keyspace-events
+    commands: Arc<Mutex<Vec<String>>>, // every command received
+    events: broadcast::Sender<Option<(String, String)>>, // keyspace event, None drops connections
+}
+
+impl FakeRedis {
+    async fn start() -> (Self, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let redis = FakeRedis {
+            keys: Arc::new(Mutex::new(HashMap::new())),
+            flags: Arc::new(Mutex::new("K$gx".into())),
+            commands: Arc::new(Mutex::new(Vec::new())),
+            events: broadcast::channel(16).0,
+        };
+        let server = redis.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(server.clone().serve(stream));
+            }
+        });
+        (redis, addr)
+    }
+
+    fn set(&self, key: &str, value: &str) {
+        let key = key.to_string();
+        self.keys.lock().unwrap().insert(key.clone(), value.into());
+        let _ = self.events.send(Some((key, "set".into())));
+    }
+
+    fn del(&self, key: &str) {
+        self.keys.lock().unwrap().remove(key);
+        let _ = self.events.send(Some((key.into(), "del".into())));
+    }
+
+    async fn serve(self, stream: TcpStream) {
+        let mut events = self.events.subscribe();
+        let (read, mut write) = stream.into_split();
+        let mut read = BufReader::new(read);
+        let mut subscribed = false;
+        loop {
+            tokio::select! {
+                cmd = read_command(&mut read) => {
+                    let cmd = match cmd {
+                        Some(cmd) => cmd,
+                        None => return,
+                    };
+                    self.commands.lock().unwrap().push(cmd.join(" "));
+                    let reply = match cmd[0].to_uppercase().as_str() {
+                        "CONFIG" if cmd[1].eq_ignore_ascii_case("GET") => {
+                            array(&[bulk(&cmd[2]), bulk(&self.flags.lock().unwrap())])
+                        }
+                        "SCAN" => {
+                            let keys: Vec<_> =
+                                self.keys.lock().unwrap().keys().map(|k| bulk(k)).collect();
+                            array(&[bulk("0"), array(&keys)])
+                        }
+                        "GET" => match self.keys.lock().unwrap().get(&cmd[1]) {
+                            Some(v) => bulk(v),
+                            None => "$-1\r\n".into(),
+                        },
+                        "PSUBSCRIBE" => {
+                            subscribed = true;
+                            array(&[bulk("psubscribe"), bulk(&cmd[1]), ":1\r\n".into()])
+                        }
+                        _ => "+OK\r\n".into(),
+                    };
+                    if write.write_all(reply.as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+                event = events.recv() => match event {
+                    Ok(Some((key, op))) if subscribed => {
+                        let channel = format!("__keyspace@0__:{}", key);
+                        let msg = array(&[bulk("pmessage"), bulk("*"), bulk(&channel), bulk(&op)]);
+                        if write.write_all(msg.as_bytes()).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(Some(_)) => {}
+                    _ => return,
+                },
+            }
+        }
+    }
+}
+
+async fn read_command(read: &mut BufReader<OwnedReadHalf>) -> Option<Vec<String>> {
+    let mut line = String::new();
+    read.read_line(&mut line).await.ok()?;
+    let count: usize = line.trim().strip_prefix('*')?.parse().ok()?;
+    let mut args = Vec::new();
+    for _ in 0..count {
+        line.clear();
+        read.read_line(&mut line).await.ok()?;
+        let len: usize = line.trim().strip_prefix('$')?.parse().ok()?;
+        let mut arg = vec![0; len + 2];
+        read.read_exact(&mut arg).await.ok()?;
+        arg.truncate(len);
+        args.push(String::from_utf8(arg).ok()?);
+    }
+    Some(args)
+}
+
+fn bulk(s: &str) -> String {
+    format!("${}\r\n{}\r\n", s.len(), s)
+}
+
+fn array(items: &[String]) -> String {
+    format!("*{}\r\n{}", items.len(), items.concat())
+}
+
+fn service(id: &str) -> String {
+    json!({
+        "service_id": id,
+        "path": format!("/{}", id),
+        "protocol": "http",
+        "auth": { "type": "None" },
+        "timeout": 3,
+        "load_balance": "random",
+        "filters": [],
+        "sla": [],
+        "upstreams": [],
+    })
+    .to_string()
+}
+
+fn client(id: &str) -> String {
+    json!({
+        "client_id": id,
+        "app_key": "key",
+        "pub_key": "",
+        "ip_whitelist": [],
+        "services": {},
+    })
+    .to_string()
+}
+
+// update received as a short description, "+service/a", "-client/c"
+async fn next(rx: &mut mpsc::Receiver<ConfigUpdate>) -> String {
+    let update = tokio::time::timeout(Duration::from_secs(3), rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    match update {
+        ConfigUpdate::ServiceUpdate(s) => format!("+service/{}", s.service_id),
+        ConfigUpdate::ServiceRemove(sid) => format!("-service/{}", sid),
+        ConfigUpdate::ClientUpdate(c) => format!("+client/{}", c.client_id),
+        ConfigUpdate::ClientRemove(cid) => format!("-client/{}", cid),
+        ConfigUpdate::ConfigReady(_) => "ready".into(),
+    }
+}
+
+// updates up to the next ConfigReady, sorted as keys are scanned in any order
+async fn next_sync(rx: &mut mpsc::Receiver<ConfigUpdate>) -> Vec<String> {
+    let mut updates = Vec::new();
+    loop {
+        match next(rx).await.as_str() {
+            "ready" => break,
+            u => updates.push(u.to_string()),
+        }
+    }
+    updates.sort();
+    updates
+}
+
+#[tokio::test]
+async fn test_redis_config() {
+    let (redis, addr) = FakeRedis::start().await;
+    redis.set("/gw/services/a", &service("a"));
+    redis.set("/gw/clients/c", &client("c"));
+    redis.set("/other/services/x", &service("x"));
+
+    let source = format!("redis://{}/gw", addr);
+    let (tx, mut rx) = mpsc::channel(16);
+    let mut state = SyncState::default();
+    let watch = {
+        let source = source.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let result = redis_config::watch_config(source, tx, &mut state).await;
+            (result, state)
+        })
+    };
+    assert_eq!(next_sync(&mut rx).await, ["+client/c", "+service/a"]);
+
+    redis.set("/gw/services/b", &service("b"));
+    assert_eq!(next(&mut rx).await, "+service/b");
+    redis.del("/gw/clients/c");
+    assert_eq!(next(&mut rx).await, "-client/c");
+
+    // lost connection, a service deleted meanwhile is removed on resync
+    let _ = redis.events.send(None);
+    let (result, mut state) = watch.await.unwrap();
+    assert!(result.is_ok());
+    redis.keys.lock().unwrap().remove("/gw/services/a");
+    redis.set("/gw/clients/d", &client("d"));
+    let watch = tokio::spawn(async move {
+        let _ = redis_config::watch_config(source, tx, &mut state).await;
+    });
+    assert_eq!(
+        next_sync(&mut rx).await,
+        ["+client/d", "+service/b", "-service/a"]
+    );
+    watch.abort();
+
+    // notifications are left to the operator
+    let commands = redis.commands.lock().unwrap();
+    assert!(commands
+        .iter()
+        .any(|c| c == "CONFIG GET notify-keyspace-events"));
+    assert!(!commands.iter().any(|c| c.starts_with("CONFIG SET")));
+}
+
+#[tokio::test]
+async fn test_redis_config_errors() {
+    let (redis, addr) = FakeRedis::start().await;
+    let (tx, _rx) = mpsc::channel(16);
+    let mut state = SyncState::default();
+
+    // keyspace notifications not enabled on the server
+    *redis.flags.lock().unwrap() = "Kg".into();
+    let source = format!("redis://{}/gw", addr);
+    let result = redis_config::watch_config(source.clone(), tx.clone(), &mut state).await;
+    assert!(result.unwrap_err().to_string().contains("lacks $x"));
+
+    // all events
+    *redis.flags.lock().unwrap() = "KA".into();
+    let mut watch = tokio::spawn(async move {
+        let mut state = SyncState::default();
+        redis_config::watch_config(source, tx.clone(), &mut state).await
+    });
+    let running = tokio::time::timeout(Duration::from_millis(100), &mut watch).await;
+    assert!(running.is_err());
+    watch.abort();
+
+    let (tx, _rx) = mpsc::channel(16);
+    let result = redis_config::watch_config("redis://[::1/gw".into(), tx, &mut state).await;
+    assert!(result.is_err());
+}
@@ -0,0 +1,120 @@
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Request, Response, Server};
+use hyperapi::config::ConfigSource;
+use hyperapi::proxy::{GatewayServer, HardenedHeaders, ResponseHeaders};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+// upstream telling its software and internal routing
+async fn start_upstream() -> SocketAddr {
+    let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr();
+    let make_svc = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|_req: Request<Body>| async {
+            let resp = Response::builder()
+                .header("server", "Apache/2.4.1")
+                .header("x-powered-by", "PHP/7.4")
+                .header("x-backend-node", "node-3")
+                .body(Body::from("pong"))
+                .unwrap();
+            Ok::<_, Infallible>(resp)
+        }))
+    });
+    tokio::spawn(Server::builder(incoming).serve(make_svc));
+    addr
+}
+
+async fn start_gateway(upstream: SocketAddr, headers: ResponseHeaders) -> SocketAddr {
+    let config = format!(
+        r#"
+clients: []
+services:
+  - service_id: hardened
+    path: /hardened
+    protocol: http
+    auth:
+      type: None
+    timeout: 3
+    load_balance: random
+    filters: []
+    sla: []
+    upstreams:
+      - id: "1"
+        target: "http://{}/"
+        max_conn: 10
+        weight: 1
+        version: "1"
+        error_threshold: 0
+        error_reset: 60
+        retry_delay: 10
+"#,
+        upstream
+    );
+    let path = std::env::temp_dir().join(format!("hyperapi_headers_{}.yaml", upstream.port()));
+    std::fs::write(&path, config).unwrap();
+
+    let mut gateway = GatewayServer::new(ConfigSource::new(path.to_string_lossy().into()));
+    gateway.response_headers = HardenedHeaders::new(headers).unwrap();
+    while *gateway.status.lock().unwrap() == 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // let middleware workers pick up the config
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr();
+    let make_svc = make_service_fn(move |_| {
+        let handler = gateway.make_service();
+        async move { Ok::<_, Infallible>(handler) }
+    });
+    tokio::spawn(Server::builder(incoming).serve(make_svc));
+    addr
+}
+
+async fn call(gateway: SocketAddr, path: &str) -> Response<Body> {
+    let uri = format!("http://{}{}", gateway, path).parse().unwrap();
+    Client::new().get(uri).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_response_headers() {
+    let upstream = start_upstream().await;
+
+    // upstream headers pass through by default
+    let gateway = start_gateway(upstream, ResponseHeaders::default()).await;
+    let resp = call(gateway, "/hardened/").await;
+    assert_eq!(resp.headers()["server"], "Apache/2.4.1");
+    assert_eq!(resp.headers()["x-powered-by"], "PHP/7.4");
+
+    let headers = ResponseHeaders {
+        server: Some("gateway".into()),
+        removal: vec!["X-Powered-By".into(), "x-backend-node".into()],
+    };
+    let gateway = start_gateway(upstream, headers).await;
+    let resp = call(gateway, "/hardened/").await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["server"], "gateway");
+    assert!(!resp.headers().contains_key("x-powered-by"));
+    assert!(!resp.headers().contains_key("x-backend-node"));
+    // gateway's own responses too
+    let resp = call(gateway, "/unknown/").await;
+    assert_ne!(resp.status(), 200);
+    assert_eq!(resp.headers()["server"], "gateway");
+
+    let headers = ResponseHeaders {
+        server: Some(String::new()),
+        removal: Vec::new(),
+    };
+    let gateway = start_gateway(upstream, headers).await;
+    let resp = call(gateway, "/hardened/").await;
+    assert!(!resp.headers().contains_key("server"));
+    assert_eq!(resp.headers()["x-powered-by"], "PHP/7.4");
+
+    let invalid = ResponseHeaders {
+        server: None,
+        removal: vec!["bad header".into()],
+    };
+    assert!(HardenedHeaders::new(invalid).is_err());
+}
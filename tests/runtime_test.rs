@@ -0,0 +1,101 @@
+use std::process::{Child, Command, Output, Stdio};
+use std::time::{Duration, Instant};
+
+const CONFIG: &str = r#"
+clients: []
+services:
+  - service_id: runtime
+    path: /runtime
+    protocol: http
+    auth:
+      type: None
+    timeout: 3
+    load_balance: random
+    filters: []
+    sla: []
+    upstreams:
+      - id: runtime1
+        target: "http://127.0.0.1:1/"
+        max_conn: 10
+        weight: 1
+        version: "1"
+        error_threshold: 0
+        error_reset: 60
+        retry_delay: 10
+"#;
+
+fn gateway(args: &[&str], env: &[(&str, &str)]) -> Command {
+    let path = std::env::temp_dir().join(format!("hyperapi_runtime_{}.yaml", std::process::id()));
+    std::fs::write(&path, CONFIG).unwrap();
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_hyperapi"));
+    cmd.arg("--config")
+        .arg(&path)
+        .args(["--listen", &addr.to_string()])
+        .args(args)
+        .envs(env.iter().copied())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    cmd
+}
+
+// runtime threads of a running gateway, once their number is stable
+fn runtime_threads(child: &Child) -> usize {
+    let count = || {
+        std::fs::read_dir(format!("/proc/{}/task", child.id()))
+            .unwrap()
+            .filter_map(|task| std::fs::read_to_string(task.unwrap().path().join("comm")).ok())
+            .filter(|name| name.starts_with("tokio-runtime"))
+            .count()
+    };
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut last = 0;
+    loop {
+        std::thread::sleep(Duration::from_millis(500));
+        let n = count();
+        if n == last || Instant::now() > deadline {
+            return n;
+        }
+        last = n;
+    }
+}
+
+fn threads_with(args: &[&str], env: &[(&str, &str)]) -> usize {
+    let mut child = gateway(args, env).spawn().unwrap();
+    let n = runtime_threads(&child);
+    child.kill().unwrap();
+    child.wait().unwrap();
+    n
+}
+
+fn rejected(args: &[&str], env: &[(&str, &str)]) -> String {
+    let Output { status, stderr, .. } = gateway(args, env).output().unwrap();
+    assert!(!status.success());
+    String::from_utf8_lossy(&stderr).into_owned()
+}
+
+#[test]
+fn test_runtime_options() {
+    let tuning = [
+        "--max_blocking_threads",
+        "4",
+        "--thread_stack_size",
+        "4194304",
+    ];
+    let two = threads_with(&[&["--worker_threads", "2"], &tuning[..]].concat(), &[]);
+    let five = threads_with(&[&["--worker_threads", "5"], &tuning[..]].concat(), &[]);
+    assert_eq!(five - two, 3);
+
+    // env is used without the flag, the flag wins over env
+    let env = [("HYPERAPI_WORKER_THREADS", "5")];
+    assert_eq!(threads_with(&[], &env), five);
+    assert_eq!(threads_with(&["--worker_threads", "2"], &env), two);
+
+    assert!(rejected(&["--worker_threads", "0"], &[]).contains("Invalid worker_threads"));
+    assert!(rejected(&["--thread_stack_size", "big"], &[]).contains("Invalid thread_stack_size"));
+    let env = [("HYPERAPI_MAX_BLOCKING_THREADS", "-1")];
+    assert!(rejected(&[], &env).contains("Invalid max_blocking_threads"));
+}
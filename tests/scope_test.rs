@@ -0,0 +1,185 @@
+use hyper::{Body, Request};
+use hyperapi::auth::{AuthProvider, AuthResponse, ClientScopes, JWTAuthProvider};
+use hyperapi::config::{validate_service, ClientInfo, ConfigError, ConfigUpdate, ServiceInfo};
+use hyperapi::middleware::{
+    GatewayError, Middleware, MwNextAction, MwPreRequest, RequestContext, ScopeMiddleware,
+};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use serde_json::json;
+use tokio::sync::oneshot;
+
+const SERVICE: &str = r#"
+service_id: scoped
+path: /svc
+protocol: http
+auth:
+  type: JWT
+  scope_claim: permissions
+timeout: 1
+load_balance: random
+upstreams:
+  - id: "1"
+    target: "http://127.0.0.1:1/"
+    max_conn: 10
+    version: "1.0"
+    weight: 1
+    error_threshold: 0
+    error_reset: 60
+    retry_delay: 10
+filters:
+  - type: RequireScopes
+    setting:
+      methods: POST,PUT
+      path_pattern: "/orders/*"
+      scopes: ["orders:write"]
+  - type: RequireScopes
+    setting:
+      path_pattern: "/admin/*"
+      scopes: [admin, "orders:write"]
+sla: []
+"#;
+
+// status of a request through the Scope middleware, 200 if passed
+async fn status(mw: &mut ScopeMiddleware, method: &str, path: &str, scopes: &[&str]) -> u16 {
+    let mut request = Request::builder()
+        .method(method)
+        .uri(path)
+        .body(Body::empty())
+        .unwrap();
+    if !scopes.is_empty() {
+        let scopes = scopes.iter().map(|s| s.to_string()).collect();
+        request.extensions_mut().insert(ClientScopes(scopes));
+    }
+    let service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    let auth = AuthResponse {
+        client_id: "app1".into(),
+        service_id: "scoped".into(),
+        sla: String::new(),
+        service_filters: service.filters.clone(),
+        client_filters: Vec::new(),
+        middlewares: Vec::new(),
+    };
+    let (tx, rx) = oneshot::channel();
+    let task = MwPreRequest {
+        context: RequestContext::new(&request, &auth),
+        request,
+        service_filters: service.filters,
+        client_filters: Vec::new(),
+        result: tx,
+    };
+    mw.request(task).await;
+    match rx.await.unwrap() {
+        Ok(resp) => {
+            assert!(matches!(resp.next, MwNextAction::Next(_)));
+            200
+        }
+        Err(e) => {
+            assert!(matches!(&e, GatewayError::Forbidden(d) if d.starts_with("missing scope")));
+            e.status().0
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_require_scopes() {
+    let service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    validate_service(&service).unwrap();
+    let mut mw = ScopeMiddleware::default();
+    mw.config_update(ConfigUpdate::ServiceUpdate(service));
+
+    assert_eq!(status(&mut mw, "GET", "/svc/orders/1", &[]).await, 200);
+    assert_eq!(status(&mut mw, "POST", "/svc/orders/1", &[]).await, 403);
+    assert_eq!(
+        status(&mut mw, "POST", "/svc/orders/1", &["orders:read"]).await,
+        403
+    );
+    assert_eq!(
+        status(
+            &mut mw,
+            "POST",
+            "/svc/orders/1",
+            &["orders:read", "orders:write"]
+        )
+        .await,
+        200
+    );
+    // every scope of a rule is required
+    assert_eq!(
+        status(&mut mw, "GET", "/svc/admin/x", &["admin"]).await,
+        403
+    );
+    assert_eq!(
+        status(&mut mw, "GET", "/svc/admin/x", &["orders:write", "admin"]).await,
+        200
+    );
+}
+
+#[test]
+fn test_invalid_require_scopes() {
+    let service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    for (from, to) in [
+        (r#"scopes: ["orders:write"]"#, "scopes: []"),
+        (
+            r#"path_pattern: "/orders/*""#,
+            r#"path_pattern: "/orders/[""#,
+        ),
+    ] {
+        let broken: ServiceInfo = serde_yaml::from_str(&SERVICE.replace(from, to)).unwrap();
+        assert_ne!(broken, service);
+        assert!(matches!(
+            validate_service(&broken),
+            Err(ConfigError::InvalidRequireScopes(..))
+        ));
+    }
+}
+
+// scopes the JWT provider extracts from a token with the given claims
+fn token_scopes(provider: &JWTAuthProvider, claims: serde_json::Value) -> Vec<String> {
+    let header = Header {
+        kid: Some("app1".into()),
+        ..Default::default()
+    };
+    let token = encode(&header, &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+    let (head, _) = Request::get("/svc/orders/1")
+        .header("authorization", format!("Bearer {}", token))
+        .body(())
+        .unwrap()
+        .into_parts();
+    let (_, result) = provider.identify_client(head, "scoped").unwrap();
+    result.scopes
+}
+
+#[test]
+fn test_jwt_scope_claim() {
+    let mut provider = JWTAuthProvider::new();
+    provider.update_config(ConfigUpdate::ClientUpdate(ClientInfo {
+        client_id: "app1".into(),
+        app_key: "key1".into(),
+        pub_key: "secret".into(),
+        ip_whitelist: Vec::new(),
+        services: [("scoped".to_string(), "Default".to_string())].into(),
+        roles: Vec::new(),
+    }));
+    let exp = 4_000_000_000u64;
+    let claims = |extra: serde_json::Value| {
+        let mut claims = json!({ "exp": exp, "iat": 0, "iss": "test", "sub": "app1" });
+        claims
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        claims
+    };
+
+    // `scope` by default, space separated
+    assert_eq!(
+        token_scopes(&provider, claims(json!({ "scope": "a b" }))),
+        vec!["a", "b"]
+    );
+    let service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    provider.update_config(ConfigUpdate::ServiceUpdate(service));
+    assert_eq!(
+        token_scopes(&provider, claims(json!({ "permissions": ["c", "d"] }))),
+        vec!["c", "d"]
+    );
+    assert!(token_scopes(&provider, claims(json!({ "scope": "a b" }))).is_empty());
+}
@@ -0,0 +1,148 @@
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Response, Server};
+use std::convert::Infallible;
+use std::net::{SocketAddr, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+const CONFIG: &str = r#"
+clients: []
+services:
+  - service_id: slow
+    path: /slow
+    protocol: http
+    auth:
+      type: None
+    timeout: 10
+    load_balance: random
+    filters: []
+    sla: []
+    upstreams:
+      - id: slow1
+        target: "http://UPSTREAM/"
+        max_conn: 10
+        weight: 1
+        version: "1"
+        error_threshold: 0
+        error_reset: 60
+        retry_delay: 10
+"#;
+
+// upstream answering after `delay`
+async fn start_upstream(delay: Duration) -> SocketAddr {
+    let make_svc = make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |_| async move {
+            tokio::time::sleep(delay).await;
+            Ok::<_, Infallible>(Response::new(Body::from("done")))
+        }))
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+// gateway binary serving the slow service, once it is ready
+async fn start_gateway(
+    name: &str,
+    upstream: SocketAddr,
+    drain_timeout: u64,
+) -> (Child, SocketAddr) {
+    let path = std::env::temp_dir().join(format!(
+        "hyperapi_shutdown_{}_{}.yaml",
+        name,
+        std::process::id()
+    ));
+    std::fs::write(&path, CONFIG.replace("UPSTREAM", &upstream.to_string())).unwrap();
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let child = Command::new(env!("CARGO_BIN_EXE_hyperapi"))
+        .arg("--config")
+        .arg(&path)
+        .args(["--listen", &addr.to_string()])
+        .args(["--drain_timeout", &drain_timeout.to_string()])
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let client = Client::new();
+    let readyz = format!("http://{}/readyz", addr)
+        .parse::<hyper::Uri>()
+        .unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        if let Ok(resp) = client.get(readyz.clone()).await {
+            if resp.status() == 200 {
+                break;
+            }
+        }
+        assert!(Instant::now() < deadline, "gateway not ready");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    (child, addr)
+}
+
+fn signal(child: &Child, name: &str) {
+    let status = Command::new("kill")
+        .args([name, &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+// exit code of the gateway, waiting up to 10 seconds
+async fn exit_code(mut child: Child) -> Option<i32> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            return status.code();
+        }
+        if Instant::now() > deadline {
+            let _ = child.kill();
+            panic!("gateway still running");
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_graceful_drain() {
+    let upstream = start_upstream(Duration::from_secs(1)).await;
+    for (i, name) in ["-TERM", "-INT"].into_iter().enumerate() {
+        let (gateway, addr) = start_gateway(&format!("drain{}", i), upstream, 5).await;
+        let url = format!("http://{}/slow/x", addr);
+        let in_flight = tokio::spawn(async move {
+            let resp = Client::new().get(url.parse().unwrap()).await.unwrap();
+            let status = resp.status().as_u16();
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            (status, body)
+        });
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        signal(&gateway, name);
+
+        // new connections are refused while the in-flight request completes
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(TcpStream::connect(addr).is_err());
+        let (status, body) = in_flight.await.unwrap();
+        assert_eq!((status, &body[..]), (200, &b"done"[..]));
+        assert_eq!(exit_code(gateway).await, Some(0));
+    }
+}
+
+#[tokio::test]
+async fn test_drain_deadline() {
+    let upstream = start_upstream(Duration::from_secs(5)).await;
+    let (gateway, addr) = start_gateway("deadline", upstream, 1).await;
+    let url = format!("http://{}/slow/x", addr);
+    let in_flight = tokio::spawn(async move { Client::new().get(url.parse().unwrap()).await });
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    let start = Instant::now();
+    signal(&gateway, "-TERM");
+    assert_eq!(exit_code(gateway).await, Some(1));
+    assert!(start.elapsed() < Duration::from_secs(3));
+    // the request is cut when the process exits
+    assert!(in_flight.await.unwrap().is_err());
+}
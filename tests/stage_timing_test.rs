@@ -0,0 +1,121 @@
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Request, Response, Server};
+use hyperapi::config::ConfigSource;
+use hyperapi::proxy::GatewayServer;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+async fn start_upstream() -> SocketAddr {
+    let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr();
+    let make_svc = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|_req: Request<Body>| async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok::<_, Infallible>(Response::new(Body::from("pong")))
+        }))
+    });
+    tokio::spawn(Server::builder(incoming).serve(make_svc));
+    addr
+}
+
+async fn start_gateway(upstream: SocketAddr) -> SocketAddr {
+    let service = |id: &str, timing: bool| {
+        format!(
+            r#"
+  - service_id: {0}
+    path: /{0}
+    protocol: http
+    auth:
+      type: None
+    timeout: 3
+    load_balance: random
+    timing_headers: {1}
+    filters: []
+    sla: []
+    upstreams:
+      - id: "1"
+        target: "http://{2}/"
+        max_conn: 10
+        weight: 1
+        version: "1"
+        error_threshold: 0
+        error_reset: 60
+        retry_delay: 10
+"#,
+            id, timing, upstream
+        )
+    };
+    let config = format!(
+        "clients: []\nservices:{}{}",
+        service("timed", true),
+        service("untimed", false)
+    );
+    let path = std::env::temp_dir().join(format!("hyperapi_stages_{}.yaml", upstream.port()));
+    std::fs::write(&path, config).unwrap();
+
+    let gateway = GatewayServer::new(ConfigSource::new(path.to_string_lossy().into()));
+    while *gateway.status.lock().unwrap() == 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // let middleware workers pick up the config
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr();
+    let make_svc = make_service_fn(move |_| {
+        let handler = gateway.make_service();
+        async move { Ok::<_, Infallible>(handler) }
+    });
+    tokio::spawn(Server::builder(incoming).serve(make_svc));
+    addr
+}
+
+// samples of the stage histogram
+fn stage_samples(stage: &str) -> u64 {
+    prometheus::gather()
+        .iter()
+        .filter(|family| family.get_name() == "gateway_middleware_duration_seconds")
+        .flat_map(|family| family.get_metric())
+        .filter(|m| m.get_label().iter().any(|l| l.get_value() == stage))
+        .map(|m| m.get_histogram().get_sample_count())
+        .sum()
+}
+
+#[tokio::test]
+async fn test_stage_timing() {
+    let gateway = start_gateway(start_upstream().await).await;
+
+    let uri = format!("http://{}/timed/", gateway).parse().unwrap();
+    let resp = Client::new().get(uri).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let timing = resp.headers()["server-timing"].to_str().unwrap();
+    // outermost first, upstream time is not counted in outer middlewares
+    let stages: Vec<(&str, f64)> = timing
+        .split(", ")
+        .map(|stage| {
+            let (name, dur) = stage.split_once(";dur=").unwrap();
+            (name, dur.parse().unwrap())
+        })
+        .collect();
+    assert_eq!(stages.first().unwrap().0, "Auth", "{}", timing);
+    let (last, upstream) = *stages.last().unwrap();
+    assert_eq!(last, "Upstream", "{}", timing);
+    assert!(upstream >= 20.0, "{}", timing);
+    assert!(
+        stages[..stages.len() - 1]
+            .iter()
+            .all(|(_, dur)| *dur < 20.0),
+        "{}",
+        timing
+    );
+
+    // header is opt-in, the histogram is not
+    let uri = format!("http://{}/untimed/", gateway).parse().unwrap();
+    let resp = Client::new().get(uri).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    assert!(!resp.headers().contains_key("server-timing"));
+    assert!(stage_samples("Auth") >= 2);
+    assert!(stage_samples("Upstream") >= 2);
+}
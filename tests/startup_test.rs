@@ -0,0 +1,62 @@
+use hyperapi::config::ConfigSource;
+use hyperapi::proxy::{GatewayServer, StartupError};
+use std::time::Duration;
+
+const SERVICE: &str = r#"
+  - service_id: startup
+    path: /startup
+    protocol: http
+    auth:
+      type: None
+    timeout: 3
+    load_balance: random
+    filters: []
+    sla: []
+    upstreams:
+      - id: startup1
+        target: "http://127.0.0.1:1/"
+        max_conn: 10
+        weight: 1
+        version: "1"
+        error_threshold: 0
+        error_reset: 60
+        retry_delay: 10
+"#;
+
+async fn start(name: &str, config: &str) -> Result<(usize, usize), StartupError> {
+    let path = std::env::temp_dir().join(format!("hyperapi_startup_{}.yaml", name));
+    std::fs::write(&path, config).unwrap();
+    let gateway = GatewayServer::new(ConfigSource::new(path.to_string_lossy().into()));
+    gateway.wait_initial_config(Duration::from_secs(5)).await
+}
+
+#[tokio::test]
+async fn test_startup_check() {
+    let config = format!("clients: []\nservices:{}", SERVICE);
+    assert_eq!(start("valid", &config).await, Ok((1, 0)));
+
+    // every service rejected by validation
+    let invalid = config.replace("max_conn: 10", "max_conn: 0");
+    assert_eq!(
+        start("invalid", &invalid).await,
+        Err(StartupError::NoService)
+    );
+    assert_eq!(
+        start("empty", "clients: []\nservices: []\n").await,
+        Err(StartupError::NoService)
+    );
+    // unparsable file, the source gives up
+    assert_eq!(
+        start("broken", "services: [").await,
+        Err(StartupError::SourceClosed)
+    );
+
+    // a source never ready
+    let (_tx, source) = ConfigSource::channel();
+    let gateway = GatewayServer::new(source);
+    let timeout = Duration::from_millis(100);
+    assert_eq!(
+        gateway.wait_initial_config(timeout).await,
+        Err(StartupError::Timeout(timeout))
+    );
+}
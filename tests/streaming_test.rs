@@ -0,0 +1,243 @@
+use hyper::body::HttpBody;
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Request, Response, Server};
+use hyperapi::config::ConfigSource;
+use hyperapi::proxy::GatewayServer;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::watch;
+
+const CHUNK: usize = 128 * 1024;
+const CHUNKS: usize = 64;
+// bytes allowed between what the client sent and what the upstream got
+const WINDOW: usize = 1024 * 1024;
+// larger than what socket buffers of both hops could hold
+const DOWNLOAD_CHUNKS: usize = 1024;
+
+// upstream publishing path and bytes of request body received so far
+async fn start_upstream(received: watch::Sender<(String, usize)>) -> SocketAddr {
+    let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr();
+    let received = std::sync::Arc::new(received);
+    let make_svc = make_service_fn(move |_| {
+        let received = received.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let received = received.clone();
+                async move {
+                    let path = req.uri().path().to_string();
+                    let mut body = req.into_body();
+                    let mut total = 0;
+                    while let Some(chunk) = body.data().await {
+                        total += chunk.unwrap().len();
+                        let _ = received.send((path.clone(), total));
+                    }
+                    Ok::<_, Infallible>(Response::new(Body::from(total.to_string())))
+                }
+            }))
+        }
+    });
+    tokio::spawn(Server::builder(incoming).serve(make_svc));
+    addr
+}
+
+// upstream answering a large body as fast as it is taken, publishing bytes sent so far
+async fn start_download(sent: watch::Sender<usize>) -> SocketAddr {
+    let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr();
+    let sent = std::sync::Arc::new(sent);
+    let make_svc = make_service_fn(move |_| {
+        let sent = sent.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |_req: Request<Body>| {
+                let sent = sent.clone();
+                async move {
+                    let (mut sender, body) = Body::channel();
+                    tokio::spawn(async move {
+                        let chunk = hyper::body::Bytes::from(vec![b'x'; CHUNK]);
+                        for i in 1..=DOWNLOAD_CHUNKS {
+                            if sender.send_data(chunk.clone()).await.is_err() {
+                                return;
+                            }
+                            let _ = sent.send(i * CHUNK);
+                        }
+                    });
+                    Ok::<_, Infallible>(Response::new(body))
+                }
+            }))
+        }
+    });
+    tokio::spawn(Server::builder(incoming).serve(make_svc));
+    addr
+}
+
+async fn start_gateway(upstream: SocketAddr, download: SocketAddr) -> SocketAddr {
+    let config = format!(
+        r#"
+clients: []
+services:
+  - service_id: upload
+    path: /upload
+    protocol: http
+    auth:
+      type: None
+    timeout: 30
+    load_balance: random
+    filters: []
+    sla: []
+    upstreams:
+      - id: upload1
+        target: "http://{0}/upload"
+        max_conn: 10
+        weight: 1
+        version: "1"
+        error_threshold: 0
+        error_reset: 60
+        retry_delay: 10
+  - service_id: mirrored
+    path: /mirrored
+    protocol: http
+    auth:
+      type: None
+    timeout: 30
+    load_balance: random
+    mirror:
+      target: "http://{0}/shadow"
+      percent: 100
+    filters: []
+    sla: []
+    upstreams:
+      - id: mirrored1
+        target: "http://{0}/mirrored"
+        max_conn: 10
+        weight: 1
+        version: "1"
+        error_threshold: 0
+        error_reset: 60
+        retry_delay: 10
+  - service_id: download
+    path: /download
+    protocol: http
+    auth:
+      type: None
+    timeout: 30
+    load_balance: random
+    filters: []
+    sla: []
+    upstreams:
+      - id: download1
+        target: "http://{1}/download"
+        max_conn: 10
+        weight: 1
+        version: "1"
+        error_threshold: 0
+        error_reset: 60
+        retry_delay: 10
+"#,
+        upstream, download
+    );
+    let path = std::env::temp_dir().join(format!("hyperapi_upload_{}.yaml", upstream.port()));
+    std::fs::write(&path, config).unwrap();
+
+    let gateway = GatewayServer::new(ConfigSource::new(path.to_string_lossy().into()));
+    while *gateway.status.lock().unwrap() == 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // let middleware workers pick up the config
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr();
+    let make_svc = make_service_fn(move |_| {
+        let handler = gateway.make_service();
+        async move { Ok::<_, Infallible>(handler) }
+    });
+    tokio::spawn(Server::builder(incoming).serve(make_svc));
+    addr
+}
+
+#[tokio::test]
+async fn test_streamed_upload() {
+    let (received_tx, received) = watch::channel((String::new(), 0usize));
+    let (sent_tx, _) = watch::channel(0);
+    let upstream = start_upstream(received_tx).await;
+    let gateway = start_gateway(upstream, start_download(sent_tx).await).await;
+    // mirror buffers up to its max_body, then streams the rest to the primary upstream
+    for service in ["upload", "mirrored"] {
+        upload(gateway, service, received.clone()).await;
+    }
+}
+
+async fn upload(
+    gateway: SocketAddr,
+    service: &str,
+    mut received: watch::Receiver<(String, usize)>,
+) {
+    let (mut sender, body) = Body::channel();
+    let req = Request::post(format!("http://{}/{}/", gateway, service))
+        .body(body)
+        .unwrap();
+    let resp = tokio::spawn(Client::new().request(req));
+
+    // next chunk is sent only once the upstream got all but WINDOW bytes,
+    // a gateway buffering the whole body would never let it through
+    let path = format!("/{}/", service);
+    let chunk = hyper::body::Bytes::from(vec![b'x'; CHUNK]);
+    for i in 1..=CHUNKS {
+        sender.send_data(chunk.clone()).await.unwrap();
+        let expected = (i * CHUNK).saturating_sub(WINDOW);
+        let arrived = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                {
+                    let (at, bytes) = &*received.borrow_and_update();
+                    if expected == 0 || (*at == path && *bytes >= expected) {
+                        break;
+                    }
+                }
+                received.changed().await.unwrap();
+            }
+        });
+        assert!(arrived.await.is_ok(), "{} stalled at chunk {}", service, i);
+    }
+    drop(sender);
+
+    let resp = resp.await.unwrap().unwrap();
+    assert_eq!(resp.status(), 200);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(body, (CHUNK * CHUNKS).to_string());
+}
+
+#[tokio::test]
+async fn test_streamed_download() {
+    let (received_tx, _) = watch::channel((String::new(), 0usize));
+    let (sent_tx, mut sent) = watch::channel(0);
+    let upstream = start_upstream(received_tx).await;
+    let gateway = start_gateway(upstream, start_download(sent_tx).await).await;
+
+    // headers arrive while the upstream is still sending
+    let uri = format!("http://{}/download/", gateway).parse().unwrap();
+    let resp = Client::new().get(uri).await.unwrap();
+    assert_eq!(resp.status(), 200);
+
+    // a client not reading stalls the upstream, the gateway holds a bounded amount
+    let total = CHUNK * DOWNLOAD_CHUNKS;
+    loop {
+        let before = *sent.borrow_and_update();
+        let changed = tokio::time::timeout(Duration::from_millis(500), sent.changed()).await;
+        if changed.is_err() {
+            assert!(before < total / 4, "{} of {} bytes buffered", before, total);
+            break;
+        }
+    }
+
+    // then the whole body is relayed as it is read
+    let mut body = resp.into_body();
+    let mut read = 0;
+    while let Some(chunk) = body.data().await {
+        read += chunk.unwrap().len();
+    }
+    assert_eq!(read, total);
+    assert_eq!(*sent.borrow(), total);
+}
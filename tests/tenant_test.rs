@@ -0,0 +1,162 @@
+use hyper::{Body, Request};
+use hyperapi::auth::{AuthRequest, AuthResponse, AuthService, Tenant, TenantMatching};
+use hyperapi::config::{validate_service, ConfigUpdate, ServiceInfo};
+use hyperapi::middleware::{
+    Middleware, MwNextAction, MwPreRequest, RateLimitMiddleware, RequestContext,
+};
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+const SERVICE: &str = r#"
+service_id: svc
+path: /svc
+protocol: http
+auth:
+  type: None
+timeout: 1
+load_balance: random
+upstreams:
+  - id: "1"
+    target: "http://127.0.0.1:1/"
+    max_conn: 10
+    version: "1.0"
+    weight: 1
+    error_threshold: 0
+    error_reset: 60
+    retry_delay: 10
+filters:
+  - type: TenantRateLimit
+    setting:
+      interval: 60
+      limit: 1
+      burst: 1
+sla: []
+"#;
+
+// auth service extracting tenants by `pattern` and `header`
+fn start_auth(
+    conf_tx: &broadcast::Sender<ConfigUpdate>,
+    pattern: Option<&str>,
+    header: Option<&str>,
+) -> mpsc::Sender<AuthRequest> {
+    let (auth_tx, auth_rx) = mpsc::channel(16);
+    let mut auth = AuthService::new(conf_tx.subscribe(), auth_rx);
+    auth.tenant_matching = TenantMatching::new(pattern, header).unwrap();
+    tokio::spawn(async move { auth.start().await });
+    auth_tx
+}
+
+// service id, path seen by middlewares, tenant extension and forwarded header
+async fn route(
+    auth_tx: &mpsc::Sender<AuthRequest>,
+    path: &str,
+    header: Option<&str>,
+) -> (String, String, Option<String>, Option<String>) {
+    let mut request = Request::get(path);
+    if let Some(tenant) = header {
+        request = request.header("x-org", tenant);
+    }
+    let (head, _) = request.body(()).unwrap().into_parts();
+    let (tx, rx) = oneshot::channel();
+    let _ = auth_tx.send(AuthRequest { head, result: tx }).await;
+    let (head, auth) = rx.await.unwrap().unwrap();
+    let forwarded = head
+        .headers
+        .get("x-org")
+        .map(|v| v.to_str().unwrap().to_string());
+    let tenant = head.extensions.get::<Tenant>().map(|t| t.0.clone());
+    (auth.service_id, head.uri.to_string(), tenant, forwarded)
+}
+
+#[tokio::test]
+async fn test_tenant_extraction() {
+    assert!(TenantMatching::new(Some("/t"), None).is_err());
+    assert!(TenantMatching::new(Some("/{tenant}/{tenant}"), None).is_err());
+    assert!(TenantMatching::new(None, Some("bad header")).is_err());
+
+    let (conf_tx, _) = broadcast::channel(16);
+    let auth_tx = start_auth(&conf_tx, Some("/t/{tenant}"), Some("x-org"));
+    let path_only = start_auth(&conf_tx, Some("/t/{tenant}"), None);
+    let service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    conf_tx.send(ConfigUpdate::ServiceUpdate(service)).unwrap();
+    // let auth services pick up the config
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let acme = Some("acme".to_string());
+    assert_eq!(
+        route(&auth_tx, "/t/acme/svc/x?q=1", None).await,
+        (
+            "svc".into(),
+            "/svc/x?q=1".into(),
+            acme.clone(),
+            acme.clone()
+        )
+    );
+    // the path wins over the header sent by the client
+    assert_eq!(
+        route(&auth_tx, "/t/acme/svc/x", Some("other")).await,
+        ("svc".into(), "/svc/x".into(), acme.clone(), acme.clone())
+    );
+    assert_eq!(
+        route(&auth_tx, "/svc/x", Some("acme")).await,
+        ("svc".into(), "/svc/x".into(), acme.clone(), acme.clone())
+    );
+    assert_eq!(
+        route(&auth_tx, "/svc/x", None).await,
+        ("svc".into(), "/svc/x".into(), None, None)
+    );
+
+    // headers other than the tenant header are left alone
+    assert_eq!(
+        route(&path_only, "/svc/x", Some("acme")).await,
+        ("svc".into(), "/svc/x".into(), None, Some("acme".into()))
+    );
+}
+
+// true if the request passed the rate limit
+async fn limit(mw: &mut RateLimitMiddleware, tenant: Option<&str>) -> bool {
+    let mut request = Request::get("/svc/").body(Body::empty()).unwrap();
+    if let Some(tenant) = tenant {
+        request.extensions_mut().insert(Tenant(tenant.into()));
+    }
+    let auth = AuthResponse {
+        client_id: String::new(),
+        service_id: "svc".into(),
+        sla: String::new(),
+        service_filters: Vec::new(),
+        client_filters: Vec::new(),
+        middlewares: Vec::new(),
+    };
+    let (tx, rx) = oneshot::channel();
+    let task = MwPreRequest {
+        context: RequestContext::new(&request, &auth),
+        request,
+        service_filters: Vec::new(),
+        client_filters: Vec::new(),
+        result: tx,
+    };
+    mw.request(task).await;
+    match rx.await.unwrap().unwrap().next {
+        MwNextAction::Next(_) => true,
+        MwNextAction::Return(resp) => {
+            assert_eq!(resp.status().as_u16(), 429);
+            assert!(resp.headers().contains_key("retry-after"));
+            false
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_tenant_rate_limit() {
+    let service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    validate_service(&service).unwrap();
+    let mut mw = RateLimitMiddleware::default();
+    mw.config_update(ConfigUpdate::ServiceUpdate(service));
+
+    assert!(limit(&mut mw, Some("acme")).await);
+    assert!(!limit(&mut mw, Some("acme")).await);
+    // a bucket per tenant, requests without tenant pass
+    assert!(limit(&mut mw, Some("other")).await);
+    assert!(limit(&mut mw, None).await);
+    assert!(limit(&mut mw, None).await);
+}
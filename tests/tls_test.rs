@@ -0,0 +1,223 @@
+use hyper::server::accept::Accept;
+use hyper::server::conn::AddrIncoming;
+use hyperapi::proxy::{TlsAcceptor, TlsConfigBuilder, TlsOptions, TlsReloader};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, ProtocolVersion, RootCertStore, ServerCertVerified,
+    ServerCertVerifier, Session, TLSError,
+};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
+
+const CA: &[u8] = include_bytes!("tls/ca.pem");
+const CERT: &[u8] = include_bytes!("tls/server.pem");
+const KEY: &[u8] = include_bytes!("tls/server.key");
+// self-signed with the key of server.pem
+const EXACT_CERT: &[u8] = include_bytes!("tls/sni_exact.pem"); // api.example.com
+const WILDCARD_CERT: &[u8] = include_bytes!("tls/sni_wildcard.pem"); // *.example.com
+
+// accept TLS connections and echo one message back
+fn start_server(builder: TlsConfigBuilder) -> SocketAddr {
+    let config = builder.cert(CERT).key(KEY).build().unwrap();
+    let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr();
+    serve(TlsAcceptor::new(config, incoming));
+    addr
+}
+
+fn serve(mut acceptor: TlsAcceptor) {
+    tokio::spawn(async move {
+        while let Some(Ok(mut conn)) =
+            futures::future::poll_fn(|cx| std::pin::Pin::new(&mut acceptor).poll_accept(cx)).await
+        {
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4];
+                if conn.read_exact(&mut buf).await.is_ok() {
+                    let _ = conn.write_all(&buf).await;
+                }
+            });
+        }
+    });
+}
+
+async fn handshake(addr: SocketAddr, versions: Vec<ProtocolVersion>) -> Result<String, String> {
+    let mut config = ClientConfig::new();
+    config
+        .root_store
+        .add_pem_file(&mut std::io::Cursor::new(CA))
+        .unwrap();
+    config.versions = versions;
+    let connector = TlsConnector::from(Arc::new(config));
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut tls = connector
+        .connect(name, stream)
+        .await
+        .map_err(|e| e.to_string())?;
+    tls.write_all(b"ping").await.map_err(|e| e.to_string())?;
+    let mut buf = [0u8; 4];
+    tls.read_exact(&mut buf).await.map_err(|e| e.to_string())?;
+    assert_eq!(&buf, b"ping");
+    let (_, session) = tls.get_ref();
+    Ok(format!("{:?}", session.get_protocol_version().unwrap()))
+}
+
+// accepts any server certificate, to see which one is presented
+struct AnyCert;
+
+impl ServerCertVerifier for AnyCert {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented: &[Certificate],
+        _name: DNSNameRef,
+        _ocsp: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+// certificate presented to a client sending `name` as SNI
+async fn presented(addr: SocketAddr, name: &str) -> Certificate {
+    let mut config = ClientConfig::new();
+    config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(AnyCert));
+    let connector = TlsConnector::from(Arc::new(config));
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let name = DNSNameRef::try_from_ascii_str(name).unwrap();
+    let tls = connector.connect(name, stream).await.unwrap();
+    let (_, session) = tls.get_ref();
+    session.get_peer_certificates().unwrap().remove(0)
+}
+
+fn cert(pem: &[u8]) -> Certificate {
+    pemfile::certs(&mut &pem[..]).unwrap().remove(0)
+}
+
+#[tokio::test]
+async fn test_min_tls_version() {
+    let addr = start_server(TlsConfigBuilder::new().min_tls_version("1.3"));
+    let err = handshake(addr, vec![ProtocolVersion::TLSv1_2])
+        .await
+        .expect_err("TLS 1.2 client is rejected");
+    assert!(err.contains("fatal alert: ProtocolVersion"), "{}", err);
+    let version = handshake(addr, vec![ProtocolVersion::TLSv1_3]).await;
+    assert_eq!(version.unwrap(), "TLSv1_3");
+
+    // default accepts both
+    let addr = start_server(TlsConfigBuilder::new());
+    let version = handshake(addr, vec![ProtocolVersion::TLSv1_2]).await;
+    assert_eq!(version.unwrap(), "TLSv1_2");
+}
+
+#[tokio::test]
+async fn test_cipher_suites() {
+    let builder = TlsConfigBuilder::new()
+        .min_tls_version("1.2")
+        .cipher_suites(&["TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384"]);
+    let addr = start_server(builder);
+    let err = handshake(addr, vec![ProtocolVersion::TLSv1_3])
+        .await
+        .expect_err("no TLS 1.3 suite enabled");
+    assert!(err.contains("fatal alert"), "{}", err);
+    let version = handshake(addr, vec![ProtocolVersion::TLSv1_2]).await;
+    assert_eq!(version.unwrap(), "TLSv1_2");
+}
+
+#[tokio::test]
+async fn test_sni_certs() {
+    // the wildcard is added first, exact names still win
+    let builder = TlsConfigBuilder::new()
+        .sni_cert("*.example.com", WILDCARD_CERT, KEY)
+        .sni_cert("api.example.com", EXACT_CERT, KEY);
+    let addr = start_server(builder);
+
+    assert_eq!(presented(addr, "api.example.com").await, cert(EXACT_CERT));
+    assert_eq!(presented(addr, "API.example.com").await, cert(EXACT_CERT));
+    assert_eq!(
+        presented(addr, "www.example.com").await,
+        cert(WILDCARD_CERT)
+    );
+    // a wildcard covers a single label
+    assert_eq!(presented(addr, "a.www.example.com").await, cert(CERT));
+    assert_eq!(presented(addr, "example.com").await, cert(CERT));
+    // unknown names get the default certificate
+    assert_eq!(presented(addr, "other.test").await, cert(CERT));
+}
+
+#[tokio::test]
+async fn test_tls_hot_reload() {
+    let dir = std::env::temp_dir().join(format!("hyperapi_tls_reload_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = (dir.join("tls.crt"), dir.join("tls.key"));
+    std::fs::write(&cert_path, CERT).unwrap();
+    std::fs::write(&key_path, KEY).unwrap();
+
+    let reloader = TlsReloader::new(&cert_path, &key_path, vec![], TlsOptions::default()).unwrap();
+    reloader.watch().unwrap();
+    let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr();
+    serve(reloader.acceptor(incoming));
+    assert_eq!(presented(addr, "localhost").await, cert(CERT));
+
+    // replaced like a mounted secret, by renaming a new file over the old one
+    let staged = dir.join("tls.crt.new");
+    std::fs::write(&staged, EXACT_CERT).unwrap();
+    std::fs::rename(&staged, &cert_path).unwrap();
+    let mut reloaded = false;
+    for _ in 0..100 {
+        if presented(addr, "localhost").await == cert(EXACT_CERT) {
+            reloaded = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(reloaded, "new handshakes present the new certificate");
+
+    // a broken file keeps the current certificate
+    std::fs::write(&cert_path, b"not a certificate").unwrap();
+    assert!(reloader.reload().is_err());
+    assert_eq!(presented(addr, "localhost").await, cert(EXACT_CERT));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_invalid_options() {
+    let build = |builder: TlsConfigBuilder| match builder.cert(CERT).key(KEY).build() {
+        Ok(_) => panic!("invalid options are rejected"),
+        Err(e) => e,
+    };
+
+    let err = build(TlsConfigBuilder::new().min_tls_version("1.1"));
+    assert_eq!(
+        err.to_string(),
+        "unsupported TLS version \"1.1\", expect 1.2 or 1.3"
+    );
+    let err = build(TlsConfigBuilder::new().cipher_suites(&["RC4_MD5"]));
+    assert!(err
+        .to_string()
+        .starts_with("unknown cipher suite \"RC4_MD5\", supported: TLS13_"));
+    let empty: &[&str] = &[];
+    let err = build(TlsConfigBuilder::new().cipher_suites(empty));
+    assert_eq!(
+        err.to_string(),
+        "no cipher suite usable with the enabled TLS versions"
+    );
+    let err = build(
+        TlsConfigBuilder::new()
+            .min_tls_version("1.3")
+            .cipher_suites(&["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"]),
+    );
+    assert_eq!(
+        err.to_string(),
+        "no cipher suite usable with the enabled TLS versions"
+    );
+    let err = build(TlsConfigBuilder::new().alpn_protocols(&["h2", ""]));
+    assert_eq!(err.to_string(), "empty ALPN protocol name");
+}
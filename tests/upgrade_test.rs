@@ -0,0 +1,139 @@
+use hyper::header::{CONNECTION, UPGRADE};
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Request, Response, Server, StatusCode};
+use hyperapi::config::ConfigSource;
+use hyperapi::proxy::GatewayServer;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+// upstream switching to an echo protocol
+async fn start_upstream() -> SocketAddr {
+    let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr();
+    let make_svc = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|mut req: Request<Body>| async move {
+            tokio::spawn(async move {
+                let mut upgraded = hyper::upgrade::on(&mut req).await.unwrap();
+                let mut buf = [0u8; 1024];
+                loop {
+                    match upgraded.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => upgraded.write_all(&buf[..n]).await.unwrap(),
+                    }
+                }
+            });
+            let resp = Response::builder()
+                .status(StatusCode::SWITCHING_PROTOCOLS)
+                .header(CONNECTION, "upgrade")
+                .header(UPGRADE, "echo")
+                .body(Body::empty())
+                .unwrap();
+            Ok::<_, Infallible>(resp)
+        }))
+    });
+    tokio::spawn(Server::builder(incoming).serve(make_svc));
+    addr
+}
+
+async fn start_gateway(upstream: SocketAddr) -> SocketAddr {
+    // idle timeout longer than the request timeout
+    let config = format!(
+        r#"
+clients: []
+services:
+  - service_id: echo
+    path: /echo
+    protocol: http
+    auth:
+      type: None
+    timeout: 1
+    upgrade_idle_timeout: 2
+    load_balance: random
+    filters: []
+    sla: []
+    upstreams:
+      - id: echo1
+        target: "http://{}/"
+        max_conn: 10
+        weight: 1
+        version: "1"
+        error_threshold: 0
+        error_reset: 60
+        retry_delay: 10
+"#,
+        upstream
+    );
+    let path = std::env::temp_dir().join(format!("hyperapi_upgrade_{}.yaml", upstream.port()));
+    std::fs::write(&path, config).unwrap();
+
+    let gateway = GatewayServer::new(ConfigSource::new(path.to_string_lossy().into()));
+    while *gateway.status.lock().unwrap() == 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // let middleware workers pick up the config
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr();
+    let make_svc = make_service_fn(move |_| {
+        let handler = gateway.make_service();
+        async move { Ok::<_, Infallible>(handler) }
+    });
+    tokio::spawn(Server::builder(incoming).serve(make_svc));
+    addr
+}
+
+#[tokio::test]
+async fn test_upgrade_idle_timeout() {
+    let gateway = start_gateway(start_upstream().await).await;
+
+    let req = Request::get(format!("http://{}/echo/", gateway))
+        .header(CONNECTION, "upgrade")
+        .header(UPGRADE, "echo")
+        .body(Body::empty())
+        .unwrap();
+    let mut resp = Client::new().request(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+    let mut conn = hyper::upgrade::on(&mut resp).await.unwrap();
+
+    // traffic keeps the tunnel open past request and idle timeouts
+    let mut buf = [0u8; 4];
+    for _ in 0..6 {
+        conn.write_all(b"ping").await.unwrap();
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        tokio::time::sleep(Duration::from_millis(700)).await;
+    }
+
+    // closed after the idle timeout
+    let closed = tokio::time::timeout(Duration::from_secs(4), conn.read(&mut buf)).await;
+    assert!(matches!(closed, Ok(Ok(0)) | Ok(Err(_))), "{:?}", closed);
+}
+
+#[tokio::test]
+async fn test_relay_half_close() {
+    let (client, mut client_peer) = tokio::io::duplex(64);
+    let (upstream, mut upstream_peer) = tokio::io::duplex(64);
+    let relay = tokio::spawn(hyperapi::middleware::relay(
+        client,
+        upstream,
+        Duration::from_secs(1),
+    ));
+
+    client_peer.write_all(b"hello").await.unwrap();
+    client_peer.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    upstream_peer.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"hello");
+
+    // other direction still open after the client is done sending
+    upstream_peer.write_all(b"bye").await.unwrap();
+    drop(upstream_peer);
+    let mut received = Vec::new();
+    client_peer.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"bye");
+    assert!(relay.await.unwrap().is_ok());
+}
@@ -0,0 +1,1586 @@
+use hyper::{Body, Request};
+use hyperapi::auth::AuthResponse;
+use hyperapi::config::{
+    CoalesceSetting, ConfigUpdate, MaintenancePage, ServiceInfo, UpstreamScheme,
+};
+use hyperapi::middleware::{
+    Deadline, GatewayError, Middleware, MwNextAction, MwPreRequest, MwPreResponse, RequestContext,
+    UpstreamMiddleware, UpstreamTime, GATEWAY_ERROR_HEADER,
+};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+const SERVICE: &str = r#"
+service_id: test/drain
+path: /drain
+protocol: http
+auth:
+  type: None
+timeout: 3
+load_balance: random
+upstreams:
+  - id: "1"
+    target: "http://127.0.0.1:1/"
+    max_conn: 10
+    version: "1.0"
+    weight: 1
+    error_threshold: 0
+    error_reset: 60
+    retry_delay: 10
+filters: []
+sla: []
+"#;
+
+type PreResult = oneshot::Receiver<Result<MwPreResponse, GatewayError>>;
+
+fn task(service_id: &str) -> (MwPreRequest, PreResult) {
+    let request = Request::get("/drain/").body(Body::empty()).unwrap();
+    let auth = AuthResponse {
+        client_id: String::new(),
+        service_id: service_id.into(),
+        sla: String::new(),
+        service_filters: Vec::new(),
+        client_filters: Vec::new(),
+        middlewares: Vec::new(),
+    };
+    let context = RequestContext::new(&request, &auth);
+    let (tx, rx) = oneshot::channel();
+    let task = MwPreRequest {
+        context,
+        request,
+        service_filters: Vec::new(),
+        client_filters: Vec::new(),
+        result: tx,
+    };
+    (task, rx)
+}
+
+#[tokio::test]
+async fn test_remove_drains_queue() {
+    let service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    let service_id = service.service_id.clone();
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+
+    // queued before the worker gets a chance to run
+    let mut results = Vec::new();
+    for _ in 0..5 {
+        let (task, rx) = task(&service_id);
+        upstream.request(task).await;
+        results.push(rx);
+    }
+    upstream.config_update(ConfigUpdate::ServiceRemove(service_id.clone()));
+
+    for rx in results {
+        let result = tokio::time::timeout(Duration::from_secs(1), rx)
+            .await
+            .expect("queued request is not answered")
+            .unwrap();
+        assert!(matches!(result, Err(GatewayError::ServiceNotFound(_))));
+    }
+
+    // later requests find no worker
+    let (task, rx) = task(&service_id);
+    upstream.request(task).await;
+    assert!(matches!(
+        rx.await.unwrap(),
+        Err(GatewayError::ServiceNotFound(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_replaced_worker_completes_queue() {
+    let service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    let service_id = service.service_id.clone();
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service.clone()));
+
+    let mut results = Vec::new();
+    for _ in 0..5 {
+        let (task, rx) = task(&service_id);
+        upstream.request(task).await;
+        results.push(rx);
+    }
+    let mut replaced = service;
+    replaced.timeout = 5;
+    upstream.config_update(ConfigUpdate::ServiceUpdate(replaced));
+
+    // proxied by the old worker, nothing listens on the upstream port
+    for rx in results {
+        let result = tokio::time::timeout(Duration::from_secs(3), rx)
+            .await
+            .expect("queued request is not answered")
+            .unwrap();
+        assert!(matches!(
+            result,
+            Err(GatewayError::UpstreamConnectError(..))
+        ));
+    }
+}
+
+#[tokio::test]
+async fn test_queue_fail_fast() {
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.service_id = "test/queue_full".into();
+    service.queue_depth = 2;
+    service.queue_fail_fast = true;
+    let service_id = service.service_id.clone();
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+
+    // worker has no chance to run, queue holds two requests
+    let mut results = Vec::new();
+    for _ in 0..3 {
+        let (task, rx) = task(&service_id);
+        upstream.request(task).await;
+        results.push(rx);
+    }
+    let rejected = results.pop().unwrap();
+    assert!(matches!(
+        rejected.await.unwrap(),
+        Err(GatewayError::ServiceOverloaded(_))
+    ));
+    for rx in results {
+        let result = tokio::time::timeout(Duration::from_secs(3), rx)
+            .await
+            .expect("queued request is not answered")
+            .unwrap();
+        assert!(matches!(
+            result,
+            Err(GatewayError::UpstreamConnectError(..))
+        ));
+    }
+}
+
+#[tokio::test]
+async fn test_upstream_host_header() {
+    // upstream echoing request headers back
+    let make_svc = hyper::service::make_service_fn(|_| async {
+        Ok::<_, std::convert::Infallible>(hyper::service::service_fn(
+            |req: Request<Body>| async move {
+                let mut resp = hyper::Response::builder();
+                for name in ["host", "x-internal-token"] {
+                    if let Some(v) = req.headers().get(name) {
+                        resp = resp.header(format!("echo-{}", name), v);
+                    }
+                }
+                Ok::<_, std::convert::Infallible>(resp.body(Body::empty()).unwrap())
+            },
+        ))
+    });
+    let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.service_id = "test/host_header".into();
+    service.upstreams[0].target = format!("http://{}/", addr);
+    service.upstreams[0].host_header = Some("api.internal".into());
+    service.upstreams[0].headers = vec![("X-Internal-Token".into(), "secret".into())];
+    let service_id = service.service_id.clone();
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+
+    let (mut task, rx) = task(&service_id);
+    task.request
+        .headers_mut()
+        .insert("host", "gateway.example.com".parse().unwrap());
+    upstream.request(task).await;
+    let resp = match rx.await.unwrap() {
+        Ok(MwPreResponse {
+            next: MwNextAction::Return(resp),
+            ..
+        }) => resp,
+        other => panic!("unexpected result {:?}", other),
+    };
+    assert_eq!(resp.headers()["echo-host"], "api.internal");
+    assert_eq!(resp.headers()["echo-x-internal-token"], "secret");
+}
+
+#[tokio::test]
+async fn test_upstream_http2_only() {
+    // upstream speaking only HTTP/2, telling the version it was called with
+    let make_svc = hyper::service::make_service_fn(|_| async {
+        Ok::<_, std::convert::Infallible>(hyper::service::service_fn(
+            |req: Request<Body>| async move {
+                let version = format!("{:?}", req.version());
+                let resp = hyper::Response::builder().header("echo-version", version);
+                Ok::<_, std::convert::Infallible>(resp.body(Body::empty()).unwrap())
+            },
+        ))
+    });
+    let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap())
+        .http2_only(true)
+        .serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+
+    let mut upstream = UpstreamMiddleware::default();
+    for http2_only in [true, false] {
+        let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+        service.service_id = "test/http2_only".into();
+        service.upstreams[0].target = format!("http://{}/", addr);
+        service.upstreams[0].http2_only = http2_only;
+        upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+
+        // requests from HTTP/1.1 clients too
+        let (task, rx) = task("test/http2_only");
+        upstream.request(task).await;
+        match rx.await.unwrap() {
+            Ok(MwPreResponse {
+                next: MwNextAction::Return(resp),
+                ..
+            }) if http2_only => assert_eq!(resp.headers()["echo-version"], "HTTP/2.0"),
+            // HTTP/1.1 is refused by the upstream
+            Err(_) if !http2_only => {}
+            other => panic!("http2_only {}: unexpected result {:?}", http2_only, other),
+        }
+    }
+}
+
+// upstream answering with its name
+fn named_upstream(name: &'static str) -> std::net::SocketAddr {
+    let make_svc = hyper::service::make_service_fn(move |_| async move {
+        Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |_req| async move {
+            let resp = hyper::Response::builder().header("upstream-name", name);
+            Ok::<_, std::convert::Infallible>(resp.body(Body::empty()).unwrap())
+        }))
+    });
+    let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+#[tokio::test]
+async fn test_route_rules() {
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.service_id = "test/routes".into();
+    let mut replica = service.upstreams[0].clone();
+    replica.id = "replica".into();
+    replica.target = format!("http://{}/", named_upstream("replica"));
+    let mut primary = service.upstreams[0].clone();
+    primary.id = "primary".into();
+    primary.target = format!("http://{}/", named_upstream("primary"));
+    service.upstreams = vec![replica, primary];
+    service.routes = serde_yaml::from_str(
+        r#"
+- methods: GET
+  path_pattern: /read/*
+  upstreams: [replica]
+- methods: POST,PUT
+  path_pattern: /*
+  upstreams: [primary]
+"#,
+    )
+    .unwrap();
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+
+    for (method, path, expected) in [
+        ("GET", "/drain/read/1", Some("replica")),
+        ("POST", "/drain/read/1", Some("primary")),
+        ("PUT", "/drain/items", Some("primary")),
+        ("GET", "/drain/items", None),
+        ("DELETE", "/drain/read/1", None),
+    ] {
+        let (mut task, rx) = task("test/routes");
+        *task.request.method_mut() = method.parse().unwrap();
+        *task.request.uri_mut() = path.parse().unwrap();
+        upstream.request(task).await;
+        match (rx.await.unwrap(), expected) {
+            (
+                Ok(MwPreResponse {
+                    next: MwNextAction::Return(resp),
+                    ..
+                }),
+                Some(name),
+            ) => assert_eq!(resp.headers()["upstream-name"], name, "{} {}", method, path),
+            (Err(GatewayError::ServiceNotFound(_)), None) => {}
+            (other, _) => panic!("{} {}: unexpected result {:?}", method, path, other),
+        }
+    }
+}
+
+// stable upstream of version v1 and canary of v2
+fn split_service() -> ServiceInfo {
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.service_id = "test/split".into();
+    let mut stable = service.upstreams[0].clone();
+    stable.id = "stable".into();
+    stable.version = "v1".into();
+    stable.target = format!("http://{}/", named_upstream("stable"));
+    let mut canary = service.upstreams[0].clone();
+    canary.id = "canary".into();
+    canary.version = "v2".into();
+    canary.target = format!("http://{}/", named_upstream("canary"));
+    service.upstreams = vec![stable, canary];
+    service
+}
+
+async fn upstream_of(
+    upstream: &mut UpstreamMiddleware,
+    client_id: &str,
+    headers: &[(&'static str, &str)],
+) -> Result<String, GatewayError> {
+    let (mut task, rx) = task("test/split");
+    task.context.client_id = client_id.into();
+    for (name, value) in headers {
+        task.request
+            .headers_mut()
+            .insert(*name, value.parse().unwrap());
+    }
+    upstream.request(task).await;
+    match rx.await.unwrap()? {
+        MwPreResponse {
+            next: MwNextAction::Return(resp),
+            ..
+        } => Ok(resp.headers()["upstream-name"].to_str().unwrap().into()),
+        _ => panic!("upstream returned no response"),
+    }
+}
+
+#[tokio::test]
+async fn test_traffic_split() {
+    let mut service = split_service();
+
+    // canary with no share gets nothing
+    service.traffic_split =
+        serde_yaml::from_str("versions: [{version: v1, percent: 100}, {version: v2, percent: 0}]")
+            .unwrap();
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service.clone()));
+    for _ in 0..20 {
+        assert_eq!(upstream_of(&mut upstream, "", &[]).await.unwrap(), "stable");
+    }
+
+    // sticky client stays on its version, clients spread over both
+    service.traffic_split = serde_yaml::from_str(
+        "{versions: [{version: v1, percent: 50}, {version: v2, percent: 50}], sticky_client: true}",
+    )
+    .unwrap();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+    let mut seen = std::collections::HashSet::new();
+    for i in 0..20 {
+        let client = format!("client-{}", i);
+        let first = upstream_of(&mut upstream, &client, &[]).await.unwrap();
+        for _ in 0..3 {
+            let picked = upstream_of(&mut upstream, &client, &[]).await.unwrap();
+            assert_eq!(picked, first);
+        }
+        seen.insert(first);
+    }
+    assert_eq!(seen.len(), 2);
+}
+
+#[tokio::test]
+async fn test_version_override() {
+    let mut service = split_service();
+    service.traffic_split =
+        serde_yaml::from_str("versions: [{version: v1, percent: 100}, {version: v2, percent: 0}]")
+            .unwrap();
+    service.version_override =
+        serde_yaml::from_str("{header: X-Canary, cookie: canary, clients: [qa]}").unwrap();
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+
+    let header = [("x-canary", "v2")];
+    assert_eq!(
+        upstream_of(&mut upstream, "qa", &header).await.unwrap(),
+        "canary"
+    );
+    let cookie = [("cookie", "a=1; canary=v2")];
+    assert_eq!(
+        upstream_of(&mut upstream, "qa", &cookie).await.unwrap(),
+        "canary"
+    );
+    // other clients follow the split
+    assert_eq!(
+        upstream_of(&mut upstream, "app", &header).await.unwrap(),
+        "stable"
+    );
+    assert!(matches!(
+        upstream_of(&mut upstream, "qa", &[("x-canary", "v3")]).await,
+        Err(GatewayError::ServiceNotFound(_))
+    ));
+}
+
+// upstream reporting name, path and body of each request, answering with the body
+fn recording_upstream(
+    name: &'static str,
+    tx: tokio::sync::mpsc::UnboundedSender<(&'static str, String, Vec<u8>)>,
+) -> std::net::SocketAddr {
+    let make_svc = hyper::service::make_service_fn(move |_| {
+        let tx = tx.clone();
+        async move {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(
+                move |req: Request<Body>| {
+                    let tx = tx.clone();
+                    async move {
+                        let path = req.uri().path().to_string();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let _ = tx.send((name, path, body.to_vec()));
+                        let resp = hyper::Response::builder().header("upstream-name", name);
+                        Ok::<_, std::convert::Infallible>(resp.body(Body::from(body)).unwrap())
+                    }
+                },
+            ))
+        }
+    });
+    let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+#[tokio::test]
+async fn test_mirror() {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.service_id = "test/mirror".into();
+    service.upstreams[0].target = format!("http://{}/", recording_upstream("primary", tx.clone()));
+    let shadow = recording_upstream("shadow", tx);
+    service.mirror =
+        serde_yaml::from_str(&format!("{{target: 'http://{}/', percent: 100}}", shadow)).unwrap();
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service.clone()));
+
+    async fn post(upstream: &mut UpstreamMiddleware) -> (String, Vec<u8>) {
+        let (mut task, rx) = task("test/mirror");
+        *task.request.method_mut() = hyper::Method::POST;
+        *task.request.uri_mut() = "/drain/items".parse().unwrap();
+        *task.request.body_mut() = Body::from("payload");
+        upstream.request(task).await;
+        match rx.await.unwrap() {
+            Ok(MwPreResponse {
+                next: MwNextAction::Return(resp),
+                ..
+            }) => {
+                let name = resp.headers()["upstream-name"]
+                    .to_str()
+                    .unwrap()
+                    .to_string();
+                let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+                (name, body.to_vec())
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    // client served by primary, both got the same request
+    assert_eq!(
+        post(&mut upstream).await,
+        ("primary".into(), b"payload".to_vec())
+    );
+    let mut seen = vec![rx.recv().await.unwrap(), rx.recv().await.unwrap()];
+    seen.sort();
+    assert_eq!(
+        seen,
+        vec![
+            ("primary", "/items".into(), b"payload".to_vec()),
+            ("shadow", "/items".into(), b"payload".to_vec()),
+        ]
+    );
+
+    // failing shadow never affects the client
+    service.mirror.as_mut().unwrap().target = "http://127.0.0.1:1/".into();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+    assert_eq!(
+        post(&mut upstream).await,
+        ("primary".into(), b"payload".to_vec())
+    );
+}
+
+#[tokio::test]
+async fn test_timing_headers() {
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.service_id = "test/timing".into();
+    service.upstreams[0].target = format!("http://{}/", named_upstream("timed"));
+    let mut upstream = UpstreamMiddleware::default();
+
+    for timing_headers in [false, true] {
+        service.timing_headers = timing_headers;
+        upstream.config_update(ConfigUpdate::ServiceUpdate(service.clone()));
+        let (task, rx) = task("test/timing");
+        upstream.request(task).await;
+        let resp = match rx.await.unwrap() {
+            Ok(MwPreResponse {
+                next: MwNextAction::Return(resp),
+                ..
+            }) => resp,
+            other => panic!("unexpected result {:?}", other),
+        };
+        let header = resp.headers().get("x-upstream-time-ms");
+        assert_eq!(header.is_some(), timing_headers);
+        assert_eq!(
+            resp.extensions().get::<UpstreamTime>().is_some(),
+            timing_headers
+        );
+        if let Some(ms) = header {
+            ms.to_str().unwrap().parse::<u64>().unwrap();
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_deadline_caps_upstream_timeout() {
+    let make_svc = hyper::service::make_service_fn(|_| async {
+        Ok::<_, std::convert::Infallible>(hyper::service::service_fn(|_req| async {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            Ok::<_, std::convert::Infallible>(hyper::Response::new(Body::empty()))
+        }))
+    });
+    let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+    let slow = server.local_addr();
+    tokio::spawn(server);
+
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.service_id = "test/deadline".into();
+    service.upstreams[0].target = format!("http://{}/", slow);
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+
+    // service timeout is 3s, the deadline leaves 300ms
+    let start = std::time::Instant::now();
+    let (mut task, rx) = task("test/deadline");
+    let deadline = Deadline(start + Duration::from_millis(300));
+    task.request.extensions_mut().insert(deadline);
+    upstream.request(task).await;
+    assert!(matches!(rx.await.unwrap(), Err(GatewayError::TimeoutError)));
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn test_abandoned_request_skipped() {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.service_id = "test/abandoned".into();
+    service.upstreams[0].target = format!("http://{}/", recording_upstream("primary", tx));
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+
+    // client is gone before the worker picks the request
+    let (mut abandoned, result) = task("test/abandoned");
+    *abandoned.request.uri_mut() = "/drain/abandoned".parse().unwrap();
+    drop(result);
+    upstream.request(abandoned).await;
+    let (waiting, result) = task("test/abandoned");
+    upstream.request(waiting).await;
+    assert!(result.await.unwrap().is_ok());
+    let (_, path, _) = rx.recv().await.unwrap();
+    assert_eq!(path, "/");
+    assert!(rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_retry_budget() {
+    let make_svc = hyper::service::make_service_fn(|_| async {
+        Ok::<_, std::convert::Infallible>(hyper::service::service_fn(|_req| async {
+            let resp = hyper::Response::builder().status(503);
+            Ok::<_, std::convert::Infallible>(resp.body(Body::empty()).unwrap())
+        }))
+    });
+    let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+    let unavailable = server.local_addr();
+    tokio::spawn(server);
+
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.service_id = "test/retry".into();
+    service.load_balance = "round_robin".into();
+    let mut bad = service.upstreams[0].clone();
+    bad.id = "bad".into();
+    bad.target = format!("http://{}/", unavailable);
+    let mut good = service.upstreams[0].clone();
+    good.id = "good".into();
+    good.target = format!("http://{}/", named_upstream("good"));
+    service.upstreams = vec![bad, good];
+
+    async fn statuses(upstream: &mut UpstreamMiddleware) -> Vec<u16> {
+        let mut statuses = Vec::new();
+        for _ in 0..10 {
+            let (task, rx) = task("test/retry");
+            upstream.request(task).await;
+            match rx.await.unwrap() {
+                Ok(MwPreResponse {
+                    next: MwNextAction::Return(resp),
+                    ..
+                }) => statuses.push(resp.status().as_u16()),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+        statuses
+    }
+
+    // every request failing on the bad upstream is retried on the good one
+    service.retry = serde_yaml::from_str("attempts: 1").unwrap();
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service.clone()));
+    assert!(statuses(&mut upstream).await.iter().all(|s| *s == 200));
+
+    // no budget, failures answered without retry
+    service.retry =
+        serde_yaml::from_str("{attempts: 1, budget_percent: 0, min_retries: 0}").unwrap();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+    let failed = statuses(&mut upstream)
+        .await
+        .iter()
+        .filter(|s| **s == 503)
+        .count();
+    assert_eq!(failed, 5);
+}
+
+// https upstream with a certificate of tests/tls/ca.pem, requiring client certificates of `client_ca`
+fn tls_upstream(client_ca: Option<&[u8]>) -> std::net::SocketAddr {
+    let mut builder = hyperapi::proxy::TlsConfigBuilder::new()
+        .cert(include_bytes!("tls/server.pem"))
+        .key(include_bytes!("tls/server.key"));
+    if let Some(ca) = client_ca {
+        builder = builder.client_auth_required(ca);
+    }
+    let config = builder.build().unwrap();
+    let incoming =
+        hyper::server::conn::AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = incoming.local_addr();
+    let make_svc = hyper::service::make_service_fn(|_| async {
+        Ok::<_, std::convert::Infallible>(hyper::service::service_fn(|_req| async {
+            Ok::<_, std::convert::Infallible>(hyper::Response::new(Body::from("pong")))
+        }))
+    });
+    let acceptor = hyperapi::proxy::TlsAcceptor::new(config, incoming);
+    tokio::spawn(hyper::Server::builder(acceptor).serve(make_svc));
+    addr
+}
+
+#[tokio::test]
+async fn test_upstream_ca_file() {
+    let addr = tls_upstream(None);
+    let ca_file = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls/ca.pem");
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.service_id = "test/upstream_tls".into();
+    service.upstreams[0].target = format!("https://localhost:{}/", addr.port());
+    let mut upstream = UpstreamMiddleware::default();
+
+    let mut trusted = service.clone();
+    trusted.upstreams[0].ca_file = Some(ca_file.into());
+    let mut insecure = service.clone();
+    insecure.upstreams[0].insecure_skip_verify = true;
+    // certificate is not signed by a default root
+    for (service, verified) in [(service.clone(), false), (trusted, true), (insecure, true)] {
+        upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+        let (task, rx) = task("test/upstream_tls");
+        upstream.request(task).await;
+        match rx.await.unwrap() {
+            Ok(MwPreResponse {
+                next: MwNextAction::Return(resp),
+                ..
+            }) => {
+                assert!(verified, "unexpected response {:?}", resp);
+                let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+                assert_eq!(&body[..], b"pong");
+            }
+            other => assert!(!verified, "unexpected result {:?}", other),
+        }
+    }
+
+    // gateway CA bundle trusted by upstreams without their own ca_file
+    let roots = hyperapi::middleware::load_ca_file(ca_file).unwrap();
+    let mut upstream = UpstreamMiddleware::new(None, Some(roots));
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+    let (task, rx) = task("test/upstream_tls");
+    upstream.request(task).await;
+    match rx.await.unwrap() {
+        Ok(MwPreResponse {
+            next: MwNextAction::Return(resp),
+            ..
+        }) => {
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            assert_eq!(&body[..], b"pong");
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    let mut missing: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    missing.upstreams[0].ca_file = Some("/nonexistent/ca.pem".into());
+    assert!(matches!(
+        hyperapi::config::validate_service(&missing),
+        Err(hyperapi::config::ConfigError::InvalidCaFile(..))
+    ));
+}
+
+#[tokio::test]
+async fn test_upstream_tls_server_name() {
+    let addr = tls_upstream(None);
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.service_id = "test/upstream_sni".into();
+    service.upstreams[0].target = format!("https://127.0.0.1:{}/", addr.port());
+    service.upstreams[0].ca_file =
+        Some(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls/ca.pem").into());
+    let mut upstream = UpstreamMiddleware::default();
+
+    let mut named = service.clone();
+    named.upstreams[0].tls_server_name = Some("localhost".into());
+    hyperapi::config::validate_service(&named).unwrap();
+    let mut wrong = service.clone();
+    wrong.upstreams[0].tls_server_name = Some("example.com".into());
+    // certificate is for localhost, an IP target can't be verified without the name
+    for (service, verified) in [(service, false), (named.clone(), true), (wrong, false)] {
+        upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+        let (task, rx) = task("test/upstream_sni");
+        upstream.request(task).await;
+        let result = rx.await.unwrap();
+        let returned = matches!(
+            &result,
+            Ok(MwPreResponse {
+                next: MwNextAction::Return(_),
+                ..
+            })
+        );
+        assert_eq!(returned, verified, "unexpected result {:?}", result);
+    }
+
+    let mut plain = named.clone();
+    plain.upstreams[0].target = "http://127.0.0.1:1/".into();
+    assert!(matches!(
+        hyperapi::config::validate_service(&plain),
+        Err(hyperapi::config::ConfigError::InvalidServerName(..))
+    ));
+    named.upstreams[0].tls_server_name = Some("not a name".into());
+    assert!(matches!(
+        hyperapi::config::validate_service(&named),
+        Err(hyperapi::config::ConfigError::InvalidServerName(..))
+    ));
+}
+
+#[tokio::test]
+async fn test_upstream_scheme() {
+    let addr = tls_upstream(None);
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.service_id = "test/upstream_scheme".into();
+    service.upstreams[0].target = format!("http://localhost:{}/", addr.port());
+    service.upstreams[0].ca_file =
+        Some(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls/ca.pem").into());
+    let mut upstream = UpstreamMiddleware::default();
+
+    let mut forced = service.clone();
+    forced.upstream_scheme = Some(UpstreamScheme::Https);
+    hyperapi::config::validate_service(&forced).unwrap();
+    // the upstream's own scheme wins over the service's
+    let mut downgraded = forced.clone();
+    downgraded.upstreams[0].scheme = Some(UpstreamScheme::Http);
+    for (service, tls) in [(service, false), (forced, true), (downgraded, false)] {
+        upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+        let (task, rx) = task("test/upstream_scheme");
+        upstream.request(task).await;
+        match rx.await.unwrap() {
+            Ok(MwPreResponse {
+                next: MwNextAction::Return(resp),
+                ..
+            }) => {
+                assert!(tls, "unexpected response {:?}", resp);
+                let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+                assert_eq!(&body[..], b"pong");
+            }
+            other => assert!(!tls, "unexpected result {:?}", other),
+        }
+    }
+
+    assert_eq!(
+        UpstreamScheme::Https.apply("http://api:8080/v1"),
+        "https://api:8080/v1"
+    );
+    let mut plain_port: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    plain_port.upstreams[0].target = "http://api:80/".into();
+    plain_port.upstreams[0].scheme = Some(UpstreamScheme::Https);
+    assert!(matches!(
+        hyperapi::config::validate_service(&plain_port),
+        Err(hyperapi::config::ConfigError::InvalidScheme(..))
+    ));
+}
+
+#[tokio::test]
+async fn test_upstream_client_cert() {
+    let addr = tls_upstream(Some(include_bytes!("tls/client.pem")));
+    let tls_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls");
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.service_id = "test/upstream_mtls".into();
+    service.upstreams[0].target = format!("https://localhost:{}/", addr.port());
+    service.upstreams[0].ca_file = Some(format!("{}/ca.pem", tls_dir));
+    let mut upstream = UpstreamMiddleware::default();
+
+    let mut identified = service.clone();
+    identified.upstreams[0].client_cert = Some(format!("{}/client.pem", tls_dir));
+    identified.upstreams[0].client_key = Some(format!("{}/client.key", tls_dir));
+    hyperapi::config::validate_service(&identified).unwrap();
+    // handshake fails without a client certificate
+    for (service, accepted) in [(service, false), (identified.clone(), true)] {
+        upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+        let (task, rx) = task("test/upstream_mtls");
+        upstream.request(task).await;
+        let result = rx.await.unwrap();
+        let returned = matches!(
+            &result,
+            Ok(MwPreResponse {
+                next: MwNextAction::Return(_),
+                ..
+            })
+        );
+        assert_eq!(returned, accepted, "unexpected result {:?}", result);
+    }
+
+    let mut keyless = identified.clone();
+    keyless.upstreams[0].client_key = None;
+    assert!(matches!(
+        hyperapi::config::validate_service(&keyless),
+        Err(hyperapi::config::ConfigError::InvalidClientCert(..))
+    ));
+    let mut bad_key = identified;
+    bad_key.upstreams[0].client_key = Some(format!("{}/client.pem", tls_dir));
+    assert!(matches!(
+        hyperapi::config::validate_service(&bad_key),
+        Err(hyperapi::config::ConfigError::InvalidClientCert(..))
+    ));
+}
+
+fn connection_uses(service_id: &str, connection: &str) -> u64 {
+    prometheus::gather()
+        .iter()
+        .filter(|family| family.get_name() == "gateway_upstream_connection_uses_total")
+        .flat_map(|family| family.get_metric())
+        .filter(|m| {
+            let labels = m.get_label();
+            labels.iter().any(|l| l.get_value() == service_id)
+                && labels.iter().any(|l| l.get_value() == connection)
+        })
+        .map(|m| m.get_counter().get_value() as u64)
+        .sum()
+}
+
+#[tokio::test]
+async fn test_connection_reuse_metrics() {
+    let addr = named_upstream("pooled");
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.upstreams[0].target = format!("http://{}/", addr);
+    let mut pooled = service.clone();
+    pooled.service_id = "test/pooled".into();
+    let mut unpooled = service;
+    unpooled.service_id = "test/unpooled".into();
+    unpooled.upstreams[0].pool_max_idle_per_host = Some(0);
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(pooled));
+    upstream.config_update(ConfigUpdate::ServiceUpdate(unpooled));
+
+    for service_id in ["test/pooled", "test/unpooled"] {
+        for _ in 0..3 {
+            let (task, rx) = task(service_id);
+            upstream.request(task).await;
+            match rx.await.unwrap() {
+                Ok(MwPreResponse {
+                    next: MwNextAction::Return(resp),
+                    ..
+                }) => {
+                    hyper::body::to_bytes(resp.into_body()).await.unwrap();
+                }
+                other => panic!("unexpected result {:?}", other),
+            }
+            // connection goes back to the pool after the response is complete
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+    assert_eq!(connection_uses("test/pooled", "new"), 1);
+    assert_eq!(connection_uses("test/pooled", "reused"), 2);
+    assert_eq!(connection_uses("test/unpooled", "new"), 3);
+    assert_eq!(connection_uses("test/unpooled", "reused"), 0);
+}
+
+#[tokio::test]
+async fn test_slow_start_added_upstream() {
+    let mut service = split_service();
+    let canary = service.upstreams.pop().unwrap();
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service.clone()));
+
+    async fn canary_picks(upstream: &mut UpstreamMiddleware) -> usize {
+        let mut picks = 0;
+        for _ in 0..300 {
+            if upstream_of(upstream, "", &[]).await.unwrap() == "canary" {
+                picks += 1;
+            }
+        }
+        picks
+    }
+    // added upstream starts at 10% of its weight
+    service.upstreams.push(canary);
+    service.slow_start = 60;
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service.clone()));
+    let picks = canary_picks(&mut upstream).await;
+    assert!((1..80).contains(&picks), "{}", picks);
+
+    // upstreams of the previous config are warm
+    service.slow_start = 30;
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+    let picks = canary_picks(&mut upstream).await;
+    assert!(picks > 100, "{}", picks);
+}
+
+#[tokio::test]
+async fn test_maintenance() {
+    let mut service = split_service();
+    service.upstreams.pop();
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service.clone()));
+    let queue = upstream.worker_queues["test/split"].clone();
+
+    service.maintenance = true;
+    service.maintenance_page = Some(MaintenancePage {
+        retry_after: 120,
+        content_type: String::new(),
+        body: "<h1>Back soon</h1>".into(),
+    });
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service.clone()));
+    let (task, rx) = task("test/split");
+    upstream.request(task).await;
+    let resp = match rx.await.unwrap() {
+        Ok(MwPreResponse {
+            next: MwNextAction::Return(resp),
+            ..
+        }) => resp,
+        other => panic!("unexpected result {:?}", other),
+    };
+    assert_eq!(resp.status(), 503);
+    assert_eq!(resp.headers()["retry-after"], "120");
+    assert_eq!(resp.headers()["content-type"], "text/html");
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(&body[..], b"<h1>Back soon</h1>");
+
+    service.maintenance_page = None;
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service.clone()));
+    let result = upstream_of(&mut upstream, "", &[]).await;
+    assert!(matches!(result, Err(GatewayError::ServiceNotReady(_))));
+
+    service.maintenance = false;
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+    assert_eq!(upstream_of(&mut upstream, "", &[]).await.unwrap(), "stable");
+    // switched without restarting the worker
+    assert!(upstream.worker_queues["test/split"].same_channel(&queue));
+}
+
+#[tokio::test]
+async fn test_body_limit() {
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.service_id = "test/body_limit".into();
+    service.upstreams[0].target = format!("http://{}/", recording_upstream("primary", tx));
+    service.body_limit = serde_yaml::from_str(
+        "{max_body_bytes: 8, content_types: {application/json: 16, image/*: 32}}",
+    )
+    .unwrap();
+    let mut upstream = UpstreamMiddleware::default();
+    hyperapi::config::validate_service(&service).unwrap();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service.clone()));
+
+    // status of a POST, 0 if proxied, chunked without content-length
+    async fn post(
+        upstream: &mut UpstreamMiddleware,
+        content_type: &str,
+        size: usize,
+        chunked: bool,
+    ) -> u16 {
+        let (mut task, rx) = task("test/body_limit");
+        *task.request.method_mut() = hyper::Method::POST;
+        let headers = task.request.headers_mut();
+        headers.insert("content-type", content_type.parse().unwrap());
+        *task.request.body_mut() = if chunked {
+            let chunks = vec![
+                Ok::<_, std::io::Error>(vec![b'x'; size / 2]),
+                Ok(vec![b'x'; size - size / 2]),
+            ];
+            Body::wrap_stream(futures::stream::iter(chunks))
+        } else {
+            task.request
+                .headers_mut()
+                .insert("content-length", size.into());
+            Body::from(vec![b'x'; size])
+        };
+        upstream.request(task).await;
+        match rx.await.unwrap() {
+            Ok(_) => 0,
+            Err(e) => e.response(false, false).status().as_u16(),
+        }
+    }
+
+    for chunked in [false, true] {
+        assert_eq!(post(&mut upstream, "text/plain", 8, chunked).await, 0);
+        assert_eq!(post(&mut upstream, "text/plain", 9, chunked).await, 413);
+        assert_eq!(
+            post(
+                &mut upstream,
+                "application/json; charset=utf-8",
+                16,
+                chunked
+            )
+            .await,
+            0
+        );
+        assert_eq!(
+            post(&mut upstream, "application/json", 17, chunked).await,
+            413
+        );
+        assert_eq!(post(&mut upstream, "image/png", 32, chunked).await, 0);
+        assert_eq!(post(&mut upstream, "image/png", 33, chunked).await, 413);
+    }
+
+    service.body_limit = serde_yaml::from_str("{content_types: {json: 16}}").unwrap();
+    assert!(matches!(
+        hyperapi::config::validate_service(&service),
+        Err(hyperapi::config::ConfigError::InvalidBodyLimit(..))
+    ));
+}
+
+// upstream counting its calls, slow enough for requests to pile up, 503 on the first /flaky call
+fn counting_upstream(
+    calls: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+) -> std::net::SocketAddr {
+    let make_svc = hyper::service::make_service_fn(move |_| {
+        let calls = calls.clone();
+        async move {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(
+                move |req: Request<Body>| {
+                    let n = calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+                    async move {
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+                        let status = match req.uri().path() {
+                            "/flaky" if n == 1 => 503,
+                            _ => 200,
+                        };
+                        let resp = hyper::Response::builder().status(status);
+                        Ok::<_, std::convert::Infallible>(
+                            resp.body(Body::from(n.to_string())).unwrap(),
+                        )
+                    }
+                },
+            ))
+        }
+    });
+    let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+// status and body of identical requests sent at once
+async fn concurrent_calls(
+    upstream: &mut UpstreamMiddleware,
+    path: &str,
+    accepts: &[&str],
+) -> Vec<(u16, String)> {
+    let mut results = Vec::new();
+    for accept in accepts {
+        let (mut task, rx) = task("test/coalesce");
+        *task.request.uri_mut() = format!("/drain{}", path).parse().unwrap();
+        task.request
+            .headers_mut()
+            .insert("accept", accept.parse().unwrap());
+        upstream.request(task).await;
+        results.push(rx);
+    }
+    let mut responses = Vec::new();
+    for rx in results {
+        match rx.await.unwrap() {
+            Ok(MwPreResponse {
+                next: MwNextAction::Return(resp),
+                ..
+            }) => {
+                let status = resp.status().as_u16();
+                let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+                responses.push((status, String::from_utf8(body.to_vec()).unwrap()));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+    responses
+}
+
+#[tokio::test]
+async fn test_coalesce() {
+    let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.service_id = "test/coalesce".into();
+    service.upstreams[0].target = format!("http://{}/", counting_upstream(calls.clone()));
+    service.coalesce = Some(CoalesceSetting {
+        vary: vec!["accept".into()],
+        max_body: 0,
+    });
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+
+    let json = "application/json";
+    let responses = concurrent_calls(&mut upstream, "/items", &[json; 5]).await;
+    assert_eq!(responses, vec![(200, "1".to_string()); 5]);
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    // requests differing by a vary header are not shared
+    let responses = concurrent_calls(&mut upstream, "/items", &[json, "text/html"]).await;
+    assert_ne!(responses[0], responses[1]);
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 3);
+
+    // failed leader response is not shared, waiters call upstream on their own
+    calls.store(0, std::sync::atomic::Ordering::SeqCst);
+    let responses = concurrent_calls(&mut upstream, "/flaky", &[json; 3]).await;
+    assert_eq!(responses[0], (503, "1".to_string()));
+    assert!(responses[1..].iter().all(|(status, _)| *status == 200));
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn test_drained_upstream() {
+    for lb in [
+        "random",
+        "round_robin",
+        "weighted_round_robin",
+        "least_conn",
+        "conn",
+        "load",
+        "hash",
+        "consistent_hash",
+    ] {
+        let mut service = split_service();
+        service.load_balance = lb.into();
+        service.upstreams[1].weight = 0;
+        let mut upstream = UpstreamMiddleware::default();
+        upstream.config_update(ConfigUpdate::ServiceUpdate(service.clone()));
+        for _ in 0..20 {
+            assert_eq!(upstream_of(&mut upstream, "", &[]).await.unwrap(), "stable");
+        }
+
+        // taken back, served again
+        service.upstreams[0].weight = 0;
+        service.upstreams[1].weight = 1;
+        upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+        assert_eq!(
+            upstream_of(&mut upstream, "", &[]).await.unwrap(),
+            "canary",
+            "{}",
+            lb
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_drain_completes_inflight() {
+    let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.upstreams[0].target = format!("http://{}/", counting_upstream(calls.clone()));
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service.clone()));
+    let (first, inflight) = task("test/drain");
+    upstream.request(first).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // no upstream left for new requests
+    service.upstreams[0].weight = 0;
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+    let (next, rx) = task("test/drain");
+    upstream.request(next).await;
+    assert!(matches!(
+        rx.await.unwrap(),
+        Err(GatewayError::ServiceNotReady(_))
+    ));
+
+    match inflight.await.unwrap() {
+        Ok(MwPreResponse {
+            next: MwNextAction::Return(resp),
+            ..
+        }) => assert_eq!(resp.status(), 200),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+}
+
+// upstream answering after the delay in milliseconds at the end of path, like /delay/1500
+fn delay_upstream() -> std::net::SocketAddr {
+    let make_svc = hyper::service::make_service_fn(|_| async {
+        Ok::<_, std::convert::Infallible>(hyper::service::service_fn(|req: Request<Body>| {
+            let ms: u64 = req
+                .uri()
+                .path()
+                .rsplit('/')
+                .next()
+                .unwrap()
+                .parse()
+                .unwrap();
+            async move {
+                tokio::time::sleep(Duration::from_millis(ms)).await;
+                Ok::<_, std::convert::Infallible>(hyper::Response::new(Body::empty()))
+            }
+        }))
+    });
+    let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+#[tokio::test]
+async fn test_route_timeout() {
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.service_id = "test/route_timeout".into();
+    service.upstreams[0].target = format!("http://{}/", delay_upstream());
+    service.upstreams[0].request_timeout = Some(1);
+    service.routes = serde_yaml::from_str(
+        r#"
+- path_pattern: /slow/*
+  upstreams: ["1"]
+  timeout: 2
+- path_pattern: /*
+  upstreams: ["1"]
+"#,
+    )
+    .unwrap();
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+
+    // route timeout, upstream timeout without one
+    for (path, ok) in [
+        ("/drain/slow/1500", true),
+        ("/drain/fast/1500", false),
+        ("/drain/fast/100", true),
+        ("/drain/slow/2500", false),
+    ] {
+        let (mut task, rx) = task("test/route_timeout");
+        *task.request.uri_mut() = path.parse().unwrap();
+        upstream.request(task).await;
+        match rx.await.unwrap() {
+            Ok(MwPreResponse {
+                next: MwNextAction::Return(resp),
+                ..
+            }) if ok => assert_eq!(resp.status(), 200),
+            Err(GatewayError::TimeoutError) if !ok => {}
+            other => panic!("{}: unexpected result {:?}", path, other),
+        }
+    }
+}
+
+fn connect_errors(service_id: &str, class: &str) -> u64 {
+    prometheus::gather()
+        .iter()
+        .filter(|f| f.get_name() == "gateway_upstream_connect_errors_total")
+        .flat_map(|f| f.get_metric())
+        .filter(|m| {
+            let labels = m.get_label();
+            labels.iter().any(|l| l.get_value() == service_id)
+                && labels.iter().any(|l| l.get_value() == class)
+        })
+        .map(|m| m.get_counter().get_value() as u64)
+        .sum()
+}
+
+#[tokio::test]
+async fn test_connect_error_class() {
+    let tls = tls_upstream(None);
+    let mut upstream = UpstreamMiddleware::default();
+    for (target, class) in [
+        (
+            "http://127.0.0.1:1/".to_string(),
+            "upstream_connection_refused",
+        ),
+        ("http://nonexistent.invalid/".into(), "upstream_dns_failure"),
+        // certificate is not signed by a default root
+        (
+            format!("https://localhost:{}/", tls.port()),
+            "upstream_tls_handshake_failure",
+        ),
+    ] {
+        let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+        service.service_id = "test/connect_error".into();
+        service.upstreams[0].target = target.clone();
+        upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+        let before = connect_errors("test/connect_error", class);
+
+        let (task, rx) = task("test/connect_error");
+        upstream.request(task).await;
+        let err = rx.await.unwrap().unwrap_err();
+        assert!(
+            matches!(err, GatewayError::UpstreamConnectError(c, _) if c == class),
+            "{}: unexpected error {:?}",
+            target,
+            err
+        );
+        let resp = err.response(false, true);
+        assert_eq!(resp.status(), 502);
+        assert_eq!(resp.headers()[GATEWAY_ERROR_HEADER], class);
+        assert!(!err
+            .response(false, false)
+            .headers()
+            .contains_key(GATEWAY_ERROR_HEADER));
+        assert_eq!(connect_errors("test/connect_error", class), before + 1);
+    }
+}
+
+// address whose connects hang, SYNs are dropped once the accept queue of a listener is full
+// the listener and queued connections are kept open by the caller
+fn unanswered_upstream() -> (
+    (socket2::Socket, Vec<std::net::TcpStream>),
+    std::net::SocketAddr,
+) {
+    use socket2::{Domain, Socket, Type};
+    let listener = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    let addr: std::net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+    listener.bind(&addr.into()).unwrap();
+    listener.listen(0).unwrap();
+    let addr = listener.local_addr().unwrap().as_socket().unwrap();
+    let mut queued = Vec::new();
+    let timeout = Duration::from_millis(200);
+    while let Ok(stream) = std::net::TcpStream::connect_timeout(&addr, timeout) {
+        queued.push(stream);
+    }
+    ((listener, queued), addr)
+}
+
+#[tokio::test]
+async fn test_connect_timeout() {
+    let (_open, addr) = unanswered_upstream();
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.service_id = "test/connect_timeout".into();
+    service.upstreams[0].target = format!("http://{}/", addr);
+    service.upstreams[0].connect_timeout = Some(1);
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+
+    // fails at the connect timeout, before the service timeout of 3 seconds
+    let start = std::time::Instant::now();
+    let (task, rx) = task("test/connect_timeout");
+    upstream.request(task).await;
+    let err = rx.await.unwrap().unwrap_err();
+    let elapsed = start.elapsed();
+    assert!(
+        matches!(
+            err,
+            GatewayError::TimeoutError | GatewayError::UpstreamConnectError(..)
+        ),
+        "unexpected error {:?}",
+        err
+    );
+    assert!(
+        elapsed >= Duration::from_millis(900) && elapsed < Duration::from_millis(2500),
+        "{:?}",
+        elapsed
+    );
+}
+
+// service whose only upstream refuses connections, circuit opens on the second error
+fn broken_service(service_id: &str, failover: &str) -> ServiceInfo {
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.service_id = service_id.into();
+    service.upstreams[0].error_threshold = 1;
+    service.upstreams[0].retry_delay = 60;
+    service.failover_service_id = Some(failover.into());
+    service
+}
+
+async fn call(upstream: &mut UpstreamMiddleware, service_id: &str) -> Result<String, GatewayError> {
+    let (request, rx) = task(service_id);
+    upstream.request(request).await;
+    let result = tokio::time::timeout(Duration::from_secs(3), rx)
+        .await
+        .expect("request is not answered")
+        .unwrap();
+    match result? {
+        MwPreResponse {
+            next: MwNextAction::Return(resp),
+            ..
+        } => Ok(resp.headers()["upstream-name"].to_str().unwrap().into()),
+        _ => panic!("upstream returned no response"),
+    }
+}
+
+#[tokio::test]
+async fn test_failover_service() {
+    let mut backup: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    backup.service_id = "test/backup".into();
+    backup.upstreams[0].target = format!("http://{}/", named_upstream("backup"));
+    let primary = broken_service("test/failover", "test/backup");
+    hyperapi::config::validate_service(&primary).unwrap();
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(backup));
+    upstream.config_update(ConfigUpdate::ServiceUpdate(primary));
+
+    // upstream is tried until its circuit opens
+    for _ in 0..2 {
+        let err = call(&mut upstream, "test/failover").await.unwrap_err();
+        assert!(matches!(err, GatewayError::UpstreamConnectError(..)));
+    }
+    for _ in 0..3 {
+        assert_eq!(
+            call(&mut upstream, "test/failover").await.unwrap(),
+            "backup"
+        );
+    }
+
+    let mut itself = broken_service("test/failover", "test/failover");
+    assert!(matches!(
+        hyperapi::config::validate_service(&itself),
+        Err(hyperapi::config::ConfigError::InvalidFailover(..))
+    ));
+    itself.failover_service_id = None;
+    hyperapi::config::validate_service(&itself).unwrap();
+}
+
+#[tokio::test]
+async fn test_failover_loop() {
+    let mut upstream = UpstreamMiddleware::default();
+    for (service_id, failover) in [
+        ("test/loop_a", "test/loop_b"),
+        ("test/loop_b", "test/loop_a"),
+    ] {
+        upstream.config_update(ConfigUpdate::ServiceUpdate(broken_service(
+            service_id, failover,
+        )));
+        for _ in 0..2 {
+            let err = call(&mut upstream, service_id).await.unwrap_err();
+            assert!(matches!(err, GatewayError::UpstreamConnectError(..)));
+        }
+    }
+
+    // a to b, b doesn't go back to a
+    for service_id in ["test/loop_a", "test/loop_b"] {
+        let err = call(&mut upstream, service_id).await.unwrap_err();
+        assert!(matches!(err, GatewayError::CircuitOpen(_)), "{:?}", err);
+    }
+}
+
+#[tokio::test]
+async fn test_circuit_open_retry_after() {
+    let mut service = broken_service("test/circuit_open", "test/none");
+    service.failover_service_id = None;
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+    for _ in 0..2 {
+        let err = call(&mut upstream, "test/circuit_open").await.unwrap_err();
+        assert!(matches!(err, GatewayError::UpstreamConnectError(..)));
+    }
+
+    // answered right away with the rest of the retry delay
+    let err = call(&mut upstream, "test/circuit_open").await.unwrap_err();
+    assert!(
+        matches!(err, GatewayError::CircuitOpen(59..=60)),
+        "{:?}",
+        err
+    );
+    let resp = err.response(false, false);
+    assert_eq!(resp.status(), 503);
+    let retry_after: u64 = resp.headers()["retry-after"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((59..=60).contains(&retry_after));
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("\"circuit_open\""));
+}
+
+#[tokio::test]
+async fn test_worker_panic_rejects_requests() {
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.service_id = "test/panic".into();
+    // more permits than a semaphore holds, building the worker panics
+    service.upstreams[0].max_conn = u64::MAX;
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service.clone()));
+
+    let (request, rx) = task("test/panic");
+    upstream.request(request).await;
+    let result = tokio::time::timeout(Duration::from_secs(1), rx)
+        .await
+        .expect("queued request is not answered")
+        .unwrap();
+    assert!(matches!(result, Err(GatewayError::ServiceNotFound(_))));
+    // dead queue is removed
+    let err = call(&mut upstream, "test/panic").await.unwrap_err();
+    assert!(matches!(err, GatewayError::ServiceNotFound(_)));
+    assert!(!UpstreamMiddleware::worker_ids().contains(&"test/panic".to_string()));
+
+    // a fixed config starts a new worker
+    service.upstreams[0].max_conn = 10;
+    service.upstreams[0].target = format!("http://{}/", named_upstream("fixed"));
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+    assert_eq!(call(&mut upstream, "test/panic").await.unwrap(), "fixed");
+}
+
+fn service_gauge(name: &str, service_id: &str) -> i64 {
+    prometheus::gather()
+        .iter()
+        .filter(|family| family.get_name() == name)
+        .flat_map(|family| family.get_metric())
+        .filter(|m| m.get_label().iter().any(|l| l.get_value() == service_id))
+        .map(|m| m.get_gauge().get_value() as i64)
+        .sum()
+}
+
+#[tokio::test]
+async fn test_max_proxy_tasks() {
+    let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.service_id = "test/proxy_tasks".into();
+    service.upstreams[0].target = format!("http://{}/", counting_upstream(calls.clone()));
+    service.max_proxy_tasks = 1;
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+
+    let mut results = Vec::new();
+    for _ in 0..3 {
+        let (task, rx) = task("test/proxy_tasks");
+        upstream.request(task).await;
+        results.push(rx);
+    }
+    // one call at a time, the others wait in the queue
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let tasks = "gateway_service_proxy_tasks";
+    assert_eq!(service_gauge(tasks, "test/proxy_tasks"), 1);
+    let queued = "gateway_service_queued_requests";
+    assert_eq!(service_gauge(queued, "test/proxy_tasks"), 2);
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    for rx in results {
+        match rx.await.unwrap() {
+            Ok(MwPreResponse {
+                next: MwNextAction::Return(resp),
+                ..
+            }) => assert_eq!(resp.status(), 200),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(service_gauge(tasks, "test/proxy_tasks"), 0);
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn test_max_proxy_tasks_removal() {
+    let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let mut service: ServiceInfo = serde_yaml::from_str(SERVICE).unwrap();
+    service.service_id = "test/proxy_tasks_removed".into();
+    service.upstreams[0].target = format!("http://{}/", counting_upstream(calls.clone()));
+    service.max_proxy_tasks = 1;
+    let service_id = service.service_id.clone();
+    let mut upstream = UpstreamMiddleware::default();
+    upstream.config_update(ConfigUpdate::ServiceUpdate(service));
+
+    let mut results = Vec::new();
+    for _ in 0..3 {
+        let (task, rx) = task(&service_id);
+        upstream.request(task).await;
+        results.push(rx);
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    // removal is seen at the cap, before the call in flight completes
+    upstream.config_update(ConfigUpdate::ServiceRemove(service_id));
+    for rx in results.split_off(1) {
+        let result = tokio::time::timeout(Duration::from_millis(100), rx)
+            .await
+            .expect("queued request is not answered at the cap")
+            .unwrap();
+        assert!(matches!(result, Err(GatewayError::ServiceNotFound(_))));
+    }
+    assert!(results.pop().unwrap().await.unwrap().is_ok());
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+}